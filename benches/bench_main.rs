@@ -6,11 +6,13 @@ extern crate rand;
 use std::thread;
 use std::sync::Arc;
 use bencher::Bencher;
-use cache::cache::LRUCache;
+use cache::array::ArrayLRUCache;
+use cache::cache::{LRUCache, LockStrategy, RecencyMode};
+use cache::sharded::ShardedLRUCache;
 use rand::prelude::*;
 
 fn bench_insert(b: &mut Bencher) {
-    let mut cache: LRUCache<u64, u64> = LRUCache::new(128);
+    let cache: LRUCache<u64, u64> = LRUCache::new(128);
     let mut idx = 0;
     b.iter(|| {
         cache.put(idx, idx);
@@ -19,7 +21,7 @@ fn bench_insert(b: &mut Bencher) {
 }
 
 fn bench_read(b: &mut Bencher) {
-    let mut cache: LRUCache<u64, u64> = LRUCache::new(4096);
+    let cache: LRUCache<u64, u64> = LRUCache::new(4096);
     let mut idx = 0;
 
     for idx in 0..4096 {
@@ -32,9 +34,84 @@ fn bench_read(b: &mut Bencher) {
     });
 }
 
+fn bench_read_hits(b: &mut Bencher) {
+    read_hits(b, RecencyMode::Exact);
+}
+
+fn bench_read_hits_buffered(b: &mut Bencher) {
+    read_hits(b, RecencyMode::Buffered);
+}
+
+fn read_hits(b: &mut Bencher, recency: RecencyMode) {
+    let cap = 4096;
+    let mut cache: LRUCache<u64, u64> = LRUCache::new(cap);
+    cache.set_recency_mode(recency);
+
+    for idx in 0..cap {
+        cache.put(idx as u64, idx as u64);
+    }
+
+    let mut rng = rand::thread_rng();
+    b.iter(|| {
+        let key = rng.gen_range(0, cap as u64);
+        cache.get(&key);
+    });
+}
+
+fn bench_small_read_hits(b: &mut Bencher) {
+    let cache: LRUCache<u64, u64> = LRUCache::new(16);
+    for idx in 0..16 {
+        cache.put(idx, idx);
+    }
+
+    let mut rng = rand::thread_rng();
+    b.iter(|| {
+        let key = rng.gen_range(0, 16);
+        cache.get(&key)
+    });
+}
+
+fn bench_array_read_hits(b: &mut Bencher) {
+    let mut cache: ArrayLRUCache<u64, u64, 16> = ArrayLRUCache::new();
+    for idx in 0..16 {
+        cache.put(idx, idx);
+    }
+
+    let mut rng = rand::thread_rng();
+    b.iter(|| {
+        let key = rng.gen_range(0, 16);
+        cache.get(&key).copied()
+    });
+}
+
+fn bench_array_churn(b: &mut Bencher) {
+    let mut cache: ArrayLRUCache<u64, u64, 16> = ArrayLRUCache::new();
+
+    let mut rng = rand::thread_rng();
+    b.iter(|| {
+        let key = rng.gen_range(0, 32);
+        if cache.get(&key).is_none() {
+            cache.put(key, key);
+        }
+    });
+}
+
+fn bench_churn(b: &mut Bencher) {
+    let cap = 4096;
+    let cache: LRUCache<u64, u64> = LRUCache::new(cap);
+
+    let mut rng = rand::thread_rng();
+    b.iter(|| {
+        let key = rng.gen_range(0, 2 * cap as u64);
+        if cache.get(&key).is_none() {
+            cache.put(key, key);
+        }
+    });
+}
+
 fn bench_threads(b: &mut Bencher) {
     let cap = 128;
-    let mut cache: LRUCache<u64, u64> = LRUCache::new(cap);
+    let cache: LRUCache<u64, u64> = LRUCache::new(cap);
 
     for idx in 0..cap {
         cache.put(idx as u64, idx as u64);
@@ -43,21 +120,21 @@ fn bench_threads(b: &mut Bencher) {
     let cache = Arc::new(cache);
     b.iter(|| {
 
-        let cacheA = Arc::clone(&cache);
+        let cache_a = Arc::clone(&cache);
         let thread1 = thread::spawn(move || {
             let mut rng = rand::thread_rng();
             for _ in 0..1000 {
                 let val: u64 = rng.gen();
-                cacheA.get(&val);
+                cache_a.get(&val);
             }
         });
 
-        let cacheB = Arc::clone(&cache);
+        let cache_b = Arc::clone(&cache);
         let thread2 = thread::spawn(move || {
             let mut rng = rand::thread_rng();
             for _ in 0..1000 {
                 let val: u64 = rng.gen();
-                cacheB.get(&val);
+                cache_b.get(&val);
             }
         });
 
@@ -66,6 +143,115 @@ fn bench_threads(b: &mut Bencher) {
     })
 }
 
+fn bench_sharded_threads(b: &mut Bencher) {
+    let cap = 128;
+    let cache: ShardedLRUCache<u64, u64> = ShardedLRUCache::new(cap, 8);
+
+    for idx in 0..cap {
+        cache.put(idx as u64, idx as u64);
+    }
+
+    let cache = Arc::new(cache);
+    b.iter(|| {
+        let threads: Vec<_> = (0..2).map(|_| {
+            let cache = Arc::clone(&cache);
+            thread::spawn(move || {
+                let mut rng = rand::thread_rng();
+                for _ in 0..1000 {
+                    let val: u64 = rng.gen();
+                    cache.get(&val);
+                }
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    })
+}
+
+/// Have 4 threads each perform 1000 operations on a full cache locked with `strategy`, putting
+/// once for every `reads_per_write` gets.
+fn bench_lock(b: &mut Bencher, strategy: LockStrategy, reads_per_write: u64) {
+    let cap = 4096;
+    let cache: LRUCache<u64, u64> = LRUCache::builder()
+        .capacity(cap)
+        .lock_strategy(strategy)
+        .build()
+        .unwrap();
+
+    for idx in 0..cap {
+        cache.put(idx as u64, idx as u64);
+    }
+
+    let cache = Arc::new(cache);
+    b.iter(|| {
+        let threads: Vec<_> = (0..4).map(|_| {
+            let cache = Arc::clone(&cache);
+            thread::spawn(move || {
+                let mut rng = rand::thread_rng();
+                for op in 0..1000 {
+                    let key = rng.gen_range(0, cap as u64);
+                    if op % (reads_per_write + 1) == 0 {
+                        cache.put(key, key);
+                    } else {
+                        cache.get(&key);
+                    }
+                }
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    })
+}
+
+fn bench_read_heavy_mutex(b: &mut Bencher) {
+    bench_lock(b, LockStrategy::Mutex, 19);
+}
+
+fn bench_read_heavy_rw_lock(b: &mut Bencher) {
+    bench_lock(b, LockStrategy::RwLock, 19);
+}
+
+fn bench_read_heavy_spin(b: &mut Bencher) {
+    bench_lock(b, LockStrategy::Spin, 19);
+}
+
+fn bench_write_heavy_mutex(b: &mut Bencher) {
+    bench_lock(b, LockStrategy::Mutex, 0);
+}
+
+fn bench_write_heavy_rw_lock(b: &mut Bencher) {
+    bench_lock(b, LockStrategy::RwLock, 0);
+}
+
+fn bench_write_heavy_spin(b: &mut Bencher) {
+    bench_lock(b, LockStrategy::Spin, 0);
+}
+
+#[cfg(feature = "parking_lot")]
+fn bench_read_heavy_parking_lot(b: &mut Bencher) {
+    bench_lock(b, LockStrategy::ParkingLot, 19);
+}
+
+#[cfg(feature = "parking_lot")]
+fn bench_write_heavy_parking_lot(b: &mut Bencher) {
+    bench_lock(b, LockStrategy::ParkingLot, 0);
+}
+
+benchmark_group!(benches, bench_insert, bench_read, bench_read_hits, bench_read_hits_buffered,
+                 bench_churn, bench_threads, bench_sharded_threads);
+benchmark_group!(array_benches, bench_small_read_hits, bench_array_read_hits, bench_array_churn);
+benchmark_group!(lock_benches, bench_read_heavy_mutex, bench_read_heavy_rw_lock,
+                 bench_read_heavy_spin, bench_write_heavy_mutex, bench_write_heavy_rw_lock,
+                 bench_write_heavy_spin);
+#[cfg(feature = "parking_lot")]
+benchmark_group!(parking_lot_benches, bench_read_heavy_parking_lot,
+                 bench_write_heavy_parking_lot);
 
-benchmark_group!(benches, bench_insert, bench_read, bench_threads);
-benchmark_main!(benches);
+#[cfg(not(feature = "parking_lot"))]
+benchmark_main!(benches, array_benches, lock_benches);
+#[cfg(feature = "parking_lot")]
+benchmark_main!(benches, array_benches, lock_benches, parking_lot_benches);
@@ -0,0 +1,30 @@
+//! Key distributions shared by the benchmarks.
+
+use rand::Rng;
+
+/// Draws ranks in `0..n` from a zipfian distribution by binary search of its cumulative
+/// distribution.
+pub struct Zipf {
+    cumulative: Vec<f64>
+}
+
+impl Zipf {
+    /// The `n`th most popular rank is drawn in proportion to `1/n^exponent`.
+    pub fn new(n: u64, exponent: f64) -> Zipf {
+        let mut total = 0.0;
+        let mut cumulative: Vec<f64> = (1..=n).map(|rank| {
+            total += 1.0 / (rank as f64).powf(exponent);
+            total
+        }).collect();
+        for probability in cumulative.iter_mut() {
+            *probability /= total;
+        }
+        Zipf { cumulative }
+    }
+
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> u64 {
+        let point: f64 = rng.gen();
+        let rank = self.cumulative.partition_point(|&probability| probability < point);
+        rank.min(self.cumulative.len() - 1) as u64
+    }
+}
@@ -0,0 +1,162 @@
+//! Runs identical workloads against this crate's caches and eviction policies, reporting their
+//! throughput and hit ratios side by side.
+//!
+//! Criterion reports the throughput of each cache in operations per second.  The hit ratio of each
+//! cache on each workload is printed as a table before its benchmarks run.  Run with
+//! `cargo bench --bench comparison`.
+
+extern crate cache;
+extern crate criterion;
+extern crate rand;
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use cache::admission::TinyLfu;
+use cache::cache::{Cache, LRUCache};
+use cache::policy::{EvictionPolicy, Fifo, Lfu, Lirs, Slru};
+use cache::sharded::ShardedLRUCache;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rand::prelude::*;
+use rand::rngs::StdRng;
+
+use common::Zipf;
+
+/// The most values each cache holds.
+const CAPACITY: usize = 4 * 1024;
+/// The number of distinct keys requested, so that each cache holds a sixteenth of them.
+const KEYS: u64 = 16 * CAPACITY as u64;
+/// The number of operations in each workload's trace.
+const OPS: usize = 100_000;
+
+/// A cache under comparison.
+trait Candidate {
+    /// Get `key`, returning whether it was present.
+    fn get(&self, key: u64) -> bool;
+
+    fn put(&self, key: u64);
+}
+
+impl <P: EvictionPolicy<u64>> Candidate for Cache<u64, u64, P> {
+    fn get(&self, key: u64) -> bool {
+        Cache::get(self, &key).is_some()
+    }
+
+    fn put(&self, key: u64) {
+        Cache::put(self, key, key);
+    }
+}
+
+impl Candidate for ShardedLRUCache<u64, u64> {
+    fn get(&self, key: u64) -> bool {
+        ShardedLRUCache::get(self, &key).is_some()
+    }
+
+    fn put(&self, key: u64) {
+        ShardedLRUCache::put(self, key, key);
+    }
+}
+
+/// The caches compared, by name.
+fn candidates() -> Vec<(&'static str, Box<dyn Candidate>)> {
+    let mut tiny_lfu: LRUCache<u64, u64> = LRUCache::new(CAPACITY);
+    tiny_lfu.set_admission_filter(Some(TinyLfu::new(CAPACITY)));
+
+    vec![
+        ("lru", Box::new(LRUCache::<u64, u64>::new(CAPACITY))),
+        ("fifo", Box::new(Cache::<u64, u64, Fifo<u64>>::new(CAPACITY))),
+        ("lfu", Box::new(Cache::<u64, u64, Lfu<u64>>::new(CAPACITY))),
+        ("slru", Box::new(Cache::with_policy(CAPACITY, Slru::<u64>::new(0.8)))),
+        ("lirs", Box::new(Cache::with_policy(CAPACITY, Lirs::<u64>::new(0.99)))),
+        ("tiny_lfu", Box::new(tiny_lfu)),
+        ("sharded", Box::new(ShardedLRUCache::<u64, u64>::new(CAPACITY, 16)))
+    ]
+}
+
+/// An operation of a workload.
+#[derive(Clone, Copy)]
+enum Op {
+    /// Get the key, putting it if it is missing.
+    Read(u64),
+    Write(u64)
+}
+
+/// The traces run against every cache, by name.
+fn workloads() -> Vec<(&'static str, Vec<Op>)> {
+    let mut rng = StdRng::seed_from_u64(1);
+    let zipf = Zipf::new(KEYS, 0.99);
+
+    let zipf_reads = (0..OPS).map(|_| Op::Read(zipf.sample(&mut rng))).collect();
+    let zipf_mixed = (0..OPS).map(|_| {
+        let key = zipf.sample(&mut rng);
+        if rng.gen_range(0, 4) == 0 { Op::Write(key) } else { Op::Read(key) }
+    }).collect();
+    // Popular keys, interrupted every 10,000 operations by a scan of 2,000 unpopular ones.
+    let mut next_scan_key = KEYS;
+    let zipf_with_scans = (0..OPS).map(|op| {
+        if op % 10_000 < 2_000 {
+            next_scan_key += 1;
+            Op::Read(next_scan_key)
+        } else {
+            Op::Read(zipf.sample(&mut rng))
+        }
+    }).collect();
+    let uniform_reads = (0..OPS).map(|_| Op::Read(rng.gen_range(0, KEYS))).collect();
+
+    vec![
+        ("zipf_reads", zipf_reads),
+        ("zipf_mixed", zipf_mixed),
+        ("zipf_with_scans", zipf_with_scans),
+        ("uniform_reads", uniform_reads)
+    ]
+}
+
+/// Run `trace` against `cache`, returning the hits and the number of reads.
+fn run(cache: &dyn Candidate, trace: &[Op]) -> (u64, u64) {
+    let (mut hits, mut reads) = (0, 0);
+    for &op in trace {
+        match op {
+            Op::Read(key) => {
+                reads += 1;
+                if cache.get(key) {
+                    hits += 1;
+                } else {
+                    cache.put(key);
+                }
+            },
+            Op::Write(key) => cache.put(key)
+        }
+    }
+    (hits, reads)
+}
+
+fn bench_comparison(c: &mut Criterion) {
+    for (workload, trace) in workloads() {
+        let hit_ratios: Vec<String> = candidates().into_iter()
+            .map(|(name, cache)| {
+                let (hits, reads) = run(&*cache, &trace);
+                format!("{} {:.3}", name, hits as f64 / reads as f64)
+            })
+            .collect();
+        println!("{} hit ratios: {}", workload, hit_ratios.join(", "));
+
+        let mut group = c.benchmark_group(workload);
+        group.throughput(Throughput::Elements(trace.len() as u64));
+        for (name, cache) in candidates() {
+            group.bench_function(name, |b| {
+                b.iter_custom(|iters| {
+                    (0..iters).map(|_| {
+                        let start = Instant::now();
+                        run(&*cache, &trace);
+                        start.elapsed()
+                    }).sum::<Duration>()
+                })
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_comparison);
+criterion_main!(benches);
@@ -0,0 +1,136 @@
+//! Throughput of a `ShardedLRUCache` shared by 1 to 16 threads under zipfian, scanning and
+//! mixed read/write workloads.
+//!
+//! Criterion reports each benchmark's throughput in operations per second, and the hit ratio of
+//! its reads is printed after it.  Run with `cargo bench --bench workloads`, optionally followed by
+//! a filter such as `zipf_read_heavy/8`.
+
+extern crate cache;
+extern crate criterion;
+extern crate rand;
+
+mod common;
+
+use std::sync::Barrier;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cache::sharded::ShardedLRUCache;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::prelude::*;
+
+use common::Zipf;
+
+/// The most values the cache holds.
+const CAPACITY: usize = 16 * 1024;
+const SHARDS: usize = 16;
+/// The number of distinct keys requested, so that the cache holds a quarter of them.
+const KEYS: u64 = 4 * CAPACITY as u64;
+/// The skew of `Keys::Zipf`: the `n`th most popular key is requested in proportion to `1/n^s`.
+const ZIPF_EXPONENT: f64 = 0.99;
+/// Operations each thread performs per iteration.
+const OPS_PER_THREAD: u64 = 10_000;
+const THREADS: [usize; 5] = [1, 2, 4, 8, 16];
+
+/// How a workload chooses keys.
+#[derive(Clone, Copy)]
+enum Keys {
+    /// Keys drawn from a zipfian distribution, so that a few are requested most of the time.
+    Zipf,
+    /// Every key in turn, each thread starting from a random one, so that keys are rarely
+    /// requested again before the cache has evicted them.
+    Scan,
+    /// Keys drawn uniformly.
+    Uniform
+}
+
+struct Workload {
+    name: &'static str,
+    keys: Keys,
+    /// The percentage of operations which get a value, putting it if it is missing, rather than
+    /// putting it unconditionally.
+    read_percent: u64
+}
+
+const WORKLOADS: [Workload; 6] = [
+    Workload { name: "zipf_read_only", keys: Keys::Zipf, read_percent: 100 },
+    Workload { name: "zipf_read_heavy", keys: Keys::Zipf, read_percent: 95 },
+    Workload { name: "zipf_mixed", keys: Keys::Zipf, read_percent: 50 },
+    Workload { name: "zipf_write_heavy", keys: Keys::Zipf, read_percent: 10 },
+    Workload { name: "scan", keys: Keys::Scan, read_percent: 100 },
+    Workload { name: "uniform_mixed", keys: Keys::Uniform, read_percent: 50 }
+];
+
+/// Have `threads` threads each perform `ops` operations of `workload` at once on `cache`,
+/// returning how long they took.
+fn run(cache: &ShardedLRUCache<u64, u64>, zipf: &Zipf, workload: &Workload, threads: usize,
+       ops: u64) -> Duration {
+    let barrier = Barrier::new(threads + 1);
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let barrier = &barrier;
+            scope.spawn(move || {
+                let mut rng = rand::thread_rng();
+                let mut next_scan_key = rng.gen_range(0, KEYS);
+                barrier.wait();
+
+                for _ in 0..ops {
+                    let key = match workload.keys {
+                        Keys::Zipf => zipf.sample(&mut rng),
+                        Keys::Scan => {
+                            next_scan_key = (next_scan_key + 1) % KEYS;
+                            next_scan_key
+                        },
+                        Keys::Uniform => rng.gen_range(0, KEYS)
+                    };
+
+                    if rng.gen_range(0, 100) < workload.read_percent {
+                        if cache.get(&key).is_none() {
+                            cache.put(key, key);
+                        }
+                    } else {
+                        cache.put(key, key);
+                    }
+                }
+                barrier.wait();
+            });
+        }
+
+        barrier.wait();
+        let start = Instant::now();
+        barrier.wait();
+        start.elapsed()
+    })
+}
+
+fn bench_workloads(c: &mut Criterion) {
+    let zipf = Zipf::new(KEYS, ZIPF_EXPONENT);
+
+    for workload in WORKLOADS.iter() {
+        let mut group = c.benchmark_group(workload.name);
+        for &threads in THREADS.iter() {
+            let mut cache = ShardedLRUCache::new(CAPACITY, SHARDS);
+            for key in 0..CAPACITY as u64 {
+                cache.put(key, key);
+            }
+            cache.enable_stats();
+
+            group.throughput(Throughput::Elements(threads as u64 * OPS_PER_THREAD));
+            group.bench_function(BenchmarkId::from_parameter(threads), |b| {
+                b.iter_custom(|iters| {
+                    (0..iters).map(|_| run(&cache, &zipf, workload, threads, OPS_PER_THREAD))
+                        .sum()
+                })
+            });
+
+            let stats = cache.stats();
+            if stats.requests() > 0 {
+                println!("{}/{}: hit ratio {:.3}", workload.name, threads, stats.hit_ratio());
+            }
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_workloads);
+criterion_main!(benches);
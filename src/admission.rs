@@ -0,0 +1,142 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+
+/// The number of rows in a `CountMinSketch`.
+const DEPTH: usize = 4;
+
+/// The largest count a `CountMinSketch` records.
+const MAX_COUNT: u8 = 15;
+
+/// CountMinSketch estimates how often each key has been seen, in constant space.
+///
+/// Each key increments one counter in each of `DEPTH` rows, chosen by hashing.  Different keys
+/// may share counters, so the estimate (the smallest of a key's counters) may overcount, but never
+/// undercounts.
+#[derive(Clone, Debug)]
+struct CountMinSketch {
+    counters: Vec<u8>,
+    /// The number of counters in each row, minus one.  Rows are a power of two wide.
+    mask: usize
+}
+
+impl CountMinSketch {
+    fn new(width: usize) -> CountMinSketch {
+        let width = width.max(1).next_power_of_two();
+        CountMinSketch {
+            counters: vec![0; width * DEPTH],
+            mask: width - 1
+        }
+    }
+
+    /// The index of the counter for `hash` in each row.
+    fn indexes(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let (h1, h2) = (hash as u32 as usize, (hash >> 32) as usize | 1);
+        let (mask, width) = (self.mask, self.mask + 1);
+        (0..DEPTH).map(move |row| row * width + (h1.wrapping_add(row.wrapping_mul(h2)) & mask))
+    }
+
+    fn increment(&mut self, hash: u64) {
+        for idx in self.indexes(hash) {
+            let counter = &mut self.counters[idx];
+            *counter = (*counter + 1).min(MAX_COUNT);
+        }
+    }
+
+    fn estimate(&self, hash: u64) -> u8 {
+        self.indexes(hash).map(|idx| self.counters[idx]).min().unwrap_or(0)
+    }
+
+    /// Halve every counter, so that old accesses count for less than recent ones.
+    fn age(&mut self) {
+        for counter in self.counters.iter_mut() {
+            *counter /= 2;
+        }
+    }
+}
+
+/// TinyLfu is an admission filter that keeps rarely used values from displacing more popular
+/// ones.
+///
+/// The filter estimates how often each key is accessed (including keys that are not in the
+/// cache), and when a full cache is asked to store a new key, only admits it if it is accessed at
+/// least as often as the value that would be evicted for it.  This greatly improves hit rates for
+/// skewed workloads, where a few keys receive most accesses, at the cost of sometimes refusing a
+/// value that would have been useful.
+///
+/// Frequencies are periodically halved, so that keys which were popular long ago do not stay
+/// protected forever.
+#[derive(Clone, Debug)]
+pub struct TinyLfu {
+    sketch: CountMinSketch,
+    hash_builder: RandomState,
+    /// Accesses recorded since frequencies were last halved.
+    samples: usize,
+    /// The number of accesses between halvings.
+    sample_size: usize
+}
+
+impl TinyLfu {
+    /// Create a filter for a cache expected to hold about `expected_items` values.
+    pub fn new(expected_items: usize) -> TinyLfu {
+        let expected_items = expected_items.max(1);
+        TinyLfu {
+            sketch: CountMinSketch::new(expected_items),
+            hash_builder: RandomState::new(),
+            samples: 0,
+            sample_size: expected_items.saturating_mul(10)
+        }
+    }
+
+    /// Record an access to `key`.
+    pub(crate) fn record<Q: Hash + ?Sized>(&mut self, key: &Q) {
+        self.sketch.increment(self.hash_builder.hash_one(key));
+
+        self.samples += 1;
+        if self.samples >= self.sample_size {
+            self.sketch.age();
+            self.samples = 0;
+        }
+    }
+
+    /// The estimated number of recent accesses to `key`.
+    pub(crate) fn frequency<Q: Hash + ?Sized>(&self, key: &Q) -> u8 {
+        self.sketch.estimate(self.hash_builder.hash_one(key))
+    }
+
+    /// Whether `candidate` should be admitted to the cache in place of `victim`.
+    pub(crate) fn admit<Q: Hash + ?Sized>(&self, candidate: &Q, victim: &Q) -> bool {
+        self.frequency(candidate) >= self.frequency(victim)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frequency() {
+        let mut filter = TinyLfu::new(64);
+        for _ in 0..5 {
+            filter.record("hot");
+        }
+        filter.record("cold");
+
+        assert!(filter.frequency("hot") >= 5);
+        assert!(filter.frequency("cold") >= 1);
+        assert!(filter.admit("hot", "cold"));
+        assert!(!filter.admit("cold", "hot"));
+    }
+
+    #[test]
+    fn aging() {
+        let mut filter = TinyLfu::new(1);
+        for _ in 0..9 {
+            filter.record(&1);
+        }
+        assert_eq!(filter.frequency(&1), 9);
+
+        // The tenth access halves all counts.
+        filter.record(&1);
+        assert_eq!(filter.frequency(&1), 5);
+    }
+}
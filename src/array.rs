@@ -0,0 +1,306 @@
+use std::borrow::Borrow;
+use std::fmt;
+
+/// The index of no entry.
+const NIL: u8 = u8::MAX;
+
+/// ArrayLRUCache is a least-recently-used cache of at most `N` values, stored inline, for very
+/// small caches on hot paths, such as the last few routes matched by a router or symbols
+/// interned by a parser.
+///
+/// Unlike `LRUCache`, it never allocates (it can live on the stack), has no lock (so updating it
+/// takes `&mut self`), and finds keys by comparing them in order from the most recently used
+/// rather than by hashing, so keys need only implement `Eq`.  That is fast for the few dozen
+/// values it is meant for, and slow for many more: `N` may be at most 255, but is best kept
+/// below about 64.
+///
+/// ```
+/// use cache::array::ArrayLRUCache;
+///
+/// let mut cache: ArrayLRUCache<&str, u32, 2> = ArrayLRUCache::new();
+/// cache.put("/", 1);
+/// cache.put("/about", 2);
+/// assert_eq!(cache.get(&"/"), Some(&1));
+///
+/// // "/about" is the least recently used.
+/// cache.put("/contact", 3);
+/// assert_eq!(cache.get(&"/about"), None);
+/// ```
+///
+/// # Implementation Notes:
+///
+/// Entries are kept in an array, and linked into a doubly-linked list from most to least
+/// recently used by the indexes in `prev` and `next`.  Vacant entries are chained through `next`
+/// from `free`.
+pub struct ArrayLRUCache<K, V, const N: usize> {
+    entries: [Option<(K, V)>; N],
+    prev: [u8; N],
+    next: [u8; N],
+    /// The most recently used entry.
+    head: u8,
+    /// The least recently used entry.
+    tail: u8,
+    /// The first vacant entry.
+    free: u8,
+    len: usize
+}
+
+impl <K: Eq, V, const N: usize> ArrayLRUCache<K, V, N> {
+    /// Create an empty cache.
+    ///
+    /// # Panics
+    ///
+    /// If `N` is greater than 255.
+    pub fn new() -> ArrayLRUCache<K, V, N> {
+        assert!(N <= NIL as usize, "ArrayLRUCache holds at most 255 values");
+        ArrayLRUCache {
+            entries: std::array::from_fn(|_| None),
+            prev: [NIL; N],
+            next: std::array::from_fn(|index| if index + 1 < N { index as u8 + 1 } else { NIL }),
+            head: NIL,
+            tail: NIL,
+            free: if N > 0 { 0 } else { NIL },
+            len: 0
+        }
+    }
+
+    /// The number of values in the cache.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The most values the cache can hold, `N`.
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Get the value for `key`, marking it as the most recently used.
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Eq + ?Sized {
+        self.get_mut(key).map(|value| &*value)
+    }
+
+    /// Get a mutable reference to the value for `key`, marking it as the most recently used.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+        where K: Borrow<Q>, Q: Eq + ?Sized {
+        let index = self.find(key)?;
+        self.move_to_front(index);
+        self.entries[index as usize].as_mut().map(|(_, value)| value)
+    }
+
+    /// Get the value for `key` without updating its recency.
+    pub fn peek<Q>(&self, key: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Eq + ?Sized {
+        let index = self.find(key)?;
+        self.entries[index as usize].as_ref().map(|(_, value)| value)
+    }
+
+    /// Whether the cache holds a value for `key`, without updating its recency.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Eq + ?Sized {
+        self.find(key).is_some()
+    }
+
+    /// Put `value` for `key` as the most recently used value, evicting the least recently used
+    /// value if the cache is full.
+    ///
+    /// # Returns
+    ///
+    /// The previous value for `key`, or `None`.
+    pub fn put(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(index) = self.find(&key) {
+            self.move_to_front(index);
+            let (_, old_value) = self.entries[index as usize].as_mut().unwrap();
+            return Some(std::mem::replace(old_value, value));
+        }
+
+        if N == 0 {
+            return None;
+        }
+        if self.free == NIL {
+            self.unlink(self.tail);
+        }
+
+        let index = self.free;
+        self.free = self.next[index as usize];
+        self.entries[index as usize] = Some((key, value));
+        self.link_front(index);
+        self.len += 1;
+        None
+    }
+
+    /// Remove the value for `key`, returning it.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Eq + ?Sized {
+        let index = self.find(key)?;
+        self.unlink(index).map(|(_, value)| value)
+    }
+
+    /// Remove and return the least recently used key and value.
+    pub fn pop_lru(&mut self) -> Option<(K, V)> {
+        match self.tail {
+            NIL => None,
+            tail => self.unlink(tail)
+        }
+    }
+
+    /// Remove all values.
+    pub fn clear(&mut self) {
+        while self.pop_lru().is_some() {}
+    }
+
+    /// Iterate over the keys and values from the most recently used to the least.
+    pub fn iter(&self) -> Iter<'_, K, V, N> {
+        Iter { cache: self, index: self.head }
+    }
+
+    /// The index of the entry for `key`, searching from the most recently used.
+    fn find<Q>(&self, key: &Q) -> Option<u8>
+        where K: Borrow<Q>, Q: Eq + ?Sized {
+        let mut index = self.head;
+        while index != NIL {
+            match self.entries[index as usize] {
+                Some((ref entry_key, _)) if entry_key.borrow() == key => return Some(index),
+                _ => index = self.next[index as usize]
+            }
+        }
+        None
+    }
+
+    fn move_to_front(&mut self, index: u8) {
+        if self.head != index {
+            self.detach(index);
+            self.link_front(index);
+        }
+    }
+
+    /// Remove the entry at `index` from the list and vacate it, returning its key and value.
+    fn unlink(&mut self, index: u8) -> Option<(K, V)> {
+        self.detach(index);
+        self.next[index as usize] = self.free;
+        self.free = index;
+        self.len -= 1;
+        self.entries[index as usize].take()
+    }
+
+    /// Remove the entry at `index` from the list, leaving it occupied.
+    fn detach(&mut self, index: u8) {
+        let (prev, next) = (self.prev[index as usize], self.next[index as usize]);
+        match prev {
+            NIL => self.head = next,
+            prev => self.next[prev as usize] = next
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.prev[next as usize] = prev
+        }
+    }
+
+    fn link_front(&mut self, index: u8) {
+        self.prev[index as usize] = NIL;
+        self.next[index as usize] = self.head;
+        match self.head {
+            NIL => self.tail = index,
+            head => self.prev[head as usize] = index
+        }
+        self.head = index;
+    }
+}
+
+impl <K: Eq, V, const N: usize> Default for ArrayLRUCache<K, V, N> {
+    fn default() -> ArrayLRUCache<K, V, N> {
+        ArrayLRUCache::new()
+    }
+}
+
+impl <K: Eq + fmt::Debug, V: fmt::Debug, const N: usize> fmt::Debug for ArrayLRUCache<K, V, N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// Iterator over the keys and values of an `ArrayLRUCache`, created by `iter`.
+pub struct Iter<'a, K, V, const N: usize> {
+    cache: &'a ArrayLRUCache<K, V, N>,
+    index: u8
+}
+
+impl <'a, K, V, const N: usize> Iterator for Iter<'a, K, V, N> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        if self.index == NIL {
+            return None;
+        }
+
+        let index = self.index as usize;
+        self.index = self.cache.next[index];
+        self.cache.entries[index].as_ref().map(|(key, value)| (key, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys<const N: usize>(cache: &ArrayLRUCache<u32, u32, N>) -> Vec<u32> {
+        cache.iter().map(|(key, _)| *key).collect()
+    }
+
+    #[test]
+    fn lru_order() {
+        let mut cache: ArrayLRUCache<u32, u32, 3> = ArrayLRUCache::new();
+        for key in 0..3 {
+            assert_eq!(cache.put(key, key), None);
+        }
+        assert_eq!(keys(&cache), vec![2, 1, 0]);
+
+        assert_eq!(cache.get(&0), Some(&0));
+        assert_eq!(cache.put(1, 10), Some(1));
+        assert_eq!(keys(&cache), vec![1, 0, 2]);
+
+        // 2 is the least recently used.
+        cache.put(3, 3);
+        assert_eq!(keys(&cache), vec![3, 1, 0]);
+        assert_eq!(cache.peek(&2), None);
+        assert_eq!(cache.len(), 3);
+
+        *cache.get_mut(&0).unwrap() += 5;
+        assert_eq!(cache.peek(&0), Some(&5));
+        assert_eq!(keys(&cache), vec![0, 3, 1]);
+    }
+
+    #[test]
+    fn remove() {
+        let mut cache: ArrayLRUCache<u32, u32, 3> = ArrayLRUCache::default();
+        for key in 0..3 {
+            cache.put(key, key);
+        }
+        assert_eq!(cache.remove(&1), Some(1));
+        assert_eq!(cache.remove(&1), None);
+        assert_eq!(cache.pop_lru(), Some((0, 0)));
+        assert_eq!(keys(&cache), vec![2]);
+
+        // Vacated entries are reused.
+        cache.put(4, 4);
+        cache.put(5, 5);
+        assert_eq!(keys(&cache), vec![5, 4, 2]);
+        assert!(cache.contains_key(&2));
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(format!("{:?}", cache), "{}");
+    }
+
+    #[test]
+    fn zero_capacity() {
+        let mut cache: ArrayLRUCache<&str, u32, 0> = ArrayLRUCache::new();
+        assert_eq!(cache.put("key1", 1), None);
+        assert_eq!(cache.get(&"key1"), None);
+        assert!(cache.is_empty());
+    }
+}
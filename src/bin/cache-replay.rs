@@ -0,0 +1,234 @@
+//! cache-replay replays a trace recorded by a `trace::TraceRecorder` through caches of several
+//! capacities and policies, reporting the hit ratio and throughput of each.
+//!
+//! Run with `--help` for its options.
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::process;
+use std::time::{Duration, Instant};
+
+use cache::cache::Cache;
+use cache::policy::{EvictionPolicy, Fifo, Lfu, Lirs, Lru, Slru};
+use cache::simulate::Simulation;
+use cache::stats::CacheStats;
+use cache::trace::{TraceOp, TraceReader, TraceRecord};
+
+const USAGE: &str = "\
+Usage: cache-replay [OPTIONS] TRACE
+
+Options:
+    --capacities N,...   Capacities to replay with (default 1024)
+    --policies NAME,...  Policies to replay with: lru, fifo, lfu, slru or lirs (default lru)
+    --simulate           Replay only lookups, as a read-through cache, with the simulator
+    --help               Print this message";
+
+const POLICIES: &[&str] = &["lru", "fifo", "lfu", "slru", "lirs"];
+
+#[derive(Debug, PartialEq)]
+struct Config {
+    trace: String,
+    capacities: Vec<usize>,
+    policies: Vec<String>,
+    simulate: bool
+}
+
+impl Config {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Config, String> {
+        let mut trace = None;
+        let mut config = Config {
+            trace: String::new(),
+            capacities: vec![1024],
+            policies: vec!["lru".to_string()],
+            simulate: false
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--help" => return Err(USAGE.to_string()),
+                "--simulate" => {
+                    config.simulate = true;
+                    continue;
+                },
+                _ if !arg.starts_with("--") && trace.is_none() => {
+                    trace = Some(arg);
+                    continue;
+                },
+                _ => {}
+            }
+
+            let value = args.next().ok_or_else(|| format!("{} requires a value", arg))?;
+            let invalid = |_| format!("invalid value for {}: {}", arg, value);
+            match arg.as_str() {
+                "--capacities" => {
+                    config.capacities = value.split(',')
+                        .map(str::parse)
+                        .collect::<Result<_, _>>()
+                        .map_err(invalid)?;
+                },
+                "--policies" => {
+                    config.policies = value.split(',').map(str::to_string).collect();
+                    if let Some(policy) = config.policies.iter()
+                        .find(|policy| !POLICIES.contains(&policy.as_str())) {
+                        return Err(format!("unknown policy {}", policy));
+                    }
+                },
+                _ => return Err(format!("unknown option {}\n\n{}", arg, USAGE))
+            }
+        }
+
+        config.trace = trace.ok_or_else(|| format!("no trace given\n\n{}", USAGE))?;
+        Ok(config)
+    }
+}
+
+/// Apply every operation of `records` to a cache, returning its statistics and how long it took.
+fn replay<P: EvictionPolicy<u64>>(records: &[TraceRecord], capacity: usize, policy: P)
+    -> (CacheStats, Duration) {
+    let mut cache = Cache::with_policy(capacity, policy);
+    cache.enable_stats();
+
+    let started = Instant::now();
+    for record in records {
+        match record.op {
+            TraceOp::Get => {
+                cache.get(&record.key_hash);
+            },
+            TraceOp::Put => {
+                cache.put(record.key_hash, ());
+            },
+            TraceOp::Remove => {
+                cache.remove(&record.key_hash);
+            }
+        }
+    }
+    (cache.stats(), started.elapsed())
+}
+
+fn replay_policy(records: &[TraceRecord], capacity: usize, policy: &str)
+    -> (CacheStats, Duration) {
+    match policy {
+        "lru" => replay(records, capacity, Lru),
+        "fifo" => replay(records, capacity, Fifo::default()),
+        "lfu" => replay(records, capacity, Lfu::default()),
+        "slru" => replay(records, capacity, Slru::default()),
+        _ => replay(records, capacity, Lirs::default())
+    }
+}
+
+/// Replay the lookups of `records` through the simulator, returning a line for each candidate.
+fn simulate(records: &[TraceRecord], config: &Config) -> Vec<String> {
+    let mut simulation = Simulation::new();
+    for &capacity in config.capacities.iter() {
+        for policy in config.policies.iter() {
+            match policy.as_str() {
+                "lru" => simulation.add(policy, capacity, Lru),
+                "fifo" => simulation.add(policy, capacity, Fifo::default()),
+                "lfu" => simulation.add(policy, capacity, Lfu::default()),
+                "slru" => simulation.add(policy, capacity, Slru::default()),
+                _ => simulation.add(policy, capacity, Lirs::default())
+            };
+        }
+    }
+
+    simulation.replay(records.iter()
+        .filter(|record| record.op == TraceOp::Get)
+        .map(|record| record.key_hash));
+    simulation.results().iter()
+        .map(|result| {
+            format!("{:<6} {:>10} {:>10.4}", result.name, result.capacity, result.hit_ratio())
+        })
+        .collect()
+}
+
+fn run(config: &Config) -> io::Result<Vec<String>> {
+    let file = File::open(&config.trace)?;
+    let records = TraceReader::new(BufReader::new(file))?.collect::<io::Result<Vec<_>>>()?;
+
+    if config.simulate {
+        return Ok(simulate(&records, config));
+    }
+
+    let mut lines = Vec::new();
+    for &capacity in config.capacities.iter() {
+        for policy in config.policies.iter() {
+            let (stats, elapsed) = replay_policy(&records, capacity, policy);
+            let ops_per_sec = records.len() as f64 / elapsed.as_secs_f64().max(1e-9);
+            lines.push(format!("{:<6} {:>10} {:>10.4} {:>14.0}", policy, capacity,
+                               stats.hit_ratio(), ops_per_sec));
+        }
+    }
+    Ok(lines)
+}
+
+fn main() {
+    let config = match Config::parse(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(2);
+        }
+    };
+
+    match run(&config) {
+        Ok(lines) => {
+            if config.simulate {
+                println!("{:<6} {:>10} {:>10}", "policy", "capacity", "hit ratio");
+            } else {
+                println!("{:<6} {:>10} {:>10} {:>14}", "policy", "capacity", "hit ratio", "ops/s");
+            }
+            for line in lines {
+                println!("{}", line);
+            }
+        },
+        Err(err) => {
+            eprintln!("cache-replay: {}: {}", config.trace, err);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(op: TraceOp, key_hash: u64) -> TraceRecord {
+        TraceRecord { timestamp: Duration::from_secs(0), op, key_hash }
+    }
+
+    #[test]
+    fn parse_args() {
+        let args = |args: &[&str]| Config::parse(args.iter().map(|arg| arg.to_string()));
+        let config = args(&["--capacities", "10,100", "trace.bin", "--simulate"]).unwrap();
+        assert_eq!(config, Config {
+            trace: "trace.bin".to_string(),
+            capacities: vec![10, 100],
+            policies: vec!["lru".to_string()],
+            simulate: true
+        });
+
+        assert!(args(&[]).is_err());
+        assert!(args(&["--capacities", "many", "trace.bin"]).is_err());
+        assert!(args(&["--policies", "lru,random", "trace.bin"]).is_err());
+    }
+
+    #[test]
+    fn replay_trace() {
+        let records = vec![
+            record(TraceOp::Get, 1),
+            record(TraceOp::Put, 1),
+            record(TraceOp::Get, 1),
+            record(TraceOp::Remove, 1),
+            record(TraceOp::Get, 1)
+        ];
+        let (stats, _) = replay_policy(&records, 10, "lirs");
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+
+        let config = Config::parse(["--simulate", "trace.bin"].iter().map(|arg| arg.to_string()))
+            .unwrap();
+        // The simulator puts after the first miss, so the removal is not seen.
+        assert_eq!(simulate(&records, &config), vec![format!("{:<6} {:>10} {:>10.4}", "lru", 1024,
+                                                             2.0 / 3.0)]);
+    }
+}
@@ -0,0 +1,472 @@
+//! cache-server serves a `ShardedLRUCache` over TCP with the memcached text protocol.
+//!
+//! Supports `get`, `gets`, `set`, `delete`, `flush_all`, `stats` and `quit`.  Run with `--help`
+//! for its options.
+
+use std::env;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use cache::cache::LRUCache;
+use cache::sharded::ShardedLRUCache;
+
+const USAGE: &str = "\
+Usage: cache-server [OPTIONS]
+
+Options:
+    --bind ADDR        Address to listen on (default 127.0.0.1:11211)
+    --capacity N       Maximum number of items (default 65536)
+    --max-bytes N      Maximum total size of keys and values, in bytes (default unbounded)
+    --max-item-size N  Maximum size of a value, in bytes (default 1048576)
+    --shards N         Number of shards (default 16)
+    --ttl SECS         Time-to-live of items set without an expiration time (default none)
+    --help             Print this message";
+
+/// The longest key the memcached protocol allows.
+const MAX_KEY_LEN: usize = 250;
+
+/// The longest command line read, including its line ending.  Longer lines close the connection.
+const MAX_LINE_LEN: usize = 8 * 1024;
+
+/// Expiration times greater than this many seconds are absolute Unix times, not relative ones.
+const MAX_RELATIVE_EXPTIME: i64 = 30 * 24 * 60 * 60;
+
+/// How often expired items are removed in the background.
+const MAINTENANCE_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Debug, PartialEq)]
+struct Config {
+    bind: String,
+    capacity: usize,
+    max_bytes: Option<u64>,
+    max_item_size: usize,
+    shards: usize,
+    ttl: Option<Duration>
+}
+
+impl Config {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Config, String> {
+        let mut config = Config {
+            bind: "127.0.0.1:11211".to_string(),
+            capacity: 65536,
+            max_bytes: None,
+            max_item_size: 1024 * 1024,
+            shards: 16,
+            ttl: None
+        };
+
+        while let Some(arg) = args.next() {
+            if arg == "--help" {
+                return Err(USAGE.to_string());
+            }
+
+            let value = args.next().ok_or_else(|| format!("{} requires a value", arg))?;
+            let invalid = |_| format!("invalid value for {}: {}", arg, value);
+            match arg.as_str() {
+                "--bind" => config.bind = value.clone(),
+                "--capacity" => config.capacity = value.parse().map_err(invalid)?,
+                "--max-bytes" => config.max_bytes = Some(value.parse().map_err(invalid)?),
+                "--max-item-size" => config.max_item_size = value.parse().map_err(invalid)?,
+                "--shards" => config.shards = value.parse().map_err(invalid)?,
+                "--ttl" => config.ttl = Some(Duration::from_secs(value.parse().map_err(invalid)?)),
+                _ => return Err(format!("unknown option {}\n\n{}", arg, USAGE))
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// A stored value, with the opaque flags the client set it with.
+#[derive(Clone)]
+struct Item {
+    flags: u32,
+    data: Arc<[u8]>
+}
+
+struct Server {
+    cache: Arc<ShardedLRUCache<String, Item>>,
+    max_item_size: usize,
+    started: Instant
+}
+
+/// A malformed request, reported to the client with `CLIENT_ERROR`.
+#[derive(Debug)]
+struct ClientError(&'static str);
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CLIENT_ERROR {}\r\n", self.0)
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// A request the server will not serve, reported to the client with `SERVER_ERROR` before the
+/// connection is closed, since the rest of the request cannot be skipped safely.
+#[derive(Debug)]
+struct ServerError(&'static str);
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SERVER_ERROR {}\r\n", self.0)
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+impl Server {
+    fn new(config: &Config) -> Result<Server, String> {
+        let mut builder = LRUCache::builder()
+            .capacity(config.capacity)
+            .stats()
+            .expiration_timers();
+        if let Some(max_bytes) = config.max_bytes {
+            builder = builder.weigher(max_bytes, |key: &String, item: &Item| {
+                (key.len() + item.data.len()) as u64
+            });
+        }
+        if let Some(ttl) = config.ttl {
+            builder = builder.ttl(ttl);
+        }
+
+        Ok(Server {
+            cache: Arc::new(builder.build_sharded(config.shards).map_err(|err| err.to_string())?),
+            max_item_size: config.max_item_size,
+            started: Instant::now()
+        })
+    }
+
+    /// Serve requests from `reader` until it is closed or the client quits.
+    fn serve<R: BufRead, W: Write>(&self, mut reader: R, mut writer: W) -> io::Result<()> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = Read::take(&mut reader, MAX_LINE_LEN as u64).read_line(&mut line)?;
+            if read == 0 {
+                return Ok(());
+            }
+            if read == MAX_LINE_LEN && !line.ends_with('\n') {
+                write!(writer, "{}", ServerError("line too long"))?;
+                return writer.flush();
+            }
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let result = match words.split_first() {
+                None => Ok(()),
+                Some((&"quit", _)) => return Ok(()),
+                Some((&"get", keys)) | Some((&"gets", keys)) => self.get(keys, &mut writer),
+                Some((&"set", args)) => self.set(args, &mut reader, &mut writer),
+                Some((&"delete", args)) => self.delete(args, &mut writer),
+                Some((&"flush_all", args)) => self.flush_all(args, &mut writer),
+                Some((&"stats", _)) => self.stats(&mut writer),
+                Some(_) => writer.write_all(b"ERROR\r\n")
+            };
+
+            if let Err(err) = result {
+                let inner = err.get_ref();
+                if let Some(error) = inner.and_then(|err| err.downcast_ref::<ClientError>()) {
+                    write!(writer, "{}", error)?;
+                } else if let Some(error) =
+                    inner.and_then(|err| err.downcast_ref::<ServerError>()) {
+                    write!(writer, "{}", error)?;
+                    return writer.flush();
+                } else {
+                    return Err(err);
+                }
+            }
+            writer.flush()?;
+        }
+    }
+
+    fn get<W: Write>(&self, keys: &[&str], writer: &mut W) -> io::Result<()> {
+        if keys.is_empty() {
+            return Err(client_error("bad command line format"));
+        }
+
+        for key in keys {
+            check_key(key)?;
+        }
+        for key in keys {
+            if let Some(item) = self.cache.get(*key) {
+                write!(writer, "VALUE {} {} {}\r\n", key, item.flags, item.data.len())?;
+                writer.write_all(&item.data)?;
+                writer.write_all(b"\r\n")?;
+            }
+        }
+        writer.write_all(b"END\r\n")?;
+        Ok(())
+    }
+
+    /// `set <key> <flags> <exptime> <bytes> [noreply]`, followed by the data block.
+    fn set<R: BufRead, W: Write>(&self, args: &[&str], reader: &mut R, writer: &mut W)
+        -> io::Result<()> {
+        let (key, flags, exptime, len, noreply) = match *args {
+            [key, flags, exptime, len] => (key, flags, exptime, len, false),
+            [key, flags, exptime, len, "noreply"] => (key, flags, exptime, len, true),
+            _ => return Err(client_error("bad command line format"))
+        };
+        let (flags, exptime, len) = match (flags.parse(), exptime.parse(), len.parse::<usize>()) {
+            (Ok(flags), Ok(exptime), Ok(len)) => (flags, exptime, len),
+            _ => return Err(client_error("bad command line format"))
+        };
+        check_key(key)?;
+        // The data block ends with "\r\n".
+        let chunk_len = match len.checked_add(2) {
+            Some(chunk_len) if len <= self.max_item_size => chunk_len,
+            _ => return Err(server_error("object too large for cache"))
+        };
+
+        let mut data = vec![0; chunk_len];
+        reader.read_exact(&mut data)?;
+        if !data.ends_with(b"\r\n") {
+            return Err(client_error("bad data chunk"));
+        }
+        data.truncate(len);
+
+        let item = Item { flags, data: data.into() };
+        match ttl(exptime) {
+            None => {
+                self.cache.put(key.to_string(), item);
+            },
+            Some(ttl) if ttl > Duration::from_secs(0) => {
+                self.cache.put_with_ttl(key.to_string(), item, ttl);
+            },
+            // An expiration time in the past deletes the item.
+            Some(_) => {
+                self.cache.remove(key);
+            }
+        }
+
+        reply(writer, noreply, b"STORED\r\n")
+    }
+
+    /// `delete <key> [noreply]`.
+    fn delete<W: Write>(&self, args: &[&str], writer: &mut W) -> io::Result<()> {
+        let (key, noreply) = match *args {
+            [key] => (key, false),
+            [key, "noreply"] => (key, true),
+            _ => return Err(client_error("bad command line format"))
+        };
+        check_key(key)?;
+
+        match self.cache.remove(key) {
+            Some(_) => reply(writer, noreply, b"DELETED\r\n"),
+            None => reply(writer, noreply, b"NOT_FOUND\r\n")
+        }
+    }
+
+    /// `flush_all [noreply]`.  Delayed flushes are not supported.
+    fn flush_all<W: Write>(&self, args: &[&str], writer: &mut W) -> io::Result<()> {
+        let noreply = match *args {
+            [] => false,
+            ["noreply"] => true,
+            _ => return Err(client_error("bad command line format"))
+        };
+
+        self.cache.clear();
+        reply(writer, noreply, b"OK\r\n")
+    }
+
+    fn stats<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let stats = self.cache.stats();
+        let stat_lines = [
+            ("pid", u64::from(process::id())),
+            ("uptime", self.started.elapsed().as_secs()),
+            ("curr_items", self.cache.len() as u64),
+            ("limit_maxitems", self.cache.capacity() as u64),
+            ("get_hits", stats.hits),
+            ("get_misses", stats.misses),
+            ("total_items", stats.insertions),
+            ("evictions", stats.evictions),
+            ("expired_unfetched", stats.expirations)
+        ];
+
+        for (name, value) in stat_lines.iter() {
+            write!(writer, "STAT {} {}\r\n", name, value)?;
+        }
+        writer.write_all(b"END\r\n")?;
+        Ok(())
+    }
+}
+
+fn client_error(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, ClientError(message))
+}
+
+fn server_error(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, ServerError(message))
+}
+
+fn check_key(key: &str) -> io::Result<()> {
+    if key.len() > MAX_KEY_LEN {
+        return Err(client_error("key too long"));
+    }
+    Ok(())
+}
+
+/// Write `response`, unless the client asked for no reply.
+fn reply<W: Write>(writer: &mut W, noreply: bool, response: &[u8]) -> io::Result<()> {
+    if !noreply {
+        writer.write_all(response)?;
+    }
+    Ok(())
+}
+
+/// The time-to-live for an item set with `exptime`: `None` to use the server's default, or zero
+/// if the item has already expired.
+fn ttl(exptime: i64) -> Option<Duration> {
+    if exptime == 0 {
+        return None;
+    }
+    if exptime < 0 {
+        return Some(Duration::from_secs(0));
+    }
+    if exptime <= MAX_RELATIVE_EXPTIME {
+        return Some(Duration::from_secs(exptime as u64));
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    Some(Duration::from_secs(exptime as u64).checked_sub(now).unwrap_or_default())
+}
+
+fn handle(server: &Server, stream: TcpStream) -> io::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    server.serve(reader, BufWriter::new(stream))
+}
+
+fn main() {
+    let config = match Config::parse(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(2);
+        }
+    };
+
+    let server = match Server::new(&config) {
+        Ok(server) => Arc::new(server),
+        Err(message) => {
+            eprintln!("cache-server: {}", message);
+            process::exit(2);
+        }
+    };
+    let listener = match TcpListener::bind(&config.bind) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("cache-server: cannot bind {}: {}", config.bind, err);
+            process::exit(1);
+        }
+    };
+
+    ShardedLRUCache::spawn_maintenance(&server.cache, MAINTENANCE_PERIOD);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("cache-server: accept failed: {}", err);
+                continue;
+            }
+        };
+
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            if let Err(err) = handle(&server, stream) {
+                eprintln!("cache-server: connection failed: {}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> Server {
+        let args = ["--capacity", "16", "--shards", "2"].iter().map(|arg| arg.to_string());
+        Server::new(&Config::parse(args).unwrap()).unwrap()
+    }
+
+    fn request(server: &Server, input: &str) -> String {
+        let mut output = Vec::new();
+        server.serve(input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn parse_args() {
+        let args = |args: &[&str]| Config::parse(args.iter().map(|arg| arg.to_string()));
+        let config = args(&["--bind", "0.0.0.0:1234", "--ttl", "60", "--shards", "4"]).unwrap();
+        assert_eq!(config.bind, "0.0.0.0:1234");
+        assert_eq!(config.ttl, Some(Duration::from_secs(60)));
+        assert_eq!(config.shards, 4);
+        assert_eq!(config.capacity, 65536);
+
+        assert!(args(&["--capacity", "many"]).is_err());
+        assert!(args(&["--shards"]).is_err());
+        assert!(args(&["--verbose", "1"]).is_err());
+    }
+
+    #[test]
+    fn get_set_delete() {
+        let server = server();
+        assert_eq!(request(&server, "set a 5 0 3\r\nabc\r\nget a b\r\n"),
+                   "STORED\r\nVALUE a 5 3\r\nabc\r\nEND\r\n");
+        assert_eq!(request(&server, "delete a\r\ndelete a\r\nget a\r\n"),
+                   "DELETED\r\nNOT_FOUND\r\nEND\r\n");
+        assert_eq!(request(&server, "set b 0 0 1 noreply\r\nx\r\nflush_all\r\nget b\r\n"),
+                   "OK\r\nEND\r\n");
+    }
+
+    #[test]
+    fn errors() {
+        let server = server();
+        assert_eq!(request(&server, "incr a 1\r\n"), "ERROR\r\n");
+        assert_eq!(request(&server, "set a 0 0\r\n"),
+                   "CLIENT_ERROR bad command line format\r\n");
+        assert_eq!(request(&server, "set a 0 0 1\r\nxy\r\nget a\r\n"),
+                   "CLIENT_ERROR bad data chunk\r\nEND\r\n");
+        assert_eq!(request(&server, "quit\r\nget a\r\n"), "");
+        let long_key = "k".repeat(MAX_KEY_LEN + 1);
+        assert_eq!(request(&server, &format!("get a {}\r\n", long_key)),
+                   "CLIENT_ERROR key too long\r\n");
+    }
+
+    #[test]
+    fn limits() {
+        let args = ["--max-item-size", "4"].iter().map(|arg| arg.to_string());
+        let server = Server::new(&Config::parse(args).unwrap()).unwrap();
+        assert_eq!(request(&server, "set a 0 0 4\r\nabcd\r\nget a\r\n"),
+                   "STORED\r\nVALUE a 0 4\r\nabcd\r\nEND\r\n");
+
+        // Oversized values close the connection instead of being read.
+        assert_eq!(request(&server, "set a 0 0 5\r\nabcde\r\nget a\r\n"),
+                   "SERVER_ERROR object too large for cache\r\n");
+        assert_eq!(request(&server, "set a 0 0 99999999999999\r\n"),
+                   "SERVER_ERROR object too large for cache\r\n");
+        assert_eq!(request(&server, &format!("set a 0 0 {}\r\n", usize::MAX)),
+                   "SERVER_ERROR object too large for cache\r\n");
+        let args = vec!["--max-item-size".to_string(), usize::MAX.to_string()];
+        let unbounded = Server::new(&Config::parse(args.into_iter()).unwrap()).unwrap();
+        assert_eq!(request(&unbounded, &format!("set a 0 0 {}\r\n", usize::MAX)),
+                   "SERVER_ERROR object too large for cache\r\n");
+
+        let long_line = format!("get {}\r\nget a\r\n", "a ".repeat(MAX_LINE_LEN));
+        assert_eq!(request(&server, &long_line), "SERVER_ERROR line too long\r\n");
+    }
+
+    #[test]
+    fn expiration() {
+        let server = server();
+        assert_eq!(request(&server, "set a 0 -1 1\r\nx\r\nget a\r\n"), "STORED\r\nEND\r\n");
+        assert_eq!(ttl(0), None);
+        assert_eq!(ttl(60), Some(Duration::from_secs(60)));
+        assert_eq!(ttl(MAX_RELATIVE_EXPTIME + 1), Some(Duration::from_secs(0)));
+    }
+}
@@ -0,0 +1,537 @@
+use std::error::Error;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::admission::TinyLfu;
+use crate::cache::{Cache, EvictionBatchListener, Limits, LoaderListener, LockStrategy, RecencyMode,
+                   SlowLockListener, Weigher};
+use crate::clock::Clock;
+use crate::listener::{EvictionCause, EvictionListener};
+use crate::policy::{EvictionPolicy, Lru};
+use crate::sharded::{clamp_shards, split, ShardedLRUCache};
+use crate::stats::MetricsRecorder;
+use crate::trace::TraceRecorder;
+
+/// A configuration rejected by `LRUCacheBuilder`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    /// Neither a capacity nor a weigher was configured, so the cache would be unbounded.
+    Unbounded,
+    /// The capacity or maximum weight is zero, so the cache could never hold a value.
+    ZeroCapacity,
+    /// A sharded cache was requested with zero shards.
+    ZeroShards
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuildError::Unbounded => write!(f, "cache requires a capacity or a weigher"),
+            BuildError::ZeroCapacity => write!(f, "cache capacity must be greater than zero"),
+            BuildError::ZeroShards => write!(f, "sharded cache requires at least one shard")
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+/// LRUCacheBuilder configures and creates a `Cache` (usually an `LRUCache`) or `ShardedLRUCache`.
+///
+/// Created by `Cache::builder`.  At least one of `capacity` and `weigher` must be set; if both
+/// are, the cache is bounded by both.
+///
+/// ```
+/// use std::time::Duration;
+/// use cache::cache::LRUCache;
+///
+/// let cache: LRUCache<String, Vec<u8>> = LRUCache::builder()
+///     .capacity(1024)
+///     .ttl(Duration::from_secs(60))
+///     .build()
+///     .unwrap();
+/// ```
+pub struct LRUCacheBuilder<K, V, P = Lru, S = RandomState> {
+    options: Options<K, V>,
+    policy: P,
+    hash_builder: S
+}
+
+/// The settings of an `LRUCacheBuilder` other than its policy and hasher.
+struct Options<K, V> {
+    capacity: Option<usize>,
+    weigher: Option<(u64, Arc<Weigher<K, V>>)>,
+    ttl: Option<Duration>,
+    tti: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    clock: Option<Arc<dyn Clock>>,
+    early_expiration: Option<Duration>,
+    keep_stale: bool,
+    idle_shrink: Option<Duration>,
+    listener: Option<Arc<EvictionListener<K, V>>>,
+    slow_lock: Option<(Duration, Arc<SlowLockListener>)>,
+    eviction_batch: Option<Arc<EvictionBatchListener>>,
+    loader: Option<Arc<LoaderListener>>,
+    trace: Option<Arc<TraceRecorder>>,
+    stats: bool,
+    metrics: Option<Arc<dyn MetricsRecorder>>,
+    eviction_ages: bool,
+    expiration_timers: bool,
+    /// The number of values the admission filter should expect, if there is one.
+    admission: Option<usize>,
+    recency: RecencyMode,
+    lock: LockStrategy,
+    max_pinned: usize,
+    max_evictions: usize,
+    low_watermark: f64
+}
+
+impl <K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K> + Default> LRUCacheBuilder<K, V, P> {
+    pub(crate) fn new() -> LRUCacheBuilder<K, V, P> {
+        LRUCacheBuilder {
+            options: Options {
+                capacity: None,
+                weigher: None,
+                ttl: None,
+                tti: None,
+                max_lifetime: None,
+                clock: None,
+                early_expiration: None,
+                keep_stale: false,
+                idle_shrink: None,
+                listener: None,
+                slow_lock: None,
+                eviction_batch: None,
+                loader: None,
+                trace: None,
+                stats: false,
+                metrics: None,
+                eviction_ages: false,
+                expiration_timers: false,
+                admission: None,
+                recency: RecencyMode::Exact,
+                lock: LockStrategy::default(),
+                max_pinned: 0,
+                max_evictions: usize::MAX,
+                low_watermark: 1.0
+            },
+            policy: P::default(),
+            hash_builder: RandomState::new()
+        }
+    }
+}
+
+impl <K, V, P, S> LRUCacheBuilder<K, V, P, S>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    /// Evict the values chosen by `policy`, instead of the least recently used.
+    pub fn policy<Q: EvictionPolicy<K>>(self, policy: Q) -> LRUCacheBuilder<K, V, Q, S> {
+        LRUCacheBuilder {
+            options: self.options,
+            policy,
+            hash_builder: self.hash_builder
+        }
+    }
+
+    /// Hash keys with `hash_builder`.  See `LRUCache::with_hasher`.
+    pub fn hasher<T: BuildHasher>(self, hash_builder: T) -> LRUCacheBuilder<K, V, P, T> {
+        LRUCacheBuilder {
+            options: self.options,
+            policy: self.policy,
+            hash_builder
+        }
+    }
+
+    /// Permit at most `capacity` values in the cache.  See `LRUCache::new`.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.options.capacity = Some(capacity);
+        self
+    }
+
+    /// Permit values with a total weight of at most `max_weight`, as computed by `weigher`.  See
+    /// `LRUCache::with_weigher`.
+    pub fn weigher<F>(mut self, max_weight: u64, weigher: F) -> Self
+        where F: Fn(&K, &V) -> u64 + Send + Sync + 'static {
+        self.options.weigher = Some((max_weight, Arc::new(weigher)));
+        self
+    }
+
+    /// Expire values `ttl` after they are put.  See `LRUCache::with_ttl`.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.options.ttl = Some(ttl);
+        self
+    }
+
+    /// Expire values which have not been read for `tti`.  See `LRUCache::set_time_to_idle`.
+    pub fn time_to_idle(mut self, tti: Duration) -> Self {
+        self.options.tti = Some(tti);
+        self
+    }
+
+    /// Expire values `max_lifetime` after they are put, even if kept stale.  See
+    /// `LRUCache::set_max_lifetime`.
+    pub fn max_lifetime(mut self, max_lifetime: Duration) -> Self {
+        self.options.max_lifetime = Some(max_lifetime);
+        self
+    }
+
+    /// Read the time for expiration from `clock`.  See `LRUCache::set_clock`.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.options.clock = Some(clock);
+        self
+    }
+
+    /// Let lookups treat values as expired early, for values which take about `recompute_time`
+    /// to compute.  See `LRUCache::set_early_expiration`.
+    pub fn early_expiration(mut self, recompute_time: Duration) -> Self {
+        self.options.early_expiration = Some(recompute_time);
+        self
+    }
+
+    /// Keep expired values to be served stale until they are evicted.  See
+    /// `LRUCache::set_keep_stale`.
+    pub fn keep_stale(mut self) -> Self {
+        self.options.keep_stale = true;
+        self
+    }
+
+    /// Release excess memory after the cache is idle for `period`.  See
+    /// `LRUCache::set_idle_shrink`.
+    pub fn idle_shrink(mut self, period: Duration) -> Self {
+        self.options.idle_shrink = Some(period);
+        self
+    }
+
+    /// Call `listener` with each value that leaves the cache.  See
+    /// `LRUCache::set_eviction_listener`.
+    pub fn eviction_listener<F>(mut self, listener: F) -> Self
+        where F: FnMut(K, V, EvictionCause) + Send + 'static {
+        self.options.listener = Some(Arc::new(EvictionListener::new(listener)));
+        self
+    }
+
+    /// Call `listener` whenever an operation waits at least `threshold` for the cache's lock.  See
+    /// `LRUCache::set_slow_lock_listener`.
+    pub fn slow_lock_listener<F>(mut self, threshold: Duration, listener: F) -> Self
+        where F: Fn(Duration) + Send + Sync + 'static {
+        self.options.slow_lock = Some((threshold, Arc::new(listener)));
+        self
+    }
+
+    /// Call `listener` whenever an operation evicts values to keep the cache within its limits.
+    /// See `LRUCache::set_eviction_batch_listener`.
+    pub fn eviction_batch_listener<F>(mut self, listener: F) -> Self
+        where F: Fn(usize, Duration) + Send + Sync + 'static {
+        self.options.eviction_batch = Some(Arc::new(listener));
+        self
+    }
+
+    /// Call `listener` whenever a loader finishes running.  See `LRUCache::set_loader_listener`.
+    pub fn loader_listener<F>(mut self, listener: F) -> Self
+        where F: Fn(Duration, bool) + Send + Sync + 'static {
+        self.options.loader = Some(Arc::new(listener));
+        self
+    }
+
+    /// Record every operation to `recorder`.  See `LRUCache::set_trace_recorder`.
+    pub fn trace_recorder(mut self, recorder: Arc<TraceRecorder>) -> Self {
+        self.options.trace = Some(recorder);
+        self
+    }
+
+    /// Record statistics about cache activity.  See `LRUCache::enable_stats`.
+    pub fn stats(mut self) -> Self {
+        self.options.stats = true;
+        self
+    }
+
+    /// Report statistics to `recorder` as they change.  See `LRUCache::set_metrics_recorder`.
+    pub fn metrics_recorder(mut self, recorder: Arc<dyn MetricsRecorder>) -> Self {
+        self.options.metrics = Some(recorder);
+        self
+    }
+
+    /// Record the ages of values evicted to make room for others.  See
+    /// `LRUCache::enable_eviction_ages`.
+    pub fn eviction_ages(mut self) -> Self {
+        self.options.eviction_ages = true;
+        self
+    }
+
+    /// Track when each value expires, so that `run_pending_tasks` can remove expired values
+    /// promptly.  See `LRUCache::enable_expiration_timers`.
+    pub fn expiration_timers(mut self) -> Self {
+        self.options.expiration_timers = true;
+        self
+    }
+
+    /// Filter new values with a `TinyLfu` admission filter sized for about `expected_items`
+    /// values.  See `LRUCache::set_admission_filter`.
+    pub fn admission_filter(mut self, expected_items: usize) -> Self {
+        self.options.admission = Some(expected_items);
+        self
+    }
+
+    /// Track recency with `recency`.  See `LRUCache::set_recency_mode`.
+    pub fn recency_mode(mut self, recency: RecencyMode) -> Self {
+        self.options.recency = recency;
+        self
+    }
+
+    /// Protect the cache's state with a lock of `strategy`, or each shard's with its own.  The
+    /// default is `LockStrategy::Mutex`.
+    pub fn lock_strategy(mut self, strategy: LockStrategy) -> Self {
+        self.options.lock = strategy;
+        self
+    }
+
+    /// Permit at most `max_pinned` values to be pinned at once.  See `LRUCache::set_max_pinned`.
+    pub fn max_pinned(mut self, max_pinned: usize) -> Self {
+        self.options.max_pinned = max_pinned;
+        self
+    }
+
+    /// Evict at most `max_evictions` values to make room for each new value.  See
+    /// `LRUCache::set_max_evictions`.
+    pub fn max_evictions(mut self, max_evictions: usize) -> Self {
+        self.options.max_evictions = max_evictions;
+        self
+    }
+
+    /// Once full, evict down to `fraction` of the limits in one pass.  See
+    /// `LRUCache::set_low_watermark`.
+    pub fn low_watermark(mut self, fraction: f64) -> Self {
+        self.options.low_watermark = fraction;
+        self
+    }
+
+    /// Create the configured `Cache`.
+    pub fn build(self) -> Result<Cache<K, V, P, S>, BuildError> {
+        let limits = self.options.limits()?;
+        let cache = self.options.build_shard(limits, self.options.admission, self.policy,
+                                             self.hash_builder);
+        cache.set_max_pinned(self.options.max_pinned);
+        Ok(cache)
+    }
+
+    /// Create a `ShardedLRUCache` with the configured limits split across `shards` shards, or as
+    /// many as the capacity or maximum weight if that is less.  Each shard has its own copy of the
+    /// policy.  The eviction listener, if any, is shared by all shards.
+    pub fn build_sharded(self, shards: usize) -> Result<ShardedLRUCache<K, V, P, S>, BuildError>
+        where P: Clone, S: Clone {
+        if shards == 0 {
+            return Err(BuildError::ZeroShards);
+        }
+
+        let limits = self.options.limits()?;
+        let shards = clamp_shards(clamp_shards(shards, limits.capacity as u64), limits.max_weight);
+        Ok(ShardedLRUCache::with_shards(shards, self.hash_builder.clone(), |idx| {
+            let limits = Limits {
+                capacity: match limits.capacity {
+                    usize::MAX => usize::MAX,
+                    capacity => split(capacity as u64, shards, idx) as usize
+                },
+                max_weight: match limits.max_weight {
+                    u64::MAX => u64::MAX,
+                    max_weight => split(max_weight, shards, idx)
+                }
+            };
+            let admission = self.options.admission
+                .map(|expected_items| split(expected_items as u64, shards, idx) as usize);
+            let (policy, hash_builder) = (self.policy.clone(), self.hash_builder.clone());
+            let cache = self.options.build_shard(limits, admission, policy, hash_builder);
+            cache.set_max_pinned(split(self.options.max_pinned as u64, shards, idx) as usize);
+            cache
+        }))
+    }
+}
+
+impl <K: Eq + Hash + Clone, V: Clone> Options<K, V> {
+    fn limits(&self) -> Result<Limits, BuildError> {
+        if self.capacity.is_none() && self.weigher.is_none() {
+            return Err(BuildError::Unbounded);
+        }
+
+        let limits = Limits {
+            capacity: self.capacity.unwrap_or(usize::MAX),
+            max_weight: self.weigher.as_ref().map_or(u64::MAX, |&(max_weight, _)| max_weight)
+        };
+
+        if limits.capacity == 0 || limits.max_weight == 0 {
+            return Err(BuildError::ZeroCapacity);
+        }
+
+        Ok(limits)
+    }
+
+    fn build_shard<P, S>(&self, limits: Limits, admission: Option<usize>, policy: P,
+                         hash_builder: S) -> Cache<K, V, P, S>
+        where P: EvictionPolicy<K>, S: BuildHasher {
+        let weigher = self.weigher.as_ref().map(|(_, weigher)| Arc::clone(weigher));
+        let mut cache = Cache::with_limits(limits, weigher, policy, hash_builder, self.lock);
+        cache.set_ttl(self.ttl);
+        cache.set_time_to_idle(self.tti);
+        cache.set_max_lifetime(self.max_lifetime);
+        if let Some(ref clock) = self.clock {
+            cache.set_clock(Arc::clone(clock));
+        }
+        cache.set_early_expiration(self.early_expiration);
+        cache.set_keep_stale(self.keep_stale);
+        cache.set_idle_shrink(self.idle_shrink);
+        if let Some(ref listener) = self.listener {
+            cache.set_shared_eviction_listener(Arc::clone(listener));
+        }
+        if let Some((threshold, ref listener)) = self.slow_lock {
+            cache.set_shared_slow_lock_listener(threshold, Arc::clone(listener));
+        }
+        if let Some(ref listener) = self.eviction_batch {
+            cache.set_shared_eviction_batch_listener(Arc::clone(listener));
+        }
+        if let Some(ref listener) = self.loader {
+            cache.set_shared_loader_listener(Arc::clone(listener));
+        }
+        cache.set_trace_recorder(self.trace.clone());
+        if self.stats {
+            cache.enable_stats();
+        }
+        if let Some(ref recorder) = self.metrics {
+            cache.set_metrics_recorder(Arc::clone(recorder));
+        }
+        if self.eviction_ages {
+            cache.enable_eviction_ages();
+        }
+        if self.expiration_timers {
+            cache.enable_expiration_timers();
+        }
+        cache.set_admission_filter(admission.map(TinyLfu::new));
+        cache.set_recency_mode(self.recency);
+        cache.set_max_evictions(self.max_evictions);
+        cache.set_low_watermark(self.low_watermark);
+        cache
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::LRUCache;
+    use crate::policy::Fifo;
+    use crate::testing::MockClock;
+
+    #[test]
+    fn build() {
+        let cache: LRUCache<u64, u64> = LRUCache::builder()
+            .capacity(2)
+            .stats()
+            .build()
+            .unwrap();
+        assert_eq!(cache.capacity(), 2);
+        assert_eq!(cache.max_weight(), u64::MAX);
+
+        cache.put(1, 1);
+        assert_eq!(cache.get(&1), Some(1));
+        assert_eq!(cache.stats().hits, 1);
+    }
+
+    #[test]
+    fn build_sharded() {
+        let cache: ShardedLRUCache<u64, Vec<u8>> = LRUCache::builder()
+            .capacity(100)
+            .weigher(1000, |_, value: &Vec<u8>| value.len() as u64)
+            .build_sharded(4)
+            .unwrap();
+        assert_eq!(cache.shard_count(), 4);
+        assert_eq!(cache.capacity(), 100);
+        assert_eq!(cache.max_weight(), 1000);
+
+        // Shards are limited to the capacity, or the maximum weight, so none is empty.
+        let cache: ShardedLRUCache<u64, u64> = LRUCache::builder()
+            .capacity(3)
+            .build_sharded(4)
+            .unwrap();
+        assert_eq!((cache.shard_count(), cache.capacity()), (3, 3));
+        let cache: ShardedLRUCache<u64, u64> = LRUCache::builder()
+            .weigher(2, |_, _| 1)
+            .build_sharded(4)
+            .unwrap();
+        assert_eq!((cache.shard_count(), cache.max_weight()), (2, 2));
+    }
+
+    #[test]
+    fn policy() {
+        let cache = LRUCache::builder()
+            .capacity(2)
+            .policy(Fifo::default())
+            .build()
+            .unwrap();
+        cache.put(1, 1);
+        cache.put(2, 2);
+        cache.get(&1);
+        cache.put(3, 3);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&2), Some(2));
+    }
+
+    #[test]
+    fn clock() {
+        let clock = Arc::new(MockClock::new());
+        let cache = LRUCache::builder()
+            .capacity(10)
+            .ttl(Duration::from_secs(10))
+            .clock(clock.clone())
+            .expiration_timers()
+            .build_sharded(2)
+            .unwrap();
+        cache.put(1, 1);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.run_pending_tasks(), 1);
+        assert_eq!(cache.get(&1), None);
+    }
+
+    #[test]
+    fn lock_strategies() {
+        let strategies = vec![LockStrategy::Mutex, LockStrategy::RwLock, LockStrategy::Spin];
+        #[cfg(feature = "parking_lot")]
+        let strategies = [strategies, vec![LockStrategy::ParkingLot]].concat();
+
+        for strategy in strategies {
+            let cache: LRUCache<u64, u64> = LRUCache::builder()
+                .capacity(2)
+                .lock_strategy(strategy)
+                .build()
+                .unwrap();
+            cache.put(1, 1);
+            cache.put(2, 2);
+            assert_eq!(cache.get(&1), Some(1));
+            assert_eq!(cache.peek(&2), Some(2));
+            // Reads under a shared lock still update recency before the next eviction.
+            cache.put(3, 3);
+            assert_eq!((cache.peek(&1), cache.peek(&2)), (Some(1), None), "{:?}", strategy);
+
+            let cache = Arc::new(cache.clone());
+            let threads: Vec<_> = (0..4).map(|thread| {
+                let cache = Arc::clone(&cache);
+                std::thread::spawn(move || {
+                    for key in 0..1000 {
+                        cache.put(key % 10 + thread, key);
+                        cache.get(&(key % 10));
+                    }
+                })
+            }).collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(cache.len(), 2);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let builder = || LRUCache::<u64, u64>::builder();
+        assert_eq!(builder().build().err(), Some(BuildError::Unbounded));
+        assert_eq!(builder().capacity(0).build().err(), Some(BuildError::ZeroCapacity));
+        assert_eq!(builder().weigher(0, |_, _| 1).build().err(), Some(BuildError::ZeroCapacity));
+        assert_eq!(builder().capacity(1).build_sharded(0).err(), Some(BuildError::ZeroShards));
+    }
+}
@@ -0,0 +1,128 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::ops::{Bound, RangeBounds};
+
+use ::bytes::Bytes;
+
+use crate::cache::Cache;
+use crate::policy::{EvictionPolicy, Lru};
+
+/// Weigh a `Bytes` value by its length, for `LRUCache::with_weigher` or
+/// `LRUCacheBuilder::weigher`, so that the cache is limited by the bytes it holds.
+pub fn weigh_bytes<K>(_key: &K, value: &Bytes) -> u64 {
+    value.len() as u64
+}
+
+/// BytesCache stores byte buffers, such as HTTP response bodies, as `Bytes`, so that reads share
+/// the stored buffer instead of copying it.
+///
+/// `get` and `get_range` only increment a reference count, however large the value, and values
+/// are weighed by their length.
+///
+/// ```
+/// use bytes::Bytes;
+/// use cache::bytes::BytesCache;
+///
+/// let cache = BytesCache::new(1 << 20);
+/// cache.put("/", Bytes::from_static(b"<p>Hello, world!</p>"));
+/// assert_eq!(cache.get("/").unwrap(), "<p>Hello, world!</p>");
+/// assert_eq!(cache.get_range("/", 3..8).unwrap(), "Hello");
+/// assert_eq!(cache.cache().weight(), 20);
+/// ```
+pub struct BytesCache<K, P = Lru, S = RandomState>
+    where K: Eq + Hash + Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    cache: Cache<K, Bytes, P, S>
+}
+
+impl <K: Eq + Hash + Clone + 'static> BytesCache<K> {
+    /// Create a cache whose values may total at most `max_bytes`.
+    pub fn new(max_bytes: u64) -> BytesCache<K> {
+        Self::with_cache(Cache::with_weigher(max_bytes, weigh_bytes))
+    }
+}
+
+impl <K, P, S> BytesCache<K, P, S>
+    where K: Eq + Hash + Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    /// Store values in `cache`, which should weigh them with `weigh_bytes`.
+    pub fn with_cache(cache: Cache<K, Bytes, P, S>) -> BytesCache<K, P, S> {
+        BytesCache { cache }
+    }
+
+    /// The underlying cache, for example to read its statistics or remove many values.
+    pub fn cache(&self) -> &Cache<K, Bytes, P, S> {
+        &self.cache
+    }
+
+    /// Get the value for `key`, sharing its buffer.  See `LRUCache::get`.
+    pub fn get<Q>(&self, key: &Q) -> Option<Bytes>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.cache.get(key)
+    }
+
+    /// Get `range` of the value for `key`, sharing its buffer, as for an HTTP range request.
+    ///
+    /// Returns `None` if `key` is missing or `range` is out of bounds of its value.
+    pub fn get_range<Q, R>(&self, key: &Q, range: R) -> Option<Bytes>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized, R: RangeBounds<usize> {
+        let value = self.cache.get_ref(key)?;
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start.checked_add(1)?,
+            Bound::Unbounded => 0
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end.checked_add(1)?,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => value.len()
+        };
+
+        if start <= end && end <= value.len() {
+            Some(value.slice(start..end))
+        } else {
+            None
+        }
+    }
+
+    /// Put `value` for `key`.
+    ///
+    /// # Returns
+    ///
+    /// The previous value for `key`, or `None`.
+    pub fn put<B: Into<Bytes>>(&self, key: K, value: B) -> Option<Bytes> {
+        self.cache.put(key, value.into())
+    }
+
+    /// Remove `key`, returning its value.
+    pub fn remove<Q>(&self, key: &Q) -> Option<Bytes>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.cache.remove(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_cache() {
+        let cache = BytesCache::new(10);
+        let body = Bytes::from(vec![1, 2, 3, 4, 5, 6]);
+        cache.put(1, body.clone());
+
+        // Reads share the stored buffer.
+        let value = cache.get(&1).unwrap();
+        assert_eq!(value.as_ptr(), body.as_ptr());
+        let range = cache.get_range(&1, 2..=3).unwrap();
+        assert_eq!((range.as_ref(), range.as_ptr()), (&[3, 4][..], body[2..].as_ptr()));
+        assert_eq!(cache.get_range(&1, 4..), Some(Bytes::from_static(&[5, 6])));
+        assert_eq!(cache.get_range(&1, 4..7), None);
+        assert_eq!(cache.get_range(&2, ..), None);
+
+        // Values are weighed by length.
+        cache.put(2, vec![0; 5]);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.cache().weight(), 5);
+        assert_eq!(cache.remove(&2).map(|value| value.len()), Some(5));
+    }
+}
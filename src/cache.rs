@@ -1,25 +1,307 @@
 use std::fmt;
+use std::borrow::Borrow;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::RandomState;
+use std::convert::Infallible;
+use std::hash::{BuildHasher, Hash};
+use std::iter::FromIterator;
 use std::mem;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use intrusive_collections::{LinkedList, LinkedListLink};
-use intrusive_collections::intrusive_adapter;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+#[cfg(not(target_arch = "wasm32"))]
+use std::thread::JoinHandle;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "rayon")]
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+
+use crate::admission::TinyLfu;
+use crate::builder::LRUCacheBuilder;
+use crate::clock::{Clock, SystemClock, Timestamp, SYSTEM_CLOCK_READABLE};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::expiry;
+use crate::expiry::{EarlyExpiration, TimerWheel};
+use crate::group::{GroupExtractor, GroupIndex};
+use crate::inflight::InFlight;
+use crate::listener::{CacheEvent, EvictionCause, EvictionListener};
+use crate::memory::MemSize;
+use crate::negative::NegativeCache;
+use crate::policy::{EvictionPolicy, Lru};
+use crate::refresh::Refresh;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::refresh::Loader;
+use crate::stats::{CacheStats, EvictionAgeRecorder, EvictionAges, HotKey, MetricsRecorder,
+                   StatsCounter};
+use crate::token::InvalidationToken;
+use crate::trace::{TraceOp, TraceRecorder};
+
+use self::access::AccessBuffer;
+use self::lists::LruLists;
+use self::lock::{Lock, LockGuard, ReadGuard, Recover};
+
+pub use self::entry::{Entry, OccupiedEntry, VacantEntry};
+
+mod access;
+mod entry;
+mod lists;
+mod lock;
+#[cfg(feature = "serde")]
+mod persist;
+mod slab;
 
 struct CacheValue<K, V> {
     key: K,
     value: V,
-    link: LinkedListLink
+    token: Option<InvalidationToken>,
+    /// When the value was put.  See `LRUCache::invalidate_all_before`.
+    put_at: Timestamp,
+    expires_at: Option<Timestamp>,
+    idle: Option<Idle>,
+    /// When the value expires regardless of its time-to-live, and even if kept stale.  See
+    /// `LRUCache::set_max_lifetime`.
+    lifetime_ends_at: Option<Timestamp>,
+    /// The nanoseconds from `idle.put_at` to when the value was last read, if it has an idle
+    /// timeout.
+    last_read: AtomicU64,
+    weight: u64,
+    version: u64,
+    /// Whether the value has been read since the clock hand last passed it, in
+    /// `RecencyMode::Clock`.
+    referenced: AtomicBool,
+    priority: Priority,
+    /// The index of the value in its priority's list in `CacheData::lru_list`.
+    index: usize
+}
+
+/// Per-value settings supplied by the various `put` methods.
+#[derive(Default)]
+struct EntryOptions {
+    token: Option<InvalidationToken>,
+    put_at: Timestamp,
+    expires_at: Option<Timestamp>,
+    idle: Option<Idle>,
+    lifetime_ends_at: Option<Timestamp>,
+    priority: Priority,
+    /// Whether to insert the value at the least recently used end.  See `LRUCache::put_cold`.
+    cold: bool
+}
+
+/// The time-to-idle of a value.  See `LRUCache::set_time_to_idle`.
+#[derive(Clone, Copy)]
+struct Idle {
+    timeout: Duration,
+    put_at: Timestamp
+}
+
+/// The priority of a value, set by `LRUCache::put_with_priority`.
+///
+/// When the cache needs room, it evicts values of the lowest priority present first, and only
+/// then considers higher priorities.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Priority {
+    Low,
+    Normal,
+    High
+}
+
+impl Default for Priority {
+    /// Values are put with `Normal` priority unless another is given.
+    fn default() -> Priority {
+        Priority::Normal
+    }
+}
+
+/// The kind of lock protecting the state of a `Cache`.  See `LRUCacheBuilder::lock_strategy`.
+///
+/// Which performs best depends on the workload, the number of threads and the hardware, so it is
+/// worth measuring: the benchmarks compare them under read-heavy and write-heavy loads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LockStrategy {
+    /// The standard library's `Mutex`, which suits most workloads.  The default.
+    #[default]
+    Mutex,
+    /// `parking_lot`'s `Mutex`, which is cheaper to acquire when contended briefly.  Requires the
+    /// `parking_lot` feature.
+    #[cfg(feature = "parking_lot")]
+    ParkingLot,
+    /// A `RwLock`, so that `get`, `peek` and the other lookups of values present in the cache run
+    /// concurrently, which suits read-heavy workloads such as web caches.
+    ///
+    /// `get` cannot move values in the LRU list under a shared lock, so it buffers the values it
+    /// reads, as `RecencyMode::Buffered` does, and the next operation to lock the cache exclusively
+    /// updates their recency.  Misses still lock the cache exclusively.
+    RwLock,
+    /// A mutex which waiting threads spin on instead of sleeping, which suits write-heavy
+    /// workloads, such as ingestion, where each thread holds the lock only briefly.  Spinning
+    /// wastes CPU time when the lock is held for long or by more threads than there are cores,
+    /// so it is best with a `ShardedLRUCache` of at least as many shards as threads.
+    Spin
+}
+
+/// How a `Cache` tracks the recency of its values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecencyMode {
+    /// Move each value to the most recently used position whenever it is read.
+    ///
+    /// Eviction order is exactly least recently used, but every read modifies the LRU list.
+    Exact,
+    /// Approximate recency with the CLOCK algorithm.
+    ///
+    /// Reads only set a flag on the value.  When the cache needs to evict, it walks the LRU list
+    /// from the least recently used end, giving each flagged value a second chance by clearing its
+    /// flag and moving it to the most recently used end, and stops at the first unflagged value.
+    /// Reads are cheaper (and hold the lock for less time), but the order of the LRU list (and so
+    /// `peek_lru`, `peek_mru` and eviction) only approximates recency.
+    Clock,
+    /// Record reads in a small buffer, and move the values read to the most recently used
+    /// position later: when the buffer fills, or when an operation which may insert or evict,
+    /// such as `put`, locks the cache anyway.
+    ///
+    /// A read then holds the lock only for a hash lookup and a push onto the buffer, which helps
+    /// when many threads share the cache, though a single thread does slightly more work in all.
+    /// Reads are dropped when many threads read at once, and `peek_lru`, `peek_mru` and iteration
+    /// may not yet reflect the latest reads, so recency is approximate, but eviction accounts for
+    /// every read recorded.
+    Buffered
 }
 
-// HACK: Fix this... not sure why but LinkedlistLink isn't sync.
-unsafe impl <K,V> Sync for CacheValue<K,V> {}
+/// The result of `LRUCache::lookup`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CacheResult<V> {
+    /// The cache holds a value for the key.
+    Hit(V),
+    /// The key was put with `put_negative`: it is known to have no value.
+    NegativeHit,
+    /// The cache knows nothing about the key.
+    Miss
+}
+
+/// The result of `LRUCache::get_allow_stale`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StaleResult<V> {
+    /// The cache holds an unexpired value for the key.
+    Fresh(V),
+    /// The cache holds a value for the key whose time-to-live or time-to-idle has elapsed, kept
+    /// by `LRUCache::set_keep_stale`.
+    Stale(V),
+    /// The cache holds no value for the key.
+    Miss
+}
+
+/// Computes the weight of a value for `LRUCache::with_weigher`.
+pub(crate) type Weigher<K, V> = dyn Fn(&K, &V) -> u64 + Send + Sync;
+
+/// Called with how long an operation waited for the cache's lock.  See
+/// `LRUCache::set_slow_lock_listener`.
+pub(crate) type SlowLockListener = dyn Fn(Duration) + Send + Sync;
+
+/// Called with how many values an operation evicted at once to keep within the cache's limits,
+/// and how long it took.  See `LRUCache::set_eviction_batch_listener`.
+pub(crate) type EvictionBatchListener = dyn Fn(usize, Duration) + Send + Sync;
+
+/// Called with how long a loader ran and whether it produced a value.  See
+/// `LRUCache::set_loader_listener`.
+pub(crate) type LoaderListener = dyn Fn(Duration, bool) + Send + Sync;
+
+/// Called, while the cache is locked, with the key of each value put into or leaving the cache.
+/// See `Cache::add_change_hook`.
+pub(crate) type ChangeHook<K> = dyn Fn(&K) + Send + Sync;
+
+/// The bounds on the contents of a cache.
+#[derive(Clone, Copy)]
+pub(crate) struct Limits {
+    /// The maximum number of values.
+    pub(crate) capacity: usize,
+    /// The maximum total weight of all values.
+    pub(crate) max_weight: u64
+}
 
 impl <K, V> CacheValue<K, V> {
-    fn new(key: K, value: V) -> CacheValue<K, V> {
+    fn new(key: K, value: V, options: EntryOptions, weight: u64, version: u64, index: usize)
+        -> CacheValue<K, V> {
         CacheValue {
             key,
             value,
-            link: LinkedListLink::new()
+            token: options.token,
+            put_at: options.put_at,
+            expires_at: options.expires_at,
+            idle: options.idle,
+            lifetime_ends_at: options.lifetime_ends_at,
+            last_read: AtomicU64::new(0),
+            weight,
+            version,
+            referenced: AtomicBool::new(false),
+            priority: options.priority,
+            index
+        }
+    }
+
+    /// Whether this value should be treated as absent from the cache, because its time-to-live,
+    /// time-to-idle or maximum lifetime has elapsed or the token it was inserted with has been
+    /// invalidated.
+    fn is_expired(&self, clock: &dyn Clock) -> bool {
+        if let Some(ref token) = self.token {
+            if token.is_invalidated() {
+                return true;
+            }
+        }
+        self.is_elapsed(clock)
+    }
+
+    /// Whether this value has expired only because its time-to-live or time-to-idle has elapsed,
+    /// and so may still be served stale.  See `LRUCache::set_keep_stale`.
+    fn is_stale(&self, clock: &dyn Clock) -> bool {
+        let now = clock.now();
+        self.token.as_ref().is_none_or(|token| !token.is_invalidated())
+            && self.lifetime_ends_at.is_none_or(|lifetime_ends_at| now < lifetime_ends_at)
+            && self.is_elapsed(clock)
+    }
+
+    /// Whether the value's time-to-live, time-to-idle or maximum lifetime has elapsed.
+    fn is_elapsed(&self, clock: &dyn Clock) -> bool {
+        match self.deadline() {
+            None => false,
+            Some(deadline) => clock.now() >= deadline
+        }
+    }
+
+    /// When the value expires, by its time-to-live, time-to-idle or maximum lifetime, if it is
+    /// not read again.
+    fn deadline(&self) -> Option<Timestamp> {
+        let idle_deadline = self.idle.map(|idle| {
+            let last_read = Duration::from_nanos(self.last_read.load(Ordering::Relaxed));
+            idle.put_at + last_read + idle.timeout
+        });
+        [self.expires_at, idle_deadline, self.lifetime_ends_at].iter().flatten().min().copied()
+    }
+
+    /// The options to put a copy of the value with, so that it expires at the same time.
+    fn options(&self) -> EntryOptions {
+        let idle = self.idle.map(|idle| Idle {
+            timeout: idle.timeout,
+            put_at: idle.put_at + Duration::from_nanos(self.last_read.load(Ordering::Relaxed))
+        });
+        EntryOptions {
+            token: self.token.clone(),
+            put_at: self.put_at,
+            expires_at: self.expires_at,
+            idle,
+            lifetime_ends_at: self.lifetime_ends_at,
+            priority: self.priority,
+            cold: false
+        }
+    }
+
+    /// Restart the value's time-to-idle, if it has one.
+    fn record_read(&self, clock: &dyn Clock) {
+        if let Some(idle) = self.idle {
+            let since_put = clock.now().saturating_duration_since(idle.put_at).as_nanos() as u64;
+            self.last_read.store(since_put, Ordering::Relaxed);
         }
     }
 }
@@ -30,28 +312,73 @@ impl <K, V> fmt::Debug for CacheValue<K, V> {
     }
 }
 
-intrusive_adapter!(CacheValueAdapter<K, V> = Arc<CacheValue<K, V>>: CacheValue<K, V> { link: LinkedListLink });
 
 
 /// LRUCache implements an in-memory cache of fixed capacity with a least-recency-used replacement
 /// policy.
 ///
-/// The cache accepts any hashable and clonable value as a key type.
+/// This is a `Cache` using the `Lru` policy; see `Cache` for all operations.
+pub type LRUCache<K, V> = Cache<K, V, Lru>;
+
+/// ArcCache is an `LRUCache` which stores reference-counted values.
+///
+/// `get` returns a new reference to the stored value rather than a deep copy, so reads are cheap
+/// even for large values, and `V` need not implement `Clone`.  Values are put as `Arc<V>`.
+pub type ArcCache<K, V> = LRUCache<K, Arc<V>>;
+
+/// ArcKeyCache is an `LRUCache` whose keys are reference-counted, so that `K` need not implement
+/// `Clone`: keys holding file handles, for example.
+///
+/// The cache clones keys freely (each value's key is held by the map, the LRU list and the
+/// policy), so for a key wrapped in an `Arc` each copy is only a new reference.  Keys are put as
+/// `Arc<K>`, but since `Arc<K>` borrows as `K`, they are looked up by `&K`:
+///
+/// ```
+/// use std::path::PathBuf;
+/// use std::sync::Arc;
+/// use cache::cache::ArcKeyCache;
+///
+/// // Not `Clone`.
+/// #[derive(PartialEq, Eq, Hash)]
+/// struct Source(PathBuf);
+///
+/// let cache: ArcKeyCache<Source, String> = ArcKeyCache::new(10);
+/// cache.put(Arc::new(Source(PathBuf::from("main.rs"))), "fn main() {}".to_string());
+/// assert!(cache.get(&Source(PathBuf::from("main.rs"))).is_some());
+/// ```
+pub type ArcKeyCache<K, V> = LRUCache<Arc<K>, V>;
+
+/// Cache implements an in-memory cache of fixed capacity with a pluggable replacement policy.
+///
+/// The cache accepts any hashable and clonable value as a key type; keys which cannot be cloned
+/// can be wrapped in an `Arc` (see `ArcKeyCache`).  The policy `P` chooses which
+/// value to evict when the cache is full (see the `policy` module); `LRUCache` is the common case.
 ///
 /// # Implementation Notes:
 ///
-/// The LRUCache maintains a HashMap and doubly-linked-list to perform usage tracking.
+/// The cache maintains a HashMap and doubly-linked-list to perform usage tracking.
 ///
-/// Within both are reference-counted pointers to a CacheValue which implements an intrusive
-/// linked list. The instrusive list is necessary so that the LRU position can be updated in O(1)
-/// time (the linked list node is returned by the map.
+/// Within both are reference-counted pointers to a CacheValue.  The list's nodes are allocated
+/// from a single buffer (a slab) and linked by index, and each CacheValue records the index of its
+/// node, so that the LRU position can be updated in O(1) time from the value returned by the map.
+/// Nodes freed by evictions are reused by later insertions.  There is one such list for each
+/// `Priority`.
 ///
-/// The reference-counted pointers are required because Rust does not support self-referential
-/// structs. (took me some time to realize this).
+/// The reference-counted pointers are required because values are shared outside of the lock, by
+/// `Snapshot`, `Iter` and `ValueGuard`.
 ///
 /// # Concurrency:
 ///
-/// ...
+/// All state is protected by a single `Mutex`, and every operation takes `&self`, so an
+/// `Arc<LRUCache>` can be shared between reader and writer threads without external locking.
+///
+/// # Panics:
+///
+/// A panic in user code while the cache is locked (in a key's `Hash` or `Eq`, a value's `Clone`,
+/// or a weigher, say) may leave its state inconsistent.  The cache does not stay poisoned: the
+/// next operation clears it, without notifying the eviction listener, and carries on.  Panics
+/// in the eviction listener do not stop the delivery of other evictions, and are resumed once
+/// they have been delivered.
 ///
 /// # Alternative implementations:
 ///
@@ -59,18 +386,137 @@ intrusive_adapter!(CacheValueAdapter<K, V> = Arc<CacheValue<K, V>>: CacheValue<K
 ///
 /// # Concerns:
 ///
-/// This data structure is pretty poor for cache-locality (if I am understanding Arc correctly).
-/// Each value is separately allocated, so the data the cache points to will not be brought into
-/// cache together.  Ideally, we would allocate the memory that each Arc points to from a single
-/// buffer.
-pub struct LRUCache<K: Eq + std::hash::Hash + Clone, V: Clone> {
-    map: Mutex<HashMap<K, Arc<CacheValue<K, V>>>>,
-    lru_list: Mutex<LinkedList<CacheValueAdapter<K, V>>>,
-    capacity: usize
+/// The list is compact, but each value is still separately allocated (so that it can be shared
+/// by reference counting), so the values the cache points to will not be brought into cache
+/// together.
+pub struct Cache<K, V, P = Lru, S = RandomState>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    data: Lock<CacheData<K, V, P, S>>,
+    weigher: Option<Arc<Weigher<K, V>>>,
+    /// The time-to-live of values put without an explicit one.
+    ttl: Option<Duration>,
+    tti: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    clock: Arc<dyn Clock>,
+    /// Whether to read `clock` for every put, for `invalidate_all_before`, rather than only when
+    /// values expire.  Not while `clock` is a `SystemClock` which cannot be read.
+    records_put_at: bool,
+    /// The number of threads in `wait_for`.
+    waiters: AtomicUsize,
+    /// Held by `wait_for` between looking for a value and waiting for `inserted`, so that an
+    /// insertion in between is not missed.
+    waiting: Mutex<()>,
+    /// Notified whenever a value is inserted into `data` while `waiters` is non-zero.
+    inserted: Condvar,
+    idle_shrink: Option<IdleShrink>,
+    listener: Option<Arc<EvictionListener<K, V>>>,
+    /// Shared with `CacheData::stats`, so that it can be read without locking.
+    stats: Option<Arc<StatsCounter>>,
+    refresh: Option<Refresh<K, V>>,
+    early_expiration: Option<EarlyExpiration>,
+    slow_lock: Option<SlowLock>,
+    loader_listener: Option<Arc<LoaderListener>>,
+    trace: Option<Arc<TraceRecorder>>,
+    /// Set by `shutdown`, to stop background refresh and maintenance.
+    shut_down: AtomicBool
+}
+
+/// The state of a `Cache`, protected by a single lock.
+struct CacheData<K, V, P, S> {
+    map: HashMap<K, Arc<CacheValue<K, V>>, S>,
+    limits: Limits,
+    /// Values ordered from most recently used (front) to least recently used (back).
+    lru_list: LruLists<K, V>,
+    /// The total weight of all values in `map`.
+    weight: u64,
+    /// The version assigned to the most recent `put`.
+    version: u64,
+    /// Keys being computed by `try_get_or_insert_with`.
+    loading: HashMap<K, Arc<InFlight<V>>>,
+    /// Whether an eviction listener is registered, and so values leaving the cache should be
+    /// collected in `evicted`.
+    listening: bool,
+    /// Values which have left the cache while locked, to be passed to the eviction listener.
+    evicted: Vec<(K, V, EvictionCause)>,
+    /// Senders for the receivers returned by `subscribe`.
+    subscribers: Vec<Sender<CacheEvent<K, V>>>,
+    change_hooks: Vec<Arc<ChangeHook<K>>>,
+    stats: Option<Arc<StatsCounter>>,
+    eviction_ages: Option<EvictionAgeRecorder>,
+    policy: P,
+    admission: Option<TinyLfu>,
+    recency: RecencyMode,
+    /// Values read whose recency is not yet updated.  See `RecencyMode::Buffered`.
+    accesses: AccessBuffer<Arc<CacheValue<K, V>>>,
+    groups: Option<GroupIndex<K>>,
+    /// When values expire, if expiration timers are enabled.
+    timers: Option<TimerWheel<K>>,
+    negative: NegativeCache<K>,
+    /// Keys exempt from eviction.  The policy does not track them while they are pinned.
+    pinned: HashSet<K>,
+    max_pinned: usize,
+    /// The most values an insert may evict.  Any excess is evicted by later operations.
+    max_evictions: usize,
+    eviction_batch_listener: Option<Arc<EvictionBatchListener>>,
+    /// The fraction of `limits` to evict down to once an insert must evict.
+    low_watermark: f64,
+    /// Whether values whose time-to-live or time-to-idle has elapsed are kept to be served stale.
+    keep_stale: bool
+}
+
+/// Occupancy, as a fraction of capacity, below which an idle cache releases memory.
+const IDLE_SHRINK_OCCUPANCY_DIVISOR: usize = 4;
+
+/// Tracks activity for `LRUCache::set_idle_shrink`.
+struct IdleShrink {
+    period: Duration,
+    /// Milliseconds since the clock's origin at the last cache operation.
+    last_activity: AtomicU64
+}
+
+impl IdleShrink {
+    fn new(period: Duration, now: Timestamp) -> IdleShrink {
+        IdleShrink {
+            period,
+            last_activity: AtomicU64::new(now.since_origin().as_millis() as u64)
+        }
+    }
+
+    /// Record activity at `now`, returning whether the cache was idle for at least `period`
+    /// beforehand.
+    fn record_activity(&self, now: Timestamp) -> bool {
+        let now = now.since_origin().as_millis() as u64;
+        let last = self.last_activity.swap(now, Ordering::Relaxed);
+        Duration::from_millis(now.saturating_sub(last)) >= self.period
+    }
+}
+
+/// Reports slow lock acquisitions for `LRUCache::set_slow_lock_listener`.
+struct SlowLock {
+    threshold: Duration,
+    listener: Arc<SlowLockListener>
+}
+
+impl SlowLock {
+    /// Lock `lock`, reporting the wait if it was at least `threshold`.
+    fn lock<'a, T: Recover>(&self, lock: &'a Lock<T>) -> LockGuard<'a, T> {
+        // Uncontended locks are not timed.
+        if let Some(guard) = lock.try_lock() {
+            return guard;
+        }
+
+        let started = Instant::now();
+        let guard = lock.lock();
+        let waited = started.elapsed();
+        if waited >= self.threshold {
+            (self.listener)(waited);
+        }
+        guard
+    }
 }
 
 
-impl <K: Eq + std::hash::Hash + Clone, V: Clone> LRUCache<K, V> {
+impl <K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K> + Default> Cache<K, V, P> {
     /// Create a LRUCache with space for `capacity` items.
     ///
     /// # Arguments:
@@ -80,178 +526,4289 @@ impl <K: Eq + std::hash::Hash + Clone, V: Clone> LRUCache<K, V> {
     /// # NB:
     ///
     /// - The cache will allocate memory for all items, even if it is not full.
-    pub fn new(capacity: usize) -> LRUCache<K, V> {
-        LRUCache {
-            map: Mutex::new(HashMap::with_capacity(capacity)),
-            lru_list: Mutex::new(LinkedList::new(CacheValueAdapter::new())),
-            capacity
-        }
+    pub fn new(capacity: usize) -> Cache<K, V, P> {
+        Self::with_policy(capacity, P::default())
     }
 
-    /// Get the value for `key` in `self`, if it exists.  Otherwise, return `None`.
-    pub fn get(&self, key: &K) -> Option<V> {
-        let map = self.map.lock().unwrap();
+    /// Create a LRUCache whose values may have a total weight of at most `max_weight`, as computed
+    /// by `weigher`.
+    ///
+    /// There is no limit on the number of values.  When a value is put, less recently used values
+    /// are evicted until it fits.  Values heavier than `max_weight` are not stored at all (and
+    /// are reported to the eviction listener with `EvictionCause::Capacity`).
+    ///
+    /// # NB:
+    ///
+    /// - Unlike `new`, the cache does not allocate memory up front.
+    pub fn with_weigher<F>(max_weight: u64, weigher: F) -> Cache<K, V, P>
+        where F: Fn(&K, &V) -> u64 + Send + Sync + 'static {
+        Self::with_shared_weigher(max_weight, Arc::new(weigher))
+    }
 
-        match map.get(key) {
-            None => None,
-            Some(cache_value) => {
-                self.touch(cache_value);
-                Some(cache_value.value.clone())
-            }
-        }
+    pub(crate) fn with_shared_weigher(max_weight: u64, weigher: Arc<Weigher<K, V>>)
+        -> Cache<K, V, P> {
+        let limits = Limits { capacity: usize::MAX, max_weight };
+        Self::with_limits(limits, Some(weigher), P::default(), RandomState::new(),
+                          LockStrategy::default())
     }
 
-    /// Put `value` into `self` for `key`.
+    /// Create a LRUCache with space for `capacity` items, which expire `ttl` after they are put.
     ///
-    /// # Returns
+    /// Expired values are treated as absent, and are reclaimed when they are next accessed, when
+    /// they reach the least recently used end of the cache, or by `purge_expired` or
+    /// `run_pending_tasks`.
+    pub fn with_ttl(capacity: usize, ttl: Duration) -> Cache<K, V, P> {
+        let mut cache = Self::new(capacity);
+        cache.set_ttl(Some(ttl));
+        cache
+    }
+
+    /// Create a LRUCache with space for `capacity` items, and put the items of `iter` into it.
     ///
-    /// The previous value in the cache, or `None`.
-    pub fn put(&mut self, key: K, value: V) -> Option<V> {
-        let cache_value = Arc::new(CacheValue::new(key.clone(), value));
+    /// Items are put in order, so later items are more recently used (and evict earlier ones if
+    /// there are more than `capacity`).
+    pub fn from_iter_with_capacity<I>(capacity: usize, iter: I) -> Cache<K, V, P>
+        where I: IntoIterator<Item = (K, V)> {
+        let mut cache = Self::new(capacity);
+        cache.extend(iter);
+        cache
+    }
 
-        // We only need to make room for a new value if we are not replacing an old one.
-        let contains_key = {
-            let map = self.map.lock().unwrap();
-            map.contains_key(&key)
-        };
+    /// Configure a cache with more options than the other constructors allow.
+    pub fn builder() -> LRUCacheBuilder<K, V, P> {
+        LRUCacheBuilder::new()
+    }
+}
+
+impl <K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>> Cache<K, V, P> {
+    /// Create a cache with space for `capacity` items, which evicts the values chosen by `policy`.
+    pub fn with_policy(capacity: usize, policy: P) -> Cache<K, V, P> {
+        let limits = Limits { capacity, max_weight: u64::MAX };
+        Self::with_limits(limits, None, policy, RandomState::new(), LockStrategy::default())
+    }
+}
+
+impl <K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher> Cache<K, V, P, S> {
+    /// Create a cache with space for `capacity` items, which hashes keys with `hash_builder`.
+    ///
+    /// The default hasher resists denial-of-service attacks by callers who choose the keys, but is
+    /// comparatively slow for small keys such as integers.
+    pub fn with_hasher(capacity: usize, hash_builder: S) -> Cache<K, V, P, S>
+        where P: Default {
+        let limits = Limits { capacity, max_weight: u64::MAX };
+        Self::with_limits(limits, None, P::default(), hash_builder, LockStrategy::default())
+    }
 
-        if !contains_key {
-            self.make_room();
+    /// Create a cache within `limits`, whose state is protected by a lock of `strategy`.  See
+    /// `LRUCacheBuilder::lock_strategy`.
+    pub(crate) fn with_limits(limits: Limits, weigher: Option<Arc<Weigher<K, V>>>, policy: P,
+                              hash_builder: S, strategy: LockStrategy) -> Cache<K, V, P, S> {
+        let preallocate = if weigher.is_none() { limits.capacity } else { 0 };
+
+        Cache {
+            data: Lock::new(strategy, CacheData {
+                map: HashMap::with_capacity_and_hasher(preallocate, hash_builder),
+                limits,
+                lru_list: LruLists::with_capacity(preallocate),
+                weight: 0,
+                version: 0,
+                loading: HashMap::new(),
+                listening: false,
+                evicted: Vec::new(),
+                subscribers: Vec::new(),
+                change_hooks: Vec::new(),
+                stats: None,
+                eviction_ages: None,
+                policy,
+                admission: None,
+                recency: RecencyMode::Exact,
+                accesses: AccessBuffer::new(),
+                groups: None,
+                timers: None,
+                negative: NegativeCache::new(),
+                pinned: HashSet::new(),
+                max_pinned: 0,
+                max_evictions: usize::MAX,
+                eviction_batch_listener: None,
+                low_watermark: 1.0,
+                keep_stale: false
+            }),
+            weigher,
+            ttl: None,
+            tti: None,
+            max_lifetime: None,
+            clock: Arc::new(SystemClock),
+            records_put_at: SYSTEM_CLOCK_READABLE,
+            waiters: AtomicUsize::new(0),
+            waiting: Mutex::new(()),
+            inserted: Condvar::new(),
+            idle_shrink: None,
+            listener: None,
+            stats: None,
+            refresh: None,
+            early_expiration: None,
+            slow_lock: None,
+            loader_listener: None,
+            trace: None,
+            shut_down: AtomicBool::new(false)
         }
+    }
 
-        let map = self.map.get_mut().unwrap();
-        let lru_list = self.lru_list.get_mut().unwrap();
-        let old_value = match map.insert(key, Arc::clone(&cache_value)) {
-            None => None,
-            Some(cache_value) => {
-                let value;
+    pub(crate) fn set_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+    }
 
-                // This unsafe block is required to remove the item from the intrusive linked list
-                // contained in `CacheValue`.
-                //
-                // Assumes that cache_value is already in `lru_list`.
-                unsafe {
+    /// Expire values which have not been read for `tti` (their time-to-idle), even if their
+    /// time-to-live has not elapsed.
+    ///
+    /// Lookups such as `get`, `touch` and `get_or_insert_with` restart a value's time-to-idle;
+    /// `peek` and friends do not.  Applies to values put after it is set.  Pass `None` to stop
+    /// expiring idle values (the default).
+    pub fn set_time_to_idle(&mut self, tti: Option<Duration>) {
+        self.tti = tti;
+    }
+
+    /// Expire values `max_lifetime` after they are put, whatever their time-to-live or
+    /// time-to-idle, so that no value is served for longer.  Unlike other expired values, values
+    /// past their maximum lifetime are never served stale (see `set_keep_stale`): they are always
+    /// misses, and so loaded again.
+    ///
+    /// Reads do not extend a value's lifetime, but putting it again (including by background
+    /// refresh) starts a new one.  Applies to values put after it is set.  Pass `None` to let
+    /// values live indefinitely (the default).
+    pub fn set_max_lifetime(&mut self, max_lifetime: Option<Duration>) {
+        self.max_lifetime = max_lifetime;
+    }
 
-                    let raw = Arc::into_raw(cache_value);
-                    let mut cursor = lru_list.cursor_mut_from_ptr(raw);
-                    value = cursor.remove();
+    /// The current time by the cache's clock, for `invalidate_all_before`.  See `set_clock`.
+    pub fn now(&self) -> Timestamp {
+        self.clock.now()
+    }
 
-                    // Converts raw pointer back into a `Arc<CacheValue>` that can be dropped at the
-                    // end of this scope.
-                    Arc::from_raw(raw);
-                }
+    /// The number of values in the cache.
+    ///
+    /// Includes values which have expired (or whose `InvalidationToken` has been invalidated) but
+    /// which have not yet been reclaimed.
+    pub fn len(&self) -> usize {
+        self.data.read().map.len()
+    }
 
+    /// Whether the cache contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-                match Arc::try_unwrap(value.expect("Unexpected error")) {
-                    Err(rc) => {
-                        panic!("Expected one owner for rc, found {}", Arc::strong_count(&rc))
-                    },
-                    Ok(value) => {
-                        Some(value.value)
-                    }
-                }
-            }
+    /// The maximum number of values permitted in the cache.
+    ///
+    /// Unbounded (`usize::MAX`) for caches created by `with_weigher`.
+    pub fn capacity(&self) -> usize {
+        self.data.read().limits.capacity
+    }
+
+    /// The total weight of the values in the cache.
+    ///
+    /// Without a weigher every value weighs 1, so this is the same as `len`.
+    pub fn weight(&self) -> u64 {
+        self.data.read().weight
+    }
+
+    /// The maximum total weight of the values permitted in the cache.
+    ///
+    /// Unbounded (`u64::MAX`) for caches without a weigher.
+    pub fn max_weight(&self) -> u64 {
+        self.data.read().limits.max_weight
+    }
+
+    /// Change the maximum number of values permitted in the cache.
+    ///
+    /// If the cache holds more than `capacity` values, the least recently used values are evicted
+    /// immediately (and reported with `EvictionCause::Capacity`).
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut data = self.lock();
+        data.limits.capacity = capacity;
+        data.enforce_limits();
+        self.unlock(data);
+    }
+
+    /// Change the maximum total weight of the values permitted in the cache.
+    ///
+    /// If the values in the cache weigh more than `max_weight`, the least recently used values are
+    /// evicted immediately (and reported with `EvictionCause::Capacity`).
+    pub fn set_max_weight(&self, max_weight: u64) {
+        let mut data = self.lock();
+        data.limits.max_weight = max_weight;
+        data.enforce_limits();
+        self.unlock(data);
+    }
+
+    /// Permit at most `max_pinned` values to be pinned at once.  See `pin`.
+    ///
+    /// The budget is zero by default, so values cannot be pinned until it is set.  Lowering it
+    /// does not unpin any values, but prevents more from being pinned until enough are unpinned.
+    pub fn set_max_pinned(&self, max_pinned: usize) {
+        self.data.lock().max_pinned = max_pinned;
+    }
+
+    /// Evict at most `max_evictions` values to make room for each new value, so that one large
+    /// value cannot hold the lock for long while it evicts many small ones.
+    ///
+    /// A value which does not fit after `max_evictions` evictions is stored anyway, leaving the
+    /// cache over its limits until later puts or `run_pending_tasks` evict the excess.  Unbounded
+    /// (`usize::MAX`) by default.
+    pub fn set_max_evictions(&self, max_evictions: usize) {
+        self.data.lock().max_evictions = max_evictions;
+    }
+
+    /// Once the cache is full, evict down to `fraction` of its capacity and maximum weight in one
+    /// pass, instead of evicting just enough for each new value.
+    ///
+    /// Under sustained inserts this evicts in batches, so most puts evict nothing.  `fraction` is
+    /// clamped to between 0 and 1, and is 1 (no batching) by default.  Batches are still bounded
+    /// by `set_max_evictions`.
+    pub fn set_low_watermark(&self, fraction: f64) {
+        self.data.lock().low_watermark = fraction.clamp(0.0, 1.0);
+    }
+
+    /// Pin the value for `key`, exempting it from eviction until it is unpinned.
+    ///
+    /// Pinned values still count towards the cache's capacity and maximum weight, and still
+    /// expire or may be removed.  If the cache is full of pinned values, new values are rejected
+    /// (and reported with `EvictionCause::Capacity`).  The pin is kept when the value is replaced
+    /// by a `put`, and dropped when it leaves the cache.
+    ///
+    /// # Returns
+    ///
+    /// Whether `key` is now pinned: `false` if it is not in the cache, or if pinning it would
+    /// exceed the budget set by `set_max_pinned`.
+    pub fn pin<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        let mut data = self.lock();
+        let key = match data.map.get(key) {
+            Some(cache_value) if !cache_value.is_expired(&*self.clock) => cache_value.key.clone(),
+            _ => return false
         };
 
-        lru_list.push_front(Arc::clone(&cache_value));
+        let pinned = data.pin(key);
+        self.unlock(data);
+        pinned
+    }
+
+    /// Unpin the value for `key`, making it eligible for eviction again.
+    ///
+    /// # Returns
+    ///
+    /// Whether `key` was pinned.
+    pub fn unpin<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        let mut data = self.lock();
+        let unpinned = data.unpin(key);
+        self.unlock(data);
+        unpinned
+    }
+
+    /// Remove all values from the cache.
+    pub fn clear(&self) {
+        let mut data = self.lock();
+        data.clear();
+        self.unlock(data);
+    }
+
+    /// Reload values in the background when they are read within `window` of expiring.
+    ///
+    /// A `get` (or `get_versioned` or `get_ref`) which finds a value expiring within `window`
+    /// returns it immediately, and starts a thread which calls `loader` for its key.  The reloaded
+    /// value replaces the old one (with a new time-to-live) on the cache's next operation, unless
+    /// the old value has been replaced or removed in the meantime.  If `loader` returns `None`,
+    /// the old value is kept until it expires.  Each key is reloaded by at most one thread at a
+    /// time.
+    ///
+    /// Only values with a time-to-live are refreshed, so hot keys can be reloaded before they
+    /// expire instead of missing at the boundary.
+    ///
+    /// Not available on `wasm32`, which has no threads.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn set_refresh<F>(&mut self, window: Duration, loader: F)
+        where F: Fn(&K) -> Option<V> + Send + Sync + 'static, K: Send + 'static, V: Send + 'static {
+        self.set_shared_refresh(window, Arc::new(loader));
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn set_shared_refresh(&mut self, window: Duration, loader: Arc<Loader<K, V>>)
+        where K: Send + 'static, V: Send + 'static {
+        self.refresh = Some(Refresh::new(window, loader));
+    }
+
+    /// Keep values whose time-to-live or time-to-idle has elapsed until they are evicted to make
+    /// room, instead of removing them, so that `get_allow_stale` can serve them while a fresh
+    /// value is fetched, as HTTP caches do with `stale-while-revalidate`.
+    ///
+    /// Other lookups, such as `get`, still report stale values as missing, and `purge_expired`
+    /// and `run_pending_tasks` leave them in place.  Values whose `InvalidationToken` has been
+    /// invalidated are never served stale.  Disabled by default.
+    pub fn set_keep_stale(&mut self, keep_stale: bool) {
+        self.data.get_mut().keep_stale = keep_stale;
+    }
+
+    /// Let lookups treat values as expired shortly before their time-to-live elapses, with a
+    /// probability which rises as it approaches, so that hot values are recomputed by one caller
+    /// of `get_or_insert_with` instead of by every caller at the moment they expire.
+    ///
+    /// `recompute_time` is about how long computing a value takes: the probability is
+    /// `exp(-remaining / recompute_time)`, so a value is unlikely to expire early until it has a
+    /// few `recompute_time`s left.  A value which expires early is reported as missing to that
+    /// lookup only, and stays in the cache until it is replaced or expires.  Values without a
+    /// time-to-live never expire early.  Pass `None` to disable (the default).
+    pub fn set_early_expiration(&mut self, recompute_time: Option<Duration>) {
+        self.early_expiration = recompute_time.map(EarlyExpiration::new);
+    }
+
+    /// Register `listener` to be called with each value that leaves the cache, and the reason it
+    /// left.  Replaces any previously registered listener.
+    ///
+    /// The listener is called after the cache is unlocked, so it may use the cache.  It may be
+    /// called on any thread performing a cache operation, not necessarily the one that caused the
+    /// eviction, but is never called concurrently with itself.
+    pub fn set_eviction_listener<F>(&mut self, listener: F)
+        where F: FnMut(K, V, EvictionCause) + Send + 'static {
+        self.set_shared_eviction_listener(Arc::new(EvictionListener::new(listener)));
+    }
+
+    pub(crate) fn set_shared_eviction_listener(&mut self, listener: Arc<EvictionListener<K, V>>) {
+        self.data.get_mut().listening = true;
+        self.listener = Some(listener);
+    }
+
+    /// Subscribe to changes to the contents of the cache.
+    ///
+    /// The returned receiver gets a `CacheEvent` for each value put into the cache, and each value
+    /// which leaves it, in the order they happen.  Events are sent while the cache is locked, but
+    /// sending never blocks: events queue in the channel until they are received.  Dropping the
+    /// receiver ends the subscription.
+    ///
+    /// Values taken out of the cache by `drain` are not reported, as with the eviction listener.
+    pub fn subscribe(&self) -> Receiver<CacheEvent<K, V>> {
+        let (sender, receiver) = mpsc::channel();
+        self.add_subscriber(sender);
+        receiver
+    }
+
+    pub(crate) fn add_subscriber(&self, sender: Sender<CacheEvent<K, V>>) {
+        self.data.lock().subscribers.push(sender);
+    }
+
+    /// Call `hook` with the key of every event that subscribers would receive, as it happens.
+    pub(crate) fn add_change_hook(&self, hook: Arc<ChangeHook<K>>) {
+        self.data.lock().change_hooks.push(hook);
+    }
 
-        old_value
+    /// Start recording statistics about cache activity, to be read with `stats`.
+    ///
+    /// Recording is off by default.  Only lookups by `get`, `get_versioned` and
+    /// `get_or_insert_with` count as hits or misses; `peek` and friends are not counted.
+    pub fn enable_stats(&mut self) {
+        let recorder = self.stats.as_ref().and_then(|stats| stats.recorder());
+        self.set_stats_counter(StatsCounter::new(CacheStats::default(), recorder));
     }
 
-    /// Update access tracking, indicating that a cache value has been accessed.
+    /// Report statistics to `recorder` as they change, and the number of values and their total
+    /// weight, so that they can be exported to a metrics system.  See `MetricsRecorder`.
     ///
-    /// Moves `cache_value` to the front of `lru_list`, indicating it has been used most recently.
+    /// Enables statistics if they are not already, keeping any recorded so far.  See
+    /// `enable_stats`.
+    pub fn set_metrics_recorder(&mut self, recorder: Arc<dyn MetricsRecorder>) {
+        self.set_stats_counter(StatsCounter::new(self.stats(), Some(recorder)));
+    }
+
+    fn set_stats_counter(&mut self, stats: StatsCounter) {
+        let stats = Arc::new(stats);
+        let data = self.data.get_mut();
+        stats.record_size(data.map.len(), data.weight);
+        data.stats = Some(Arc::clone(&stats));
+        self.stats = Some(stats);
+    }
+
+    /// Start recording the ages of values evicted to make room for others, to be read with
+    /// `eviction_ages`.
+    pub fn enable_eviction_ages(&mut self) {
+        self.data.get_mut().eviction_ages = Some(EvictionAgeRecorder::new(Arc::clone(&self.clock)));
+    }
+
+    /// The ages of the values most recently evicted to make room for others, up to about a
+    /// thousand, so as to tell whether the cache is too small.  See `EvictionAges`.
     ///
-    /// # Safety
+    /// Empty if recording is not enabled.  See `enable_eviction_ages`.
+    pub fn eviction_ages(&self) -> EvictionAges {
+        let data = self.data.read();
+        data.eviction_ages.as_ref().map_or_else(EvictionAges::default, |ages| ages.snapshot())
+    }
+
+    /// The `k` most used keys in the cache, most used first.
     ///
-    /// - Assumes that ``cache_value`` is already in lru_list.  If not, behavior is
-    ///   undefined.
-    fn touch(&self, cache_value: &CacheValue<K, V>) {
-        let mut lru_list = self.lru_list.lock().unwrap();
+    /// With an admission filter, keys are ranked by their estimated recent accesses, which are
+    /// reported with them, and then by recency (see `set_admission_filter`).  Otherwise, they are
+    /// the `k` most recently used keys.  Takes time proportional to the number of values.
+    pub fn hot_keys(&self, k: usize) -> Vec<HotKey<K>> {
+        let data = self.lock();
+        let mut ranked: Vec<(Option<u8>, &K)> = data.lru_list.iter()
+            .filter(|cache_value| !cache_value.is_expired(&*self.clock))
+            .map(|cache_value| {
+                let key = &cache_value.key;
+                (data.admission.as_ref().map(|admission| admission.frequency(key)), key)
+            })
+            .collect();
+        // The sort is stable, so keys of equal frequency stay in recency order.
+        ranked.sort_by_key(|&(frequency, _)| Reverse(frequency));
+        let hot_keys = ranked.into_iter()
+            .take(k)
+            .map(|(frequency, key)| HotKey { key: key.clone(), frequency })
+            .collect();
 
-        let mut cursor;
-        unsafe {
-            cursor = lru_list.cursor_mut_from_ptr(cache_value);
+        self.unlock(data);
+        hot_keys
+    }
+
+    /// The statistics recorded since `enable_stats` or the last `reset_stats`.
+    ///
+    /// All zero if recording is not enabled.
+    pub fn stats(&self) -> CacheStats {
+        match self.stats {
+            None => CacheStats::default(),
+            Some(ref stats) => stats.snapshot()
         }
+    }
 
-        if let Some(removed_value) = cursor.remove() {
-            lru_list.push_front(removed_value);
-        } else {
-            unreachable!()
+    /// Reset all recorded statistics to zero.
+    pub fn reset_stats(&self) {
+        if let Some(ref stats) = self.stats {
+            stats.reset();
         }
     }
 
-    /// Make room for a new value.  If the cache is full, perform eviction.
-    fn make_room(&mut self) {
-        let len = {
-            let map =self.map.lock().unwrap();
-            map.len()
+    /// The cache's statistics, `len` and `weight` in the Prometheus text exposition format, for
+    /// a metrics endpoint.  Each metric is named with the prefix `name`, such as
+    /// `name_hits_total`, which must be a valid Prometheus metric name.
+    ///
+    /// The counters are all zero unless statistics are enabled.  See `enable_stats`.
+    #[cfg(feature = "prometheus")]
+    pub fn prometheus_text(&self, name: &str) -> String {
+        let (len, weight) = {
+            let data = self.data.read();
+            (data.map.len(), data.weight)
         };
+        crate::stats::prometheus_text(name, &self.stats(), len, weight)
+    }
+
+    /// Filter new values with `filter` when the cache is full, so that rarely used values do not
+    /// displace popular ones.  See `TinyLfu`.
+    ///
+    /// Values refused by the filter are reported to the eviction listener with
+    /// `EvictionCause::Capacity`, as if they were evicted immediately.  Replacing the value of a
+    /// key that is already in the cache is always admitted.
+    pub fn set_admission_filter(&mut self, filter: Option<TinyLfu>) {
+        self.data.get_mut().admission = filter;
+    }
+
+    /// Set how the cache tracks the recency of its values.  The default is `RecencyMode::Exact`.
+    pub fn set_recency_mode(&mut self, recency: RecencyMode) {
+        self.data.get_mut().recency = recency;
+    }
 
-        if len == self.capacity {
-            self.evict_lru();
+    /// Index values by the group that `extract` assigns to their key, so that `invalidate_group`
+    /// can remove a whole group in time proportional to its size.
+    ///
+    /// For example, grouping URL keys by their first path segment allows all of the values under
+    /// `/users/` to be invalidated at once.  `extract` must return the same group each time it is
+    /// called with the same key.  Values already in the cache are indexed immediately.
+    pub fn set_group_index<F>(&mut self, extract: F)
+        where F: Fn(&K) -> Option<String> + Send + Sync + 'static {
+        self.set_shared_group_index(Arc::new(extract));
+    }
+
+    pub(crate) fn set_shared_group_index(&mut self, extract: Arc<GroupExtractor<K>>) {
+        let data = self.data.get_mut();
+        let mut groups = GroupIndex::new(extract);
+        for key in data.map.keys() {
+            groups.insert(key);
         }
+        data.groups = Some(groups);
     }
 
-    /// Perform lru eviction.
-    fn evict_lru(&mut self) {
-        let lru_value = self.lru_list.get_mut().unwrap().pop_front();
-        if let None = self.map.get_mut().unwrap().remove(&lru_value.expect("List must not be none").key) {
-            unreachable!();
+    /// Track when each value expires in a timer wheel, so that `run_pending_tasks` can remove
+    /// expired values promptly, in time proportional to the number expiring rather than the size
+    /// of the cache.
+    ///
+    /// Expired values are otherwise only reclaimed lazily, so a value which is never read again
+    /// holds its memory until it is evicted.  Timers cost a little memory per value with a
+    /// time-to-live, and a little time per `put` and removal.  Values already in the cache are
+    /// tracked immediately.
+    pub fn enable_expiration_timers(&mut self) {
+        let data = self.data.get_mut();
+        let mut timers = TimerWheel::new(self.clock.now());
+        for cache_value in data.map.values() {
+            if let Some(deadline) = cache_value.deadline() {
+                timers.schedule(cache_value.key.clone(), deadline);
+            }
         }
+        data.timers = Some(timers);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Read the time for expiration from `clock`, instead of the system clock.
+    ///
+    /// Intended for tests, with a `testing::MockClock`.  Should be set before values are put,
+    /// since their expiration times were read from the previous clock.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+        self.records_put_at = true;
+        if self.data.get_mut().timers.is_some() {
+            self.enable_expiration_timers();
+        }
+        if self.data.get_mut().eviction_ages.is_some() {
+            self.enable_eviction_ages();
+        }
+    }
 
-    #[test]
-    fn hit() {
-        let k1 = "key";
-        let mut cache: LRUCache<&str, u64> = LRUCache::new(1);
-        cache.put(k1, 2);
-        assert_eq!(cache.get(&k1), Some(2));
+    /// Release excess memory after the cache has been idle.
+    ///
+    /// When an operation follows a period of at least `period` without any cache operations, and
+    /// the cache holds less than a quarter of its capacity, the cache shrinks its internal storage
+    /// to fit its current contents.  This keeps long-lived, low-traffic caches from holding memory
+    /// sized for peak occupancy forever.
+    ///
+    /// Pass `None` to disable (the default).
+    pub fn set_idle_shrink(&mut self, period: Option<Duration>) {
+        self.idle_shrink = period.map(|period| IdleShrink::new(period, self.clock.now()));
     }
 
-    #[test]
-    fn miss() {
-        let k1 = "no key";
-        let mut cache: LRUCache<&str, u64> = LRUCache::new(10);
-        assert_eq!(cache.get(&k1), None);
+    /// Call `listener` with how long an operation waited for the cache's lock, whenever it waited
+    /// at least `threshold`, to help diagnose contention.  Replaces any previous listener.
+    ///
+    /// Operations which record activity, such as `get` and `put`, are timed.  The listener is
+    /// called while the cache is locked, so it must not use the cache, and should be quick.
+    pub fn set_slow_lock_listener<F>(&mut self, threshold: Duration, listener: F)
+        where F: Fn(Duration) + Send + Sync + 'static {
+        self.set_shared_slow_lock_listener(threshold, Arc::new(listener));
     }
 
-    #[test]
-    fn evict() {
-        let k1 = "key1";
-        let k2 = "key2";
-        let v1 = 1;
-        let v2 = 2;
+    pub(crate) fn set_shared_slow_lock_listener(&mut self, threshold: Duration,
+                                                listener: Arc<SlowLockListener>) {
+        self.slow_lock = Some(SlowLock { threshold, listener });
+    }
 
-        let mut cache: LRUCache<&str, u64> = LRUCache::new(1);
-        assert_eq!(cache.map.lock().unwrap().len(), 0);
+    /// Call `listener` with how many values an operation evicted to keep the cache within its
+    /// limits, and how long evicting them took, whenever it evicted any.  Replaces any previous
+    /// listener.
+    ///
+    /// Puts, and changes to the cache's limits, evict values in batches; values which expire or
+    /// are removed are not counted.  The listener is called while the cache is locked, so it
+    /// must not use the cache, and should be quick.
+    pub fn set_eviction_batch_listener<F>(&mut self, listener: F)
+        where F: Fn(usize, Duration) + Send + Sync + 'static {
+        self.set_shared_eviction_batch_listener(Arc::new(listener));
+    }
 
-        cache.put(k1, v1);
-        assert_eq!(cache.map.lock().unwrap().len(), 1);
+    pub(crate) fn set_shared_eviction_batch_listener(&mut self,
+                                                     listener: Arc<EvictionBatchListener>) {
+        self.data.get_mut().eviction_batch_listener = Some(listener);
+    }
 
-        cache.put(k2, v2);
-        assert_eq!(cache.map.lock().unwrap().len(), 1);
+    /// Call `listener` with how long each loader ran, and whether it produced a value, to help
+    /// diagnose slow or failing loads.  Replaces any previous listener.
+    ///
+    /// Covers the functions passed to `get_or_insert_with` and `try_get_or_insert_with` (which
+    /// fail with an error), and the background reloads started by `set_refresh` (which fail by
+    /// returning `None` or panicking).  The cache is not locked while the listener is called, but
+    /// reloads call it from their own threads.
+    pub fn set_loader_listener<F>(&mut self, listener: F)
+        where F: Fn(Duration, bool) + Send + Sync + 'static {
+        self.set_shared_loader_listener(Arc::new(listener));
+    }
 
-        assert_eq!(cache.get(&k1), None);
+    pub(crate) fn set_shared_loader_listener(&mut self, listener: Arc<LoaderListener>) {
+        self.loader_listener = Some(listener);
     }
 
-    #[test]
-    fn replace() {
-        let k1 = "key1";
-        let k2 = "key2";
-        let v1 = 1;
-        let v2 = 2;
+    /// Record every `get`, `put` and `remove` (and their variants) to `recorder`, for replaying
+    /// later with `cache-replay`.  See the `trace` module.
+    ///
+    /// Pass `None` to stop recording (the default).
+    pub fn set_trace_recorder(&mut self, recorder: Option<Arc<TraceRecorder>>) {
+        self.trace = recorder;
+    }
 
-        let mut cache: LRUCache<&str, u64> = LRUCache::new(1);
-        assert_eq!(cache.map.lock().unwrap().len(), 0);
+    /// Shrink internal storage to fit the current contents of the cache.
+    ///
+    /// Storage grows again as values are inserted, up to `capacity`.
+    pub fn shrink_to_fit(&self) {
+        self.data.lock().shrink_to_fit();
+    }
+
+    /// Estimate the bytes of memory used by the cache: its map and lists, and its keys and
+    /// values as measured by `MemSize`.
+    ///
+    /// Visits every value, so it takes time in proportion to `len`.  Memory held by the eviction
+    /// policy, admission filter and other optional features is not counted.
+    pub fn memory_usage(&self) -> usize
+        where K: MemSize, V: MemSize {
+        let data = self.data.read();
+        // Each map slot holds a key, a pointer to the value and a byte of control data.
+        let slot_size = mem::size_of::<K>() + mem::size_of::<Arc<CacheValue<K, V>>>() + 1;
+        let map = data.map.capacity() * slot_size;
+        // Each value is allocated with its reference counts, and its key is also kept in the map.
+        let values: usize = data.map.values()
+            .map(|cache_value| {
+                2 * mem::size_of::<usize>() + mem::size_of::<CacheValue<K, V>>()
+                    + 2 * cache_value.key.heap_size() + cache_value.value.heap_size()
+            })
+            .sum();
+        mem::size_of::<Self>() + map + data.lru_list.allocated_bytes() + values
+    }
+
+    /// Lock `self.data`, recording a cache operation.
+    ///
+    /// Shrinks the map if the cache had been idle, puts any values reloaded in the background,
+    /// and updates the recency of buffered reads, so that eviction accounts for them.
+    fn lock(&self) -> LockGuard<'_, CacheData<K, V, P, S>> {
+        let mut data = self.lock_for_lookup();
+        data.apply_accesses();
+        data
+    }
+
+    /// Lock `self.data` as `lock` does, but leave buffered reads for a later operation, since
+    /// looking values up neither inserts nor evicts.
+    fn lock_for_lookup(&self) -> LockGuard<'_, CacheData<K, V, P, S>> {
+        let mut data = match self.slow_lock {
+            None => self.data.lock(),
+            Some(ref slow_lock) => slow_lock.lock(&self.data)
+        };
+
+        if let Some(ref idle_shrink) = self.idle_shrink {
+            if idle_shrink.record_activity(self.clock.now())
+                && data.map.len() < data.limits.capacity / IDLE_SHRINK_OCCUPANCY_DIVISOR {
+                data.shrink_to_fit();
+            }
+        }
+
+        if let Some(ref refresh) = self.refresh {
+            for (key, value, version) in refresh.take_loaded() {
+                self.put_refreshed(&mut data, key, value, version);
+            }
+        }
+
+        data
+    }
+
+    /// Lock `self.data` for reading only: shared with other readers under
+    /// `LockStrategy::RwLock`, and otherwise as `lock` does.
+    fn read(&self) -> ReadGuard<'_, CacheData<K, V, P, S>> {
+        match self.data.read_shared() {
+            Some(data) => ReadGuard::Shared(data),
+            None => ReadGuard::Exclusive(self.lock())
+        }
+    }
+
+    /// Look up `key` under a shared lock, if the cache uses `LockStrategy::RwLock`, and return
+    /// `f` of its value.  The read is buffered, and its recency updated by the next operation to
+    /// lock the cache exclusively.
+    ///
+    /// Returns `None` if the lookup needs an exclusive lock: if the lock is not a `RwLock`, or if
+    /// `key` is missing or expired, since a miss may update the admission filter or remove the
+    /// expired value.
+    fn lookup_shared<Q, T, F>(&self, key: &Q, f: F) -> Option<T>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized,
+              F: FnOnce(&Arc<CacheValue<K, V>>) -> T {
+        let data = self.data.read_shared()?;
+        let cache_value = data.map.get(key)
+            .filter(|cache_value| !cache_value.is_expired(&*self.clock))?;
+        cache_value.record_read(&*self.clock);
+        let result = f(cache_value);
+        let full = data.accesses.push(Arc::clone(cache_value));
+        drop(data);
+
+        // Readers do not wait for the lock to apply the buffered reads: if it is busy, its holder
+        // or a later writer will.
+        if full {
+            if let Some(mut data) = self.data.try_lock() {
+                data.apply_accesses();
+                self.unlock(data);
+            }
+        }
+        Some(result)
+    }
+
+    /// Put `value`, reloaded in the background, for `key` if the value in `data` still has
+    /// `version`.
+    fn put_refreshed(&self, data: &mut CacheData<K, V, P, S>, key: K, value: V, version: u64) {
+        let current = data.map.get(&key).is_some_and(|cache_value| cache_value.version == version);
+        if current && !self.is_shut_down() {
+            let weight = self.weigh(&key, &value);
+            data.insert(key, value, self.entry_options(), weight);
+        }
+    }
+
+    /// The key and version of `cache_value`, if reading it should start a background reload.
+    fn refresh_due(&self, cache_value: &CacheValue<K, V>) -> Option<(K, u64)> {
+        let window = self.refresh.as_ref()?.window;
+        let remaining = cache_value.expires_at?.saturating_duration_since(self.clock.now());
+        if remaining > window {
+            return None;
+        }
+
+        Some((cache_value.key.clone(), cache_value.version))
+    }
+
+    /// Whether a lookup should treat `cache_value` as expired early.  See `set_early_expiration`.
+    fn expires_early(&self, cache_value: &CacheValue<K, V>) -> bool {
+        match (self.early_expiration.as_ref(), cache_value.expires_at) {
+            (Some(early_expiration), Some(expires_at)) => {
+                let remaining = expires_at.saturating_duration_since(self.clock.now());
+                early_expiration.expires_early(remaining)
+            },
+            _ => false
+        }
+    }
+
+    /// Start reloading the key returned by `refresh_due`, if any.
+    fn start_refresh(&self, due: Option<(K, u64)>) {
+        if let (Some(refresh), Some((key, version))) = (self.refresh.as_ref(), due) {
+            if !self.is_shut_down() {
+                refresh.start(key, version, self.loader_listener.clone());
+            }
+        }
+    }
+
+    /// Unlock `data`, then pass any values which left the cache while it was locked to the
+    /// eviction listener.
+    fn unlock(&self, mut data: LockGuard<'_, CacheData<K, V, P, S>>) {
+        if let Some(ref stats) = data.stats {
+            stats.record_size(data.map.len(), data.weight);
+        }
+        if data.evicted.is_empty() {
+            return;
+        }
+
+        let evicted = std::mem::take(&mut data.evicted);
+        drop(data);
+
+        if let Some(ref listener) = self.listener {
+            listener.notify(evicted);
+        }
+    }
+
+    /// Wake the threads in `wait_for`.
+    ///
+    /// Must be called after unlocking `self.data`, since `wait_for` locks it while holding
+    /// `self.waiting`.
+    fn notify_inserted(&self) {
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            let _waiting = self.waiting.lock().unwrap_or_else(PoisonError::into_inner);
+            self.inserted.notify_all();
+        }
+    }
+
+    /// Get the value for `key` in `self`, if it exists.  Otherwise, return `None`.
+    ///
+    /// `key` may be any borrowed form of the cache's key type, as with `HashMap::get`.
+    ///
+    /// Values which have expired, or whose `InvalidationToken` has been invalidated, are removed
+    /// and reported as missing.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.get_versioned(key).map(|(value, _)| value)
+    }
+
+    /// Get the value for `key` in `self` along with its version, if it exists.
+    ///
+    /// Every `put` assigns the value a new version, greater than any version previously assigned
+    /// by this cache, so versions increase monotonically per key even across eviction and
+    /// re-insertion.  Pass the version to `put_if_version` to perform an optimistic update.
+    pub fn get_versioned<Q>(&self, key: &Q) -> Option<(V, u64)>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.record_trace(TraceOp::Get, key);
+        let mut due = None;
+        let mut read = |cache_value: &Arc<CacheValue<K, V>>| {
+            if self.expires_early(cache_value) {
+                return None;
+            }
+            due = self.refresh_due(cache_value);
+            Some((cache_value.value.clone(), cache_value.version))
+        };
+
+        let result = match self.lookup_shared(key, &mut read) {
+            Some(result) => result,
+            None => {
+                let mut data = self.lock_for_lookup();
+                let result = data.lookup(key, &*self.clock).and_then(&mut read);
+                self.unlock(data);
+                result
+            }
+        };
+        self.start_refresh(due);
+        self.record_lookup(result.is_some());
+        result
+    }
+
+    /// Get a reference to the value for `key` in `self`, if it exists, without cloning it.
+    ///
+    /// The returned guard shares the value with the cache and holds no lock, so the cache may be
+    /// used while it is alive.  If the value is removed or replaced, the guard continues to refer
+    /// to the old value.
+    pub fn get_ref<Q>(&self, key: &Q) -> Option<ValueGuard<K, V>>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.record_trace(TraceOp::Get, key);
+        let read = |cache_value: &Arc<CacheValue<K, V>>| {
+            Some(cache_value).filter(|cache_value| !self.expires_early(cache_value)).map(Arc::clone)
+        };
+
+        let result = match self.lookup_shared(key, read) {
+            Some(result) => result,
+            None => {
+                let mut data = self.lock_for_lookup();
+                let result = data.lookup(key, &*self.clock).and_then(read);
+                self.unlock(data);
+                result
+            }
+        };
+        self.start_refresh(result.as_ref().and_then(|cache_value| self.refresh_due(cache_value)));
+        self.record_lookup(result.is_some());
+        result.map(|cache_value| ValueGuard { cache_value })
+    }
+
+    /// Get a mutable reference to the value for `key` in `self`, if it exists, to update it in
+    /// place: to increment a counter or append to a buffer, say, without copying it to `put` it
+    /// back.
+    ///
+    /// Counts as a hit or miss and updates the value's recency, as `get` does.  The cache stays
+    /// locked until the guard is dropped, so the read and the update are atomic, but the cache must
+    /// not be used from the same thread while the guard is alive.  On drop, the value is reweighed
+    /// and given a new version, keeping its expiry time, and evicted if it no longer fits.
+    ///
+    /// The value is copied only if a `ValueGuard` or `Snapshot` shares it, which keeps the old
+    /// value.  The guard holds the cache's lock, so leaking it, with `mem::forget`, deadlocks the
+    /// cache: the lock is never released.
+    pub fn get_mut<Q>(&self, key: &Q) -> Option<ValueGuardMut<'_, K, V, P, S>>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.record_trace(TraceOp::Get, key);
+        let mut data = self.lock();
+        let found = data.lookup(key, &*self.clock).is_some();
+        self.record_lookup(found);
+        if !found {
+            self.unlock(data);
+            return None;
+        }
+
+        let cache_value = data.detach(key);
+        Some(ValueGuardMut { cache: self, data: Some(data), cache_value: Some(cache_value) })
+    }
+
+    /// Look up `key` in `self`, distinguishing keys put with `put_negative` from unknown keys.
+    ///
+    /// Negative hits count as hits in `stats`.  `get` reports both as `None`.
+    pub fn lookup<Q>(&self, key: &Q) -> CacheResult<V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.record_trace(TraceOp::Get, key);
+        let mut data = self.lock_for_lookup();
+        let mut due = None;
+        let value = data.lookup(key, &*self.clock)
+            .filter(|cache_value| !self.expires_early(cache_value))
+            .map(|cache_value| {
+                due = self.refresh_due(cache_value);
+                cache_value.value.clone()
+            });
+
+        let result = match value {
+            Some(value) => CacheResult::Hit(value),
+            None if data.negative.contains(key, self.clock.now()) => CacheResult::NegativeHit,
+            None => CacheResult::Miss
+        };
+        self.unlock(data);
+        self.start_refresh(due);
+        self.record_lookup(!matches!(result, CacheResult::Miss));
+        result
+    }
+
+    /// Get the value for `key` in `self`, including a value kept after expiring by
+    /// `set_keep_stale`.
+    ///
+    /// A stale value is returned as `StaleResult::Stale`, without updating its recency, and starts
+    /// a background reload of `key` if `set_refresh` is enabled, so that the caller can serve it
+    /// while a fresh value is fetched.  Stale values count as hits in `stats`.
+    pub fn get_allow_stale<Q>(&self, key: &Q) -> StaleResult<V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.record_trace(TraceOp::Get, key);
+        let mut data = self.lock_for_lookup();
+        let mut due = None;
+        let fresh = data.lookup(key, &*self.clock).map(|cache_value| {
+            due = self.refresh_due(cache_value);
+            cache_value.value.clone()
+        });
+
+        let result = match fresh {
+            Some(value) => StaleResult::Fresh(value),
+            None => match data.map.get(key) {
+                Some(cache_value) if data.keep_stale && cache_value.is_stale(&*self.clock) => {
+                    due = Some((cache_value.key.clone(), cache_value.version));
+                    StaleResult::Stale(cache_value.value.clone())
+                },
+                _ => StaleResult::Miss
+            }
+        };
+        self.unlock(data);
+        self.start_refresh(due);
+        self.record_lookup(!matches!(result, StaleResult::Miss));
+        result
+    }
+
+    /// Get the value for `key` in `self` without updating its recency.
+    pub fn peek<Q>(&self, key: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        let data = self.read();
+        match data.map.get(key) {
+            Some(cache_value) if !cache_value.is_expired(&*self.clock) => {
+                Some(cache_value.value.clone())
+            },
+            _ => None
+        }
+    }
+
+    /// Whether `self` holds a value for `key`, without updating its recency or cloning it.
+    ///
+    /// Values which have expired are reported as missing, but not removed.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        let data = self.read();
+        data.map.get(key).is_some_and(|cache_value| !cache_value.is_expired(&*self.clock))
+    }
+
+    /// Mark the value for `key` as used, as `get` would, without retrieving it.
+    ///
+    /// Useful after reading a value with `peek`, once it turns out to have been needed.  Unlike
+    /// `get`, does not count as a hit or miss in `stats`.
+    ///
+    /// # Returns
+    ///
+    /// Whether `self` holds a value for `key`.  Values which have expired are removed and
+    /// reported as missing.
+    pub fn touch<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        let mut data = self.lock_for_lookup();
+        let found = data.lookup(key, &*self.clock).is_some();
+        self.unlock(data);
+        found
+    }
+
+    /// Get the least recently used key and value in `self`, without updating its recency.
+    pub fn peek_lru(&self) -> Option<(K, V)> {
+        let data = self.read();
+        let cache_value = data.lru_list.iter().rev()
+            .find(|cache_value| !cache_value.is_expired(&*self.clock));
+        cache_value.map(|cache_value| (cache_value.key.clone(), cache_value.value.clone()))
+    }
+
+    /// Get the most recently used key and value in `self`, without updating its recency.
+    pub fn peek_mru(&self) -> Option<(K, V)> {
+        let data = self.read();
+        let cache_value = data.lru_list.iter()
+            .find(|cache_value| !cache_value.is_expired(&*self.clock));
+        cache_value.map(|cache_value| (cache_value.key.clone(), cache_value.value.clone()))
+    }
+
+    /// Remove and return the least recently used key and value in `self`.
+    ///
+    /// Expired values found on the way are removed (and reported with `EvictionCause::Expired`);
+    /// the returned value is reported with `EvictionCause::Removed`.
+    pub fn pop_lru(&self) -> Option<(K, V)> {
+        let mut data = self.lock();
+        let entry = data.pop(&*self.clock, |lru_list| lru_list.back());
+        self.unlock(data);
+        entry
+    }
+
+    /// Remove and return the most recently used key and value in `self`.  See `pop_lru`.
+    pub fn pop_mru(&self) -> Option<(K, V)> {
+        let mut data = self.lock();
+        let entry = data.pop(&*self.clock, |lru_list| lru_list.iter().next());
+        self.unlock(data);
+        entry
+    }
+
+    /// Get the value for `key` in `self`, waiting up to `timeout` for another thread to insert it
+    /// if it is not present.
+    ///
+    /// # Returns
+    ///
+    /// The value for `key`, or `None` if it was not inserted before `timeout` elapsed.
+    ///
+    /// Not available on `wasm32`, where no other thread could insert it.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn wait_for<Q>(&self, key: &Q, timeout: Duration) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        let deadline = Instant::now() + timeout;
+        let mut waiting = self.waiting.lock().unwrap_or_else(PoisonError::into_inner);
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+
+        let value = loop {
+            let mut data = self.lock_for_lookup();
+            let value = data.lookup(key, &*self.clock).map(|cache_value| cache_value.value.clone());
+            self.unlock(data);
+
+            let now = Instant::now();
+            if value.is_some() || now >= deadline {
+                break value;
+            }
+
+            waiting = self.inserted.wait_timeout(waiting, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner).0;
+        };
+
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+        value
+    }
+
+    /// Take a point-in-time snapshot of the contents of `self`.
+    ///
+    /// The snapshot shares values with the cache rather than cloning them, and does not hold any
+    /// locks once it is returned.  Values put after the snapshot was taken are not visible in it,
+    /// and values removed or replaced afterwards remain visible.  Recency is not updated.
+    pub fn snapshot(&self) -> Snapshot<K, V> {
+        let data = self.data.read();
+        let values = data.map.values()
+            .filter(|cache_value| !cache_value.is_expired(&*self.clock))
+            .map(Arc::clone)
+            .collect();
+
+        Snapshot {
+            values,
+            version: data.version
+        }
+    }
+
+    /// Iterate over the values in `self`, in arbitrary order.
+    ///
+    /// Like `snapshot`, the iterator is a point-in-time view which shares values with the cache
+    /// and holds no locks.  Each item is a `ValueGuard`, which dereferences to the value and
+    /// provides the key.  Recency is not updated.
+    pub fn iter(&self) -> Iter<K, V> {
+        let data = self.data.read();
+        Iter {
+            inner: self.live_values(data.map.values())
+        }
+    }
+
+    /// Iterate over the values in `self` from least to most recently used.  See `iter`.
+    pub fn iter_lru_order(&self) -> Iter<K, V> {
+        let data = self.data.read();
+        Iter {
+            inner: self.live_values(data.lru_list.iter().rev())
+        }
+    }
+
+    /// The unexpired values in `values`.
+    fn live_values<'a, I>(&self, values: I) -> std::vec::IntoIter<Arc<CacheValue<K, V>>>
+        where I: Iterator<Item = &'a Arc<CacheValue<K, V>>>, K: 'a, V: 'a {
+        values.filter(|cache_value| !cache_value.is_expired(&*self.clock))
+            .map(Arc::clone)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Remove all values from `self`, returning them in order from least to most recently used.
+    ///
+    /// The cache is emptied immediately, under a single lock.  Drained values are not passed to
+    /// the eviction listener, but expired values (which are not returned) are.
+    pub fn drain(&self) -> Drain<K, V> {
+        let mut data = self.lock();
+        data.forget_all();
+
+        let mut drained = Vec::with_capacity(data.map.len());
+        while let Some(cache_value) = data.lru_list.pop_back() {
+            data.map.remove(&cache_value.key);
+            let expired = cache_value.is_expired(&*self.clock);
+            let (key, value) = into_entry(cache_value);
+            if expired {
+                data.evicted(key, value, EvictionCause::Expired);
+            } else {
+                drained.push((key, value));
+            }
+        }
+
+        self.unlock(data);
+        Drain {
+            inner: drained.into_iter()
+        }
+    }
+
+    /// Put `value` into `self` for `key`.
+    ///
+    /// # Returns
+    ///
+    /// The previous value in the cache, or `None`.
+    pub fn put(&self, key: K, value: V) -> Option<V> {
+        self.insert(key, value, self.entry_options()).0
+    }
+
+    /// Put `value` into `self` for `key`, expiring after `ttl` instead of the cache's default
+    /// time-to-live.
+    ///
+    /// # Returns
+    ///
+    /// The previous value in the cache, or `None`.
+    pub fn put_with_ttl(&self, key: K, value: V, ttl: Duration) -> Option<V> {
+        let options = EntryOptions {
+            expires_at: Some(self.clock.now() + ttl),
+            ..self.entry_options()
+        };
+        self.insert(key, value, options).0
+    }
+
+    /// Put `value` into `self` for `key` with `priority`.
+    ///
+    /// Values of lower priority are evicted before any value of higher priority, least recently
+    /// used first.  The priority lasts until the value is replaced; `put` uses
+    /// `Priority::Normal`.  `peek_lru`, `iter_lru_order` and `drain` order values by priority
+    /// first, in eviction order.
+    ///
+    /// Priorities are only respected by policies which evict by recency, such as `Lru`; other
+    /// policies ignore them.
+    ///
+    /// # Returns
+    ///
+    /// The previous value in the cache, or `None`.
+    pub fn put_with_priority(&self, key: K, value: V, priority: Priority) -> Option<V> {
+        let options = EntryOptions {
+            priority,
+            ..self.entry_options()
+        };
+        self.insert(key, value, options).0
+    }
+
+    /// Put `value` into `self` for `key` as the least recently used value (of its priority), so
+    /// that it is evicted before the values already in the cache unless it is read first.
+    ///
+    /// Use it for values which may never be read, such as those loaded by a bulk backfill or a
+    /// prefetch, so that they do not flush out the values in use.  Once the cache is full, each
+    /// cold value evicts the previous one, if it has not been read since.  Policies which do not
+    /// evict by recency may treat it as `put`; `Slru` and `Fifo` evict cold values first.
+    ///
+    /// # Returns
+    ///
+    /// The previous value in the cache, or `None`.
+    pub fn put_cold(&self, key: K, value: V) -> Option<V> {
+        let options = EntryOptions {
+            cold: true,
+            ..self.entry_options()
+        };
+        self.insert(key, value, options).0
+    }
+
+    /// Put `value` into `self` for `key`, only if the current version of `key` is
+    /// `expected_version`.
+    ///
+    /// # Returns
+    ///
+    /// The new version of `key`, or `Err(value)` if `key` is not in the cache or its version has
+    /// changed.
+    pub fn put_if_version(&self, key: K, value: V, expected_version: u64) -> Result<u64, V> {
+        let mut data = self.lock();
+
+        let matches = match data.map.get(&key) {
+            None => false,
+            Some(cache_value) => {
+                !cache_value.is_expired(&*self.clock) && cache_value.version == expected_version
+            }
+        };
+
+        if !matches {
+            self.unlock(data);
+            return Err(value);
+        }
+
+        self.record_trace(TraceOp::Put, &key);
+        let weight = self.weigh(&key, &value);
+        let (_, version) = data.insert(key, value, self.entry_options(), weight);
+        self.unlock(data);
+        self.notify_inserted();
+        Ok(version)
+    }
+
+    /// Record that `key` has no value, for `ttl`, so that `lookup` reports a `NegativeHit` instead
+    /// of a `Miss` and callers need not ask the backing data again.
+    ///
+    /// Any value for `key` is replaced.  Negative entries are held separately from values, and do
+    /// not count towards the cache's capacity or `len`, but at most `capacity` of them are kept:
+    /// beyond that, the entries which expire soonest are forgotten.  Putting a value for `key`
+    /// forgets its negative entry.
+    pub fn put_negative(&self, key: K, ttl: Duration) {
+        let mut data = self.lock();
+        let replaced = data.remove(&key, EvictionCause::Replaced);
+        // Subscribers see no `Update` for a negative entry, so report the value as removed.
+        if let (Some(value), true) = (replaced, data.is_subscribed()) {
+            data.publish(&key, || CacheEvent::Remove(key.clone(), value));
+        }
+        let (expires_at, capacity) = (self.clock.now() + ttl, data.limits.capacity);
+        data.negative.insert(key, expires_at, capacity);
+        self.unlock(data);
+    }
+
+    /// Put `value` into `self` for `key`, tied to `token`.
+    ///
+    /// Once `token` is invalidated, `key` is treated as absent from the cache (unless it has since
+    /// been replaced by another `put`).
+    ///
+    /// # Returns
+    ///
+    /// The previous value in the cache, or `None`.
+    pub fn put_with_token(&self, key: K, value: V, token: &InvalidationToken) -> Option<V> {
+        let options = EntryOptions {
+            token: Some(token.clone()),
+            ..self.entry_options()
+        };
+        self.insert(key, value, options).0
+    }
+
+    /// Remove `key` from `self`.
+    ///
+    /// # Returns
+    ///
+    /// The removed value, or `None` if `key` was not in the cache.
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.record_trace(TraceOp::Remove, key);
+        let mut data = self.lock();
+        let value = data.remove_live(key, &*self.clock);
+        self.unlock(data);
+        value
+    }
+
+    /// Get the values for `keys`, in the same order, under a single lock.  See `get`.
+    pub fn get_many<'a, Q, I>(&self, keys: I) -> Vec<Option<V>>
+        where I: IntoIterator<Item = &'a Q>, K: Borrow<Q>, Q: Hash + Eq + ?Sized + 'a {
+        let mut data = self.lock_for_lookup();
+        let mut due = Vec::new();
+        let values: Vec<Option<V>> = keys.into_iter()
+            .map(|key| {
+                self.record_trace(TraceOp::Get, key);
+                data.lookup(key, &*self.clock).map(|cache_value| {
+                    due.extend(self.refresh_due(cache_value));
+                    cache_value.value.clone()
+                })
+            })
+            .collect();
+        self.unlock(data);
+
+        for due in due {
+            self.start_refresh(Some(due));
+        }
+        for value in values.iter() {
+            self.record_lookup(value.is_some());
+        }
+        values
+    }
+
+    /// Put every key and value in `items` under a single lock.  See `put`.
+    ///
+    /// # Returns
+    ///
+    /// The previous value for each key, in the order of `items`.
+    pub fn put_many<I>(&self, items: I) -> Vec<Option<V>>
+        where I: IntoIterator<Item = (K, V)> {
+        let items: Vec<(K, V, u64)> = items.into_iter()
+            .map(|(key, value)| {
+                self.record_trace(TraceOp::Put, &key);
+                let weight = self.weigh(&key, &value);
+                (key, value, weight)
+            })
+            .collect();
+
+        let mut data = self.lock();
+        let old_values = items.into_iter()
+            .map(|(key, value, weight)| data.insert(key, value, self.entry_options(), weight).0)
+            .collect();
+        self.unlock(data);
+        self.notify_inserted();
+        old_values
+    }
+
+    /// Remove `keys` under a single lock.  See `remove`.
+    ///
+    /// # Returns
+    ///
+    /// The value for each key, in the order of `keys`.
+    pub fn remove_many<'a, Q, I>(&self, keys: I) -> Vec<Option<V>>
+        where I: IntoIterator<Item = &'a Q>, K: Borrow<Q>, Q: Hash + Eq + ?Sized + 'a {
+        let mut data = self.lock();
+        let values = keys.into_iter()
+            .map(|key| {
+                self.record_trace(TraceOp::Remove, key);
+                data.remove_live(key, &*self.clock)
+            })
+            .collect();
+        self.unlock(data);
+        values
+    }
+
+    /// Remove every value in `group`.  See `set_group_index`.
+    ///
+    /// # Returns
+    ///
+    /// The number of values removed, or zero if there is no group index.
+    pub fn invalidate_group(&self, group: &str) -> usize {
+        let mut data = self.lock();
+        let members = match data.groups {
+            None => Vec::new(),
+            Some(ref groups) => groups.members(group)
+        };
+
+        for key in members.iter() {
+            data.remove(key, EvictionCause::Removed);
+        }
+
+        self.unlock(data);
+        members.len()
+    }
+
+    /// Remove every value put before `before`, such as values cached before a deployment changed
+    /// their format.  Values put again since are kept.
+    ///
+    /// `before` is a time by the cache's clock: see `now`.  On `wasm32` without the `wasm`
+    /// feature, where the system clock cannot be read, put times are only recorded once
+    /// `set_clock` is called, and values put before then are all removed.
+    ///
+    /// # Returns
+    ///
+    /// The number of values removed.
+    pub fn invalidate_all_before(&self, before: Timestamp) -> usize {
+        let mut data = self.lock();
+        let removed: Vec<K> = data.lru_list.iter()
+            .filter(|cache_value| cache_value.put_at < before)
+            .map(|cache_value| cache_value.key.clone())
+            .collect();
+
+        for key in removed.iter() {
+            data.remove(key, EvictionCause::Removed);
+        }
+
+        self.unlock(data);
+        removed.len()
+    }
+
+    /// Remove all expired values from `self`.
+    ///
+    /// Expired values are otherwise only reclaimed lazily.  Values kept by `set_keep_stale` are
+    /// not removed.
+    ///
+    /// # Returns
+    ///
+    /// The number of values removed.
+    pub fn purge_expired(&self) -> usize {
+        let mut data = self.lock();
+        let expired: Vec<K> = data.lru_list.iter()
+            .filter(|cache_value| data.is_reclaimable(cache_value, &*self.clock))
+            .map(|cache_value| cache_value.key.clone())
+            .collect();
+
+        for key in expired.iter() {
+            data.remove(key, EvictionCause::Expired);
+        }
+
+        self.unlock(data);
+        expired.len()
+    }
+
+    /// Perform the maintenance which the cache otherwise leaves to later operations: remove
+    /// expired values, evict values left over its limits by `set_max_evictions`, and put values
+    /// reloaded in the background (see `set_refresh`).
+    ///
+    /// With expiration timers enabled (see `enable_expiration_timers`), only the values whose
+    /// time-to-live has elapsed are visited; values whose `InvalidationToken` has been invalidated
+    /// are still reclaimed lazily.  Without timers this is the same as `purge_expired`, which
+    /// scans the whole cache.
+    ///
+    /// Call this periodically, or let `spawn_maintenance` call it on a background thread.
+    ///
+    /// # Returns
+    ///
+    /// The number of values removed.
+    pub fn run_pending_tasks(&self) -> usize {
+        let mut data = self.lock();
+        let expired = match data.timers {
+            None => {
+                self.unlock(data);
+                let expired = self.purge_expired();
+                data = self.lock();
+                expired
+            },
+            Some(ref mut timers) => {
+                let fired = timers.advance(self.clock.now());
+                data.expire_fired(fired, &*self.clock)
+            }
+        };
+
+        let len = data.map.len();
+        data.enforce_limits();
+        let evicted = len - data.map.len();
+
+        self.unlock(data);
+        expired + evicted
+    }
+
+    /// Start a thread which calls `run_pending_tasks` on `cache` every `period`, so that expired
+    /// values are removed even if the cache is not used.
+    ///
+    /// The thread does not keep the cache alive: it exits within `period` of the last `Arc` to
+    /// the cache being dropped, or of `shutdown`.  On `wasm32`, which has no threads, call
+    /// `run_pending_tasks` from a timer instead.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn spawn_maintenance(cache: &Arc<Self>, period: Duration) -> JoinHandle<()>
+        where Self: Send + Sync + 'static {
+        expiry::spawn_maintenance(cache, period, |cache: &Self| {
+            cache.run_pending_tasks();
+            !cache.is_shut_down()
+        })
+    }
+
+    /// Shut the cache down: stop its background work, and remove every value, passing them to
+    /// the eviction listener and subscribers with `EvictionCause::Removed`.
+    ///
+    /// Threads started by `spawn_maintenance` exit within their period, no more values are
+    /// reloaded by `set_refresh`, and values still being reloaded are discarded.  Subscribers
+    /// receive every event published until now, and then find their channels disconnected.  The
+    /// cache remains usable, without background work.
+    ///
+    /// Dropping a cache shuts it down if it has an eviction listener or subscribers, on a
+    /// best-effort basis: panics by the eviction listener are ignored, and nothing is done while
+    /// the thread is panicking.  See also `store::CacheLayer::shutdown`.
+    pub fn shutdown(&self) {
+        self.shut_down.store(true, Ordering::Release);
+        if let Some(ref refresh) = self.refresh {
+            drop(refresh.take_loaded());
+        }
+
+        let mut data = self.lock();
+        data.clear();
+        data.subscribers.clear();
+        self.unlock(data);
+    }
+
+    pub(crate) fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::Acquire)
+    }
+
+    /// Remove all values for which `f(key, value)` returns `false`.
+    ///
+    /// Expired values are removed without being passed to `f`.  `f` is called with the cache
+    /// locked, so it must not use the cache.
+    ///
+    /// # Returns
+    ///
+    /// The number of values removed, including expired values.
+    pub fn retain<F>(&self, mut f: F) -> usize
+        where F: FnMut(&K, &V) -> bool {
+        let mut data = self.lock();
+        let removed: Vec<(K, EvictionCause)> = data.lru_list.iter()
+            .filter_map(|cache_value| {
+                if cache_value.is_expired(&*self.clock) {
+                    Some((cache_value.key.clone(), EvictionCause::Expired))
+                } else if !f(&cache_value.key, &cache_value.value) {
+                    Some((cache_value.key.clone(), EvictionCause::Removed))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        for (key, cause) in removed.iter() {
+            data.remove(key, *cause);
+        }
+
+        self.unlock(data);
+        removed.len()
+    }
+
+    /// The weight of `value`.
+    fn weigh(&self, key: &K, value: &V) -> u64 {
+        match self.weigher {
+            None => 1,
+            Some(ref weigher) => weigher(key, value)
+        }
+    }
+
+    /// Count a hit or a miss, if statistics are enabled.
+    fn record_lookup(&self, hit: bool) {
+        if let Some(ref stats) = self.stats {
+            stats.record_lookup(hit);
+        }
+    }
+
+    /// Record `op` on `key`, if tracing is enabled.
+    fn record_trace<Q: Hash + ?Sized>(&self, op: TraceOp, key: &Q) {
+        if let Some(ref trace) = self.trace {
+            trace.record(op, key, self.clock.now());
+        }
+    }
+
+    /// The options for a value put with the cache's defaults.
+    fn entry_options(&self) -> EntryOptions {
+        if !self.records_put_at && self.ttl.is_none() && self.tti.is_none()
+            && self.max_lifetime.is_none() {
+            return EntryOptions::default();
+        }
+
+        let now = self.clock.now();
+        EntryOptions {
+            put_at: now,
+            expires_at: self.ttl.map(|ttl| now + ttl),
+            idle: self.tti.map(|timeout| Idle { timeout, put_at: now }),
+            lifetime_ends_at: self.max_lifetime.map(|max_lifetime| now + max_lifetime),
+            ..EntryOptions::default()
+        }
+    }
+
+    /// Get the value for `key`, or compute it with `f` and insert it if it is not present.
+    ///
+    /// Only one caller computes the value for a key at a time: concurrent callers that miss on the
+    /// same key block until the first caller's value is ready and then return it, instead of
+    /// computing it again.  The cache is not locked while `f` runs (callers for other keys are not
+    /// blocked), but `f` must not itself call `get_or_insert_with` for `key`.
+    pub fn get_or_insert_with<F>(&self, key: K, f: F) -> V
+        where F: FnOnce() -> V {
+        match self.try_get_or_insert_with(key, || Ok::<V, Infallible>(f())) {
+            Ok(value) => value,
+            Err(never) => match never {}
+        }
+    }
+
+    /// Get the value for `key`, or compute it with `f` and insert it if it is not present.
+    ///
+    /// Like `get_or_insert_with`, but `f` may fail.  If it does, nothing is inserted and the error
+    /// is returned; one of the callers waiting on the same key (if any) then computes the value
+    /// instead.
+    pub fn try_get_or_insert_with<F, E>(&self, key: K, f: F) -> Result<V, E>
+        where F: FnOnce() -> Result<V, E> {
+        self.record_trace(TraceOp::Get, &key);
+        let in_flight = loop {
+            let mut data = self.lock();
+
+            let value = data.lookup(&key, &*self.clock)
+                .filter(|cache_value| !self.expires_early(cache_value))
+                .map(|cache_value| cache_value.value.clone());
+            if let Some(value) = value {
+                self.unlock(data);
+                self.record_lookup(true);
+                return Ok(value);
+            }
+
+            self.record_lookup(false);
+            let in_flight = data.loading.get(&key).map(Arc::clone);
+            let in_flight = match in_flight {
+                None => {
+                    let in_flight = Arc::new(InFlight::new());
+                    data.loading.insert(key.clone(), Arc::clone(&in_flight));
+                    self.unlock(data);
+                    break in_flight;
+                },
+                Some(in_flight) => in_flight
+            };
+
+            self.unlock(data);
+
+            // If the load failed, try again (possibly computing the value ourselves).
+            if let Some(value) = in_flight.wait() {
+                return Ok(value);
+            }
+        };
+
+        let guard = LoadGuard { cache: self, key: &key, in_flight: &in_flight };
+        let value = match self.loader_listener {
+            None => f(),
+            Some(ref listener) => {
+                let started = Instant::now();
+                let value = f();
+                listener(started.elapsed(), value.is_ok());
+                value
+            }
+        }?;
+        guard.disarm();
+
+        self.record_trace(TraceOp::Put, &key);
+        let weight = self.weigh(&key, &value);
+        let mut data = self.lock();
+        data.loading.remove(&key);
+        data.insert(key, value.clone(), self.entry_options(), weight);
+        self.unlock(data);
+
+        self.notify_inserted();
+        in_flight.complete(Some(value.clone()));
+
+        Ok(value)
+    }
+
+    fn insert(&self, key: K, value: V, options: EntryOptions) -> (Option<V>, u64) {
+        self.record_trace(TraceOp::Put, &key);
+        let weight = self.weigh(&key, &value);
+        let mut data = self.lock();
+        let result = data.insert(key, value, options, weight);
+        self.unlock(data);
+        self.notify_inserted();
+        result
+    }
+}
+
+impl <K, V, P, S> Drop for Cache<K, V, P, S>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    /// Shut the cache down, on a best-effort basis, if its values are observed.  See `shutdown`.
+    fn drop(&mut self) {
+        if thread::panicking() || !self.data.get_mut().has_listeners() {
+            return;
+        }
+
+        drop(panic::catch_unwind(AssertUnwindSafe(|| self.shutdown())));
+    }
+}
+
+impl <K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher> Extend<(K, V)>
+    for Cache<K, V, P, S> {
+    /// Put each item of `iter`, in order, under a single lock.  See `put_many`.
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        self.put_many(iter);
+    }
+}
+
+impl <K, V, P, S> Clone for Cache<K, V, P, S>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K> + Clone, S: BuildHasher + Clone {
+    /// Copy the cache deeply: its values in the same recency order, with their expiry times,
+    /// along with its limits, policy, weigher, clock and lock strategy.
+    ///
+    /// Eviction listeners, subscribers, statistics, background refresh and the other hooks
+    /// registered on the cache are not copied.  Values are cloned unless `V` shares them, as with
+    /// an `Arc`.
+    fn clone(&self) -> Cache<K, V, P, S> {
+        let data = self.data.read();
+        let mut clone = Cache::with_limits(data.limits, self.weigher.clone(), data.policy.clone(),
+                                           data.map.hasher().clone(), self.data.strategy());
+        clone.ttl = self.ttl;
+        clone.tti = self.tti;
+        clone.max_lifetime = self.max_lifetime;
+        clone.clock = Arc::clone(&self.clock);
+        clone.records_put_at = self.records_put_at;
+        clone.idle_shrink = self.idle_shrink.as_ref().map(|idle_shrink| {
+            IdleShrink::new(idle_shrink.period, self.clock.now())
+        });
+        clone.early_expiration = self.early_expiration.as_ref().map(|early_expiration| {
+            EarlyExpiration::new(early_expiration.recompute_time)
+        });
+
+        let clone_data = clone.data.get_mut();
+        // The policy was copied with its view of the values, so they are linked in directly.
+        for cache_value in data.lru_list.iter().rev() {
+            let options = cache_value.options();
+            let index = clone_data.lru_list.vacant_index(options.priority);
+            let copy = Arc::new(CacheValue::new(cache_value.key.clone(), cache_value.value.clone(),
+                                                options, cache_value.weight, cache_value.version,
+                                                index));
+            let referenced = cache_value.referenced.load(Ordering::Relaxed);
+            copy.referenced.store(referenced, Ordering::Relaxed);
+            clone_data.map.insert(copy.key.clone(), Arc::clone(&copy));
+            clone_data.lru_list.push_front(copy);
+        }
+        clone_data.weight = data.weight;
+        clone_data.version = data.version;
+        clone_data.admission = data.admission.clone();
+        clone_data.recency = data.recency;
+        clone_data.groups = data.groups.clone();
+        clone_data.timers = data.timers.clone();
+        clone_data.negative = data.negative.clone();
+        clone_data.pinned = data.pinned.clone();
+        clone_data.max_pinned = data.max_pinned;
+        clone_data.max_evictions = data.max_evictions;
+        clone_data.low_watermark = data.low_watermark;
+        clone_data.keep_stale = data.keep_stale;
+        drop(data);
+
+        clone
+    }
+}
+
+impl <K, V, P, S> fmt::Debug for Cache<K, V, P, S>
+    where K: Eq + Hash + Clone + fmt::Debug, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    /// Show the cache's size and limits.  The alternate form (`{:#?}`) also lists its keys, from
+    /// least to most recently used.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let data = self.data.read();
+        let keys = f.alternate().then(|| {
+            data.lru_list.iter().rev().map(|cache_value| &cache_value.key).collect::<Vec<_>>()
+        });
+
+        let mut debug = f.debug_struct("Cache");
+        debug.field("len", &data.map.len())
+            .field("capacity", &data.limits.capacity)
+            .field("weight", &data.weight)
+            .field("max_weight", &data.limits.max_weight);
+        if let Some(keys) = keys {
+            debug.field("keys", &keys);
+        }
+        debug.finish()
+    }
+}
+
+impl <K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher> IntoIterator
+    for Cache<K, V, P, S> {
+    type Item = (K, V);
+    type IntoIter = Drain<K, V>;
+
+    /// Take the values out of the cache, from least to most recently used.  See `drain`.
+    fn into_iter(self) -> Drain<K, V> {
+        self.drain()
+    }
+}
+
+impl <K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher> IntoIterator
+    for &Cache<K, V, P, S> {
+    type Item = ValueGuard<K, V>;
+    type IntoIter = Iter<K, V>;
+
+    /// Iterate over the values in the cache, in arbitrary order.  See `iter`.
+    fn into_iter(self) -> Iter<K, V> {
+        self.iter()
+    }
+}
+
+impl <K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K> + Default> FromIterator<(K, V)>
+    for Cache<K, V, P> {
+    /// Create a cache with exactly enough capacity for the distinct keys of `iter`, holding its
+    /// items.  See `from_iter_with_capacity`.
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Cache<K, V, P> {
+        let items: Vec<(K, V)> = iter.into_iter().collect();
+        let keys: HashSet<&K> = items.iter().map(|(key, _)| key).collect();
+        Self::from_iter_with_capacity(keys.len(), items)
+    }
+}
+
+/// Releases a key claimed by `try_get_or_insert_with` if its loader fails or panics.
+struct LoadGuard<'a, K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher> {
+    cache: &'a Cache<K, V, P, S>,
+    key: &'a K,
+    in_flight: &'a InFlight<V>
+}
+
+impl <'a, K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher>
+    LoadGuard<'a, K, V, P, S> {
+    /// The load succeeded; the caller will release the key itself.
+    fn disarm(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl <'a, K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher> Drop
+    for LoadGuard<'a, K, V, P, S> {
+    fn drop(&mut self) {
+        self.cache.data.lock().loading.remove(self.key);
+        self.in_flight.complete(None);
+    }
+}
+
+impl <K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher> CacheData<K, V, P, S> {
+    /// Look up `key`, updating its recency.
+    fn lookup<Q>(&mut self, key: &Q, clock: &dyn Clock) -> Option<&Arc<CacheValue<K, V>>>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        let expired = self.map.get(key).map(|cache_value| cache_value.is_expired(clock));
+
+        // A buffered hit is recorded by the admission filter when the buffer is drained.
+        if expired != Some(false) || self.recency != RecencyMode::Buffered {
+            if let Some(ref mut admission) = self.admission {
+                admission.record(key);
+            }
+        }
+
+        match expired {
+            None => return None,
+            Some(true) => {
+                if self.is_reclaimable(&self.map[key], clock) {
+                    self.remove(key, EvictionCause::Expired);
+                }
+                return None;
+            },
+            Some(false) => {}
+        }
+
+        if self.recency == RecencyMode::Buffered {
+            let cache_value = &self.map[key];
+            cache_value.record_read(clock);
+            if self.accesses.push(Arc::clone(cache_value)) {
+                self.apply_accesses();
+            }
+            return self.map.get(key);
+        }
+
+        let cache_value = &self.map[key];
+        cache_value.record_read(clock);
+        if self.recency == RecencyMode::Clock {
+            cache_value.referenced.store(true, Ordering::Relaxed);
+        } else {
+            self.lru_list.move_to_front(cache_value);
+        }
+        if !self.is_pinned(key) {
+            self.policy.on_access(&cache_value.key);
+        }
+        Some(cache_value)
+    }
+
+    /// Update the recency of the values in `accesses`, as `lookup` would have, skipping any
+    /// which have since been removed or replaced.
+    fn apply_accesses(&mut self) {
+        for cache_value in self.accesses.drain() {
+            let key = &cache_value.key;
+            if let Some(ref mut admission) = self.admission {
+                admission.record(key);
+            }
+
+            match self.map.get(key) {
+                Some(current) if Arc::ptr_eq(current, &cache_value) => {},
+                _ => continue
+            }
+            match self.recency {
+                RecencyMode::Clock => cache_value.referenced.store(true, Ordering::Relaxed),
+                RecencyMode::Exact | RecencyMode::Buffered => {
+                    self.lru_list.move_to_front(&cache_value)
+                }
+            }
+            if !self.is_pinned(key) {
+                self.policy.on_access(key);
+            }
+        }
+    }
+
+    /// Insert `value` for `key` with `weight`, evicting the least recently used values until it
+    /// fits within the cache's limits.
+    ///
+    /// # Returns
+    ///
+    /// The previous value for `key`, if any, and the version assigned to `value`.
+    fn insert(&mut self, key: K, value: V, options: EntryOptions, weight: u64) -> (Option<V>, u64) {
+        self.version += 1;
+        let version = self.version;
+
+        // Remove the old value first, so that its weight does not count against the new one.
+        let pinned = self.is_pinned(&key);
+        let old_value = self.remove(&key, EvictionCause::Replaced);
+        self.negative.remove(&key);
+
+        // Values which could never fit, or which the admission filter refuses, are not stored.
+        if self.limits.capacity == 0 || weight > self.limits.max_weight
+            || (old_value.is_none() && !self.admit(&key, weight)) {
+            self.refused(key, value, old_value.as_ref());
+            return (old_value, version);
+        }
+
+        // Values which do not fit even after evicting every unpinned value are not stored.
+        if self.make_room(weight) && self.is_full(weight) {
+            self.refused(key, value, old_value.as_ref());
+            return (old_value, version);
+        }
+
+        let (index, cold) = (self.lru_list.vacant_index(options.priority), options.cold);
+        let cache_value =
+            Arc::new(CacheValue::new(key.clone(), value, options, weight, version, index));
+        self.weight += weight;
+        if pinned {
+            self.pinned.insert(key.clone());
+        } else if cold {
+            self.policy.on_insert_cold(&key);
+        } else {
+            self.policy.on_insert(&key);
+        }
+        if let Some(ref mut groups) = self.groups {
+            groups.insert(&key);
+        }
+        if let (Some(timers), Some(deadline)) = (self.timers.as_mut(), cache_value.deadline()) {
+            timers.schedule(key.clone(), deadline);
+        }
+        if self.is_subscribed() {
+            let event = if old_value.is_none() { CacheEvent::Insert } else { CacheEvent::Update };
+            self.publish(&key, || event(key.clone(), cache_value.value.clone()));
+        }
+        self.map.insert(key, Arc::clone(&cache_value));
+        if cold {
+            self.lru_list.push_back(cache_value);
+        } else {
+            self.lru_list.push_front(cache_value);
+        }
+        if let Some(ref stats) = self.stats {
+            stats.record_insertion();
+        }
+
+        (old_value, version)
+    }
+
+    /// Record that `value`, put for `key`, was not stored for lack of room.  Subscribers are told
+    /// that `old_value`, which it replaced, is gone too, since no `Update` reports it.
+    fn refused(&mut self, key: K, value: V, old_value: Option<&V>) {
+        if let (Some(old_value), true) = (old_value, self.is_subscribed()) {
+            self.publish(&key, || CacheEvent::Remove(key.clone(), old_value.clone()));
+        }
+        self.evicted(key, value, EvictionCause::Capacity);
+    }
+
+    /// Remove `key`, returning its value.
+    fn remove<Q>(&mut self, key: &Q, cause: EvictionCause) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        let cache_value = self.map.remove(key)?;
+        let (key, value) = into_entry(self.unlink(cache_value));
+        if self.has_listeners() {
+            self.evicted(key, value.clone(), cause);
+        } else {
+            self.forgotten(&key, cause);
+        }
+        Some(value)
+    }
+
+    /// Take the value for `key`, which must be present, out of `map` and `lru_list` to be updated
+    /// in place and restored by `reattach`.  The policy and the other indexes keep tracking it.
+    ///
+    /// The value is copied only if it is still shared, by a `ValueGuard` or `Snapshot`.
+    fn detach<Q>(&mut self, key: &Q) -> CacheValue<K, V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        // Reads buffered by `RecencyMode::Buffered` share the value.
+        self.apply_accesses();
+        let cache_value = self.map.remove(key).expect("Detached value must be in the cache");
+        drop(self.lru_list.remove(&cache_value));
+
+        Arc::try_unwrap(cache_value).unwrap_or_else(|shared| {
+            CacheValue::new(shared.key.clone(), shared.value.clone(), shared.options(),
+                            shared.weight, shared.version, shared.index)
+        })
+    }
+
+    /// Restore `cache_value`, taken by `detach` and now of `weight`, as a new version at the most
+    /// recently used position.  Evicts it if it no longer fits, and otherwise evicts other values
+    /// until the cache is within its limits.
+    fn reattach(&mut self, mut cache_value: CacheValue<K, V>, weight: u64) {
+        self.version += 1;
+        cache_value.version = self.version;
+        self.weight = self.weight - cache_value.weight + weight;
+        cache_value.weight = weight;
+        cache_value.index = self.lru_list.vacant_index(cache_value.priority);
+        if self.is_subscribed() {
+            let (key, value) = (&cache_value.key, &cache_value.value);
+            self.publish(key, || CacheEvent::Update(key.clone(), value.clone()));
+        }
+
+        let key = cache_value.key.clone();
+        let cache_value = Arc::new(cache_value);
+        self.map.insert(key.clone(), Arc::clone(&cache_value));
+        self.lru_list.push_front(cache_value);
+
+        if weight > self.limits.max_weight {
+            self.remove(&key, EvictionCause::Capacity);
+        } else {
+            self.enforce_limits();
+        }
+    }
+
+    /// Remove the values whose timers have fired, returning how many were removed.
+    ///
+    /// Values read since their timers were scheduled expire later, and are rescheduled instead.
+    fn expire_fired(&mut self, fired: Vec<K>, clock: &dyn Clock) -> usize {
+        let mut expired = 0;
+        for key in fired {
+            let deadline = match self.map.get(&key) {
+                None => continue,
+                Some(cache_value) if self.is_reclaimable(cache_value, clock) => None,
+                // Kept to be served stale.
+                Some(cache_value) if cache_value.is_expired(clock) => continue,
+                Some(cache_value) => cache_value.deadline()
+            };
+            match (deadline, self.timers.as_mut()) {
+                (Some(deadline), Some(timers)) => timers.schedule(key, deadline),
+                _ => {
+                    self.remove(&key, EvictionCause::Expired);
+                    expired += 1;
+                }
+            }
+        }
+        expired
+    }
+
+    /// Whether `cache_value` has expired and may be removed, rather than kept to be served stale.
+    fn is_reclaimable(&self, cache_value: &CacheValue<K, V>, clock: &dyn Clock) -> bool {
+        cache_value.is_expired(clock) && !(self.keep_stale && cache_value.is_stale(clock))
+    }
+
+    /// Remove `key` at the caller's request, returning its value unless it had expired.
+    fn remove_live<Q>(&mut self, key: &Q, clock: &dyn Clock) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.negative.remove(key);
+
+        let expired = self.map.get(key)?.is_expired(clock);
+        let cause = if expired { EvictionCause::Expired } else { EvictionCause::Removed };
+        let value = self.remove(key, cause);
+
+        if expired {
+            None
+        } else {
+            value
+        }
+    }
+
+    /// Remove and return the first unexpired value chosen by `next`, removing expired values until
+    /// one is found.
+    fn pop<F>(&mut self, clock: &dyn Clock, next: F) -> Option<(K, V)>
+        where F: Fn(&LruLists<K, V>) -> Option<&Arc<CacheValue<K, V>>> {
+        loop {
+            let (key, expired) = match next(&self.lru_list) {
+                None => return None,
+                Some(cache_value) => (cache_value.key.clone(), cache_value.is_expired(clock))
+            };
+
+            let cause = if expired { EvictionCause::Expired } else { EvictionCause::Removed };
+            let value = self.remove(&key, cause);
+            if !expired {
+                return value.map(|value| (key, value));
+            }
+        }
+    }
+
+    /// Remove all values.
+    fn clear(&mut self) {
+        self.forget_all();
+
+        if !self.has_listeners() {
+            // Change hooks and statistics need only the keys, so the values are not taken out one
+            // by one.
+            if !self.change_hooks.is_empty() || self.stats.is_some() {
+                for cache_value in self.lru_list.iter().rev() {
+                    self.forgotten(&cache_value.key, EvictionCause::Removed);
+                }
+            }
+            self.lru_list.clear();
+            self.map.clear();
+            return;
+        }
+
+        while let Some(cache_value) = self.lru_list.pop_back() {
+            self.map.remove(&cache_value.key);
+            let (key, value) = into_entry(cache_value);
+            self.evicted(key, value, EvictionCause::Removed);
+        }
+    }
+
+    /// Release memory not needed for the current contents of the cache.
+    fn shrink_to_fit(&mut self) {
+        self.map.shrink_to_fit();
+        self.lru_list.shrink_to_fit();
+    }
+
+    /// Reset all tracking of the values in the cache, which are about to be removed in bulk.
+    fn forget_all(&mut self) {
+        self.weight = 0;
+        self.policy.clear();
+        if let Some(ref mut groups) = self.groups {
+            groups.clear();
+        }
+        if let Some(ref mut timers) = self.timers {
+            timers.clear();
+        }
+        self.negative.clear();
+        self.pinned.clear();
+    }
+
+    /// Remove `cache_value`, which has just been removed from `map`, from `lru_list`.
+    ///
+    /// # Returns
+    ///
+    /// The only other reference to `cache_value` held by the cache.
+    fn unlink(&mut self, cache_value: Arc<CacheValue<K, V>>) -> Arc<CacheValue<K, V>> {
+        let removed = self.lru_list.remove(&cache_value);
+
+        self.weight -= cache_value.weight;
+        if !self.pinned.remove(&cache_value.key) {
+            self.policy.on_remove(&cache_value.key);
+        }
+        if let Some(ref mut groups) = self.groups {
+            groups.remove(&cache_value.key);
+        }
+        if let Some(ref mut timers) = self.timers {
+            timers.cancel(&cache_value.key);
+        }
+        removed
+    }
+
+    /// Record that `value` left the cache, to be passed to the eviction listener and subscribers.
+    fn evicted(&mut self, key: K, value: V, cause: EvictionCause) {
+        if let Some(ref stats) = self.stats {
+            stats.record_eviction(cause);
+        }
+
+        if let (true, Some(event)) = (self.is_subscribed(), CacheEvent::left(cause)) {
+            self.publish(&key, || event(key.clone(), value.clone()));
+        }
+        if self.listening {
+            self.evicted.push((key, value, cause));
+        }
+    }
+
+    /// Record that the value for `key` left the cache, as `evicted` does, when no eviction
+    /// listener or subscriber needs the value, so that change hooks are passed only the key.
+    fn forgotten(&self, key: &K, cause: EvictionCause) {
+        if let Some(ref stats) = self.stats {
+            stats.record_eviction(cause);
+        }
+        if cause != EvictionCause::Replaced {
+            for hook in self.change_hooks.iter() {
+                hook(key);
+            }
+        }
+    }
+
+    /// Whether an eviction listener or subscribers, rather than only change hooks, observe the
+    /// cache.
+    fn has_listeners(&self) -> bool {
+        self.listening || !self.subscribers.is_empty()
+    }
+
+    /// Whether events are published, to subscribers or change hooks.
+    fn is_subscribed(&self) -> bool {
+        !self.subscribers.is_empty() || !self.change_hooks.is_empty()
+    }
+
+    /// Pass `key` to every change hook, and send the event made by `event` for it to every
+    /// subscriber, dropping those whose receivers have been dropped.  The event, which clones the
+    /// key and value, is only made if there are subscribers.
+    fn publish<F>(&mut self, key: &K, event: F)
+        where F: FnOnce() -> CacheEvent<K, V> {
+        for hook in self.change_hooks.iter() {
+            hook(key);
+        }
+        if self.subscribers.is_empty() {
+            return;
+        }
+
+        let event = event();
+        self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Whether a new value of `weight` would not fit within `limits` without evicting others.
+    fn is_full(&self, weight: u64) -> bool {
+        self.exceeds(self.limits, weight)
+    }
+
+    /// Whether a new value of `weight` would not fit within `limits`.
+    fn exceeds(&self, limits: Limits, weight: u64) -> bool {
+        self.map.len() >= limits.capacity || self.weight.saturating_add(weight) > limits.max_weight
+    }
+
+    /// The limits to evict down to, scaled by `low_watermark`.
+    fn low_watermark_limits(&self) -> Limits {
+        // Casts saturate, so unbounded limits stay unbounded.
+        Limits {
+            capacity: (self.limits.capacity as f64 * self.low_watermark) as usize,
+            max_weight: (self.limits.max_weight as f64 * self.low_watermark) as u64
+        }
+    }
+
+    /// Make room for a new value of `weight`.  Evicts values until it fits within `limits` scaled
+    /// by `low_watermark`, or only pinned values remain.
+    ///
+    /// # Returns
+    ///
+    /// `false` if it stopped after `max_evictions` evictions instead.
+    fn make_room(&mut self, weight: u64) -> bool {
+        if !self.is_full(weight) {
+            return true;
+        }
+
+        let started = self.eviction_batch_started();
+        let low_watermark = self.low_watermark_limits();
+        let (mut evictions, mut fits) = (0, true);
+        // The low watermark is within `limits`, so the value fits once it is reached.
+        while !self.map.is_empty() && self.exceeds(low_watermark, weight) {
+            if evictions == self.max_evictions {
+                fits = false;
+                break;
+            }
+            if !self.evict() {
+                break;
+            }
+            evictions += 1;
+        }
+        self.report_eviction_batch(started, evictions);
+        fits
+    }
+
+    /// Evict values until the cache is within `limits`, or only pinned values remain.
+    fn enforce_limits(&mut self) {
+        let started = self.eviction_batch_started();
+        let mut evictions = 0;
+        while !self.map.is_empty()
+            && (self.map.len() > self.limits.capacity || self.weight > self.limits.max_weight) {
+            if !self.evict() {
+                break;
+            }
+            evictions += 1;
+        }
+        self.report_eviction_batch(started, evictions);
+    }
+
+    /// The time a batch of evictions started, if an eviction batch listener is set.
+    fn eviction_batch_started(&self) -> Option<Instant> {
+        self.eviction_batch_listener.as_ref().map(|_| Instant::now())
+    }
+
+    /// Pass a batch of `evictions` which began at `started` to the eviction batch listener, if
+    /// it evicted any.
+    fn report_eviction_batch(&self, started: Option<Instant>, evictions: usize) {
+        if evictions == 0 {
+            return;
+        }
+        if let (Some(listener), Some(started)) = (self.eviction_batch_listener.as_ref(), started) {
+            listener(evictions, started.elapsed());
+        }
+    }
+
+    /// Whether `key` is pinned.
+    fn is_pinned<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        !self.pinned.is_empty() && self.pinned.contains(key)
+    }
+
+    /// Pin `key`, which is in the cache, if the budget allows.  Returns whether it is pinned.
+    fn pin(&mut self, key: K) -> bool {
+        if self.pinned.contains(&key) {
+            return true;
+        }
+        if self.pinned.len() >= self.max_pinned {
+            return false;
+        }
+
+        self.policy.on_remove(&key);
+        self.pinned.insert(key);
+        true
+    }
+
+    /// Unpin `key`, returning the policy to tracking it.  Returns whether it was pinned.
+    fn unpin<Q>(&mut self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        if !self.pinned.remove(key) {
+            return false;
+        }
+
+        // Pinned keys are always in the cache, since leaving it unpins them.
+        self.policy.on_insert(&self.map[key].key);
+        true
+    }
+
+    /// Whether the admission filter (if any) admits `key`, a new key of `weight`.
+    ///
+    /// Records the access to `key`.  If the cache is full, `key` is compared against the first
+    /// value the policy would evict for it.
+    fn admit(&mut self, key: &K, weight: u64) -> bool {
+        match self.admission {
+            None => return true,
+            Some(ref mut admission) => admission.record(key)
+        }
+
+        if self.map.is_empty() || !self.is_full(weight) {
+            return true;
+        }
+
+        let victim = match self.select_victim() {
+            None => return true,
+            Some(victim) => victim
+        };
+        self.admission.as_ref().is_none_or(|admission| admission.admit(key, &victim))
+    }
+
+    /// The key the policy would evict next, or `None` if every value is pinned.
+    fn select_victim(&mut self) -> Option<K> {
+        if self.pinned.len() == self.map.len() {
+            return None;
+        }
+
+        if self.recency == RecencyMode::Clock {
+            self.advance_clock();
+        }
+
+        let pinned = &self.pinned;
+        let mut lru_order = self.lru_list.iter().rev()
+            .map(|cache_value| &cache_value.key)
+            .filter(|key| pinned.is_empty() || !pinned.contains(*key));
+        let victim = self.policy.select_victim(&mut lru_order);
+        assert!(victim.is_some(), "Policy must select a victim");
+        victim
+    }
+
+    /// Move values which have been read since the clock hand last passed them to the most
+    /// recently used end of `lru_list`, until the least recently used value has not been read.
+    fn advance_clock(&mut self) {
+        // Each value is passed at most once, since passing it clears its flag.
+        for _ in 0..self.map.len() {
+            let cache_value = match self.lru_list.back() {
+                Some(cache_value) if cache_value.referenced.swap(false, Ordering::Relaxed) => {
+                    Arc::clone(cache_value)
+                },
+                _ => return
+            };
+
+            self.lru_list.move_to_front(&cache_value);
+        }
+    }
+
+    /// Evict the value chosen by the policy.
+    ///
+    /// # Returns
+    ///
+    /// `false` if there was nothing to evict, because every value is pinned.
+    fn evict(&mut self) -> bool {
+        let victim = match self.select_victim() {
+            None => return false,
+            Some(victim) => victim
+        };
+
+        let cache_value = self.map.remove(&victim).expect("Victim must be in the cache");
+        if let Some(ref mut eviction_ages) = self.eviction_ages {
+            eviction_ages.record(cache_value.put_at);
+        }
+        let (key, value) = into_entry(self.unlink(cache_value));
+        self.evicted(key, value, EvictionCause::Capacity);
+        true
+    }
+}
+
+impl <K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher> Recover
+    for CacheData<K, V, P, S> {
+    /// Drop every value, since a panic part way through an operation may have left `map`,
+    /// `lru_list` and the policy out of step.  Values are dropped without cloning them for the
+    /// eviction listener, which could panic again.
+    fn recover(&mut self) {
+        self.forget_all();
+        self.map.clear();
+        self.lru_list.clear();
+        self.evicted.clear();
+        self.accesses.drain();
+    }
+}
+
+/// Take the key and value out of `cache_value`, which must no longer be in the cache.
+///
+/// They are cloned only if a `Snapshot` or `ValueGuard` still shares `cache_value`.
+fn into_entry<K: Clone, V: Clone>(cache_value: Arc<CacheValue<K, V>>) -> (K, V) {
+    match Arc::try_unwrap(cache_value) {
+        Err(rc) => (rc.key.clone(), rc.value.clone()),
+        Ok(cache_value) => (cache_value.key, cache_value.value)
+    }
+}
+
+/// A reference to a value in a `Cache`, returned by `get_ref`.
+///
+/// Dereferences to the value.
+pub struct ValueGuard<K, V> {
+    cache_value: Arc<CacheValue<K, V>>
+}
+
+impl <K, V> ValueGuard<K, V> {
+    /// The key of the value.
+    pub fn key(&self) -> &K {
+        &self.cache_value.key
+    }
+}
+
+impl <K, V> std::ops::Deref for ValueGuard<K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.cache_value.value
+    }
+}
+
+impl <K, V: fmt::Debug> fmt::Debug for ValueGuard<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.cache_value.value, f)
+    }
+}
+
+/// A mutable reference to a value in a `Cache`, returned by `get_mut`.
+///
+/// Dereferences to the value.  The cache stays locked until the guard is dropped.
+pub struct ValueGuardMut<'a, K, V, P = Lru, S = RandomState>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    cache: &'a Cache<K, V, P, S>,
+    /// Both `Some` until dropped.
+    data: Option<LockGuard<'a, CacheData<K, V, P, S>>>,
+    cache_value: Option<CacheValue<K, V>>
+}
+
+impl <'a, K, V, P, S> ValueGuardMut<'a, K, V, P, S>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    /// The key of the value.
+    pub fn key(&self) -> &K {
+        &self.cache_value.as_ref().unwrap().key
+    }
+}
+
+impl <'a, K, V, P, S> std::ops::Deref for ValueGuardMut<'a, K, V, P, S>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.cache_value.as_ref().unwrap().value
+    }
+}
+
+impl <'a, K, V, P, S> std::ops::DerefMut for ValueGuardMut<'a, K, V, P, S>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    fn deref_mut(&mut self) -> &mut V {
+        &mut self.cache_value.as_mut().unwrap().value
+    }
+}
+
+impl <'a, K, V, P, S> Drop for ValueGuardMut<'a, K, V, P, S>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    fn drop(&mut self) {
+        if let (Some(mut data), Some(cache_value)) = (self.data.take(), self.cache_value.take()) {
+            self.cache.record_trace(TraceOp::Put, &cache_value.key);
+            let weight = self.cache.weigh(&cache_value.key, &cache_value.value);
+            data.reattach(cache_value, weight);
+            self.cache.unlock(data);
+        }
+    }
+}
+
+/// Iterator over the values in a `Cache`, created by `iter` or `iter_lru_order`.
+pub struct Iter<K, V> {
+    inner: std::vec::IntoIter<Arc<CacheValue<K, V>>>
+}
+
+impl <K, V> Iterator for Iter<K, V> {
+    type Item = ValueGuard<K, V>;
+
+    fn next(&mut self) -> Option<ValueGuard<K, V>> {
+        self.inner.next().map(|cache_value| ValueGuard { cache_value })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl <K, V> ExactSizeIterator for Iter<K, V> {}
+
+/// Iterator over the `(key, value)` pairs removed from a `Cache` by `drain`.
+pub struct Drain<K, V> {
+    inner: std::vec::IntoIter<(K, V)>
+}
+
+impl <K, V> Iterator for Drain<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl <K, V> ExactSizeIterator for Drain<K, V> {}
+
+/// A consistent, point-in-time view of the contents of an `LRUCache`.
+///
+/// Created by `LRUCache::snapshot`.
+pub struct Snapshot<K, V> {
+    values: Vec<Arc<CacheValue<K, V>>>,
+    version: u64
+}
+
+impl <K, V> Snapshot<K, V> {
+    /// Iterate over the `(key, value)` pairs in the snapshot, in arbitrary order.
+    pub fn iter(&self) -> SnapshotIter<'_, K, V> {
+        SnapshotIter {
+            inner: self.values.iter()
+        }
+    }
+
+    /// Iterate over the `(key, value)` pairs in the snapshot in parallel with rayon, such as to
+    /// aggregate over a large cache.
+    #[cfg(feature = "rayon")]
+    pub fn par_iter(&self) -> impl IndexedParallelIterator<Item = (&K, &V)>
+        where K: Send + Sync, V: Send + Sync {
+        self.values.par_iter().map(|cache_value| (&cache_value.key, &cache_value.value))
+    }
+
+    /// The number of values in the snapshot.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Whether the snapshot contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// The latest version assigned by the cache when the snapshot was taken.
+    ///
+    /// Every value in the snapshot has a version no greater than this.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Combine the snapshots of several caches, such as the shards of a `ShardedLRUCache`.
+    pub(crate) fn concat<I: IntoIterator<Item = Snapshot<K, V>>>(snapshots: I) -> Snapshot<K, V> {
+        let mut combined = Snapshot { values: Vec::new(), version: 0 };
+        for snapshot in snapshots {
+            combined.values.extend(snapshot.values);
+            combined.version = combined.version.max(snapshot.version);
+        }
+        combined
+    }
+}
+
+impl <'a, K, V> IntoIterator for &'a Snapshot<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = SnapshotIter<'a, K, V>;
+
+    fn into_iter(self) -> SnapshotIter<'a, K, V> {
+        self.iter()
+    }
+}
+
+/// Iterator over the contents of a `Snapshot`.
+pub struct SnapshotIter<'a, K, V> {
+    inner: std::slice::Iter<'a, Arc<CacheValue<K, V>>>
+}
+
+impl <'a, K, V> Iterator for SnapshotIter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        self.inner.next().map(|cache_value| (&cache_value.key, &cache_value.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockClock;
+    use crate::policy::{Lfu, Slru};
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::BuildHasherDefault;
+
+    fn mock_clock<K: Eq + Hash + Clone, V: Clone>(cache: &mut LRUCache<K, V>) -> Arc<MockClock> {
+        let clock = Arc::new(MockClock::new());
+        cache.set_clock(clock.clone());
+        clock
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn auto_traits() {
+        assert_send_sync::<LRUCache<String, Vec<u8>>>();
+        assert_send_sync::<Arc<LRUCache<u64, Arc<String>>>>();
+        assert_send_sync::<Snapshot<String, Vec<u8>>>();
+        assert_send_sync::<ValueGuard<String, Vec<u8>>>();
+    }
+
+    #[test]
+    fn hit() {
+        let k1 = "key";
+        let cache: LRUCache<&str, u64> = LRUCache::new(1);
+        cache.put(k1, 2);
+        assert_eq!(cache.get(&k1), Some(2));
+    }
+
+    #[test]
+    fn miss() {
+        let k1 = "no key";
+        let cache: LRUCache<&str, u64> = LRUCache::new(10);
+        assert_eq!(cache.get(&k1), None);
+    }
+
+    #[test]
+    fn evict() {
+        let k1 = "key1";
+        let k2 = "key2";
+        let v1 = 1;
+        let v2 = 2;
+
+        let cache: LRUCache<&str, u64> = LRUCache::new(1);
+        assert_eq!(cache.len(), 0);
+
+        cache.put(k1, v1);
+        assert_eq!(cache.len(), 1);
+
+        cache.put(k2, v2);
+        assert_eq!(cache.len(), 1);
+
+        assert_eq!(cache.get(&k1), None);
+    }
+
+    #[test]
+    fn evict_lru_order() {
+        let cache: LRUCache<&str, u64> = LRUCache::new(2);
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+        cache.get(&"key1");
+
+        cache.put("key3", 3);
+        assert_eq!(cache.get(&"key1"), Some(1));
+        assert_eq!(cache.get(&"key2"), None);
+        assert_eq!(cache.get(&"key3"), Some(3));
+    }
+
+    #[test]
+    fn shared_writers() {
+        let cache: Arc<LRUCache<u64, u64>> = Arc::new(LRUCache::new(1024));
+
+        let threads: Vec<_> = (0..4).map(|t| {
+            let cache = Arc::clone(&cache);
+            std::thread::spawn(move || {
+                for idx in 0..256 {
+                    cache.put(t * 256 + idx, idx);
+                }
+            })
+        }).collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(cache.len(), 1024);
+        assert_eq!(cache.get(&(3 * 256 + 7)), Some(7));
+    }
+
+    #[test]
+    fn policy() {
+        let cache: Cache<&str, u64, Lfu<&str>> = Cache::new(2);
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+        cache.get(&"key1");
+        cache.get(&"key1");
+        cache.get(&"key2");
+
+        // key2 is more recently used, but key1 is more frequently used.
+        cache.put("key3", 3);
+        assert_eq!(cache.get(&"key1"), Some(1));
+        assert_eq!(cache.get(&"key2"), None);
+
+        cache.remove(&"key1");
+        cache.put("key4", 4);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&"key3"), Some(3));
+    }
+
+    #[test]
+    fn admission_filter() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::new(2);
+        cache.set_admission_filter(Some(TinyLfu::new(64)));
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+        for _ in 0..3 {
+            cache.get(&"key1");
+            cache.get(&"key2");
+        }
+
+        // key3 is less popular than key1, the least recently used value.
+        cache.put("key3", 3);
+        assert_eq!(cache.peek(&"key3"), None);
+        assert_eq!(cache.len(), 2);
+
+        for _ in 0..5 {
+            cache.get(&"key3");
+        }
+        cache.put("key3", 3);
+        assert_eq!(cache.peek(&"key3"), Some(3));
+        assert_eq!(cache.peek(&"key1"), None);
+    }
+
+    #[test]
+    fn recency_clock() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::new(3);
+        cache.set_recency_mode(RecencyMode::Clock);
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+        cache.put("key3", 3);
+
+        // Reads do not reorder the list.
+        cache.get(&"key1");
+        assert_eq!(cache.peek_lru(), Some(("key1", 1)));
+
+        // key1 gets a second chance.
+        cache.put("key4", 4);
+        assert_eq!(cache.peek(&"key1"), Some(1));
+        assert_eq!(cache.peek(&"key2"), None);
+        assert_eq!(cache.peek_lru(), Some(("key3", 3)));
+        assert_eq!(cache.peek_mru(), Some(("key4", 4)));
+    }
+
+    #[test]
+    fn recency_buffered() {
+        let mut cache: LRUCache<u64, u64> = LRUCache::new(3);
+        cache.set_recency_mode(RecencyMode::Buffered);
+        cache.put(1, 1);
+        cache.put(2, 2);
+        cache.put(3, 3);
+
+        // The buffered read is applied before evicting.
+        cache.get(&1);
+        cache.put(4, 4);
+        assert_eq!(cache.peek(&1), Some(1));
+        assert_eq!(cache.peek(&2), None);
+
+        // Buffered reads of values since removed are skipped.
+        cache.get(&3);
+        cache.remove(&3);
+        cache.put(5, 5);
+        assert_eq!(cache.len(), 3);
+
+        for _ in 0..1000 {
+            cache.get(&4);
+        }
+        assert_eq!(cache.peek_mru(), Some((4, 4)));
+        cache.put(6, 6);
+        assert_eq!(cache.peek(&1), None);
+    }
+
+    #[test]
+    fn get_ref() {
+        let cache: LRUCache<&str, Vec<u8>> = LRUCache::new(1);
+        cache.put("key1", vec![1, 2, 3]);
+
+        let value = cache.get_ref(&"key1").unwrap();
+        assert_eq!(*value, vec![1, 2, 3]);
+        assert_eq!(value.key(), &"key1");
+
+        // The guard outlives the value's time in the cache.
+        cache.put("key2", vec![4]);
+        assert!(cache.get_ref(&"key1").is_none());
+        assert_eq!(value.len(), 3);
+    }
+
+    #[test]
+    fn arc_cache() {
+        // Not `Clone`.
+        #[derive(Debug, PartialEq)]
+        struct Large(Vec<u8>);
+
+        let cache: ArcCache<&str, Large> = ArcCache::new(1);
+        let value = Arc::new(Large(vec![0; 1024]));
+        cache.put("key1", Arc::clone(&value));
+
+        let cached = cache.get(&"key1").unwrap();
+        assert!(Arc::ptr_eq(&cached, &value));
+        assert_eq!(*cached, Large(vec![0; 1024]));
+    }
+
+    #[test]
+    fn arc_key_cache() {
+        // Not `Clone`.
+        #[derive(Debug, PartialEq, Eq, Hash)]
+        struct Handle(u64);
+
+        let cache: ArcKeyCache<Handle, u64> = ArcKeyCache::new(1);
+        let key = Arc::new(Handle(1));
+        cache.put(Arc::clone(&key), 1);
+        assert_eq!(cache.get(&Handle(1)), Some(1));
+
+        cache.put(Arc::new(Handle(2)), 2);
+        assert_eq!(cache.get(&Handle(1)), None);
+        // The cache no longer holds the evicted key.
+        assert_eq!(Arc::strong_count(&key), 1);
+    }
+
+    #[test]
+    fn get_mut() {
+        let cache: LRUCache<&str, Vec<u8>> =
+            LRUCache::with_weigher(10, |_, value: &Vec<u8>| value.len() as u64);
+        cache.put("key1", vec![1]);
+        cache.put("key2", vec![2]);
+        let (_, version) = cache.get_versioned(&"key1").unwrap();
+        let shared = cache.get_ref(&"key1").unwrap();
+
+        cache.get_mut(&"key1").unwrap().push(3);
+        let (value, new_version) = cache.get_versioned(&"key1").unwrap();
+        assert_eq!(value, vec![1, 3]);
+        assert!(new_version > version);
+        assert_eq!(cache.weight(), 3);
+        // A shared value keeps the old value.
+        assert_eq!(*shared, vec![1]);
+        assert!(cache.get_mut(&"key3").is_none());
+
+        // key1 is the most recently used, so growing it evicts key2.
+        cache.get_mut(&"key1").unwrap().extend_from_slice(&[0; 8]);
+        assert_eq!((cache.len(), cache.weight()), (1, 10));
+
+        // A value which no longer fits is evicted.
+        cache.get_mut(&"key1").unwrap().push(0);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn recover_from_panic() {
+        #[derive(Debug, PartialEq)]
+        struct Fragile(bool);
+
+        impl Clone for Fragile {
+            fn clone(&self) -> Fragile {
+                assert!(!self.0, "cloned a fragile value");
+                Fragile(self.0)
+            }
+        }
+
+        for &strategy in [LockStrategy::Mutex, LockStrategy::RwLock, LockStrategy::Spin].iter() {
+            let cache: LRUCache<&str, Fragile> =
+                LRUCache::builder().capacity(2).lock_strategy(strategy).build().unwrap();
+            cache.put("key1", Fragile(false));
+            cache.put("key2", Fragile(true));
+
+            // Cloning the value panics with the cache locked exclusively.
+            let result = panic::catch_unwind(AssertUnwindSafe(|| cache.lookup(&"key2")));
+            assert!(result.is_err());
+
+            // The next operation clears the cache instead of panicking.
+            assert_eq!(cache.get(&"key1"), None);
+            assert!(cache.is_empty());
+            cache.put("key1", Fragile(false));
+            assert_eq!(cache.get(&"key1"), Some(Fragile(false)));
+        }
+    }
+
+    #[test]
+    fn panicking_listener() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::new(2);
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let listener_evicted = Arc::clone(&evicted);
+        cache.set_eviction_listener(move |key, _, _| {
+            listener_evicted.lock().unwrap().push(key);
+            assert_ne!(key, "key1", "listener panicked");
+        });
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+
+        // Both evictions are delivered before the panic is resumed.
+        let keys = ["key1", "key2"];
+        let result = panic::catch_unwind(AssertUnwindSafe(|| cache.remove_many(keys.iter())));
+        assert!(result.is_err());
+        assert_eq!(*evicted.lock().unwrap(), vec!["key1", "key2"]);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn iter() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::with_ttl(3, Duration::from_secs(10));
+        let clock = mock_clock(&mut cache);
+        cache.put("key1", 1);
+        clock.advance(Duration::from_secs(5));
+        cache.put("key2", 2);
+        cache.put("key3", 3);
+        cache.get(&"key1");
+
+        let lru_order: Vec<_> = cache.iter_lru_order()
+            .map(|value| (*value.key(), *value))
+            .collect();
+        assert_eq!(lru_order, vec![("key2", 2), ("key3", 3), ("key1", 1)]);
+
+        let mut contents: Vec<_> = cache.iter().map(|value| *value).collect();
+        contents.sort();
+        assert_eq!(contents, vec![1, 2, 3]);
+
+        // Iteration does not update recency.
+        assert_eq!(cache.peek_lru(), Some(("key2", 2)));
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(cache.iter().len(), 2);
+    }
+
+    #[test]
+    fn drain() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::with_ttl(3, Duration::from_secs(10));
+        let clock = mock_clock(&mut cache);
+        let evictions = record_evictions(&mut cache);
+        cache.put("key1", 1);
+        clock.advance(Duration::from_secs(5));
+        cache.put("key2", 2);
+        cache.put("key3", 3);
+        clock.advance(Duration::from_secs(5));
+
+        let drained: Vec<_> = cache.drain().collect();
+        assert_eq!(drained, vec![("key2", 2), ("key3", 3)]);
+        assert!(cache.is_empty());
+        assert_eq!(cache.weight(), 0);
+        assert_eq!(*evictions.lock().unwrap(), vec![("key1", 1, EvictionCause::Expired)]);
+    }
+
+    #[test]
+    fn retain() {
+        let cache: LRUCache<String, u64> = LRUCache::new(10);
+        for idx in 0..10 {
+            cache.put(format!("tenant{}/{}", idx % 2, idx), idx);
+        }
+
+        assert_eq!(cache.retain(|key, _| !key.starts_with("tenant1/")), 5);
+        assert_eq!(cache.len(), 5);
+        assert_eq!(cache.weight(), 5);
+        assert!(cache.iter().all(|value| *value % 2 == 0));
+
+        // The LRU list is intact.
+        assert_eq!(cache.peek_lru(), Some(("tenant0/0".to_string(), 0)));
+        assert_eq!(cache.peek_mru(), Some(("tenant0/8".to_string(), 8)));
+    }
+
+    #[test]
+    fn invalidate_group() {
+        let mut cache: LRUCache<String, u64> = LRUCache::new(4);
+        cache.put("/about".to_string(), 0);
+        cache.set_group_index(|key: &String| {
+            key.strip_prefix("/users/").map(|_| "users".to_string())
+        });
+
+        for idx in 1..4 {
+            cache.put(format!("/users/{}", idx), idx);
+        }
+        cache.put("/users/4".to_string(), 4);
+        assert_eq!(cache.get(&"/about".to_string()), None);
+
+        cache.remove(&"/users/2".to_string());
+        assert_eq!(cache.invalidate_group("users"), 3);
+        assert_eq!(cache.invalidate_group("users"), 0);
+        assert!(cache.is_empty());
+
+        cache.put("/about".to_string(), 0);
+        assert_eq!(cache.invalidate_group("about"), 0);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn set_capacity() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::new(3);
+        let evictions = record_evictions(&mut cache);
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+        cache.put("key3", 3);
+        cache.get(&"key1");
+
+        cache.set_capacity(1);
+        assert_eq!(cache.capacity(), 1);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&"key1"), Some(1));
+        assert_eq!(*evictions.lock().unwrap(), vec![
+            ("key2", 2, EvictionCause::Capacity),
+            ("key3", 3, EvictionCause::Capacity)
+        ]);
+
+        cache.set_capacity(2);
+        cache.put("key2", 2);
+        assert_eq!(cache.len(), 2);
+
+        cache.set_max_weight(1);
+        assert_eq!(cache.max_weight(), 1);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&"key2"), Some(2));
+    }
+
+    #[test]
+    fn with_hasher() {
+        type FixedState = BuildHasherDefault<DefaultHasher>;
+
+        let cache: Cache<&str, u64, Lru, FixedState> = Cache::with_hasher(2, FixedState::default());
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+        cache.put("key3", 3);
+        assert_eq!(cache.get(&"key1"), None);
+        assert_eq!(cache.get(&"key3"), Some(3));
+
+        let cache = LRUCache::<&str, u64>::builder()
+            .capacity(2)
+            .hasher(FixedState::default())
+            .build()
+            .unwrap();
+        cache.put("key1", 1);
+        assert_eq!(cache.get(&"key1"), Some(1));
+    }
+
+    #[test]
+    fn pin() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::new(3);
+        let evictions = record_evictions(&mut cache);
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+        cache.put("key3", 3);
+        assert!(!cache.pin(&"key1"));
+
+        cache.set_max_pinned(2);
+        assert!(cache.pin(&"key1"));
+        assert!(cache.pin(&"key1"));
+        assert!(!cache.pin(&"key4"));
+        cache.put("key4", 4);
+        assert_eq!(cache.get(&"key1"), Some(1));
+        assert_eq!(cache.get(&"key2"), None);
+
+        // Replacing a pinned value keeps the pin.
+        cache.put("key1", 10);
+        cache.put("key5", 5);
+        assert_eq!(cache.peek(&"key1"), Some(10));
+        assert_eq!(cache.peek(&"key3"), None);
+
+        // A cache full of pinned values rejects new ones.
+        cache.set_capacity(2);
+        assert!(cache.pin(&"key5"));
+        cache.put("key6", 6);
+        assert_eq!(cache.get(&"key6"), None);
+        assert_eq!(evictions.lock().unwrap().last(), Some(&("key6", 6, EvictionCause::Capacity)));
+
+        assert!(cache.unpin(&"key1"));
+        assert!(!cache.unpin(&"key1"));
+        cache.put("key6", 6);
+        assert_eq!(cache.peek(&"key1"), None);
+        assert_eq!(cache.peek(&"key5"), Some(5));
+    }
+
+    #[test]
+    fn pin_policy() {
+        let cache: Cache<u64, u64, Lfu<u64>> = Cache::new(2);
+        cache.set_max_pinned(1);
+        cache.put(1, 1);
+        cache.put(2, 2);
+        cache.get(&2);
+        assert!(cache.pin(&1));
+        cache.get(&1);
+
+        cache.put(3, 3);
+        assert_eq!(cache.peek(&1), Some(1));
+        assert_eq!(cache.peek(&2), None);
+    }
+
+    #[test]
+    fn priority() {
+        let cache: LRUCache<&str, u64> = LRUCache::new(3);
+        cache.put_with_priority("key1", 1, Priority::High);
+        cache.put("key2", 2);
+        cache.put_with_priority("key3", 3, Priority::Low);
+        assert_eq!(cache.peek_lru(), Some(("key3", 3)));
+        assert_eq!(cache.peek_mru(), Some(("key1", 1)));
+
+        cache.get(&"key2");
+        cache.put("key4", 4);
+        assert_eq!(cache.peek(&"key3"), None);
+        cache.put_with_priority("key5", 5, Priority::Low);
+        assert_eq!(cache.peek(&"key2"), None);
+
+        let keys: Vec<&str> = cache.iter_lru_order().map(|value| *value.key()).collect();
+        assert_eq!(keys, vec!["key5", "key4", "key1"]);
+
+        // Replacing a value resets its priority.
+        cache.put("key1", 10);
+        cache.put("key6", 6);
+        assert_eq!(cache.peek(&"key5"), None);
+        cache.put("key7", 7);
+        assert_eq!(cache.peek(&"key4"), None);
+        assert_eq!(cache.peek(&"key1"), Some(10));
+    }
+
+    #[test]
+    fn put_cold() {
+        let cache: LRUCache<&str, u64> = LRUCache::new(3);
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+        cache.put_cold("key3", 3);
+        assert_eq!(cache.peek_lru(), Some(("key3", 3)));
+
+        // Cold values evict each other rather than the values in use.
+        cache.put_cold("key4", 4);
+        cache.put_cold("key5", 5);
+        let keys: Vec<&str> = cache.iter_lru_order().map(|value| *value.key()).collect();
+        assert_eq!(keys, vec!["key5", "key1", "key2"]);
+
+        // Reading a cold value makes it the most recently used.
+        cache.get(&"key5");
+        cache.put("key6", 6);
+        assert_eq!(cache.peek(&"key1"), None);
+        assert_eq!(cache.peek_mru(), Some(("key6", 6)));
+        assert_eq!(cache.peek_lru(), Some(("key2", 2)));
+
+        let cache = Cache::with_policy(2, Slru::new(0.5));
+        cache.put("key1", 1);
+        cache.put_cold("key2", 2);
+        cache.put("key3", 3);
+        assert_eq!(cache.peek(&"key2"), None);
+    }
+
+    #[test]
+    fn batch() {
+        let cache: LRUCache<&str, u64> = LRUCache::new(3);
+        assert_eq!(cache.put_many(vec![("key1", 1), ("key2", 2), ("key1", 10)]),
+                   vec![None, None, Some(1)]);
+        assert_eq!(cache.get_many(&["key2", "key3", "key1"]), vec![Some(2), None, Some(10)]);
+        assert_eq!(cache.remove_many(&["key1", "key1"]), vec![Some(10), None]);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn from_iter() {
+        let cache: LRUCache<&str, u64> =
+            LRUCache::from_iter_with_capacity(2, vec![("key1", 1), ("key2", 2), ("key3", 3)]);
+        assert_eq!(cache.peek_lru(), Some(("key2", 2)));
+        assert_eq!(cache.peek_mru(), Some(("key3", 3)));
+
+        let mut cache: LRUCache<&str, u64> = vec![("key1", 1), ("key2", 2), ("key1", 10)]
+            .into_iter()
+            .collect();
+        assert_eq!(cache.capacity(), 2);
+        assert_eq!(cache.peek_mru(), Some(("key1", 10)));
+
+        cache.extend(vec![("key3", 3)]);
+        assert_eq!(cache.peek(&"key2"), None);
+        assert_eq!(cache.peek(&"key3"), Some(3));
+    }
+
+    #[test]
+    fn pop() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::with_ttl(3, Duration::from_secs(10));
+        let clock = mock_clock(&mut cache);
+        let evictions = record_evictions(&mut cache);
+        cache.put("key1", 1);
+        clock.advance(Duration::from_secs(5));
+        cache.put("key2", 2);
+        cache.put("key3", 3);
+        cache.get(&"key2");
+
+        assert_eq!(cache.pop_mru(), Some(("key2", 2)));
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(cache.pop_lru(), Some(("key3", 3)));
+        assert_eq!(cache.pop_lru(), None);
+        assert!(cache.is_empty());
+        assert_eq!(*evictions.lock().unwrap(), vec![
+            ("key2", 2, EvictionCause::Removed),
+            ("key1", 1, EvictionCause::Expired),
+            ("key3", 3, EvictionCause::Removed)
+        ]);
+    }
+
+    #[test]
+    fn contains_key_touch() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::with_ttl(2, Duration::from_secs(10));
+        let clock = mock_clock(&mut cache);
+        cache.enable_stats();
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+
+        assert!(cache.contains_key(&"key1"));
+        assert!(!cache.contains_key(&"key3"));
+        assert_eq!(cache.peek_lru(), Some(("key1", 1)));
+
+        assert!(cache.touch(&"key1"));
+        assert!(!cache.touch(&"key3"));
+        assert_eq!(cache.peek_lru(), Some(("key2", 2)));
+        assert_eq!(cache.stats().requests(), 0);
+
+        clock.advance(Duration::from_secs(10));
+        assert!(!cache.contains_key(&"key1"));
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.touch(&"key1"));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn remove() {
+        let cache: LRUCache<&str, u64> = LRUCache::new(2);
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+
+        assert_eq!(cache.remove(&"key1"), Some(1));
+        assert_eq!(cache.remove(&"key1"), None);
+        assert_eq!(cache.get(&"key1"), None);
+        assert_eq!(cache.len(), 1);
+
+        // The freed slot is reused without evicting.
+        cache.put("key3", 3);
+        assert_eq!(cache.get(&"key2"), Some(2));
+        assert_eq!(cache.get(&"key3"), Some(3));
+
+        let token = InvalidationToken::new();
+        cache.put_with_token("key4", 4, &token);
+        token.invalidate();
+        assert_eq!(cache.remove(&"key4"), None);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn clear() {
+        let cache: LRUCache<&str, u64> = LRUCache::new(2);
+        assert!(cache.is_empty());
+        assert_eq!(cache.capacity(), 2);
+
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+        assert_eq!(cache.len(), 2);
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.get(&"key1"), None);
+
+        cache.put("key3", 3);
+        assert_eq!(cache.get(&"key3"), Some(3));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn borrowed_keys() {
+        let cache: LRUCache<String, u64> = LRUCache::new(2);
+        cache.put("key1".to_string(), 1);
+
+        assert_eq!(cache.get("key1"), Some(1));
+        assert_eq!(cache.get_versioned("key1").map(|(value, _)| value), Some(1));
+        assert_eq!(cache.remove("key1"), Some(1));
+        assert_eq!(cache.get("key1"), None);
+    }
+
+    #[test]
+    fn peek() {
+        let cache: LRUCache<&str, u64> = LRUCache::new(2);
+        assert_eq!(cache.peek_lru(), None);
+        assert_eq!(cache.peek_mru(), None);
+
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+        assert_eq!(cache.peek_lru(), Some(("key1", 1)));
+        assert_eq!(cache.peek_mru(), Some(("key2", 2)));
+
+        // Peeking does not promote key1, so it is still evicted first.
+        assert_eq!(cache.peek(&"key1"), Some(1));
+        assert_eq!(cache.peek(&"key3"), None);
+        cache.put("key3", 3);
+        assert_eq!(cache.peek(&"key1"), None);
+        assert_eq!(cache.peek_lru(), Some(("key2", 2)));
+        assert_eq!(cache.peek_mru(), Some(("key3", 3)));
+    }
+
+    #[test]
+    fn ttl() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::with_ttl(3, Duration::from_secs(10));
+        let clock = mock_clock(&mut cache);
+
+        cache.put("key1", 1);
+        cache.put_with_ttl("key2", 2, Duration::from_secs(20));
+        clock.advance(Duration::from_secs(5));
+        cache.put("key3", 3);
+        assert_eq!(cache.get(&"key1"), Some(1));
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(cache.get(&"key1"), None);
+        assert_eq!(cache.peek(&"key2"), Some(2));
+        assert_eq!(cache.len(), 2);
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.peek(&"key2"), None);
+        assert_eq!(cache.peek_lru(), None);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.purge_expired(), 2);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn expiration_timers() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::with_ttl(4, Duration::from_secs(10));
+        cache.enable_expiration_timers();
+        let clock = mock_clock(&mut cache);
+        let evictions = record_evictions(&mut cache);
+
+        cache.put("key1", 1);
+        cache.put_with_ttl("key2", 2, Duration::from_secs(3600));
+        cache.put("key3", 3);
+        clock.advance(Duration::from_secs(5));
+        cache.put("key3", 3);
+        assert_eq!(cache.run_pending_tasks(), 0);
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(cache.run_pending_tasks(), 1);
+        assert_eq!(evictions.lock().unwrap().pop(), Some(("key1", 1, EvictionCause::Expired)));
+
+        cache.remove(&"key3");
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(cache.run_pending_tasks(), 1);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn max_evictions() {
+        let cache: LRUCache<u64, u64> = LRUCache::with_weigher(10, |_, weight| *weight);
+        for key in 0..10 {
+            cache.put(key, 1);
+        }
+        cache.set_max_evictions(2);
+
+        // The large value is stored after two evictions, leaving the cache over its limit.
+        cache.put(10, 5);
+        assert_eq!(cache.len(), 9);
+        assert_eq!(cache.weight(), 13);
+        assert_eq!(cache.peek(&10), Some(5));
+
+        assert_eq!(cache.run_pending_tasks(), 3);
+        assert_eq!(cache.weight(), 10);
+        assert_eq!(cache.peek(&4), None);
+        assert_eq!(cache.peek(&5), Some(1));
+    }
+
+    #[test]
+    fn clone() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::with_ttl(3, Duration::from_secs(10));
+        let clock = mock_clock(&mut cache);
+        cache.put("key1", 1);
+        cache.put_with_ttl("key2", 2, Duration::from_secs(5));
+        cache.put("key3", 3);
+        cache.get(&"key1");
+
+        let copy = cache.clone();
+        cache.put("key4", 4);
+        assert_eq!(copy.len(), 3);
+        assert_eq!(copy.peek_lru(), Some(("key2", 2)));
+        assert_eq!(copy.peek_mru(), Some(("key1", 1)));
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(copy.get(&"key2"), None);
+        copy.put("key5", 5);
+        copy.put("key6", 6);
+        assert_eq!(copy.into_iter().collect::<Vec<_>>(),
+                   vec![("key1", 1), ("key5", 5), ("key6", 6)]);
+    }
+
+    #[test]
+    fn debug() {
+        let cache = LRUCache::new(3);
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+        assert_eq!(format!("{:?}", cache),
+                   format!("Cache {{ len: 2, capacity: 3, weight: 2, max_weight: {} }}", u64::MAX));
+        assert!(format!("{:#?}", cache)
+            .contains("keys: [\n        \"key1\",\n        \"key2\",\n    ],"));
+
+        let mut values: Vec<u64> = (&cache).into_iter().map(|value| *value).collect();
+        values.sort();
+        assert_eq!(values, vec![1, 2]);
+    }
+
+    #[test]
+    fn low_watermark() {
+        let evictions = Arc::new(Mutex::new(0));
+        let listener_evictions = Arc::clone(&evictions);
+        let mut cache: LRUCache<u64, u64> = LRUCache::new(10);
+        cache.set_eviction_listener(move |_, _, _| *listener_evictions.lock().unwrap() += 1);
+        cache.set_low_watermark(0.5);
+        for key in 0..10 {
+            cache.put(key, key);
+        }
+
+        // Making room for one value evicts down to half the capacity.
+        cache.put(10, 10);
+        assert_eq!(*evictions.lock().unwrap(), 6);
+        assert_eq!(cache.len(), 5);
+        assert_eq!(cache.peek(&5), None);
+        assert_eq!(cache.peek(&6), Some(6));
+
+        for key in 11..16 {
+            cache.put(key, key);
+        }
+        assert_eq!(*evictions.lock().unwrap(), 6);
+        assert_eq!(cache.len(), 10);
+    }
+
+    #[test]
+    fn early_expiration() {
+        let mut cache: LRUCache<u64, u64> = LRUCache::with_ttl(10, Duration::from_secs(100));
+        let clock = mock_clock(&mut cache);
+        cache.set_early_expiration(Some(Duration::from_secs(1)));
+        cache.put(1, 1);
+        cache.put_with_ttl(2, 2, Duration::from_secs(1));
+
+        // Far from expiring, values practically never expire early.
+        assert!((0..1000).all(|_| cache.get(&1) == Some(1)));
+
+        // With one recompute time left, about 37% of lookups miss.
+        let misses = (0..1000).filter(|_| cache.get(&2).is_none()).count();
+        assert!(misses > 200 && misses < 550, "{} misses", misses);
+        assert_eq!(cache.peek(&2), Some(2));
+
+        // A caller which misses recomputes the value.
+        clock.advance(Duration::from_millis(999));
+        let mut computed = 0;
+        while computed == 0 {
+            cache.get_or_insert_with(2, || {
+                computed += 1;
+                3
+            });
+        }
+        assert_eq!(cache.peek(&2), Some(3));
+    }
+
+    #[test]
+    fn memory_usage() {
+        let cache: LRUCache<u64, String> = LRUCache::new(100);
+        let empty = cache.memory_usage();
+        assert!(empty > 100 * mem::size_of::<u64>());
+
+        cache.put(1, "a".repeat(1000));
+        cache.put(2, "b".repeat(1000));
+        assert!(cache.memory_usage() > empty + 2000);
+        assert!(cache.memory_usage() < empty + 3000);
+
+        cache.clear();
+        assert_eq!(cache.memory_usage(), empty);
+    }
+
+    #[test]
+    fn time_to_idle() {
+        let mut cache: LRUCache<u64, u64> = LRUCache::with_ttl(10, Duration::from_secs(100));
+        let clock = mock_clock(&mut cache);
+        cache.set_time_to_idle(Some(Duration::from_secs(10)));
+        cache.enable_expiration_timers();
+        cache.put(1, 1);
+        cache.put(2, 2);
+        cache.put(3, 3);
+
+        // Reads restart the time-to-idle, but peeks do not.
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(cache.get(&1), Some(1));
+        assert!(cache.touch(&2));
+        assert_eq!(cache.peek(&3), Some(3));
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(cache.run_pending_tasks(), 1);
+        assert_eq!(cache.peek(&3), None);
+        assert_eq!(cache.get(&1), Some(1));
+
+        // Replacing a value with an entry keeps its time-to-idle from the last read.
+        cache.entry(2).and_modify(|value| *value += 1);
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(cache.peek(&2), Some(3));
+
+        // Values which stay busy still expire by their time-to-live.
+        for _ in 0..20 {
+            clock.advance(Duration::from_secs(5));
+            cache.get(&1);
+        }
+        assert_eq!(cache.run_pending_tasks(), 1);
+        assert!(cache.is_empty());
+
+        // Clones keep the time-to-idle of the values they copy, and of those put later.
+        cache.put(4, 4);
+        let clone = cache.clone();
+        clone.put(5, 5);
+        clock.advance(Duration::from_secs(11));
+        assert_eq!(clone.peek(&4), None);
+        assert_eq!(clone.peek(&5), None);
+    }
+
+    #[test]
+    fn spawn_maintenance() {
+        let cache: Arc<LRUCache<u64, u64>> = Arc::new(LRUCache::new(2));
+        cache.put_with_ttl(1, 1, Duration::from_millis(10));
+        let maintenance = LRUCache::spawn_maintenance(&cache, Duration::from_millis(5));
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(cache.is_empty());
+
+        drop(cache);
+        maintenance.join().unwrap();
+    }
+
+    #[test]
+    fn refresh() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::with_ttl(2, Duration::from_secs(10));
+        let clock = mock_clock(&mut cache);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let receiver = Mutex::new(receiver);
+        cache.set_refresh(Duration::from_secs(3), move |_| receiver.lock().unwrap().recv().ok());
+
+        cache.put("key1", 1);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(cache.get(&"key1"), Some(1));
+
+        // Within the window, the stale value is served while it is reloaded.
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(cache.get(&"key1"), Some(1));
+        assert_eq!(cache.get(&"key1"), Some(1));
+        sender.send(2).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while cache.get(&"key1") != Some(2) {
+            assert!(Instant::now() < deadline, "value was not refreshed");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        // The reloaded value has a new time-to-live.
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(cache.peek(&"key1"), Some(2));
+    }
+
+    #[test]
+    fn keep_stale() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::with_ttl(2, Duration::from_secs(10));
+        let clock = mock_clock(&mut cache);
+        cache.set_keep_stale(true);
+        cache.enable_stats();
+        let token = InvalidationToken::new();
+        cache.put("key1", 1);
+        cache.put_with_token("key2", 2, &token);
+        assert_eq!(cache.get_allow_stale(&"key1"), StaleResult::Fresh(1));
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.get(&"key1"), None);
+        assert_eq!(cache.purge_expired(), 0);
+        assert_eq!(cache.get_allow_stale(&"key1"), StaleResult::Stale(1));
+        assert_eq!(cache.stats().hits, 2);
+
+        // Invalidated values are not served stale.
+        token.invalidate();
+        assert_eq!(cache.get_allow_stale(&"key2"), StaleResult::Miss);
+        assert_eq!(cache.len(), 1);
+
+        // Stale values are evicted to make room.
+        cache.put("key3", 3);
+        cache.put("key4", 4);
+        assert_eq!(cache.get_allow_stale(&"key1"), StaleResult::Miss);
+
+        cache.set_keep_stale(false);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.get_allow_stale(&"key3"), StaleResult::Miss);
+        assert_eq!(cache.purge_expired(), 1);
+    }
+
+    #[test]
+    fn keep_stale_refresh() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::with_ttl(2, Duration::from_secs(10));
+        let clock = mock_clock(&mut cache);
+        cache.set_keep_stale(true);
+        cache.set_refresh(Duration::from_secs(1), |_| Some(2));
+
+        cache.put("key1", 1);
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(cache.get_allow_stale(&"key1"), StaleResult::Stale(1));
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while cache.get_allow_stale(&"key1") != StaleResult::Fresh(2) {
+            assert!(Instant::now() < deadline, "stale value was not refreshed");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn max_lifetime() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::with_ttl(3, Duration::from_secs(5));
+        let clock = mock_clock(&mut cache);
+        cache.set_max_lifetime(Some(Duration::from_secs(10)));
+        cache.set_keep_stale(true);
+        cache.put("key1", 1);
+        cache.put_with_ttl("key2", 2, Duration::from_secs(60));
+
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(cache.get_allow_stale(&"key1"), StaleResult::Stale(1));
+        assert_eq!(cache.get(&"key2"), Some(2));
+        cache.put("key3", 3);
+
+        // Values past their maximum lifetime are misses, even if kept stale.
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(cache.get_allow_stale(&"key1"), StaleResult::Miss);
+        assert_eq!(cache.get(&"key2"), None);
+        assert_eq!(cache.get(&"key3"), Some(3));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn invalidate_all_before() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::new(3);
+        let clock = mock_clock(&mut cache);
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+        clock.advance(Duration::from_secs(1));
+        let deployed = cache.now();
+        cache.put("key2", 20);
+        cache.put("key3", 3);
+
+        assert_eq!(cache.invalidate_all_before(deployed), 1);
+        assert_eq!(cache.peek(&"key1"), None);
+        assert_eq!(cache.peek(&"key2"), Some(20));
+        assert_eq!(cache.invalidate_all_before(deployed), 0);
+    }
+
+    #[test]
+    fn hot_keys() {
+        let cache: LRUCache<&str, u64> = LRUCache::new(3);
+        for (key, value) in [("key1", 1), ("key2", 2), ("key3", 3)].iter() {
+            cache.put(key, *value);
+        }
+        cache.get(&"key1");
+        let hot_keys = cache.hot_keys(2);
+        assert_eq!(hot_keys, vec![
+            HotKey { key: "key1", frequency: None },
+            HotKey { key: "key3", frequency: None }
+        ]);
+
+        // With an admission filter, keys are ranked by frequency.  The filter is sized for many
+        // more keys than the cache holds so that its estimates are exact.
+        let mut cache: LRUCache<&str, u64> = LRUCache::new(3);
+        cache.set_admission_filter(Some(TinyLfu::new(1024)));
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+        for _ in 0..3 {
+            cache.get(&"key1");
+        }
+        let keys: Vec<(&str, Option<u8>)> = cache.hot_keys(3).into_iter()
+            .map(|hot_key| (hot_key.key, hot_key.frequency))
+            .collect();
+        assert_eq!(keys, vec![("key1", Some(4)), ("key2", Some(1))]);
+    }
+
+    #[test]
+    fn eviction_ages() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::new(2);
+        let clock = mock_clock(&mut cache);
+        assert!(cache.eviction_ages().is_empty());
+        cache.enable_eviction_ages();
+
+        cache.put("key1", 1);
+        clock.advance(Duration::from_secs(5));
+        cache.put("key2", 2);
+        cache.put("key3", 3);
+        clock.advance(Duration::from_secs(1));
+        cache.put("key4", 4);
+        // Removed values are not counted.
+        cache.remove(&"key3");
+
+        let ages = cache.eviction_ages();
+        assert_eq!(ages.ages(), &[Duration::from_secs(1), Duration::from_secs(5)]);
+        assert_eq!(ages.quantile(0.5), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn negative() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::new(2);
+        let clock = mock_clock(&mut cache);
+        cache.enable_stats();
+
+        cache.put("key1", 1);
+        cache.put_negative("key1", Duration::from_secs(5));
+        cache.put_negative("key2", Duration::from_secs(10));
+        assert_eq!(cache.lookup(&"key1"), CacheResult::NegativeHit);
+        assert_eq!(cache.get(&"key1"), None);
+        assert_eq!(cache.lookup(&"key3"), CacheResult::Miss);
+        assert!(cache.is_empty());
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(cache.lookup(&"key1"), CacheResult::Miss);
+        assert_eq!(cache.lookup(&"key2"), CacheResult::NegativeHit);
+
+        cache.put("key2", 2);
+        assert_eq!(cache.lookup(&"key2"), CacheResult::Hit(2));
+        cache.remove(&"key2");
+        assert_eq!(cache.lookup(&"key2"), CacheResult::Miss);
+
+        // At most `capacity` negative entries are kept, forgetting those which expire soonest.
+        for (idx, key) in ["key1", "key2", "key3"].iter().enumerate() {
+            cache.put_negative(key, Duration::from_secs(10 - idx as u64));
+        }
+        assert_eq!(cache.lookup(&"key1"), CacheResult::NegativeHit);
+        assert_eq!(cache.lookup(&"key2"), CacheResult::Miss);
+        assert_eq!(cache.lookup(&"key3"), CacheResult::NegativeHit);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (5, 5));
+    }
+
+    #[test]
+    fn ttl_replace() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::with_ttl(1, Duration::from_secs(10));
+        let clock = mock_clock(&mut cache);
+
+        cache.put("key1", 1);
+        clock.advance(Duration::from_secs(8));
+        cache.put("key1", 2);
+        clock.advance(Duration::from_secs(8));
+        assert_eq!(cache.get(&"key1"), Some(2));
+    }
+
+    #[test]
+    fn zero_capacity() {
+        let cache: LRUCache<&str, u64> = LRUCache::new(0);
+        assert_eq!(cache.put("key1", 1), None);
+        assert_eq!(cache.get(&"key1"), None);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn get_or_insert_with() {
+        let cache: LRUCache<&str, u64> = LRUCache::new(2);
+        assert_eq!(cache.get_or_insert_with("key1", || 1), 1);
+        assert_eq!(cache.get_or_insert_with("key1", || 2), 1);
+
+        assert_eq!(cache.try_get_or_insert_with("key2", || Err("failed")), Err("failed"));
+        assert_eq!(cache.get(&"key2"), None);
+        assert_eq!(cache.try_get_or_insert_with("key2", || Ok::<_, ()>(2)), Ok(2));
+        assert_eq!(cache.get(&"key2"), Some(2));
+    }
+
+    #[test]
+    fn get_or_insert_with_concurrent() {
+        let cache: Arc<LRUCache<u64, u64>> = Arc::new(LRUCache::new(16));
+        let loads = Arc::new(AtomicU64::new(0));
+
+        let threads: Vec<_> = (0..8).map(|_| {
+            let cache = Arc::clone(&cache);
+            let loads = Arc::clone(&loads);
+            std::thread::spawn(move || {
+                cache.get_or_insert_with(1, || {
+                    loads.fetch_add(1, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(20));
+                    42
+                })
+            })
+        }).collect();
+
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), 42);
+        }
+
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn get_or_insert_with_coalesces_without_capacity() {
+        // Waiters receive the computed value even though the cache cannot hold it.
+        let cache: Arc<LRUCache<u64, u64>> = Arc::new(LRUCache::new(0));
+        let loads = Arc::new(AtomicU64::new(0));
+        let start = Arc::new(std::sync::Barrier::new(8));
+
+        let threads: Vec<_> = (0..8).map(|_| {
+            let cache = Arc::clone(&cache);
+            let loads = Arc::clone(&loads);
+            let start = Arc::clone(&start);
+            std::thread::spawn(move || {
+                start.wait();
+                cache.get_or_insert_with(1, || {
+                    loads.fetch_add(1, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(100));
+                    42
+                })
+            })
+        }).collect();
+
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), 42);
+        }
+
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn get_or_insert_with_panic() {
+        let cache: Arc<LRUCache<u64, u64>> = Arc::new(LRUCache::new(16));
+
+        let result = {
+            let cache = Arc::clone(&cache);
+            std::thread::spawn(move || {
+                cache.get_or_insert_with(1, || panic!("loader failed"))
+            }).join()
+        };
+
+        assert!(result.is_err());
+        assert_eq!(cache.get_or_insert_with(1, || 2), 2);
+    }
+
+    type Evictions = Arc<Mutex<Vec<(&'static str, u64, EvictionCause)>>>;
+
+    fn record_evictions(cache: &mut LRUCache<&'static str, u64>) -> Evictions {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Arc::clone(&evicted);
+        cache.set_eviction_listener(move |key, value, cause| {
+            recorder.lock().unwrap().push((key, value, cause));
+        });
+        evicted
+    }
+
+    #[test]
+    fn eviction_listener() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::new(2);
+        let clock = mock_clock(&mut cache);
+        let evicted = record_evictions(&mut cache);
+
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+        cache.put("key3", 3);
+        cache.put("key3", 4);
+        assert_eq!(cache.remove(&"key2"), Some(2));
+        cache.put_with_ttl("key5", 5, Duration::from_secs(1));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.get(&"key5"), None);
+        cache.clear();
+
+        assert_eq!(*evicted.lock().unwrap(), vec![
+            ("key1", 1, EvictionCause::Capacity),
+            ("key3", 3, EvictionCause::Replaced),
+            ("key2", 2, EvictionCause::Removed),
+            ("key5", 5, EvictionCause::Expired),
+            ("key3", 4, EvictionCause::Removed)
+        ]);
+    }
+
+    #[test]
+    fn subscribe() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::with_ttl(2, Duration::from_secs(10));
+        let clock = mock_clock(&mut cache);
+        let events = cache.subscribe();
+        let dropped = cache.subscribe();
+        drop(dropped);
+
+        cache.put("key1", 1);
+        cache.put("key1", 2);
+        cache.put("key2", 3);
+        cache.put("key3", 4);
+        cache.remove(&"key2");
+        clock.advance(Duration::from_secs(10));
+        cache.get(&"key3");
+        cache.put("key4", 5);
+        cache.put_negative("key4", Duration::from_secs(1));
+
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![
+            CacheEvent::Insert("key1", 1),
+            CacheEvent::Update("key1", 2),
+            CacheEvent::Insert("key2", 3),
+            CacheEvent::Evict("key1", 2),
+            CacheEvent::Insert("key3", 4),
+            CacheEvent::Remove("key2", 3),
+            CacheEvent::Expire("key3", 4),
+            CacheEvent::Insert("key4", 5),
+            CacheEvent::Remove("key4", 5)
+        ]);
+        assert_eq!(cache.data.read().subscribers.len(), 1);
+    }
+
+    #[test]
+    fn subscribe_refused_replacement() {
+        let cache = LRUCache::with_weigher(10, |_, value: &u64| *value);
+        let events = cache.subscribe();
+        cache.put("key1", 5);
+        // A replacement which does not fit takes the old value with it.
+        cache.put("key1", 20);
+
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![
+            CacheEvent::Insert("key1", 5),
+            CacheEvent::Remove("key1", 5),
+            CacheEvent::Evict("key1", 20)
+        ]);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn change_hooks() {
+        /// Counts how many times it is cloned.
+        struct Counted(Arc<AtomicUsize>);
+
+        impl Clone for Counted {
+            fn clone(&self) -> Counted {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                Counted(Arc::clone(&self.0))
+            }
+        }
+
+        let cache: LRUCache<u64, Counted> = LRUCache::new(2);
+        let keys = Arc::new(Mutex::new(Vec::new()));
+        let hook_keys = Arc::clone(&keys);
+        cache.add_change_hook(Arc::new(move |key: &u64| hook_keys.lock().unwrap().push(*key)));
+
+        // Without subscribers, hooks are passed keys without cloning values for events.
+        let clones = Arc::new(AtomicUsize::new(0));
+        for key in 0..3 {
+            cache.put(key, Counted(Arc::clone(&clones)));
+        }
+        cache.remove(&1);
+        cache.clear();
+        assert_eq!(*keys.lock().unwrap(), vec![0, 1, 0, 2, 1, 2]);
+        assert_eq!(clones.load(Ordering::Relaxed), 0);
+
+        let events = cache.subscribe();
+        cache.put(3, Counted(Arc::clone(&clones)));
+        assert_eq!(events.try_iter().count(), 1);
+        assert_eq!(keys.lock().unwrap().last(), Some(&3));
+    }
+
+    #[test]
+    fn shutdown() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::new(2);
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let listener_evicted = Arc::clone(&evicted);
+        cache.set_eviction_listener(move |key, value, cause| {
+            listener_evicted.lock().unwrap().push((key, value, cause));
+        });
+        let events = cache.subscribe();
+        let cache = Arc::new(cache);
+        let maintenance = LRUCache::spawn_maintenance(&cache, Duration::from_millis(1));
+
+        cache.put("key1", 1);
+        cache.shutdown();
+        maintenance.join().unwrap();
+        assert!(cache.is_empty());
+        assert_eq!(events.iter().collect::<Vec<_>>(), vec![
+            CacheEvent::Insert("key1", 1),
+            CacheEvent::Remove("key1", 1)
+        ]);
+
+        // The cache remains usable.
+        cache.put("key2", 2);
+        assert_eq!(cache.get(&"key2"), Some(2));
+
+        // Dropping the cache passes its values to the listener.
+        drop(Arc::try_unwrap(cache).ok().unwrap());
+        assert_eq!(*evicted.lock().unwrap(), vec![
+            ("key1", 1, EvictionCause::Removed),
+            ("key2", 2, EvictionCause::Removed)
+        ]);
+    }
+
+    #[test]
+    fn eviction_listener_reentrant() {
+        // The listener re-inserts an evicted value under a new key, which evicts again.
+        let cache = Arc::new_cyclic(|weak: &std::sync::Weak<LRUCache<u64, u64>>| {
+            let weak = weak.clone();
+            let mut cache = LRUCache::new(2);
+            cache.set_eviction_listener(move |key, value, _| {
+                if let Some(cache) = weak.upgrade() {
+                    if key == 1 {
+                        cache.put(11, value);
+                    }
+                }
+            });
+            cache
+        });
+
+        cache.put(1, 1);
+        cache.put(2, 2);
+        cache.put(3, 3);
+        assert_eq!(cache.get(&11), Some(1));
+        assert_eq!(cache.get(&3), Some(3));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn weigher() {
+        let mut cache: LRUCache<&str, String> = LRUCache::with_weigher(10, |_, value: &String| {
+            value.len() as u64
+        });
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        {
+            let evicted = Arc::clone(&evicted);
+            cache.set_eviction_listener(move |key, _, cause| {
+                evicted.lock().unwrap().push((key, cause))
+            });
+        }
+        assert_eq!(cache.max_weight(), 10);
+
+        cache.put("key1", "aaaa".to_string());
+        cache.put("key2", "bbbb".to_string());
+        assert_eq!(cache.weight(), 8);
+
+        // Evicts both older values to fit.
+        cache.put("key3", "cccccccc".to_string());
+        assert_eq!(cache.weight(), 8);
+        assert_eq!(cache.len(), 1);
+
+        // Replacing frees the old value's weight first.
+        cache.put("key3", "dddddddddd".to_string());
+        assert_eq!(cache.weight(), 10);
+
+        // Too heavy to ever fit.
+        cache.put("key4", "eeeeeeeeeee".to_string());
+        assert_eq!(cache.get(&"key4"), None);
+        assert_eq!(cache.get(&"key3"), Some("dddddddddd".to_string()));
+
+        assert_eq!(cache.remove(&"key3").map(|value| value.len()), Some(10));
+        assert_eq!(cache.weight(), 0);
+
+        assert_eq!(*evicted.lock().unwrap(), vec![
+            ("key1", EvictionCause::Capacity),
+            ("key2", EvictionCause::Capacity),
+            ("key3", EvictionCause::Replaced),
+            ("key4", EvictionCause::Capacity),
+            ("key3", EvictionCause::Removed)
+        ]);
+    }
+
+    #[test]
+    fn replace() {
+        let k1 = "key1";
+        let k2 = "key2";
+        let v1 = 1;
+        let v2 = 2;
+
+        let cache: LRUCache<&str, u64> = LRUCache::new(1);
+        assert_eq!(cache.len(), 0);
 
         cache.put(k1, v1);
         cache.put(k1, v2);
-        assert_eq!(cache.map.lock().unwrap().len(), 1);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&k1), Some(v2));
+        assert_eq!(cache.get(&k2), None);
+    }
+
+    #[test]
+    fn token_invalidate() {
+        let k1 = "key1";
+        let k2 = "key2";
+        let token = InvalidationToken::new();
+
+        let cache: LRUCache<&str, u64> = LRUCache::new(2);
+        cache.put_with_token(k1, 1, &token);
+        cache.put(k2, 2);
+        assert_eq!(cache.get(&k1), Some(1));
+
+        token.invalidate();
+        assert_eq!(cache.get(&k1), None);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get(&k2), Some(2));
+
+        // Replacing an invalidated value detaches it from the token.
+        cache.put_with_token(k2, 3, &token);
+        cache.put(k2, 4);
+        assert_eq!(cache.get(&k2), Some(4));
+    }
+
+    #[test]
+    fn versions() {
+        let k1 = "key1";
+        let k2 = "key2";
+
+        let cache: LRUCache<&str, u64> = LRUCache::new(1);
+        cache.put(k1, 1);
+        let (_, v1) = cache.get_versioned(&k1).unwrap();
+
+        cache.put(k1, 2);
+        let (value, v2) = cache.get_versioned(&k1).unwrap();
+        assert_eq!(value, 2);
+        assert!(v2 > v1);
+
+        assert_eq!(cache.put_if_version(k1, 3, v1), Err(3));
+        let v3 = cache.put_if_version(k1, 3, v2).unwrap();
+        assert_eq!(cache.get_versioned(&k1), Some((3, v3)));
+
+        // Versions keep increasing across eviction and re-insertion.
+        cache.put(k2, 4);
+        assert_eq!(cache.put_if_version(k1, 5, v3), Err(5));
+        cache.put(k1, 5);
+        assert!(cache.get_versioned(&k1).unwrap().1 > v3);
+    }
+
+    #[test]
+    fn put_if_version_mismatch_notifies() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::with_ttl(2, Duration::from_secs(10));
+        let clock = mock_clock(&mut cache);
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let listener_evicted = Arc::clone(&evicted);
+        cache.set_eviction_listener(move |key, value, cause| {
+            listener_evicted.lock().unwrap().push((key, value, cause));
+        });
+        cache.set_refresh(Duration::from_secs(3), |_| Some(2));
+        cache.put("key1", 1);
+        clock.advance(Duration::from_secs(8));
+        assert_eq!(cache.get(&"key1"), Some(1));
+
+        // Applying the reloaded value replaces the old one even if the put is refused.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while evicted.lock().unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "replaced value was not passed to the listener");
+            assert_eq!(cache.put_if_version("key2", 3, 0), Err(3));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(*evicted.lock().unwrap(), vec![("key1", 1, EvictionCause::Replaced)]);
+    }
+
+    #[test]
+    fn wait_for() {
+        let k1 = "key1";
+        let k2 = "key2";
+
+        let cache: LRUCache<&str, u64> = LRUCache::new(1);
+        cache.put(k1, 1);
+        assert_eq!(cache.wait_for(&k1, Duration::from_millis(0)), Some(1));
+
+        let start = Instant::now();
+        assert_eq!(cache.wait_for(&k2, Duration::from_millis(20)), None);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        let cache = Arc::new(cache);
+        let producer = {
+            let cache = Arc::clone(&cache);
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(10));
+                cache.put(k2, 2);
+            })
+        };
+
+        assert_eq!(cache.wait_for(&k2, Duration::from_secs(10)), Some(2));
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn idle_shrink() {
+        let mut cache: LRUCache<u64, u64> = LRUCache::new(1024);
+        cache.set_idle_shrink(Some(Duration::from_millis(50)));
+        cache.put(1, 1);
+        assert!(cache.data.read().map.capacity() >= 1024);
+
+        // Not idle long enough.
+        cache.get(&1);
+        assert!(cache.data.read().map.capacity() >= 1024);
+
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(cache.get(&1), Some(1));
+        assert!(cache.data.read().map.capacity() < 1024);
+    }
+
+    #[test]
+    fn slow_lock_listener() {
+        let waits = Arc::new(Mutex::new(Vec::new()));
+        let listener_waits = Arc::clone(&waits);
+        let mut cache: LRUCache<u64, u64> = LRUCache::new(2);
+        cache.set_slow_lock_listener(Duration::from_millis(10), move |waited| {
+            listener_waits.lock().unwrap().push(waited);
+        });
+        let cache = Arc::new(cache);
+
+        cache.put(1, 1);
+        assert!(waits.lock().unwrap().is_empty());
+
+        let data = cache.data.read();
+        let getter = {
+            let cache = Arc::clone(&cache);
+            std::thread::spawn(move || cache.get(&1))
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        drop(data);
+        assert_eq!(getter.join().unwrap(), Some(1));
+
+        let waits = waits.lock().unwrap();
+        assert_eq!(waits.len(), 1);
+        assert!(waits[0] >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn eviction_batch_listener() {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let listener_batches = Arc::clone(&batches);
+        let mut cache: LRUCache<u64, u64> = LRUCache::new(4);
+        cache.set_eviction_batch_listener(move |evicted, _| {
+            listener_batches.lock().unwrap().push(evicted);
+        });
+
+        for key in 0..5 {
+            cache.put(key, key);
+        }
+        cache.set_low_watermark(0.5);
+        cache.put(5, 5);
+        cache.set_capacity(1);
+        // Removals and expirations are not batches of evictions.
+        cache.remove(&5);
+
+        assert_eq!(*batches.lock().unwrap(), vec![1, 3, 1]);
+    }
+
+    #[test]
+    fn loader_listener() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::with_ttl(2, Duration::from_secs(10));
+        let clock = mock_clock(&mut cache);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        cache.set_loader_listener(move |_, loaded| sender.send(loaded).unwrap());
+        cache.set_refresh(Duration::from_secs(3), |_| None);
+
+        assert_eq!(cache.get_or_insert_with("key1", || 1), 1);
+        assert_eq!(cache.try_get_or_insert_with("key2", || Err(())), Err(()));
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![true, false]);
+
+        // Background reloads are reported from their own threads.
+        clock.advance(Duration::from_secs(8));
+        assert_eq!(cache.get(&"key1"), Some(1));
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(10)), Ok(false));
+    }
+
+    #[test]
+    fn snapshot() {
+        let cache: LRUCache<&str, u64> = LRUCache::new(2);
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+
+        let snapshot = cache.snapshot();
+        cache.put("key2", 3);
+        cache.put("key3", 4);
+
+        let mut contents: Vec<_> = snapshot.iter().map(|(k, v)| (*k, *v)).collect();
+        contents.sort();
+        assert_eq!(contents, vec![("key1", 1), ("key2", 2)]);
+        assert!(snapshot.version() < cache.get_versioned(&"key3").unwrap().1);
+    }
+
+    #[test]
+    fn stats() {
+        let mut cache: LRUCache<&str, u64> = LRUCache::with_ttl(2, Duration::from_secs(10));
+        let clock = mock_clock(&mut cache);
+        cache.put("key1", 1);
+        assert_eq!(cache.stats(), CacheStats::default());
+
+        cache.enable_stats();
+        cache.put("key1", 1);
+        cache.put("key2", 2);
+        cache.put("key3", 3);
+        assert_eq!(cache.get(&"key1"), None);
+        assert_eq!(cache.get(&"key3"), Some(3));
+        assert_eq!(cache.get_or_insert_with("key3", || 0), 3);
+        assert_eq!(cache.peek(&"key2"), Some(2));
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(cache.get(&"key2"), None);
+
+        let stats = cache.stats();
+        assert_eq!(stats, CacheStats {
+            hits: 2,
+            misses: 2,
+            insertions: 3,
+            evictions: 1,
+            expirations: 1,
+            replaced: 1
+        });
+        assert_eq!(stats.hit_ratio(), 0.5);
+
+        cache.reset_stats();
+        assert_eq!(cache.stats(), CacheStats::default());
+        assert_eq!(cache.stats().hit_ratio(), 1.0);
+    }
+
+    #[test]
+    fn stats_rejected() {
+        let mut cache = LRUCache::with_weigher(10, |_, value: &u64| *value);
+        cache.enable_stats();
+        cache.put("key1", 5);
+        // Values which are not stored are not insertions.
+        cache.put("key2", 20);
+        cache.set_max_pinned(1);
+        assert!(cache.pin(&"key1"));
+        cache.put("key3", 8);
+
+        let stats = cache.stats();
+        assert_eq!((stats.insertions, stats.evictions), (1, 2));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn prometheus_text() {
+        let mut cache = LRUCache::new(2);
+        cache.enable_stats();
+        cache.put("key1", 1);
+        cache.get(&"key1");
+        cache.get(&"key2");
+
+        let text = cache.prometheus_text("sessions");
+        assert!(text.contains("# TYPE sessions_hits_total counter\nsessions_hits_total 1\n"));
+        assert!(text.contains("sessions_misses_total 1\n"));
+        assert!(text.contains("# TYPE sessions_size gauge\nsessions_size 1\n"));
+        assert!(text.ends_with("sessions_weight 1\n"));
     }
 }
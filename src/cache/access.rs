@@ -0,0 +1,89 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The number of independently locked parts of an `AccessBuffer`, so that concurrent readers
+/// rarely record to the same one.
+const STRIPES: usize = 4;
+
+/// The most accesses each stripe holds before it must be drained.
+const STRIPE_LEN: usize = 32;
+
+/// AccessBuffer records reads of a cache's values whose recency has not yet been updated, so that
+/// a read need not reorder the LRU list.  A thread holding the cache's lock exclusively drains it
+/// and applies the reads.
+///
+/// The buffer is bounded and lossy: reads are dropped when their stripe is full or another
+/// thread is recording to it, so recency is approximate under heavy load.
+pub(crate) struct AccessBuffer<T> {
+    stripes: Vec<Mutex<Vec<T>>>,
+    /// The stripe for the next read, round robin.
+    next: AtomicUsize,
+    /// The number of reads in the buffer, so that draining an empty buffer locks nothing.
+    len: AtomicUsize
+}
+
+impl <T> AccessBuffer<T> {
+    pub(crate) fn new() -> AccessBuffer<T> {
+        AccessBuffer {
+            stripes: (0..STRIPES).map(|_| Mutex::new(Vec::new())).collect(),
+            next: AtomicUsize::new(0),
+            len: AtomicUsize::new(0)
+        }
+    }
+
+    /// Record a read of `item`, unless its stripe is full or busy.
+    ///
+    /// # Returns
+    ///
+    /// Whether the stripe is full, so that the buffer should be drained.
+    pub(crate) fn push(&self, item: T) -> bool {
+        let stripe = self.next.fetch_add(1, Ordering::Relaxed) % STRIPES;
+        match self.stripes[stripe].try_lock() {
+            Ok(mut items) if items.len() < STRIPE_LEN => {
+                items.push(item);
+                self.len.fetch_add(1, Ordering::Release);
+                items.len() == STRIPE_LEN
+            },
+            Ok(_) => true,
+            Err(_) => false
+        }
+    }
+
+    /// Take the recorded reads, oldest first within each stripe.
+    pub(crate) fn drain(&self) -> Vec<T> {
+        if self.len.load(Ordering::Acquire) == 0 {
+            return Vec::new();
+        }
+
+        let mut drained = Vec::new();
+        for stripe in self.stripes.iter() {
+            let mut items = stripe.lock().unwrap();
+            self.len.fetch_sub(items.len(), Ordering::Release);
+            drained.append(&mut items);
+        }
+        drained
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_and_drain() {
+        let buffer = AccessBuffer::new();
+        assert!(buffer.drain().is_empty());
+
+        let full: Vec<bool> = (0..STRIPES * STRIPE_LEN).map(|item| buffer.push(item)).collect();
+        // Each stripe fills on its last push.
+        assert_eq!(full.iter().filter(|&&full| full).count(), STRIPES);
+        assert!(full[STRIPES * STRIPE_LEN - 1]);
+        // Further reads are dropped.
+        assert!(buffer.push(0));
+
+        let mut drained = buffer.drain();
+        drained.sort();
+        assert_eq!(drained, (0..STRIPES * STRIPE_LEN).collect::<Vec<_>>());
+        assert!(buffer.drain().is_empty());
+    }
+}
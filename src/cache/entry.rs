@@ -0,0 +1,266 @@
+use std::hash::{BuildHasher, Hash};
+
+use crate::listener::EvictionCause;
+use crate::policy::EvictionPolicy;
+use crate::trace::TraceOp;
+
+use super::{Cache, CacheData};
+use super::lock::LockGuard;
+
+/// A view into a single key of a `Cache`, which may be present or absent.
+///
+/// Created by `Cache::entry`.  The cache stays locked until the entry is dropped, so the entry's
+/// lookup and any update that follows are atomic.  The cache must not be used from the same thread
+/// while an entry is alive, including from closures passed to the entry's methods.
+pub enum Entry<'a, K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher> {
+    Occupied(OccupiedEntry<'a, K, V, P, S>),
+    Vacant(VacantEntry<'a, K, V, P, S>)
+}
+
+/// An `Entry` for a key which is present in the cache.
+pub struct OccupiedEntry<'a, K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher> {
+    locked: Locked<'a, K, V, P, S>,
+    key: K
+}
+
+/// An `Entry` for a key which is absent from the cache.
+pub struct VacantEntry<'a, K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher> {
+    locked: Locked<'a, K, V, P, S>,
+    key: K
+}
+
+/// The locked state of a cache, which is unlocked (passing evictions to the listener) on drop.
+struct Locked<'a, K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher> {
+    cache: &'a Cache<K, V, P, S>,
+    /// Always `Some` until dropped.
+    data: Option<LockGuard<'a, CacheData<K, V, P, S>>>,
+    /// Whether a value has been inserted, so that waiters should be notified on drop.
+    inserted: bool
+}
+
+impl <K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher> Cache<K, V, P, S> {
+    /// Get the entry for `key`, for in-place inspection and update.
+    ///
+    /// If `key` is present, it counts as a hit and its recency is updated, as with `get`.
+    pub fn entry(&self, key: K) -> Entry<'_, K, V, P, S> {
+        self.record_trace(TraceOp::Get, &key);
+        let mut data = self.lock();
+        let hit = data.lookup(&key, &*self.clock).is_some();
+        self.record_lookup(hit);
+
+        let locked = Locked { cache: self, data: Some(data), inserted: false };
+        if hit {
+            Entry::Occupied(OccupiedEntry { locked, key })
+        } else {
+            Entry::Vacant(VacantEntry { locked, key })
+        }
+    }
+}
+
+impl <'a, K, V, P, S> Entry<'a, K, V, P, S>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    /// The key of this entry.
+    pub fn key(&self) -> &K {
+        match *self {
+            Entry::Occupied(ref entry) => entry.key(),
+            Entry::Vacant(ref entry) => entry.key()
+        }
+    }
+
+    /// Get the value, inserting `default` if the key is absent.
+    pub fn or_insert(self, default: V) -> V {
+        self.or_insert_with(|| default)
+    }
+
+    /// Get the value, inserting the result of `f` if the key is absent.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, f: F) -> V {
+        self.or_insert_with_key(|_| f())
+    }
+
+    /// Get the value, inserting the result of `f(key)` if the key is absent.
+    pub fn or_insert_with_key<F: FnOnce(&K) -> V>(self, f: F) -> V {
+        match self {
+            Entry::Occupied(entry) => entry.get().clone(),
+            Entry::Vacant(entry) => {
+                let value = f(entry.key());
+                entry.insert(value)
+            }
+        }
+    }
+
+    /// Get the value, inserting `V::default()` if the key is absent.
+    pub fn or_default(self) -> V
+        where V: Default {
+        self.or_insert_with(V::default)
+    }
+
+    /// Modify the value with `f` if the key is present.
+    ///
+    /// The modified value replaces the old one (which is reported to the eviction listener with
+    /// `EvictionCause::Replaced`), keeping its expiry time and `InvalidationToken`.  If the
+    /// modified value no longer fits in the cache, it is evicted, and the entry becomes vacant.
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(mut entry) => {
+                let mut value = entry.get().clone();
+                f(&mut value);
+                entry.replace(value, false);
+
+                if entry.locked.data().map.contains_key(&entry.key) {
+                    Entry::Occupied(entry)
+                } else {
+                    Entry::Vacant(VacantEntry { locked: entry.locked, key: entry.key })
+                }
+            },
+            vacant => vacant
+        }
+    }
+}
+
+impl <'a, K, V, P, S> OccupiedEntry<'a, K, V, P, S>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    /// The key of this entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// The value of this entry.
+    pub fn get(&self) -> &V {
+        &self.locked.data.as_ref().unwrap().map[&self.key].value
+    }
+
+    /// Replace the value of this entry with `value`, as if by `put`.
+    ///
+    /// # Returns
+    ///
+    /// The old value.
+    pub fn insert(&mut self, value: V) -> V {
+        self.replace(value, true)
+    }
+
+    /// Remove this entry from the cache.
+    ///
+    /// # Returns
+    ///
+    /// The removed value.
+    pub fn remove(mut self) -> V {
+        self.locked.cache.record_trace(TraceOp::Remove, &self.key);
+        self.locked.data().remove(&self.key, EvictionCause::Removed)
+            .expect("Occupied entry must be in the cache")
+    }
+
+    /// Replace the value, returning the old one.  `reset` determines whether the new value gets
+    /// fresh options (as from `put`) or keeps those of the old value.
+    fn replace(&mut self, value: V, reset: bool) -> V {
+        let cache = self.locked.cache;
+        cache.record_trace(TraceOp::Put, &self.key);
+        let options = if reset {
+            cache.entry_options()
+        } else {
+            self.locked.data().map[&self.key].options()
+        };
+
+        let weight = cache.weigh(&self.key, &value);
+        let (old_value, _) = self.locked.data()
+            .insert(self.key.clone(), value, options, weight);
+        self.locked.inserted = true;
+        old_value.expect("Occupied entry must be in the cache")
+    }
+}
+
+impl <'a, K, V, P, S> VacantEntry<'a, K, V, P, S>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    /// The key of this entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Take ownership of the key.
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Insert `value` for this entry's key, as if by `put`.
+    ///
+    /// # Returns
+    ///
+    /// `value`.
+    pub fn insert(mut self, value: V) -> V {
+        let cache = self.locked.cache;
+        cache.record_trace(TraceOp::Put, &self.key);
+        let weight = cache.weigh(&self.key, &value);
+        self.locked.data()
+            .insert(self.key, value.clone(), cache.entry_options(), weight);
+        self.locked.inserted = true;
+        value
+    }
+}
+
+impl <'a, K, V, P, S> Locked<'a, K, V, P, S>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    fn data(&mut self) -> &mut CacheData<K, V, P, S> {
+        self.data.as_mut().unwrap()
+    }
+}
+
+impl <'a, K, V, P, S> Drop for Locked<'a, K, V, P, S>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    fn drop(&mut self) {
+        if let Some(data) = self.data.take() {
+            self.cache.unlock(data);
+        }
+
+        if self.inserted {
+            self.cache.notify_inserted();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::LRUCache;
+    use super::*;
+
+    #[test]
+    fn or_insert() {
+        let cache: LRUCache<&str, u64> = LRUCache::new(2);
+        assert_eq!(cache.entry("key1").or_insert(1), 1);
+        assert_eq!(cache.entry("key1").or_insert(2), 1);
+        assert_eq!(cache.entry("key2").or_insert_with_key(|key| key.len() as u64), 4);
+        assert_eq!(cache.entry("key3").or_default(), 0);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&"key1"), None);
+    }
+
+    #[test]
+    fn and_modify() {
+        let cache: LRUCache<&str, u64> = LRUCache::new(2);
+        for _ in 0..3 {
+            cache.entry("count").and_modify(|count| *count += 1).or_insert(1);
+        }
+        assert_eq!(cache.get(&"count"), Some(3));
+
+        match cache.entry("count") {
+            Entry::Occupied(mut entry) => {
+                assert_eq!(*entry.get(), 3);
+                assert_eq!(entry.insert(10), 3);
+                assert_eq!(entry.remove(), 10);
+            },
+            Entry::Vacant(_) => panic!("count should be present")
+        }
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn and_modify_too_heavy() {
+        let cache: LRUCache<&str, String> = LRUCache::with_weigher(4, |_, value: &String| {
+            value.len() as u64
+        });
+        cache.put("key1", "abc".to_string());
+
+        let entry = cache.entry("key1").and_modify(|value| value.push_str("de"));
+        assert!(matches!(entry, Entry::Vacant(_)));
+        drop(entry);
+        assert!(cache.is_empty());
+    }
+}
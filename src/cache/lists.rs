@@ -0,0 +1,95 @@
+use std::iter::Chain;
+use std::sync::Arc;
+
+use super::{CacheValue, Priority};
+use super::slab::{Iter as SlabIter, SlabList};
+
+type ListIter<'a, K, V> = SlabIter<'a, Arc<CacheValue<K, V>>>;
+
+/// An iterator over the values of `LruLists`, created by `LruLists::iter`.
+pub(super) type Iter<'a, K, V> =
+    Chain<Chain<ListIter<'a, K, V>, ListIter<'a, K, V>>, ListIter<'a, K, V>>;
+
+/// LruLists orders the values of a cache for eviction, with one LRU list per `Priority`.
+///
+/// The lists behave as a single list, ordered by priority and then by recency: the back of the
+/// combined list is the least recently used value of the lowest priority that has any values.
+/// Each value is found in its list by its priority and index.
+pub(super) struct LruLists<K, V> {
+    /// Indexed by priority, lowest first.
+    lists: [SlabList<Arc<CacheValue<K, V>>>; 3]
+}
+
+impl <K, V> LruLists<K, V> {
+    /// Create empty lists, with room for `capacity` values of `Priority::Normal`.
+    pub(super) fn with_capacity(capacity: usize) -> LruLists<K, V> {
+        LruLists {
+            lists: [
+                SlabList::with_capacity(0),
+                SlabList::with_capacity(capacity),
+                SlabList::with_capacity(0)
+            ]
+        }
+    }
+
+    /// The index that the next value of `priority` will be pushed at.
+    pub(super) fn vacant_index(&self, priority: Priority) -> usize {
+        self.lists[priority as usize].vacant_index()
+    }
+
+    /// Push `cache_value` to the most recently used end of its priority's list.
+    pub(super) fn push_front(&mut self, cache_value: Arc<CacheValue<K, V>>) {
+        let (priority, index) = (cache_value.priority, cache_value.index);
+        let pushed = self.lists[priority as usize].push_front(cache_value);
+        debug_assert_eq!(pushed, index, "Value must be pushed at its vacant index");
+    }
+
+    /// Push `cache_value` to the least recently used end of its priority's list.
+    pub(super) fn push_back(&mut self, cache_value: Arc<CacheValue<K, V>>) {
+        let (priority, index) = (cache_value.priority, cache_value.index);
+        let pushed = self.lists[priority as usize].push_back(cache_value);
+        debug_assert_eq!(pushed, index, "Value must be pushed at its vacant index");
+    }
+
+    pub(super) fn remove(&mut self, cache_value: &CacheValue<K, V>) -> Arc<CacheValue<K, V>> {
+        self.lists[cache_value.priority as usize].remove(cache_value.index)
+    }
+
+    /// Move `cache_value` to the most recently used end of its priority's list.
+    pub(super) fn move_to_front(&mut self, cache_value: &CacheValue<K, V>) {
+        self.lists[cache_value.priority as usize].move_to_front(cache_value.index);
+    }
+
+    /// The value which would be evicted first by recency.
+    pub(super) fn back(&self) -> Option<&Arc<CacheValue<K, V>>> {
+        self.lists.iter().find_map(|list| list.back())
+    }
+
+    pub(super) fn pop_back(&mut self) -> Option<Arc<CacheValue<K, V>>> {
+        self.lists.iter_mut().find_map(|list| list.pop_back())
+    }
+
+    /// Iterate over the values from the most recently used value of the highest priority to the
+    /// least recently used value of the lowest.
+    pub(super) fn iter(&self) -> Iter<'_, K, V> {
+        let [ref low, ref normal, ref high] = self.lists;
+        high.iter().chain(normal.iter()).chain(low.iter())
+    }
+
+    pub(super) fn clear(&mut self) {
+        for list in self.lists.iter_mut() {
+            list.clear();
+        }
+    }
+
+    /// The bytes allocated for the lists, not counting the values they point to.
+    pub(super) fn allocated_bytes(&self) -> usize {
+        self.lists.iter().map(SlabList::allocated_bytes).sum()
+    }
+
+    pub(super) fn shrink_to_fit(&mut self) {
+        for list in self.lists.iter_mut() {
+            list.shrink_to_fit();
+        }
+    }
+}
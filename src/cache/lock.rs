@@ -0,0 +1,315 @@
+use std::hint;
+use std::ops::{Deref, DerefMut};
+#[cfg(feature = "parking_lot")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LockResult, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard,
+                RwLockWriteGuard, TryLockError};
+use std::thread;
+
+use super::LockStrategy;
+
+/// How many times a `LockStrategy::Spin` lock spins before yielding its time slice.
+const SPINS_BEFORE_YIELD: u32 = 64;
+
+/// State which can be made consistent again after a thread panicked while modifying it, leaving
+/// its lock poisoned.
+pub(crate) trait Recover {
+    /// Restore the invariants of `self`, which the panic may have broken.
+    fn recover(&mut self);
+}
+
+/// Lock protects the state of a cache with the kind of lock chosen by its `LockStrategy`.
+///
+/// Rather than propagating poisoning, the first thread to lock it after another thread panicked
+/// while holding it calls `Recover::recover` on the state and clears the poison.
+pub(crate) enum Lock<T> {
+    Mutex(Mutex<T>),
+    /// A `parking_lot` mutex, with whether it is poisoned, since `parking_lot` does not track it.
+    #[cfg(feature = "parking_lot")]
+    ParkingLot(parking_lot::Mutex<T>, AtomicBool),
+    RwLock(RwLock<T>),
+    /// A mutex acquired by spinning on `try_lock`, so that waiting threads never sleep.
+    Spin(Mutex<T>)
+}
+
+/// An exclusive lock of a `Lock`.
+pub(crate) enum LockGuard<'a, T> {
+    Mutex(MutexGuard<'a, T>),
+    #[cfg(feature = "parking_lot")]
+    ParkingLot(ParkingLotGuard<'a, T>),
+    RwLock(RwLockWriteGuard<'a, T>)
+}
+
+/// An exclusive lock of a `Lock::ParkingLot`, which poisons it if the thread panics while
+/// holding it, as the standard library's locks do.
+#[cfg(feature = "parking_lot")]
+pub(crate) struct ParkingLotGuard<'a, T> {
+    guard: parking_lot::MutexGuard<'a, T>,
+    poisoned: &'a AtomicBool,
+    /// Whether the thread was already panicking when it locked, in which case it does not poison
+    /// the lock.
+    panicking: bool
+}
+
+/// A lock of a `Lock` which permits only reading: shared with other readers if it is a
+/// `RwLock`, and otherwise exclusive.
+pub(crate) enum ReadGuard<'a, T> {
+    Shared(RwLockReadGuard<'a, T>),
+    Exclusive(LockGuard<'a, T>)
+}
+
+impl <T> Lock<T> {
+    pub(crate) fn new(strategy: LockStrategy, value: T) -> Lock<T> {
+        match strategy {
+            LockStrategy::Mutex => Lock::Mutex(Mutex::new(value)),
+            #[cfg(feature = "parking_lot")]
+            LockStrategy::ParkingLot => {
+                Lock::ParkingLot(parking_lot::Mutex::new(value), AtomicBool::new(false))
+            },
+            LockStrategy::RwLock => Lock::RwLock(RwLock::new(value)),
+            LockStrategy::Spin => Lock::Spin(Mutex::new(value))
+        }
+    }
+
+    pub(crate) fn strategy(&self) -> LockStrategy {
+        match self {
+            Lock::Mutex(_) => LockStrategy::Mutex,
+            #[cfg(feature = "parking_lot")]
+            Lock::ParkingLot(..) => LockStrategy::ParkingLot,
+            Lock::RwLock(_) => LockStrategy::RwLock,
+            Lock::Spin(_) => LockStrategy::Spin
+        }
+    }
+
+    /// Lock for reading, shared with other readers, if `self` is a `RwLock`.  Otherwise, or if a
+    /// thread panicked while holding the lock (so that the state must be recovered first), return
+    /// `None`.
+    pub(crate) fn read_shared(&self) -> Option<RwLockReadGuard<'_, T>> {
+        match self {
+            Lock::RwLock(rw_lock) => rw_lock.read().ok(),
+            _ => None
+        }
+    }
+}
+
+impl <T: Recover> Lock<T> {
+    /// Lock exclusively, recovering the state if a thread panicked while holding the lock.
+    pub(crate) fn lock(&self) -> LockGuard<'_, T> {
+        match self {
+            Lock::Mutex(mutex) => {
+                LockGuard::Mutex(recovered(mutex.lock(), || mutex.clear_poison()))
+            },
+            #[cfg(feature = "parking_lot")]
+            Lock::ParkingLot(mutex, poisoned) => parking_lot_recovered(mutex.lock(), poisoned),
+            Lock::RwLock(rw_lock) => {
+                LockGuard::RwLock(recovered(rw_lock.write(), || rw_lock.clear_poison()))
+            },
+            Lock::Spin(mutex) => {
+                let mut spins = 0;
+                loop {
+                    match mutex.try_lock() {
+                        Ok(guard) => return LockGuard::Mutex(guard),
+                        Err(TryLockError::Poisoned(poisoned)) => {
+                            return LockGuard::Mutex(recovered(Err(poisoned),
+                                                              || mutex.clear_poison()));
+                        },
+                        Err(TryLockError::WouldBlock) => {}
+                    }
+
+                    spins += 1;
+                    if spins < SPINS_BEFORE_YIELD {
+                        hint::spin_loop();
+                    } else {
+                        spins = 0;
+                        thread::yield_now();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Lock exclusively if no other thread holds the lock, without waiting, recovering the state
+    /// if a thread panicked while holding the lock.
+    pub(crate) fn try_lock(&self) -> Option<LockGuard<'_, T>> {
+        fn try_recovered<G, T, F>(result: Result<G, TryLockError<G>>, clear_poison: F) -> Option<G>
+            where G: DerefMut<Target = T>, T: Recover, F: FnOnce() {
+            match result {
+                Ok(guard) => Some(guard),
+                Err(TryLockError::WouldBlock) => None,
+                Err(TryLockError::Poisoned(poisoned)) => {
+                    Some(recovered(Err(poisoned), clear_poison))
+                }
+            }
+        }
+
+        match self {
+            Lock::Mutex(mutex) | Lock::Spin(mutex) => {
+                try_recovered(mutex.try_lock(), || mutex.clear_poison()).map(LockGuard::Mutex)
+            },
+            #[cfg(feature = "parking_lot")]
+            Lock::ParkingLot(mutex, poisoned) => {
+                mutex.try_lock().map(|guard| parking_lot_recovered(guard, poisoned))
+            },
+            Lock::RwLock(rw_lock) => {
+                try_recovered(rw_lock.try_write(), || rw_lock.clear_poison())
+                    .map(LockGuard::RwLock)
+            }
+        }
+    }
+
+    /// Lock for reading, shared with other readers if `self` is a `RwLock`.
+    pub(crate) fn read(&self) -> ReadGuard<'_, T> {
+        match self.read_shared() {
+            Some(guard) => ReadGuard::Shared(guard),
+            None => ReadGuard::Exclusive(self.lock())
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        let (value, poisoned) = match self {
+            Lock::Mutex(mutex) | Lock::Spin(mutex) => {
+                let poisoned = mutex.is_poisoned();
+                mutex.clear_poison();
+                (mutex.get_mut().unwrap_or_else(PoisonError::into_inner), poisoned)
+            },
+            #[cfg(feature = "parking_lot")]
+            Lock::ParkingLot(mutex, poisoned) => {
+                (mutex.get_mut(), std::mem::replace(poisoned.get_mut(), false))
+            },
+            Lock::RwLock(rw_lock) => {
+                let poisoned = rw_lock.is_poisoned();
+                rw_lock.clear_poison();
+                (rw_lock.get_mut().unwrap_or_else(PoisonError::into_inner), poisoned)
+            }
+        };
+        if poisoned {
+            value.recover();
+        }
+        value
+    }
+}
+
+/// The guard of `result`, recovering the state it protects and clearing the poison with
+/// `clear_poison` if a thread panicked while holding the lock.
+fn recovered<G, T, F>(result: LockResult<G>, clear_poison: F) -> G
+    where G: DerefMut<Target = T>, T: Recover, F: FnOnce() {
+    result.unwrap_or_else(|poisoned| {
+        let mut guard = poisoned.into_inner();
+        guard.recover();
+        clear_poison();
+        guard
+    })
+}
+
+/// The guard of a `Lock::ParkingLot`, recovering the state it protects and clearing `poisoned`
+/// if a thread panicked while holding the lock.
+#[cfg(feature = "parking_lot")]
+fn parking_lot_recovered<'a, T: Recover>(mut guard: parking_lot::MutexGuard<'a, T>,
+                                         poisoned: &'a AtomicBool) -> LockGuard<'a, T> {
+    // The mutex orders these with the stores of the threads which held it before.
+    if poisoned.load(Ordering::Relaxed) {
+        guard.recover();
+        poisoned.store(false, Ordering::Relaxed);
+    }
+    LockGuard::ParkingLot(ParkingLotGuard { guard, poisoned, panicking: thread::panicking() })
+}
+
+#[cfg(feature = "parking_lot")]
+impl <'a, T> Drop for ParkingLotGuard<'a, T> {
+    fn drop(&mut self) {
+        if !self.panicking && thread::panicking() {
+            self.poisoned.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl <'a, T> Deref for LockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            LockGuard::Mutex(guard) => guard,
+            #[cfg(feature = "parking_lot")]
+            LockGuard::ParkingLot(guard) => &guard.guard,
+            LockGuard::RwLock(guard) => guard
+        }
+    }
+}
+
+impl <'a, T> DerefMut for LockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        match self {
+            LockGuard::Mutex(guard) => guard,
+            #[cfg(feature = "parking_lot")]
+            LockGuard::ParkingLot(guard) => &mut guard.guard,
+            LockGuard::RwLock(guard) => guard
+        }
+    }
+}
+
+impl <'a, T> Deref for ReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            ReadGuard::Shared(guard) => guard,
+            ReadGuard::Exclusive(guard) => guard
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Recover for i32 {
+        fn recover(&mut self) {
+            *self = 0;
+        }
+    }
+
+    #[test]
+    fn strategies() {
+        let strategies = vec![LockStrategy::Mutex, LockStrategy::RwLock, LockStrategy::Spin];
+        #[cfg(feature = "parking_lot")]
+        let strategies = [strategies, vec![LockStrategy::ParkingLot]].concat();
+
+        for strategy in strategies {
+            let mut lock = Lock::new(strategy, 1);
+            assert_eq!(lock.strategy(), strategy);
+
+            let mut guard = lock.lock();
+            *guard += 1;
+            assert!(lock.try_lock().is_none());
+            drop(guard);
+
+            {
+                let (first, second) = (lock.read(), lock.read_shared());
+                assert_eq!(*first, 2);
+                assert_eq!(second.is_some(), strategy == LockStrategy::RwLock);
+            }
+            assert_eq!(*lock.get_mut(), 2);
+        }
+    }
+
+    #[test]
+    fn recover() {
+        let strategies = vec![LockStrategy::Mutex, LockStrategy::RwLock, LockStrategy::Spin];
+        #[cfg(feature = "parking_lot")]
+        let strategies = [strategies, vec![LockStrategy::ParkingLot]].concat();
+
+        for strategy in strategies {
+            let lock = Lock::new(strategy, 1);
+            thread::scope(|scope| {
+                scope.spawn(|| {
+                    let _guard = lock.lock();
+                    panic!("poison the lock");
+                }).join().unwrap_err();
+            });
+
+            assert!(lock.read_shared().is_none());
+            assert_eq!(*lock.lock(), 0);
+            assert_eq!(lock.read_shared().is_some(), strategy == LockStrategy::RwLock);
+        }
+    }
+}
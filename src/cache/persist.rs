@@ -0,0 +1,178 @@
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::clock::Timestamp;
+use crate::policy::EvictionPolicy;
+
+use super::{Cache, CacheValue, EntryOptions, Idle, Priority};
+
+/// A value written by `Cache::serialize_snapshot`.  Times are kept as the time left from when
+/// the snapshot was taken, since timestamps are only meaningful to the clock which made them.
+#[derive(Serialize, Deserialize)]
+struct SavedValue<K, V> {
+    key: K,
+    value: V,
+    /// The time left until the value's time-to-live elapses.
+    ttl: Option<Duration>,
+    /// The value's time-to-idle, and the time left until it elapses unless the value is read.
+    tti: Option<(Duration, Duration)>,
+    /// The time left until the value's maximum lifetime ends.
+    lifetime: Option<Duration>,
+    weight: u64,
+    priority: Priority
+}
+
+impl <K, V, P, S> Cache<K, V, P, S>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    /// Write the values in the cache with `serializer`, from least to most recently used, with
+    /// their weights, priorities and the time left until each expires, so that a restarted
+    /// service can restore them with `deserialize_snapshot` instead of starting cold.
+    ///
+    /// Requires the `serde` feature.  Like `snapshot`, the values are those present when it is
+    /// called, and the cache is not locked while they are written.  Expired values are left out,
+    /// and invalidation tokens are not written, so values put with one are restored without it.
+    ///
+    /// ```
+    /// # #[cfg(feature = "json")] {
+    /// use cache::cache::LRUCache;
+    ///
+    /// let cache = LRUCache::new(16);
+    /// cache.put("a".to_string(), 1);
+    /// cache.put("b".to_string(), 2);
+    /// let mut saved = Vec::new();
+    /// cache.serialize_snapshot(&mut serde_json::Serializer::new(&mut saved)).unwrap();
+    ///
+    /// let restored: LRUCache<String, u64> = LRUCache::new(16);
+    /// let mut deserializer = serde_json::Deserializer::from_slice(&saved);
+    /// assert_eq!(restored.deserialize_snapshot(&mut deserializer).unwrap(), 2);
+    /// assert_eq!(restored.peek_mru(), Some(("b".to_string(), 2)));
+    /// # }
+    /// ```
+    pub fn serialize_snapshot<Ser>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error>
+        where Ser: Serializer, K: Serialize, V: Serialize {
+        let values: Vec<Arc<CacheValue<K, V>>> = {
+            let data = self.data.read();
+            data.lru_list.iter().rev()
+                .filter(|cache_value| !cache_value.is_expired(&*self.clock))
+                .map(Arc::clone)
+                .collect()
+        };
+
+        let now = self.clock.now();
+        let time_left = |deadline: Timestamp| deadline.saturating_duration_since(now);
+        serializer.collect_seq(values.iter().map(|cache_value| {
+            let idle = cache_value.options().idle;
+            SavedValue {
+                key: &cache_value.key,
+                value: &cache_value.value,
+                ttl: cache_value.expires_at.map(time_left),
+                tti: idle.map(|idle| (idle.timeout, time_left(idle.put_at + idle.timeout))),
+                lifetime: cache_value.lifetime_ends_at.map(time_left),
+                weight: cache_value.weight,
+                priority: cache_value.priority
+            }
+        }))
+    }
+
+    /// Put the values written by `serialize_snapshot`, read with `deserializer`, in the same
+    /// order, so that the most recently used value of the snapshot becomes the most recently
+    /// used value of the cache.
+    ///
+    /// Requires the `serde` feature.  Each value keeps the time it had left until it expires, and
+    /// its priority and weight: weights are not recomputed, so the cache should weigh values as
+    /// the one serialized did.  The cache's own time-to-live and other defaults do not apply.
+    /// Values which do not fit evict others, as with `put`, and nothing is put if the snapshot
+    /// cannot be read.
+    ///
+    /// # Returns
+    ///
+    /// The number of values read from the snapshot.
+    pub fn deserialize_snapshot<'de, D>(&self, deserializer: D) -> Result<usize, D::Error>
+        where D: Deserializer<'de>, K: Deserialize<'de>, V: Deserialize<'de> {
+        let saved: Vec<SavedValue<K, V>> = Vec::deserialize(deserializer)?;
+        let count = saved.len();
+
+        let now = self.clock.now();
+        let mut data = self.lock();
+        for saved_value in saved {
+            let idle = saved_value.tti.map(|(timeout, time_left)| {
+                // Start the time-to-idle early enough that it has only `time_left` to run.
+                let since_origin = (now + time_left).since_origin().saturating_sub(timeout);
+                Idle { timeout, put_at: Timestamp::from_origin(since_origin) }
+            });
+            let options = EntryOptions {
+                put_at: now,
+                expires_at: saved_value.ttl.map(|ttl| now + ttl),
+                idle,
+                lifetime_ends_at: saved_value.lifetime.map(|lifetime| now + lifetime),
+                priority: saved_value.priority,
+                ..EntryOptions::default()
+            };
+            data.insert(saved_value.key, saved_value.value, options, saved_value.weight);
+        }
+        self.unlock(data);
+        self.notify_inserted();
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::cache::{LRUCache, Priority};
+    use crate::testing::MockClock;
+
+    #[test]
+    fn snapshot_round_trip() {
+        let clock = Arc::new(MockClock::new());
+        let mut cache = LRUCache::with_weigher(100, |_, value: &String| value.len() as u64);
+        cache.set_clock(clock.clone());
+        cache.put_with_ttl(1, "ttl".to_string(), Duration::from_secs(10));
+        cache.put_with_priority(2, "high".to_string(), Priority::High);
+        cache.set_time_to_idle(Some(Duration::from_secs(8)));
+        cache.put(4, "idle".to_string());
+        clock.advance(Duration::from_secs(4));
+        cache.get(&4);
+
+        let saved = serde_json::to_vec(&SerializeWith(&cache)).unwrap();
+
+        // The restored values keep their order, weights and the time they had left.
+        let restored_clock = Arc::new(MockClock::new());
+        restored_clock.advance(Duration::from_secs(100));
+        let mut restored = LRUCache::with_weigher(100, |_, _: &String| 1);
+        restored.set_clock(restored_clock.clone());
+        let mut deserializer = serde_json::Deserializer::from_slice(&saved);
+        assert_eq!(restored.deserialize_snapshot(&mut deserializer).unwrap(), 3);
+        let keys: Vec<u64> = restored.iter_lru_order().map(|value| *value.key()).collect();
+        assert_eq!(keys, vec![1, 4, 2]);
+        assert_eq!(restored.weight(), cache.weight());
+
+        restored_clock.advance(Duration::from_secs(6));
+        assert_eq!(restored.peek(&1), None);
+        assert_eq!(restored.peek(&4), Some("idle".to_string()));
+        restored_clock.advance(Duration::from_secs(3));
+        assert_eq!(restored.peek(&4), None);
+        assert_eq!(restored.peek(&2), Some("high".to_string()));
+
+        // A snapshot which cannot be read puts nothing.
+        let mut deserializer = serde_json::Deserializer::from_slice(b"[{\"key\": 5}]");
+        assert!(restored.deserialize_snapshot(&mut deserializer).is_err());
+        let keys: Vec<u64> = restored.iter_lru_order().map(|value| *value.key()).collect();
+        assert_eq!(keys, vec![2]);
+    }
+
+    /// Serializes a cache with `serialize_snapshot`, for `serde_json::to_vec`.
+    struct SerializeWith<'a>(&'a LRUCache<u64, String>);
+
+    impl serde::Serialize for SerializeWith<'_> {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            self.0.serialize_snapshot(serializer)
+        }
+    }
+}
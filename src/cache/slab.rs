@@ -0,0 +1,303 @@
+use std::mem;
+
+/// The index of no node.
+const NIL: usize = usize::MAX;
+
+struct Node<T> {
+    /// `None` if the node is vacant.
+    value: Option<T>,
+    prev: usize,
+    /// The next occupied node, or the next vacant node if this node is vacant.
+    next: usize
+}
+
+/// SlabList is a doubly-linked list whose nodes are allocated from a single buffer (the slab) and
+/// refer to each other by index.
+///
+/// Each value keeps the index it was pushed at until it is removed, so any value can be moved or
+/// removed in O(1) time given its index.  The nodes of removed values are reused by later pushes,
+/// so a list which stays the same length does not allocate.
+pub(crate) struct SlabList<T> {
+    nodes: Vec<Node<T>>,
+    head: usize,
+    tail: usize,
+    /// The most recently vacated node.  Vacant nodes are chained through `next`.
+    free: usize,
+    len: usize
+}
+
+impl <T> SlabList<T> {
+    /// Create an empty list with room for `capacity` values.
+    pub(crate) fn with_capacity(capacity: usize) -> SlabList<T> {
+        SlabList {
+            nodes: Vec::with_capacity(capacity),
+            head: NIL,
+            tail: NIL,
+            free: NIL,
+            len: 0
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The index that the next push will store its value at.
+    pub(crate) fn vacant_index(&self) -> usize {
+        match self.free {
+            NIL => self.nodes.len(),
+            free => free
+        }
+    }
+
+    /// Push `value` to the front of the list, returning its index.
+    pub(crate) fn push_front(&mut self, value: T) -> usize {
+        let index = self.occupy(value);
+        self.link_front(index);
+        index
+    }
+
+    /// Push `value` to the back of the list, returning its index.
+    pub(crate) fn push_back(&mut self, value: T) -> usize {
+        let index = self.occupy(value);
+        self.link_back(index);
+        index
+    }
+
+    /// Store `value` in a vacant node, returning its index.  The node is not yet linked.
+    fn occupy(&mut self, value: T) -> usize {
+        let node = Node {
+            value: Some(value),
+            prev: NIL,
+            next: NIL
+        };
+
+        let index = match self.free {
+            NIL => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            },
+            free => {
+                self.free = self.nodes[free].next;
+                self.nodes[free] = node;
+                free
+            }
+        };
+
+        self.len += 1;
+        index
+    }
+
+    /// Remove and return the value at `index`.
+    ///
+    /// # Panics
+    ///
+    /// If there is no value at `index`.
+    pub(crate) fn remove(&mut self, index: usize) -> T {
+        self.unlink(index);
+
+        let node = &mut self.nodes[index];
+        let value = node.value.take().expect("Node must be occupied");
+        node.next = self.free;
+        self.free = index;
+        self.len -= 1;
+        value
+    }
+
+    /// Move the value at `index` to the front of the list.
+    pub(crate) fn move_to_front(&mut self, index: usize) {
+        if self.head != index {
+            self.unlink(index);
+            self.link_front(index);
+        }
+    }
+
+    pub(crate) fn back(&self) -> Option<&T> {
+        match self.tail {
+            NIL => None,
+            tail => self.nodes[tail].value.as_ref()
+        }
+    }
+
+    pub(crate) fn pop_back(&mut self) -> Option<T> {
+        match self.tail {
+            NIL => None,
+            tail => Some(self.remove(tail))
+        }
+    }
+
+    /// Iterate over the values from front to back.
+    pub(crate) fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            nodes: &self.nodes,
+            head: self.head,
+            tail: self.tail,
+            len: self.len
+        }
+    }
+
+    /// Remove all values, keeping the slab's memory for reuse.
+    pub(crate) fn clear(&mut self) {
+        self.nodes.clear();
+        self.head = NIL;
+        self.tail = NIL;
+        self.free = NIL;
+        self.len = 0;
+    }
+
+    /// The bytes allocated for the slab.
+    pub(crate) fn allocated_bytes(&self) -> usize {
+        self.nodes.capacity() * mem::size_of::<Node<T>>()
+    }
+
+    /// Release vacant nodes at the end of the slab, and any unused memory.
+    ///
+    /// Occupied nodes are never moved, so vacant nodes between them are kept.
+    pub(crate) fn shrink_to_fit(&mut self) {
+        while let Some(&Node { value: None, .. }) = self.nodes.last() {
+            self.nodes.pop();
+        }
+        self.nodes.shrink_to_fit();
+
+        // Rebuild the free list without the released nodes.
+        self.free = NIL;
+        for index in (0..self.nodes.len()).rev() {
+            if self.nodes[index].value.is_none() {
+                self.nodes[index].next = self.free;
+                self.free = index;
+            }
+        }
+    }
+
+    /// Make the (detached) node at `index` the head of the list.
+    fn link_front(&mut self, index: usize) {
+        self.nodes[index].prev = NIL;
+        self.nodes[index].next = self.head;
+        match self.head {
+            NIL => self.tail = index,
+            head => self.nodes[head].prev = index
+        }
+        self.head = index;
+    }
+
+    /// Make the (detached) node at `index` the tail of the list.
+    fn link_back(&mut self, index: usize) {
+        self.nodes[index].prev = self.tail;
+        self.nodes[index].next = NIL;
+        match self.tail {
+            NIL => self.head = index,
+            tail => self.nodes[tail].next = index
+        }
+        self.tail = index;
+    }
+
+    /// Detach the node at `index` from its neighbours.
+    fn unlink(&mut self, index: usize) {
+        let (prev, next) = (self.nodes[index].prev, self.nodes[index].next);
+        match prev {
+            NIL => self.head = next,
+            prev => self.nodes[prev].next = next
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.nodes[next].prev = prev
+        }
+    }
+}
+
+/// An iterator over the values of a `SlabList`, created by `SlabList::iter`.
+pub(crate) struct Iter<'a, T> {
+    nodes: &'a [Node<T>],
+    head: usize,
+    tail: usize,
+    /// The number of values not yet yielded from either end.
+    len: usize
+}
+
+impl <'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let node = &self.nodes[self.head];
+        self.head = node.next;
+        self.len -= 1;
+        node.value.as_ref()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl <'a, T> DoubleEndedIterator for Iter<'a, T> {
+    fn next_back(&mut self) -> Option<&'a T> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let node = &self.nodes[self.tail];
+        self.tail = node.prev;
+        self.len -= 1;
+        node.value.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(list: &SlabList<u64>) -> Vec<u64> {
+        list.iter().cloned().collect()
+    }
+
+    #[test]
+    fn push_remove() {
+        let mut list = SlabList::with_capacity(4);
+        let indexes: Vec<usize> = (0..4).map(|value| list.push_front(value)).collect();
+        assert_eq!(values(&list), vec![3, 2, 1, 0]);
+        assert_eq!(list.iter().rev().cloned().collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+
+        list.move_to_front(indexes[1]);
+        assert_eq!(values(&list), vec![1, 3, 2, 0]);
+        assert_eq!(list.back(), Some(&0));
+
+        assert_eq!(list.remove(indexes[2]), 2);
+        assert_eq!(list.pop_back(), Some(0));
+        assert_eq!(values(&list), vec![1, 3]);
+        assert_eq!(list.len(), 2);
+
+        // Vacated nodes are reused.
+        assert_eq!(list.vacant_index(), indexes[0]);
+        assert_eq!(list.push_front(4), indexes[0]);
+        assert_eq!(list.push_front(5), indexes[2]);
+        assert_eq!(list.vacant_index(), 4);
+        assert_eq!(values(&list), vec![5, 4, 1, 3]);
+
+        assert_eq!(list.push_back(6), 4);
+        assert_eq!(list.back(), Some(&6));
+        assert_eq!(values(&list), vec![5, 4, 1, 3, 6]);
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        let mut list = SlabList::with_capacity(0);
+        let indexes: Vec<usize> = (0..4).map(|value| list.push_front(value)).collect();
+        list.remove(indexes[0]);
+        list.remove(indexes[3]);
+        list.remove(indexes[2]);
+
+        list.shrink_to_fit();
+        assert_eq!(list.nodes.len(), 2);
+        assert_eq!(list.vacant_index(), indexes[0]);
+        assert_eq!(values(&list), vec![1]);
+
+        list.clear();
+        assert_eq!(list.pop_back(), None);
+        assert_eq!(list.vacant_index(), 0);
+    }
+}
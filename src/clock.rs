@@ -0,0 +1,110 @@
+use std::ops::{Add, AddAssign};
+use std::time::Duration;
+
+/// Timestamp is a point in time read from a `Clock`, as the time since an origin fixed by the
+/// clock.
+///
+/// Unlike `std::time::Instant`, timestamps can be created on platforms with no system clock, such
+/// as `wasm32-unknown-unknown`, and compared only with timestamps from the same clock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(Duration);
+
+impl Timestamp {
+    /// The timestamp `since_origin` after the clock's origin.
+    pub fn from_origin(since_origin: Duration) -> Timestamp {
+        Timestamp(since_origin)
+    }
+
+    /// The time since the clock's origin.
+    pub fn since_origin(self) -> Duration {
+        self.0
+    }
+
+    /// The time from `earlier` to `self`, or zero if `earlier` is later.
+    pub fn saturating_duration_since(self, earlier: Timestamp) -> Duration {
+        self.0.checked_sub(earlier.0).unwrap_or_default()
+    }
+}
+
+impl Add<Duration> for Timestamp {
+    type Output = Timestamp;
+
+    fn add(self, duration: Duration) -> Timestamp {
+        Timestamp(self.0 + duration)
+    }
+}
+
+impl AddAssign<Duration> for Timestamp {
+    fn add_assign(&mut self, duration: Duration) {
+        self.0 += duration;
+    }
+}
+
+/// Clock provides the current time to the cache's expiration logic.
+///
+/// Abstracting over the clock allows tests to control the passage of time instead of sleeping.
+/// See `LRUCache::set_clock` and `testing::MockClock`.
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> Timestamp;
+}
+
+/// SystemClock reads the monotonic system clock, from an origin when it is first read.
+///
+/// On `wasm32` with the `wasm` feature it reads `performance.now()` instead (or `Date.now()`
+/// where there is no `performance`), since the standard library has no clock there.  Without the
+/// feature, reading it there panics, so caches there should not use time-to-live or other
+/// features which read the clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+/// Whether `SystemClock` can be read on this platform.
+pub(crate) const SYSTEM_CLOCK_READABLE: bool =
+    !cfg!(all(target_arch = "wasm32", not(feature = "wasm")));
+
+#[cfg(not(all(target_arch = "wasm32", feature = "wasm")))]
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        use std::sync::OnceLock;
+        use std::time::Instant;
+
+        static ORIGIN: OnceLock<Instant> = OnceLock::new();
+        Timestamp(ORIGIN.get_or_init(Instant::now).elapsed())
+    }
+}
+
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        use js_sys::{Date, Function, Reflect};
+        use wasm_bindgen::{JsCast, JsValue};
+
+        let performance = Reflect::get(&js_sys::global(), &JsValue::from_str("performance"));
+        let millis = performance.ok()
+            .filter(|performance| performance.is_object())
+            .and_then(|performance| {
+                let now = Reflect::get(&performance, &JsValue::from_str("now")).ok()?;
+                now.dyn_into::<Function>().ok()?.call0(&performance).ok()?.as_f64()
+            })
+            .unwrap_or_else(Date::now);
+        Timestamp(Duration::from_secs_f64(millis.max(0.0) / 1000.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamp() {
+        let start = Timestamp::from_origin(Duration::from_secs(1));
+        let later = start + Duration::from_millis(500);
+        assert!(later > start);
+        assert_eq!(later.saturating_duration_since(start), Duration::from_millis(500));
+        assert_eq!(start.saturating_duration_since(later), Duration::from_secs(0));
+        assert_eq!(later.since_origin(), Duration::from_millis(1500));
+
+        let (first, second) = (SystemClock.now(), SystemClock.now());
+        assert!(second >= first);
+    }
+}
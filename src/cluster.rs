@@ -0,0 +1,399 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::Write as _;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::cache::LRUCache;
+use crate::tiered::Encode;
+
+/// The number of points each node has on the hash ring.
+const VIRTUAL_NODES: usize = 160;
+
+/// The longest key the memcached protocol allows.
+const MAX_KEY_LEN: usize = 250;
+
+/// Time-to-lives longer than this many seconds are sent as absolute Unix times.
+const MAX_RELATIVE_EXPTIME: u64 = 30 * 24 * 60 * 60;
+
+/// How long to wait to connect to, write to or read from a node.
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long a node which failed a request is considered down.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A server in the cluster, with its connection if one is open.
+struct Node {
+    addr: SocketAddr,
+    connection: Mutex<Option<BufReader<TcpStream>>>,
+    /// When the node may next be tried, if it is down.
+    down_until: Mutex<Option<Instant>>
+}
+
+/// ClusterClient partitions keys across a cluster of memcached-protocol servers, such as
+/// `cache-server`, with a consistent hash ring.
+///
+/// Each server has many points on the ring, and a key belongs to the server with the first point
+/// at or after the key's hash.  Adding or removing a server only moves the keys between its
+/// points and their predecessors.
+///
+/// A server which fails a request is considered down for a second, and its keys are kept in a
+/// local `LRUCache` meanwhile.  Values put in the local cache are not copied to the server when
+/// it comes back.
+///
+/// # NB:
+///
+/// - Keys are sent to the servers as their encoding in hexadecimal.  Keys which encode to more
+///   than 125 bytes are only kept in the local cache.
+/// - Each server has one connection, shared by all threads.
+pub struct ClusterClient<K: Eq + Hash + Clone, V: Clone> {
+    nodes: Vec<Node>,
+    /// Points on the ring, and the index of the node each belongs to, sorted by point.
+    ring: Vec<(u64, usize)>,
+    fallback: LRUCache<K, V>
+}
+
+impl <K, V> ClusterClient<K, V>
+    where K: Eq + Hash + Clone + Encode, V: Clone + Encode {
+    /// Partition keys across the servers at `addrs`, keeping up to `fallback_capacity` values
+    /// locally while servers are down.
+    ///
+    /// Connections are opened as they are first needed.
+    pub fn new(addrs: Vec<SocketAddr>, fallback_capacity: usize) -> ClusterClient<K, V> {
+        let mut ring = Vec::with_capacity(addrs.len() * VIRTUAL_NODES);
+        for (index, addr) in addrs.iter().enumerate() {
+            for point in 0..VIRTUAL_NODES {
+                ring.push((hash(format!("{}-{}", addr, point).as_bytes()), index));
+            }
+        }
+        ring.sort_unstable();
+
+        let nodes = addrs.into_iter().map(|addr| Node {
+            addr,
+            connection: Mutex::new(None),
+            down_until: Mutex::new(None)
+        }).collect();
+
+        ClusterClient { nodes, ring, fallback: LRUCache::new(fallback_capacity) }
+    }
+
+    /// The local cache of values whose servers are down.
+    pub fn fallback(&self) -> &LRUCache<K, V> {
+        &self.fallback
+    }
+
+    /// The address of the server which `key` belongs to, or `None` if there are no servers.
+    pub fn node_for(&self, key: &K) -> Option<SocketAddr> {
+        self.node(&key.encode()).map(|node| node.addr)
+    }
+
+    /// Get the value for `key` from its server, or from the local cache if the server is down.
+    ///
+    /// A value which cannot be decoded is reported as missing.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let key_bytes = key.encode();
+        match self.request(&key_bytes, get) {
+            Some(data) => data.and_then(|data| V::decode(&data)),
+            None => self.fallback.get(key)
+        }
+    }
+
+    /// Put `value` for `key` on its server, or in the local cache if the server is down.
+    pub fn put(&self, key: K, value: V) {
+        self.store(key, value, None)
+    }
+
+    /// Put `value` for `key`, expiring it after `ttl`.  The server rounds `ttl` up to whole
+    /// seconds.
+    pub fn put_with_ttl(&self, key: K, value: V, ttl: Duration) {
+        self.store(key, value, Some(ttl))
+    }
+
+    /// Remove the value for `key` from its server and from the local cache.
+    ///
+    /// # Returns
+    ///
+    /// Whether there was a value to remove, on whichever of them was reachable.
+    pub fn remove(&self, key: &K) -> bool {
+        let removed_locally = self.fallback.remove(key).is_some();
+        let removed = self.request(&key.encode(), delete);
+        removed.unwrap_or(false) || removed_locally
+    }
+
+    fn store(&self, key: K, value: V, ttl: Option<Duration>) {
+        let data = value.encode();
+        let exptime = ttl.map_or(0, exptime);
+        let stored = self.request(&key.encode(), |connection, key| {
+            set(connection, key, &data, exptime)
+        });
+
+        // A stale local value would be read again if the server goes down.
+        match (stored, ttl) {
+            (Some(()), _) => {
+                self.fallback.remove(&key);
+            },
+            (None, Some(ttl)) => {
+                self.fallback.put_with_ttl(key, value, ttl);
+            },
+            (None, None) => {
+                self.fallback.put(key, value);
+            }
+        }
+    }
+
+    fn node(&self, key_bytes: &[u8]) -> Option<&Node> {
+        let point = hash(key_bytes);
+        let index = match self.ring.binary_search(&(point, 0)) {
+            Ok(index) | Err(index) => index
+        };
+        self.ring.get(index).or_else(|| self.ring.first())
+            .map(|&(_, node)| &self.nodes[node])
+    }
+
+    /// Make a request to the server for the key encoded as `key_bytes`, passing `send` the
+    /// connection and the protocol key.
+    ///
+    /// # Returns
+    ///
+    /// The response, or `None` if the server is down or the key is too long to send.
+    fn request<T, F>(&self, key_bytes: &[u8], send: F) -> Option<T>
+        where F: FnOnce(&mut BufReader<TcpStream>, &str) -> io::Result<T> {
+        let node = self.node(key_bytes)?;
+        let key = hex(key_bytes);
+        if key.len() > MAX_KEY_LEN {
+            return None;
+        }
+
+        let mut down_until = node.down_until.lock().unwrap();
+        match *down_until {
+            Some(until) if Instant::now() < until => return None,
+            _ => *down_until = None
+        }
+        drop(down_until);
+
+        let mut connection = node.connection.lock().unwrap();
+        let result = match *connection {
+            Some(ref mut connection) => send(connection, &key),
+            None => connect(node.addr).and_then(|mut opened| {
+                let result = send(&mut opened, &key);
+                *connection = Some(opened);
+                result
+            })
+        };
+
+        match result {
+            Ok(response) => Some(response),
+            Err(_) => {
+                // The connection may be part way through a response, so it cannot be reused.
+                *connection = None;
+                *node.down_until.lock().unwrap() = Some(Instant::now() + RETRY_INTERVAL);
+                None
+            }
+        }
+    }
+}
+
+fn connect(addr: SocketAddr) -> io::Result<BufReader<TcpStream>> {
+    let stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    Ok(BufReader::new(stream))
+}
+
+/// `get <key>`, returning the value's data if there is one.
+fn get(connection: &mut BufReader<TcpStream>, key: &str) -> io::Result<Option<Vec<u8>>> {
+    connection.get_mut().write_all(format!("get {}\r\n", key).as_bytes())?;
+
+    let line = read_line(connection)?;
+    if line == "END" {
+        return Ok(None);
+    }
+    let len = match *line.split(' ').collect::<Vec<_>>() {
+        ["VALUE", value_key, _, len] if value_key == key => len.parse::<usize>().ok(),
+        _ => None
+    }.ok_or_else(|| unexpected(&line))?;
+
+    let mut data = vec![0; len + 2];
+    connection.read_exact(&mut data)?;
+    data.truncate(len);
+    let line = read_line(connection)?;
+    if line != "END" {
+        return Err(unexpected(&line));
+    }
+    Ok(Some(data))
+}
+
+/// `set <key> 0 <exptime> <bytes>`, followed by `data`.
+fn set(connection: &mut BufReader<TcpStream>, key: &str, data: &[u8], exptime: u64)
+    -> io::Result<()> {
+    let mut request = format!("set {} 0 {} {}\r\n", key, exptime, data.len()).into_bytes();
+    request.extend_from_slice(data);
+    request.extend_from_slice(b"\r\n");
+    connection.get_mut().write_all(&request)?;
+
+    match read_line(connection)?.as_str() {
+        "STORED" => Ok(()),
+        line => Err(unexpected(line))
+    }
+}
+
+/// `delete <key>`, returning whether there was a value to delete.
+fn delete(connection: &mut BufReader<TcpStream>, key: &str) -> io::Result<bool> {
+    connection.get_mut().write_all(format!("delete {}\r\n", key).as_bytes())?;
+
+    match read_line(connection)?.as_str() {
+        "DELETED" => Ok(true),
+        "NOT_FOUND" => Ok(false),
+        line => Err(unexpected(line))
+    }
+}
+
+/// Read a response line, without its line ending.
+fn read_line(connection: &mut BufReader<TcpStream>) -> io::Result<String> {
+    let mut line = String::new();
+    if connection.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(line.trim_end_matches("\r\n").to_string())
+}
+
+fn unexpected(line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("unexpected response: {}", line))
+}
+
+/// The memcached expiration time for `ttl`: relative seconds, or an absolute Unix time if it is
+/// too long to be relative.
+fn exptime(ttl: Duration) -> u64 {
+    // Zero would never expire, so round up.
+    let secs = ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0);
+    if secs <= MAX_RELATIVE_EXPTIME {
+        return secs.max(1);
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_secs() + secs
+}
+
+fn hash(bytes: &[u8]) -> u64 {
+    // `DefaultHasher::new` always hashes the same bytes to the same value, so every client
+    // builds the same ring.
+    let mut hasher = DefaultHasher::new();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread;
+
+    /// Serve `get`, `set` and `delete` on one connection, without expiration.
+    fn server() -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let stream = listener.accept().unwrap().0;
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut values: HashMap<String, String> = HashMap::new();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let words: Vec<String> = line.split_whitespace().map(String::from).collect();
+                let response = match words[0].as_str() {
+                    "get" => match values.get(&words[1]) {
+                        Some(data) => format!("VALUE {} 0 {}\r\n{}\r\nEND\r\n",
+                                              words[1], data.len(), data),
+                        None => "END\r\n".to_string()
+                    },
+                    "set" => {
+                        let mut data = vec![0; words[4].parse::<usize>().unwrap() + 2];
+                        reader.read_exact(&mut data).unwrap();
+                        data.truncate(data.len() - 2);
+                        values.insert(words[1].clone(), String::from_utf8(data).unwrap());
+                        "STORED\r\n".to_string()
+                    },
+                    _ => match values.remove(&words[1]) {
+                        Some(_) => "DELETED\r\n".to_string(),
+                        None => "NOT_FOUND\r\n".to_string()
+                    }
+                };
+                writer.write_all(response.as_bytes()).unwrap();
+                line.clear();
+            }
+        });
+        addr
+    }
+
+    /// An address with nothing listening on it.
+    fn closed_addr() -> SocketAddr {
+        TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap()
+    }
+
+    fn addrs(count: u16) -> Vec<SocketAddr> {
+        (0..count).map(|port| SocketAddr::from((Ipv4Addr::LOCALHOST, 10000 + port))).collect()
+    }
+
+    #[test]
+    fn ring() {
+        let three: ClusterClient<u64, u64> = ClusterClient::new(addrs(3), 0);
+        let four: ClusterClient<u64, u64> = ClusterClient::new(addrs(4), 0);
+        let new_node = addrs(4)[3];
+
+        let mut counts = HashMap::new();
+        for key in 0..3000 {
+            let node = three.node_for(&key).unwrap();
+            *counts.entry(node).or_insert(0) += 1;
+            // Adding a node only moves keys to it.
+            let moved = four.node_for(&key).unwrap();
+            assert!(moved == node || moved == new_node);
+        }
+        assert_eq!(counts.len(), 3);
+        assert!(counts.values().all(|&count| count > 500));
+
+        let empty: ClusterClient<u64, u64> = ClusterClient::new(Vec::new(), 1);
+        assert_eq!(empty.node_for(&1), None);
+    }
+
+    #[test]
+    fn get_put_remove() {
+        let client = ClusterClient::new(vec![server()], 10);
+        client.put("key".to_string(), "value".to_string());
+        assert_eq!(client.get(&"key".to_string()), Some("value".to_string()));
+        assert_eq!(client.fallback().len(), 0);
+
+        assert!(client.remove(&"key".to_string()));
+        assert!(!client.remove(&"key".to_string()));
+        assert_eq!(client.get(&"key".to_string()), None);
+    }
+
+    #[test]
+    fn failover() {
+        let client = ClusterClient::new(vec![closed_addr()], 10);
+        client.put(1u64, 10u64);
+        assert_eq!(client.get(&1), Some(10));
+        assert_eq!(client.fallback().peek(&1), Some(10));
+        assert!(client.remove(&1));
+        assert_eq!(client.get(&1), None);
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(exptime(Duration::from_millis(1)), 1);
+        assert_eq!(exptime(Duration::from_millis(1500)), 2);
+        assert!(exptime(Duration::from_secs(MAX_RELATIVE_EXPTIME + 1)) > MAX_RELATIVE_EXPTIME);
+        assert_eq!(hex(&[0, 0xab]), "00ab");
+    }
+}
@@ -0,0 +1,310 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::convert::TryInto;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+
+use crate::cache::Cache;
+use crate::memory::MemSize;
+use crate::policy::{EvictionPolicy, Lru};
+
+/// The shortest match the compressor encodes.
+const MIN_MATCH: usize = 4;
+
+/// The furthest back a match may refer.
+const MAX_OFFSET: usize = 65535;
+
+/// The format requires the last match to start at least this far before the end of the input...
+const MATCH_FIND_LIMIT: usize = 12;
+
+/// ...and the last bytes to be literals.
+const LAST_LITERALS: usize = 5;
+
+const HASH_BITS: u32 = 12;
+
+/// Compressed is a value stored by a `CompressedCache`: compressed if it was large and
+/// compressible, and otherwise as it was put.
+#[derive(Clone, Debug)]
+pub struct Compressed {
+    data: Arc<[u8]>,
+    /// The length of the value, if `data` is compressed.
+    original_len: Option<usize>
+}
+
+impl Compressed {
+    /// The number of bytes stored for the value.
+    pub fn stored_len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// The length of the value when decompressed.
+    pub fn original_len(&self) -> usize {
+        self.original_len.unwrap_or_else(|| self.data.len())
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.original_len.is_some()
+    }
+
+    fn decompress(&self) -> Vec<u8> {
+        match self.original_len {
+            None => self.data.to_vec(),
+            Some(len) => decompress(&self.data, len).expect("compressed values are valid")
+        }
+    }
+}
+
+impl MemSize for Compressed {
+    fn heap_size(&self) -> usize {
+        self.data.heap_size()
+    }
+}
+
+/// CompressedCache stores byte values, such as JSON or HTML bodies, compressed when they are at
+/// least a threshold size, and decompresses them when they are read.
+///
+/// Compression trades CPU for capacity, so it suits caches limited by weight.  Values are
+/// compressed in the LZ4 block format, and kept uncompressed if that does not make them smaller.
+///
+/// ```
+/// use cache::compress::CompressedCache;
+///
+/// let cache = CompressedCache::new(1 << 20, 256);
+/// let body = "<p>Hello, world!</p>".repeat(100).into_bytes();
+/// cache.put("/", body.clone());
+/// assert_eq!(cache.get("/"), Some(body));
+/// assert!(cache.cache().weight() < 200);
+/// ```
+pub struct CompressedCache<K, P = Lru, S = RandomState>
+    where K: Eq + Hash + Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    cache: Cache<K, Compressed, P, S>,
+    threshold: usize
+}
+
+impl <K: Eq + Hash + Clone> CompressedCache<K> {
+    /// Create a cache whose values may occupy at most `max_bytes` once compressed, which
+    /// compresses values of at least `threshold` bytes.
+    pub fn new(max_bytes: u64, threshold: usize) -> CompressedCache<K> {
+        let cache = Cache::with_weigher(max_bytes, |_: &K, value: &Compressed| {
+            value.stored_len() as u64
+        });
+        Self::with_cache(cache, threshold)
+    }
+}
+
+impl <K, P, S> CompressedCache<K, P, S>
+    where K: Eq + Hash + Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    /// Store values in `cache`, compressing values of at least `threshold` bytes.
+    ///
+    /// A weigher of `cache` should weigh values by `Compressed::stored_len`.
+    pub fn with_cache(cache: Cache<K, Compressed, P, S>, threshold: usize)
+        -> CompressedCache<K, P, S> {
+        CompressedCache { cache, threshold }
+    }
+
+    /// The underlying cache, for example to read its statistics or remove many values.
+    pub fn cache(&self) -> &Cache<K, Compressed, P, S> {
+        &self.cache
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    /// Get the value for `key`, decompressed.  See `LRUCache::get`.
+    pub fn get<Q>(&self, key: &Q) -> Option<Vec<u8>>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.cache.get(key).map(|value| value.decompress())
+    }
+
+    /// Put `value` for `key`, compressing it if it is at least the threshold size.
+    ///
+    /// # Returns
+    ///
+    /// The previous value in the cache, decompressed, or `None`.
+    pub fn put(&self, key: K, value: Vec<u8>) -> Option<Vec<u8>> {
+        self.cache.put(key, self.compress(value)).map(|old_value| old_value.decompress())
+    }
+
+    /// Remove `key`, returning its value decompressed.
+    pub fn remove<Q>(&self, key: &Q) -> Option<Vec<u8>>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.cache.remove(key).map(|value| value.decompress())
+    }
+
+    fn compress(&self, value: Vec<u8>) -> Compressed {
+        if value.len() >= self.threshold {
+            let compressed = compress(&value);
+            if compressed.len() < value.len() {
+                return Compressed { data: compressed.into(), original_len: Some(value.len()) };
+            }
+        }
+        Compressed { data: value.into(), original_len: None }
+    }
+}
+
+/// Compress `input` to an LZ4 block.
+fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len() / 2);
+    // The position after the last occurrence of each hashed four bytes, or zero.
+    let mut table = vec![0; 1 << HASH_BITS];
+    let mut literals_start = 0;
+    let mut pos = 0;
+
+    while pos + MATCH_FIND_LIMIT < input.len() {
+        let bytes = read_u32(input, pos);
+        let hash = (bytes.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize;
+        let candidate = table[hash];
+        table[hash] = pos + 1;
+
+        if candidate == 0 || pos + 1 - candidate > MAX_OFFSET
+            || read_u32(input, candidate - 1) != bytes {
+            pos += 1;
+            continue;
+        }
+
+        let candidate = candidate - 1;
+        let (mut len, match_limit) = (MIN_MATCH, input.len() - LAST_LITERALS);
+        while pos + len < match_limit && input[candidate + len] == input[pos + len] {
+            len += 1;
+        }
+        write_sequence(&mut output, &input[literals_start..pos], Some((pos - candidate, len)));
+        pos += len;
+        literals_start = pos;
+    }
+
+    write_sequence(&mut output, &input[literals_start..], None);
+    output
+}
+
+/// Write `literals` followed by a match of `(offset, len)`, or by nothing if this is the last
+/// sequence.
+fn write_sequence(output: &mut Vec<u8>, literals: &[u8], copy: Option<(usize, usize)>) {
+    let match_len = copy.map_or(0, |(_, len)| len - MIN_MATCH);
+    output.push((literals.len().min(15) << 4 | match_len.min(15)) as u8);
+    write_len(output, literals.len());
+    output.extend_from_slice(literals);
+
+    if let Some((offset, _)) = copy {
+        output.extend_from_slice(&(offset as u16).to_le_bytes());
+        write_len(output, match_len);
+    }
+}
+
+/// Write the part of `len` which did not fit in a sequence's token.
+fn write_len(output: &mut Vec<u8>, len: usize) {
+    if len < 15 {
+        return;
+    }
+    let mut rest = len - 15;
+    while rest >= 255 {
+        output.push(255);
+        rest -= 255;
+    }
+    output.push(rest as u8);
+}
+
+fn read_u32(input: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(input[pos..pos + 4].try_into().unwrap())
+}
+
+/// Decompress an LZ4 block of `len` bytes, or return `None` if it is not valid.
+fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(len);
+    let mut pos = 0;
+    loop {
+        let token = *input.get(pos)?;
+        pos += 1;
+
+        let literals_len = read_len(input, &mut pos, (token >> 4) as usize)?;
+        output.extend_from_slice(input.get(pos..pos.checked_add(literals_len)?)?);
+        pos += literals_len;
+        if pos == input.len() {
+            break;
+        }
+
+        let offset = u16::from_le_bytes(input.get(pos..pos + 2)?.try_into().unwrap()) as usize;
+        pos += 2;
+        if offset == 0 || offset > output.len() {
+            return None;
+        }
+        let match_len = read_len(input, &mut pos, (token & 15) as usize)? + MIN_MATCH;
+        // The match may overlap the bytes it produces, so copy one byte at a time.
+        let start = output.len() - offset;
+        for i in start..start + match_len {
+            output.push(output[i]);
+        }
+    }
+
+    if output.len() == len {
+        Some(output)
+    } else {
+        None
+    }
+}
+
+/// Read a length which starts as `len` in a sequence's token.
+fn read_len(input: &[u8], pos: &mut usize, mut len: usize) -> Option<usize> {
+    if len < 15 {
+        return Some(len);
+    }
+    loop {
+        let byte = *input.get(*pos)?;
+        *pos += 1;
+        len += byte as usize;
+        if byte != 255 {
+            return Some(len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut state = 1u32;
+        let noise: Vec<u8> = (0..5000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect();
+        let repetitive = b"abcabcabd".repeat(1000);
+        let long_literals = [&noise[..300], &repetitive[..], &noise[..20]].concat();
+
+        for input in [&b""[..], b"a", b"abcdabcdabcdabcd", &noise, &repetitive, &long_literals]
+            .iter() {
+            let compressed = compress(input);
+            assert_eq!(decompress(&compressed, input.len()).as_deref(), Some(*input));
+        }
+        assert!(compress(&repetitive).len() < 100);
+        assert_eq!(decompress(&compress(&repetitive), 5), None);
+        assert_eq!(decompress(&[0x0f, 0, 0], 100), None);
+    }
+
+    #[test]
+    fn compressed_cache() {
+        let cache = CompressedCache::new(1000, 100);
+        let large = b"0123456789".repeat(50);
+        cache.put(1, large.clone());
+        cache.put(2, b"small".to_vec());
+
+        assert_eq!(cache.get(&1), Some(large.clone()));
+        assert_eq!(cache.get(&2), Some(b"small".to_vec()));
+        assert!(cache.cache().peek(&1).unwrap().is_compressed());
+        assert!(!cache.cache().peek(&2).unwrap().is_compressed());
+        assert_eq!(cache.cache().peek(&1).unwrap().original_len(), 500);
+
+        // Compressed, the values weigh far less than their original size.
+        assert!(cache.cache().weight() < 100);
+        for key in 3..10 {
+            cache.put(key, large.clone());
+        }
+        assert_eq!(cache.cache().len(), 9);
+
+        assert_eq!(cache.put(1, vec![]), Some(large.clone()));
+        assert_eq!(cache.remove(&3), Some(large));
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::hash_map::RandomState;
+use std::convert::TryInto;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io;
+use std::marker::PhantomData;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::cache::Cache;
+use crate::policy::{EvictionPolicy, Lru};
+use crate::tiered::Encode;
+
+/// The largest datagram `UdpBus` sends or receives.
+const MAX_DATAGRAM: usize = 65507;
+
+/// How long `UdpBus::receive` waits for a message.
+const RECEIVE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// InvalidationBus carries invalidated keys between the instances of a service which each keep a
+/// local cache.  See `CoherentCache`.
+pub trait InvalidationBus<K>: Send + Sync {
+    type Error;
+
+    /// Tell the other instances that `key` has changed.
+    fn publish(&self, key: &K) -> Result<(), Self::Error>;
+
+    /// Wait briefly for a key published by another instance, returning `None` if none arrives.
+    ///
+    /// Must not return keys published by this instance, and must return (rather than block
+    /// forever) so that the caller can stop receiving.
+    fn receive(&self) -> Result<Option<K>, Self::Error>;
+}
+
+/// UdpBus is an `InvalidationBus` which sends each key in a UDP datagram to a multicast group, or
+/// to a fixed list of peers.
+///
+/// Delivery is best effort: a lost datagram leaves a stale value in another instance's cache
+/// until it is evicted or expires, so caches using the bus should also have a time-to-live.
+/// Keys must encode to at most about 64KiB.
+pub struct UdpBus<K> {
+    socket: UdpSocket,
+    peers: Vec<SocketAddr>,
+    /// Identifies this instance's datagrams, so that it ignores its own multicasts.
+    id: u64,
+    keys: PhantomData<fn(K) -> K>
+}
+
+impl <K: Encode> UdpBus<K> {
+    /// Send keys to `peers` from `socket`, and receive keys sent to `socket`.
+    pub fn new(socket: UdpSocket, peers: Vec<SocketAddr>) -> io::Result<UdpBus<K>> {
+        socket.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+
+        // `RandomState` is seeded randomly, so ids differ even between instances in one process.
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(process::id());
+
+        Ok(UdpBus {
+            socket,
+            peers,
+            id: hasher.finish(),
+            keys: PhantomData
+        })
+    }
+
+    /// Join the multicast `group`, sending keys to and receiving keys from every member.
+    pub fn multicast(group: SocketAddrV4) -> io::Result<UdpBus<K>> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, group.port()))?;
+        socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)?;
+        Self::new(socket, vec![SocketAddr::V4(group)])
+    }
+}
+
+impl <K: Encode> InvalidationBus<K> for UdpBus<K> {
+    type Error = io::Error;
+
+    fn publish(&self, key: &K) -> io::Result<()> {
+        let mut datagram = self.id.to_le_bytes().to_vec();
+        datagram.extend_from_slice(&key.encode());
+        for peer in self.peers.iter() {
+            self.socket.send_to(&datagram, peer)?;
+        }
+        Ok(())
+    }
+
+    fn receive(&self) -> io::Result<Option<K>> {
+        let mut buffer = vec![0; MAX_DATAGRAM];
+        let len = match self.socket.recv(&mut buffer) {
+            Ok(len) => len,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock
+                || err.kind() == io::ErrorKind::TimedOut => return Ok(None),
+            Err(err) => return Err(err)
+        };
+
+        let (id, key) = buffer[..len].split_at(len.min(8));
+        match id.try_into() {
+            Ok(id) if u64::from_le_bytes(id) != self.id => Ok(K::decode(key)),
+            _ => Ok(None)
+        }
+    }
+}
+
+/// CoherentCache keeps a local `Cache` coherent with the caches of other instances, by
+/// publishing the keys it changes on an `InvalidationBus` and removing the keys others publish.
+///
+/// Changes made through `put` and `remove` are published.  Changes made directly to the
+/// underlying cache are not.  Values which other instances invalidate are removed by a
+/// background thread, which stops when the cache is dropped.
+pub struct CoherentCache<K, V, B, P = Lru, S = RandomState>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    cache: Arc<Cache<K, V, P, S>>,
+    bus: Arc<B>
+}
+
+impl <K, V, B, P, S> CoherentCache<K, V, B, P, S>
+    where K: Eq + Hash + Clone, V: Clone, B: InvalidationBus<K>, P: EvictionPolicy<K>,
+          S: BuildHasher {
+    /// Keep `cache` coherent over `bus`, starting a thread to receive invalidations.
+    pub fn new(cache: Arc<Cache<K, V, P, S>>, bus: B) -> CoherentCache<K, V, B, P, S>
+        where Cache<K, V, P, S>: Send + Sync + 'static, B: 'static {
+        let bus = Arc::new(bus);
+        let (receiving_cache, receiving_bus) = (Arc::downgrade(&cache), Arc::clone(&bus));
+        thread::spawn(move || loop {
+            // A failed receive loses at most one invalidation, so keep receiving.
+            let key = receiving_bus.receive().ok().flatten();
+            let cache = match receiving_cache.upgrade() {
+                None => return,
+                Some(cache) => cache
+            };
+            if let Some(key) = key {
+                cache.remove(&key);
+            }
+        });
+
+        CoherentCache { cache, bus }
+    }
+
+    pub fn cache(&self) -> &Arc<Cache<K, V, P, S>> {
+        &self.cache
+    }
+
+    pub fn bus(&self) -> &B {
+        &self.bus
+    }
+
+    /// Get the value for `key` from the local cache.
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.cache.get(key)
+    }
+
+    /// Put `value` for `key` in the local cache, and tell other instances to remove their values
+    /// for `key`.
+    ///
+    /// # Returns
+    ///
+    /// The previous local value, or the bus's error if it could not publish.  The local value is
+    /// put either way.
+    pub fn put(&self, key: K, value: V) -> Result<Option<V>, B::Error> {
+        let published = self.bus.publish(&key);
+        let old_value = self.cache.put(key, value);
+        published.map(|_| old_value)
+    }
+
+    /// Remove `key` from the local cache and tell other instances to do the same.
+    ///
+    /// # Returns
+    ///
+    /// The local value for `key`, if any, or the bus's error if it could not publish.  The local
+    /// value is removed either way.
+    pub fn remove(&self, key: &K) -> Result<Option<V>, B::Error> {
+        let value = self.cache.remove(key);
+        self.bus.publish(key)?;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::cache::LRUCache;
+
+    fn local_socket() -> UdpSocket {
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap()
+    }
+
+    /// Wait up to a second for `condition` to hold.
+    fn eventually<F: Fn() -> bool>(condition: F) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        false
+    }
+
+    #[test]
+    fn udp_bus() {
+        let (a, b) = (local_socket(), local_socket());
+        let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        let a: UdpBus<u64> = UdpBus::new(a, vec![b_addr, a_addr]).unwrap();
+        let b: UdpBus<u64> = UdpBus::new(b, vec![a_addr]).unwrap();
+
+        a.publish(&7).unwrap();
+        assert_eq!(b.receive().unwrap(), Some(7));
+        // Instances ignore their own keys.
+        assert_eq!(a.receive().unwrap(), None);
+    }
+
+    #[test]
+    fn coherent_cache() {
+        let (a, b) = (local_socket(), local_socket());
+        let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        let a = CoherentCache::new(Arc::new(LRUCache::new(10)),
+                                   UdpBus::<String>::new(a, vec![b_addr]).unwrap());
+        let b = CoherentCache::new(Arc::new(LRUCache::new(10)),
+                                   UdpBus::<String>::new(b, vec![a_addr]).unwrap());
+
+        b.cache().put("key".to_string(), 1);
+        a.put("key".to_string(), 2).unwrap();
+        assert!(eventually(|| b.get(&"key".to_string()).is_none()));
+        assert_eq!(a.get(&"key".to_string()), Some(2));
+
+        b.cache().put("key".to_string(), 3);
+        b.remove(&"key".to_string()).unwrap();
+        assert!(eventually(|| a.get(&"key".to_string()).is_none()));
+    }
+}
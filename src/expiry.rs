@@ -0,0 +1,250 @@
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::mem;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(not(target_arch = "wasm32"))]
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::clock::Timestamp;
+
+/// The number of levels of the wheel, not counting the overflow bucket.
+const LEVELS: usize = 4;
+
+/// The number of buckets in each level.
+const BUCKETS: usize = 64;
+
+/// The time covered by one bucket of each level, in nanoseconds: about 1 second, 1 minute,
+/// 1 hour and 3 days.  Each level covers `BUCKETS` buckets of its span, which is one bucket of the
+/// next level.
+const SPANS: [u64; LEVELS] = [1 << 30, 1 << 36, 1 << 42, 1 << 48];
+
+/// The bucket for deadlines beyond the last level (about 200 days away).
+const OVERFLOW: usize = LEVELS * BUCKETS;
+
+#[derive(Clone)]
+struct Timer {
+    /// Nanoseconds since `TimerWheel::origin`.
+    deadline: u64,
+    bucket: usize
+}
+
+/// TimerWheel tracks when each key in a cache expires, so that expired keys can be found without
+/// scanning the whole cache.  See `LRUCache::enable_expiration_timers`.
+///
+/// Keys are kept in a hierarchical timer wheel: a key expiring soon is kept in a bucket of the
+/// finest level, and a key expiring later in a coarser one.  As time advances, the buckets which
+/// have been passed are emptied, and each of their keys has either expired or is moved down to a
+/// finer level.  Scheduling and cancelling are O(1), and advancing visits only the keys in the
+/// buckets passed.
+#[derive(Clone)]
+pub(crate) struct TimerWheel<K> {
+    origin: Timestamp,
+    /// Nanoseconds since `origin` when the wheel was last advanced.
+    elapsed: u64,
+    /// `BUCKETS` buckets for each level, finest first, then the overflow bucket.
+    buckets: Vec<HashSet<K>>,
+    timers: HashMap<K, Timer>
+}
+
+impl <K: Eq + Hash + Clone> TimerWheel<K> {
+    pub(crate) fn new(now: Timestamp) -> TimerWheel<K> {
+        TimerWheel {
+            origin: now,
+            elapsed: 0,
+            buckets: (0..=OVERFLOW).map(|_| HashSet::new()).collect(),
+            timers: HashMap::new()
+        }
+    }
+
+    /// Schedule `key` to expire at `expires_at`, replacing any timer it already has.
+    pub(crate) fn schedule(&mut self, key: K, expires_at: Timestamp) {
+        self.cancel(&key);
+        let deadline = self.nanos(expires_at);
+        self.place(key, deadline);
+    }
+
+    /// Forget the timer for `key`, if any.
+    pub(crate) fn cancel<Q>(&mut self, key: &Q)
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        if let Some(timer) = self.timers.remove(key) {
+            self.buckets[timer.bucket].remove(key);
+        }
+    }
+
+    /// Advance the wheel to `now`, removing and returning the keys which have expired.
+    pub(crate) fn advance(&mut self, now: Timestamp) -> Vec<K> {
+        let previous = self.elapsed;
+        self.elapsed = self.nanos(now).max(previous);
+
+        let mut expired = Vec::new();
+        for (level, &span) in SPANS.iter().enumerate() {
+            let (from, to) = (previous / span, self.elapsed / span);
+            // The current bucket of the finest level is always revisited, since it may hold keys
+            // which expire later in its span.  Coarser levels only need visiting as they turn.
+            if level > 0 && from == to {
+                break;
+            }
+
+            let ticks = (to - from + 1).min(BUCKETS as u64);
+            for tick in from..from + ticks {
+                let bucket = level * BUCKETS + (tick % BUCKETS as u64) as usize;
+                self.expire_bucket(bucket, &mut expired);
+            }
+        }
+
+        let span = SPANS[LEVELS - 1];
+        if previous / span != self.elapsed / span {
+            self.expire_bucket(OVERFLOW, &mut expired);
+        }
+
+        expired
+    }
+
+    pub(crate) fn clear(&mut self) {
+        for bucket in self.buckets.iter_mut() {
+            bucket.clear();
+        }
+        self.timers.clear();
+    }
+
+    /// Empty the bucket at `index`, moving its expired keys to `expired` and rescheduling the rest.
+    fn expire_bucket(&mut self, index: usize, expired: &mut Vec<K>) {
+        for key in mem::take(&mut self.buckets[index]) {
+            let deadline = self.timers[&key].deadline;
+            if deadline <= self.elapsed {
+                self.timers.remove(&key);
+                expired.push(key);
+            } else {
+                self.place(key, deadline);
+            }
+        }
+    }
+
+    /// Put `key`, which has no timer, in the bucket for `deadline`.
+    fn place(&mut self, key: K, deadline: u64) {
+        let delay = deadline.saturating_sub(self.elapsed);
+        let bucket = match SPANS.iter().position(|&span| delay < span * BUCKETS as u64) {
+            None => OVERFLOW,
+            Some(level) => level * BUCKETS + ((deadline / SPANS[level]) % BUCKETS as u64) as usize
+        };
+
+        self.buckets[bucket].insert(key.clone());
+        self.timers.insert(key, Timer { deadline, bucket });
+    }
+
+    /// The nanoseconds from `origin` to `instant`, or zero if `instant` is earlier.
+    fn nanos(&self, instant: Timestamp) -> u64 {
+        instant.saturating_duration_since(self.origin).as_nanos() as u64
+    }
+}
+
+/// EarlyExpiration decides when a lookup should treat a value as expired before its time-to-live
+/// has elapsed, for `LRUCache::set_early_expiration`.
+///
+/// This is the XFetch algorithm: a value with `remaining` time to live expires early if
+/// `remaining <= -recompute_time * ln(u)`, for `u` uniform in (0, 1].  The probability,
+/// `exp(-remaining / recompute_time)`, rises towards 1 as the deadline approaches, so that one
+/// reader of a hot value usually recomputes it before every reader misses at once.
+pub(crate) struct EarlyExpiration {
+    pub(crate) recompute_time: Duration,
+    /// xorshift64 state.  Lost updates between racing threads only repeat a number.
+    state: AtomicU64
+}
+
+impl EarlyExpiration {
+    pub(crate) fn new(recompute_time: Duration) -> EarlyExpiration {
+        // `RandomState` is seeded randomly, so separate caches draw different numbers.
+        let seed = RandomState::new().build_hasher().finish();
+        EarlyExpiration {
+            recompute_time,
+            state: AtomicU64::new(seed | 1)
+        }
+    }
+
+    /// Whether a value with `remaining` time to live should expire now.
+    pub(crate) fn expires_early(&self, remaining: Duration) -> bool {
+        let mut x = self.state.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state.store(x, Ordering::Relaxed);
+
+        let uniform = ((x >> 11) + 1) as f64 / (1u64 << 53) as f64;
+        remaining.as_secs_f64() <= -self.recompute_time.as_secs_f64() * uniform.ln()
+    }
+}
+
+/// Start a thread which calls `run` with `cache` every `period`, until `cache` is dropped or
+/// `run` returns `false`.
+///
+/// The thread holds only a weak reference to `cache`, so it does not keep the cache alive; it
+/// exits within `period` of the last strong reference being dropped.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn spawn_maintenance<T, F>(cache: &Arc<T>, period: Duration, run: F) -> JoinHandle<()>
+    where T: Send + Sync + 'static, F: Fn(&T) -> bool + Send + 'static {
+    let cache = Arc::downgrade(cache);
+    thread::spawn(move || loop {
+        thread::sleep(period);
+        match cache.upgrade() {
+            Some(cache) if run(&cache) => {},
+            _ => return
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(mut keys: Vec<u64>) -> Vec<u64> {
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn advance() {
+        let origin = Timestamp::default();
+        let at = |secs| origin + Duration::from_secs(secs);
+        let mut wheel = TimerWheel::new(origin);
+        wheel.schedule(1, at(1));
+        wheel.schedule(2, at(90));
+        wheel.schedule(3, at(2 * 3600));
+        wheel.schedule(4, at(400 * 86400));
+        wheel.schedule(5, at(5));
+        wheel.cancel(&5);
+
+        assert_eq!(wheel.advance(origin), Vec::<u64>::new());
+        assert_eq!(wheel.advance(at(1)), vec![1]);
+        assert_eq!(wheel.advance(at(89)), Vec::<u64>::new());
+        assert_eq!(wheel.advance(at(91)), vec![2]);
+        assert_eq!(wheel.advance(at(3600)), Vec::<u64>::new());
+
+        // Rescheduling replaces the earlier timer.
+        wheel.schedule(3, at(3601));
+        assert_eq!(wheel.advance(at(3601)), vec![3]);
+        assert_eq!(wheel.advance(at(2 * 3600)), Vec::<u64>::new());
+
+        assert_eq!(wheel.advance(at(399 * 86400)), Vec::<u64>::new());
+        assert_eq!(wheel.advance(at(400 * 86400)), vec![4]);
+        assert!(wheel.timers.is_empty());
+    }
+
+    #[test]
+    fn advance_far() {
+        let origin = Timestamp::default();
+        let mut wheel = TimerWheel::new(origin);
+        for key in 0..200 {
+            wheel.schedule(key, origin + Duration::from_secs(key * 97));
+        }
+
+        let expired = wheel.advance(origin + Duration::from_secs(100 * 97));
+        assert_eq!(sorted(expired), (0..=100).collect::<Vec<u64>>());
+        let expired = wheel.advance(origin + Duration::from_secs(1_000_000));
+        assert_eq!(sorted(expired), (101..200).collect::<Vec<u64>>());
+    }
+}
@@ -0,0 +1,334 @@
+use std::any::Any;
+use std::borrow::Borrow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::clock::Timestamp;
+use crate::policy::{EvictionPolicy, Lru};
+use crate::raw::RawLRUCache;
+use crate::sharded::ShardedLRUCache;
+
+/// How long a value is served from a thread's front before it is read from the shared cache
+/// again, unless changed with `ThreadLocalFront::set_ttl`.
+pub const DEFAULT_FRONT_TTL: Duration = Duration::from_millis(100);
+
+/// The number of generations that changes to keys are counted in.  Keys whose hashes share a
+/// generation invalidate each other's copies.
+const GENERATIONS: usize = 1024;
+
+/// Identifies each `ThreadLocalFront` among the fronts of a thread.
+static NEXT_FRONT_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// The fronts of this thread, by the id of their `ThreadLocalFront`.
+    static FRONTS: RefCell<HashMap<u64, LocalFront>> = RefCell::new(HashMap::new());
+}
+
+/// One thread's front for a `ThreadLocalFront`, whose type is erased so that fronts of caches
+/// of different types can share `FRONTS`.
+struct LocalFront {
+    /// Dead once the `ThreadLocalFront` is dropped, so that the front can be freed.
+    owner: Weak<()>,
+    front: Box<dyn Any>
+}
+
+struct Front<K, V> {
+    /// The value of `Invalidations::all` when the front was last cleared.
+    all: u64,
+    values: RawLRUCache<K, FrontValue<V>>
+}
+
+struct FrontValue<V> {
+    value: V,
+    /// The generation of the value's key when it was read from the shared cache.
+    generation: u64,
+    expires_at: Timestamp
+}
+
+/// Counts changes to the keys of the shared cache, which make copies in fronts stale.
+struct Invalidations {
+    generations: Box<[AtomicU64]>,
+    /// Incremented by `ThreadLocalFront::invalidate_all`.
+    all: AtomicU64
+}
+
+impl Invalidations {
+    fn generation(&self, hash: u64) -> &AtomicU64 {
+        &self.generations[hash as usize % GENERATIONS]
+    }
+}
+
+/// ThreadLocalFront keeps a tiny least-recently-used cache of the values each thread reads most in
+/// front of a `ShardedLRUCache`, so that the hottest keys are read without taking any lock.
+///
+/// Created by `ShardedLRUCache::with_thread_local_front`.  Values found in a thread's front are
+/// served from it for a short time-to-live (`DEFAULT_FRONT_TTL`, or as set by `set_ttl`), and
+/// reads that miss it fall back to the shared cache and copy the value into the front.
+///
+/// Every change to a key in the shared cache, whether made through the `ThreadLocalFront` or by
+/// `cache()`, and whether the value was updated, removed, evicted or expired, invalidates the
+/// copies of that key in every thread's front.  Values taken out by `drain` are not seen, so call
+/// `invalidate_all` after draining.
+///
+/// ```
+/// use cache::sharded::ShardedLRUCache;
+///
+/// let cache: ShardedLRUCache<&str, u64> = ShardedLRUCache::new(1024, 16);
+/// let front = cache.with_thread_local_front(8);
+/// front.put("config", 1);
+/// assert_eq!(front.get(&"config"), Some(1));
+///
+/// // The front's copy is invalidated when the shared cache changes.
+/// front.cache().put("config", 2);
+/// assert_eq!(front.get(&"config"), Some(2));
+/// ```
+///
+/// # Implementation Notes:
+///
+/// Fronts are `RawLRUCache`s kept in a thread-local map by the id of their `ThreadLocalFront`.
+/// Copies are checked against generations, counted per hash of their key by a change hook on every
+/// shard, so a hit reads only the front and an atomic counter that changes with its key.  Fronts
+/// are freed when their thread exits; after the `ThreadLocalFront` is dropped, they are also freed
+/// when the thread next creates a front.
+pub struct ThreadLocalFront<K, V, P = Lru, S = RandomState>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    cache: ShardedLRUCache<K, V, P, S>,
+    id: u64,
+    owner: Arc<()>,
+    capacity: usize,
+    ttl: Duration,
+    invalidations: Arc<Invalidations>,
+    /// Hashes keys for both the fronts and `invalidations`.
+    hash_builder: RandomState
+}
+
+impl <K, V, P, S> ThreadLocalFront<K, V, P, S>
+    where K: Eq + Hash + Clone + 'static, V: Clone + 'static, P: EvictionPolicy<K>,
+          S: BuildHasher {
+    pub(crate) fn new(cache: ShardedLRUCache<K, V, P, S>, capacity: usize)
+        -> ThreadLocalFront<K, V, P, S> {
+        assert!(capacity > 0, "A thread-local front must have space for at least one value");
+
+        let invalidations = Arc::new(Invalidations {
+            generations: (0..GENERATIONS).map(|_| AtomicU64::new(0)).collect(),
+            all: AtomicU64::new(0)
+        });
+        let hash_builder = RandomState::new();
+        let hook_invalidations = Arc::clone(&invalidations);
+        let hook_hash_builder = hash_builder.clone();
+        cache.add_change_hook(Arc::new(move |key: &K| {
+            let hash = hook_hash_builder.hash_one(key);
+            hook_invalidations.generation(hash).fetch_add(1, Ordering::AcqRel);
+        }));
+
+        ThreadLocalFront {
+            cache,
+            id: NEXT_FRONT_ID.fetch_add(1, Ordering::Relaxed),
+            owner: Arc::new(()),
+            capacity,
+            ttl: DEFAULT_FRONT_TTL,
+            invalidations,
+            hash_builder
+        }
+    }
+
+    /// The shared cache.  Changes made through it invalidate the fronts as usual.
+    pub fn cache(&self) -> &ShardedLRUCache<K, V, P, S> {
+        &self.cache
+    }
+
+    /// The most values each thread's front holds.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Serve values from the fronts for at most `ttl` after they were read from the shared cache.
+    /// Applies to values copied into fronts after it is set.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// Get the value for `key`, from this thread's front if it holds a fresh copy, and otherwise
+    /// from the shared cache.  See `LRUCache::get`.
+    ///
+    /// Reads served by the front are not counted in the shared cache's statistics, and do not
+    /// update its recency.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq + ToOwned<Owned = K> + ?Sized {
+        let hash = self.hash_builder.hash_one(key);
+        let generation = self.invalidations.generation(hash).load(Ordering::Acquire);
+        let all = self.invalidations.all.load(Ordering::Acquire);
+        let now = self.cache.now();
+
+        let copy = self.with_front(all, |front| {
+            let handle = front.values.find_hashed(hash, key)?;
+            let fresh = match front.values.peek_handle(handle) {
+                Some((_, copy)) => copy.generation == generation && now < copy.expires_at,
+                None => false
+            };
+            if !fresh {
+                front.values.remove_handle(handle);
+                return None;
+            }
+            front.values.touch_handle(handle);
+            front.values.peek_handle(handle).map(|(_, copy)| copy.value.clone())
+        }).flatten();
+        if copy.is_some() {
+            return copy;
+        }
+
+        // The front is not borrowed while the shared cache is read, in case that reads it.
+        let value = self.cache.get(key)?;
+        let copy = FrontValue { value: value.clone(), generation, expires_at: now + self.ttl };
+        self.with_front(all, |front| front.values.put_hashed(hash, key.to_owned(), copy));
+        Some(value)
+    }
+
+    /// Put `value` for `key` into the shared cache.  See `LRUCache::put`.
+    pub fn put(&self, key: K, value: V) -> Option<V> {
+        self.cache.put(key, value)
+    }
+
+    /// Remove `key` from the shared cache.  See `LRUCache::remove`.
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.cache.remove(key)
+    }
+
+    /// Invalidate every copy in every thread's front, for changes to the shared cache which are
+    /// not seen, such as `drain`.
+    pub fn invalidate_all(&self) {
+        self.invalidations.all.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Run `f` on this thread's front, creating it if necessary, and clearing it first if
+    /// `invalidate_all` has been called since it was last cleared.
+    ///
+    /// Returns `None` without running `f` if the front is in use, because `f` was called from
+    /// within another call to `f` (such as by `V::clone`), or the thread is exiting.
+    fn with_front<T, F>(&self, all: u64, f: F) -> Option<T>
+        where F: FnOnce(&mut Front<K, V>) -> T {
+        FRONTS.try_with(|fronts| {
+            let mut fronts = fronts.try_borrow_mut().ok()?;
+            if !fronts.contains_key(&self.id) {
+                fronts.retain(|_, local| local.owner.strong_count() > 0);
+                let front: Front<K, V> = Front {
+                    all,
+                    values: RawLRUCache::with_hasher(self.capacity, self.hash_builder.clone())
+                };
+                fronts.insert(self.id, LocalFront {
+                    owner: Arc::downgrade(&self.owner),
+                    front: Box::new(front)
+                });
+            }
+
+            let front = fronts.get_mut(&self.id)?.front.downcast_mut::<Front<K, V>>()?;
+            if front.all != all {
+                front.values.clear();
+                front.all = all;
+            }
+            Some(f(front))
+        }).ok().flatten()
+    }
+}
+
+impl <K, V, P, S> Drop for ThreadLocalFront<K, V, P, S>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    /// Free this thread's front.  Other threads' fronts are freed when they exit, or next create a
+    /// front.
+    fn drop(&mut self) {
+        let _ = FRONTS.try_with(|fronts| {
+            if let Ok(mut fronts) = fronts.try_borrow_mut() {
+                fronts.remove(&self.id);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+    use std::thread;
+
+    use super::*;
+    use crate::testing::MockClock;
+
+    #[test]
+    fn invalidation() {
+        let mut cache: ShardedLRUCache<String, u64> = ShardedLRUCache::new(2, 1);
+        let clock = Arc::new(MockClock::new());
+        cache.set_clock(clock.clone());
+        cache.enable_stats();
+        let mut front = cache.with_thread_local_front(2);
+        front.set_ttl(Duration::from_secs(1));
+
+        front.put("key1".to_string(), 1);
+        assert_eq!(front.get("key1"), Some(1));
+        // Served by the front, so the shared cache sees only the first read.
+        assert_eq!(front.get("key1"), Some(1));
+        assert_eq!(front.cache().stats().requests(), 1);
+
+        // Updates, removals and evictions invalidate the copy.
+        front.cache().put("key1".to_string(), 2);
+        assert_eq!(front.get("key1"), Some(2));
+        front.remove("key1");
+        assert_eq!(front.get("key1"), None);
+        front.put("key1".to_string(), 3);
+        assert_eq!(front.get("key1"), Some(3));
+        front.put("key2".to_string(), 4);
+        front.put("key3".to_string(), 5);
+        assert_eq!(front.get("key1"), None);
+
+        // Copies expire after the front's time-to-live.
+        assert_eq!(front.get("key2"), Some(4));
+        front.cache().drain().for_each(drop);
+        assert_eq!(front.get("key2"), Some(4));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(front.get("key2"), None);
+
+        // Or when they are all invalidated.
+        front.put("key2".to_string(), 6);
+        assert_eq!(front.get("key2"), Some(6));
+        front.cache().drain().for_each(drop);
+        front.invalidate_all();
+        assert_eq!(front.get("key2"), None);
+    }
+
+    #[test]
+    fn threads() {
+        let cache: ShardedLRUCache<u64, u64> = ShardedLRUCache::new(16, 4);
+        let front = cache.with_thread_local_front(4);
+        front.put(1, 1);
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    assert_eq!(front.get(&1), Some(1));
+                    FRONTS.with(|fronts| assert_eq!(fronts.borrow().len(), 1));
+                });
+            }
+        });
+
+        // Copies in other threads' fronts are invalidated too.
+        let barrier = Barrier::new(2);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                assert_eq!(front.get(&1), Some(1));
+                barrier.wait();
+                barrier.wait();
+                assert_eq!(front.get(&1), Some(2));
+            });
+            barrier.wait();
+            front.put(1, 2);
+            barrier.wait();
+        });
+
+        assert_eq!(front.get(&1), Some(2));
+        drop(front);
+        FRONTS.with(|fronts| assert!(fronts.borrow().is_empty()));
+    }
+}
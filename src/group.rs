@@ -0,0 +1,118 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::sync::Arc;
+
+/// Maps a key to the group it belongs to, if any.  See `LRUCache::set_group_index`.
+pub(crate) type GroupExtractor<K> = dyn Fn(&K) -> Option<String> + Send + Sync;
+
+/// GroupIndex tracks which keys in a cache belong to each group, so that a group can be
+/// invalidated without scanning the whole cache.
+#[derive(Clone)]
+pub(crate) struct GroupIndex<K> {
+    extract: Arc<GroupExtractor<K>>,
+    members: HashMap<String, HashSet<K>>
+}
+
+impl <K: Eq + Hash + Clone> GroupIndex<K> {
+    pub(crate) fn new(extract: Arc<GroupExtractor<K>>) -> GroupIndex<K> {
+        GroupIndex {
+            extract,
+            members: HashMap::new()
+        }
+    }
+
+    /// Record that `key` entered the cache.
+    pub(crate) fn insert(&mut self, key: &K) {
+        if let Some(group) = (self.extract)(key) {
+            self.members.entry(group).or_default().insert(key.clone());
+        }
+    }
+
+    /// Record that `key` left the cache.
+    pub(crate) fn remove(&mut self, key: &K) {
+        let group = match (self.extract)(key) {
+            None => return,
+            Some(group) => group
+        };
+
+        if let Some(members) = self.members.get_mut(&group) {
+            members.remove(key);
+            if members.is_empty() {
+                self.members.remove(&group);
+            }
+        }
+    }
+
+    /// The keys in `group`.
+    pub(crate) fn members(&self, group: &str) -> Vec<K> {
+        match self.members.get(group) {
+            None => Vec::new(),
+            Some(members) => members.iter().cloned().collect()
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.members.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::LRUCache;
+
+    fn sorted(mut keys: Vec<String>) -> Vec<String> {
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn members() {
+        let extract: Arc<GroupExtractor<String>> = Arc::new(|key: &String| {
+            key.split_once('/').map(|(group, _)| group.to_string())
+        });
+        let mut index = GroupIndex::new(extract);
+        for key in &["users/1", "users/2", "posts/1", "about"] {
+            index.insert(&key.to_string());
+        }
+        assert_eq!(sorted(index.members("users")), vec!["users/1", "users/2"]);
+        assert_eq!(index.members("posts"), vec!["posts/1"]);
+        assert!(index.members("about").is_empty());
+
+        index.remove(&"users/1".to_string());
+        index.remove(&"about".to_string());
+        assert_eq!(index.members("users"), vec!["users/2"]);
+
+        // A group is dropped with its last member.
+        index.remove(&"posts/1".to_string());
+        assert!(index.members("posts").is_empty());
+        assert!(!index.members.contains_key("posts"));
+
+        index.clear();
+        assert!(index.members("users").is_empty());
+    }
+
+    #[test]
+    fn members_after_eviction() {
+        let mut cache: LRUCache<String, u64> = LRUCache::new(2);
+        cache.set_group_index(|key: &String| {
+            key.split_once('/').map(|(group, _)| group.to_string())
+        });
+        cache.put("users/1".to_string(), 1);
+        cache.put("users/2".to_string(), 2);
+        cache.put("posts/1".to_string(), 3);
+        assert_eq!(cache.get(&"users/1".to_string()), None);
+
+        // The evicted value is no longer a member, so only the remaining one is invalidated.
+        assert_eq!(cache.invalidate_group("users"), 1);
+        assert_eq!(cache.get(&"users/2".to_string()), None);
+        assert_eq!(cache.len(), 1);
+
+        cache.put("users/3".to_string(), 4);
+        cache.put("users/4".to_string(), 5);
+        assert_eq!(cache.get(&"posts/1".to_string()), None);
+        assert_eq!(cache.invalidate_group("posts"), 0);
+        assert_eq!(cache.invalidate_group("users"), 2);
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,542 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::cache::LRUCache;
+
+/// The status codes of responses which may be stored, given explicit freshness or a validator.
+const CACHEABLE_STATUSES: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// An HTTP request, as seen by an `HttpCache`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>
+}
+
+/// An HTTP response, as stored by an `HttpCache`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>
+}
+
+impl Request {
+    pub fn new<M: Into<String>, U: Into<String>>(method: M, url: U) -> Request {
+        Request {
+            method: method.into(),
+            url: url.into(),
+            headers: Vec::new()
+        }
+    }
+
+    /// Add a header.
+    pub fn with_header<N: Into<String>, T: Into<String>>(mut self, name: N, value: T) -> Request {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// The value of the first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+}
+
+impl Response {
+    pub fn new<B: Into<Vec<u8>>>(status: u16, body: B) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: body.into()
+        }
+    }
+
+    /// Add a header.
+    pub fn with_header<N: Into<String>, T: Into<String>>(mut self, name: N, value: T) -> Response {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// The value of the first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    /// Replace every header called `name` with a single header.
+    fn set_header(&mut self, name: &str, value: String) {
+        self.headers.retain(|(header, _)| !header.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value));
+    }
+}
+
+/// The result of `HttpCache::get`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Lookup {
+    /// A stored response which is fresh, and may be served without contacting the origin.
+    Fresh(Response),
+    /// A stored response which must be revalidated before it is served.  Send `revalidation` to
+    /// the origin and pass its response to `HttpCache::revalidated`.
+    Stale { response: Response, revalidation: Request },
+    /// No response is stored for the request.
+    Miss
+}
+
+/// A stored response and its freshness.
+struct Stored {
+    response: Response,
+    /// When the response was generated by the origin: when it was stored, less its age then.
+    generated: SystemTime,
+    /// How long after `generated` the response is fresh.
+    lifetime: Duration,
+    /// Whether the response must be revalidated before every use (`Cache-Control: no-cache`).
+    no_cache: bool
+}
+
+impl Stored {
+    fn age(&self, now: SystemTime) -> Duration {
+        now.duration_since(self.generated).unwrap_or_default()
+    }
+
+    fn is_fresh(&self, now: SystemTime) -> bool {
+        !self.no_cache && self.age(now) < self.lifetime
+    }
+}
+
+/// HttpCache stores responses to HTTP requests, following the caching rules of RFC 7234 for a
+/// shared cache.
+///
+/// Responses are keyed by request method and URL, and are stored with their headers in an
+/// `LRUCache` bounded by their total size.  A stored response is served while it is fresh, as
+/// determined by its `Cache-Control` (`s-maxage`, `max-age`) or `Expires` headers.  Once stale, it
+/// may be revalidated with a conditional request built from its `ETag` and `Last-Modified`
+/// headers, instead of being fetched again.
+///
+/// ```
+/// use cache::http::{HttpCache, Lookup, Request, Response};
+///
+/// let cache = HttpCache::new(1 << 20);
+/// let request = Request::new("GET", "https://example.com/");
+/// let response = Response::new(200, "hello").with_header("Cache-Control", "max-age=60");
+/// assert!(cache.store(&request, response));
+///
+/// match cache.get(&request) {
+///     Lookup::Fresh(response) => assert_eq!(response.body, b"hello"),
+///     _ => panic!("Response should be fresh")
+/// }
+/// ```
+///
+/// # NB:
+///
+/// - Responses with a `Vary` header, and responses without explicit freshness or a validator, are
+///   not stored.  Freshness is never estimated heuristically.
+/// - Only IMF-fixdate (`Sun, 06 Nov 1994 08:49:37 GMT`) dates are understood.  Other dates in
+///   `Expires` are treated as already expired, as the RFC requires.
+pub struct HttpCache {
+    cache: LRUCache<(String, String), Arc<Stored>>
+}
+
+impl HttpCache {
+    /// Create a cache whose responses (including their headers) total at most `max_bytes`.
+    pub fn new(max_bytes: u64) -> HttpCache {
+        HttpCache {
+            cache: LRUCache::with_weigher(max_bytes, weigh)
+        }
+    }
+
+    /// The number of stored responses.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Whether no responses are stored.
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    /// Look up the stored response for `request`.
+    ///
+    /// A fresh response is returned with its `Age` header set.  A request with
+    /// `Cache-Control: no-cache` (or `max-age=0`) treats any stored response as stale.
+    pub fn get(&self, request: &Request) -> Lookup {
+        let stored = match self.cache.get(&key(request)) {
+            None => return Lookup::Miss,
+            Some(stored) => stored
+        };
+
+        let now = SystemTime::now();
+        let directives = cache_control(&request.headers);
+        let forced = has_directive(&directives, "no-cache")
+            || directive_seconds(&directives, "max-age") == Some(Duration::from_secs(0));
+        if stored.is_fresh(now) && !forced {
+            let mut response = stored.response.clone();
+            response.set_header("Age", stored.age(now).as_secs().to_string());
+            return Lookup::Fresh(response);
+        }
+
+        let mut revalidation = request.clone();
+        if let Some(etag) = stored.response.header("ETag") {
+            revalidation.headers.push(("If-None-Match".to_string(), etag.to_string()));
+        }
+        if let Some(last_modified) = stored.response.header("Last-Modified") {
+            revalidation.headers.push(("If-Modified-Since".to_string(), last_modified.to_string()));
+        }
+
+        Lookup::Stale {
+            response: stored.response.clone(),
+            revalidation
+        }
+    }
+
+    /// Store `response`, received from the origin for `request`, if the caching rules allow.
+    ///
+    /// A successful response to an unsafe request (such as `POST`) removes the stored responses
+    /// for its URL instead.
+    ///
+    /// # Returns
+    ///
+    /// Whether `response` was stored.
+    pub fn store(&self, request: &Request, response: Response) -> bool {
+        if !is_safe(&request.method) {
+            if (200..400).contains(&response.status) {
+                self.invalidate(&request.url);
+            }
+            return false;
+        }
+
+        match stored(request, response, SystemTime::now()) {
+            None => {
+                self.cache.remove(&key(request));
+                false
+            },
+            Some(stored) => {
+                self.cache.put(key(request), Arc::new(stored));
+                true
+            }
+        }
+    }
+
+    /// Handle `response`, received from the origin for the `revalidation` request of a
+    /// `Lookup::Stale`.
+    ///
+    /// A `304 Not Modified` response updates the headers and freshness of the stored response,
+    /// which is returned.  Any other response replaces the stored response, as by `store`, and is
+    /// returned itself.
+    pub fn revalidated(&self, request: &Request, response: Response) -> Response {
+        let stored = match self.cache.peek(&key(request)) {
+            Some(stored) if response.status == 304 => stored,
+            _ => {
+                self.store(request, response.clone());
+                return response;
+            }
+        };
+
+        let mut updated = stored.response.clone();
+        for (name, value) in response.headers {
+            if !name.eq_ignore_ascii_case("Content-Length") {
+                updated.set_header(&name, value);
+            }
+        }
+
+        self.store(request, updated.clone());
+        updated
+    }
+
+    /// Remove the stored responses to `GET` and `HEAD` requests for `url`.
+    pub fn invalidate(&self, url: &str) {
+        for method in ["GET", "HEAD"].iter() {
+            self.cache.remove(&(method.to_string(), url.to_string()));
+        }
+    }
+
+    /// Remove all stored responses.
+    pub fn clear(&self) {
+        self.cache.clear();
+    }
+}
+
+/// The size of `stored` and its key, in bytes.
+fn weigh((method, url): &(String, String), stored: &Arc<Stored>) -> u64 {
+    let response = &stored.response;
+    let headers: usize = response.headers.iter()
+        .map(|(name, value)| name.len() + value.len())
+        .sum();
+    (method.len() + url.len() + headers + response.body.len()) as u64
+}
+
+fn key(request: &Request) -> (String, String) {
+    (request.method.to_ascii_uppercase(), request.url.clone())
+}
+
+fn is_safe(method: &str) -> bool {
+    ["GET", "HEAD", "OPTIONS", "TRACE"].iter().any(|safe| method.eq_ignore_ascii_case(safe))
+}
+
+/// `response` to `request`, received at `now`, with its freshness, or `None` if it may not be
+/// stored.
+fn stored(request: &Request, response: Response, now: SystemTime) -> Option<Stored> {
+    let method = request.method.to_ascii_uppercase();
+    if (method != "GET" && method != "HEAD") || !CACHEABLE_STATUSES.contains(&response.status) {
+        return None;
+    }
+
+    let requested = cache_control(&request.headers);
+    let directives = cache_control(&response.headers);
+    if has_directive(&requested, "no-store") || has_directive(&directives, "no-store")
+        || has_directive(&directives, "private") || response.header("Vary").is_some() {
+        return None;
+    }
+
+    // A shared cache may only store responses to authorized requests which explicitly allow it.
+    let shareable = ["public", "s-maxage", "must-revalidate"].iter()
+        .any(|directive| has_directive(&directives, directive));
+    if request.header("Authorization").is_some() && !shareable {
+        return None;
+    }
+
+    let date = response.header("Date").and_then(parse_http_date);
+    let lifetime = directive_seconds(&directives, "s-maxage")
+        .or_else(|| directive_seconds(&directives, "max-age"))
+        .or_else(|| {
+            let expires = response.header("Expires")?;
+            let expires = parse_http_date(expires).unwrap_or(UNIX_EPOCH);
+            Some(expires.duration_since(date.unwrap_or(now)).unwrap_or_default())
+        });
+
+    let has_validator = response.header("ETag").is_some()
+        || response.header("Last-Modified").is_some();
+    if lifetime.is_none() && !has_validator {
+        return None;
+    }
+
+    let age = response.header("Age").and_then(|age| age.trim().parse().ok())
+        .map_or(Duration::from_secs(0), Duration::from_secs);
+    let apparent_age = date.and_then(|date| now.duration_since(date).ok()).unwrap_or_default();
+
+    Some(Stored {
+        response,
+        // An age from before the epoch is nonsense, so it is taken to be as old as can be.
+        generated: now.checked_sub(age.max(apparent_age)).unwrap_or(UNIX_EPOCH),
+        lifetime: lifetime.unwrap_or_default(),
+        no_cache: has_directive(&directives, "no-cache")
+    })
+}
+
+/// The value of the first header called `name`, ignoring case.
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// The directives of all `Cache-Control` headers, with their names in lower case.
+fn cache_control(headers: &[(String, String)]) -> Vec<(String, Option<String>)> {
+    headers.iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Cache-Control"))
+        .flat_map(|(_, value)| value.split(','))
+        .filter(|directive| !directive.trim().is_empty())
+        .map(|directive| {
+            let mut parts = directive.splitn(2, '=');
+            let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+            let value = parts.next().map(|value| value.trim().trim_matches('"').to_string());
+            (name, value)
+        })
+        .collect()
+}
+
+fn has_directive(directives: &[(String, Option<String>)], name: &str) -> bool {
+    directives.iter().any(|(directive, _)| directive == name)
+}
+
+/// The value of directive `name`, a number of seconds.
+fn directive_seconds(directives: &[(String, Option<String>)], name: &str) -> Option<Duration> {
+    directives.iter()
+        .find(|(directive, _)| directive == name)
+        .and_then(|(_, value)| value.as_ref()?.parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// Parse an IMF-fixdate, such as `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn parse_http_date(date: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] =
+        ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let parts: Vec<&str> = date.split_whitespace().collect();
+    let (day, month, year, time) = match *parts {
+        [_, day, month, year, time, "GMT"] => (day, month, year, time),
+        _ => return None
+    };
+
+    let day: u64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|name| *name == month)? as u64 + 1;
+    let year: u64 = year.parse().ok()?;
+    let time: Vec<u64> = time.split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let (hours, minutes, seconds) = match *time {
+        [hours, minutes, seconds] if hours < 24 && minutes < 60 && seconds < 61 => {
+            (hours, minutes, seconds)
+        },
+        _ => return None
+    };
+    if !(1970..=9999).contains(&year) || day == 0 || day > days_in_month(year, month) {
+        return None;
+    }
+
+    let seconds = days_since_epoch(year, month, day).checked_mul(86400)?
+        .checked_add(hours * 3600 + minutes * 60 + seconds)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+}
+
+/// The number of days in `month` (from 1) of `year`.
+fn days_in_month(year: u64, month: u64) -> u64 {
+    let leap = year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400));
+    match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31
+    }
+}
+
+/// The number of days from 1970-01-01 to the given date (which must not be earlier).
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    // Count years from March, so that the leap day is the last day of the year.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(url: &str) -> Request {
+        Request::new("GET", url)
+    }
+
+    #[test]
+    fn fresh() {
+        let cache = HttpCache::new(1000);
+        let response = Response::new(200, "body")
+            .with_header("Cache-Control", "public, max-age=60")
+            .with_header("Age", "10");
+        assert!(cache.store(&get("/a"), response));
+
+        match cache.get(&get("/a")) {
+            Lookup::Fresh(response) => {
+                assert_eq!(response.body, b"body");
+                assert_eq!(response.header("age"), Some("10"));
+            },
+            lookup => panic!("Unexpected {:?}", lookup)
+        }
+        assert_eq!(cache.get(&get("/b")), Lookup::Miss);
+        assert_eq!(cache.get(&Request::new("HEAD", "/a")), Lookup::Miss);
+    }
+
+    #[test]
+    fn not_stored() {
+        let cache = HttpCache::new(1000);
+        let cacheable = || Response::new(200, "body").with_header("Cache-Control", "max-age=60");
+        let store = |request: Request, response: Response| cache.store(&request, response);
+
+        assert!(!store(get("/"), Response::new(200, "body")));
+        assert!(!store(get("/"), Response::new(500, "error").with_header("ETag", "\"x\"")));
+        assert!(!store(get("/"), cacheable().with_header("cache-control", "no-store")));
+        assert!(!store(get("/"), cacheable().with_header("Cache-Control", "private")));
+        assert!(!store(get("/"), cacheable().with_header("Vary", "Accept")));
+        assert!(!store(get("/").with_header("Authorization", "secret"), cacheable()));
+        assert!(!store(Request::new("POST", "/"), cacheable()));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn revalidate() {
+        let cache = HttpCache::new(1000);
+        let response = Response::new(200, "body")
+            .with_header("Cache-Control", "max-age=0")
+            .with_header("ETag", "\"v1\"");
+        assert!(cache.store(&get("/a"), response.clone()));
+
+        let revalidation = match cache.get(&get("/a")) {
+            Lookup::Stale { response: stale, revalidation } => {
+                assert_eq!(stale, response);
+                revalidation
+            },
+            lookup => panic!("Unexpected {:?}", lookup)
+        };
+        assert_eq!(revalidation.header("If-None-Match"), Some("\"v1\""));
+
+        let not_modified = Response::new(304, "").with_header("Cache-Control", "max-age=60");
+        let updated = cache.revalidated(&get("/a"), not_modified);
+        assert_eq!(updated.body, b"body");
+        assert_eq!(updated.header("Cache-Control"), Some("max-age=60"));
+        assert!(matches!(cache.get(&get("/a")), Lookup::Fresh(_)));
+
+        // The client can insist on revalidation.
+        let forced = get("/a").with_header("Cache-Control", "no-cache");
+        assert!(matches!(cache.get(&forced), Lookup::Stale { .. }));
+    }
+
+    #[test]
+    fn expires() {
+        let cache = HttpCache::new(1000);
+        let response = Response::new(200, "body")
+            .with_header("Date", "Sun, 06 Nov 1994 08:49:37 GMT")
+            .with_header("Expires", "Sun, 06 Nov 2094 08:49:37 GMT");
+        assert!(cache.store(&get("/a"), response));
+        assert!(matches!(cache.get(&get("/a")), Lookup::Fresh(_)));
+
+        let response = Response::new(200, "body").with_header("Expires", "0");
+        assert!(cache.store(&get("/b"), response));
+        assert!(matches!(cache.get(&get("/b")), Lookup::Stale { .. }));
+    }
+
+    #[test]
+    fn unsafe_methods_invalidate() {
+        let cache = HttpCache::new(1000);
+        let response = Response::new(200, "body").with_header("Cache-Control", "max-age=60");
+        cache.store(&get("/a"), response.clone());
+        cache.store(&Request::new("HEAD", "/a"), response);
+
+        assert!(!cache.store(&Request::new("POST", "/a"), Response::new(500, "")));
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.store(&Request::new("POST", "/a"), Response::new(204, "")));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn http_date() {
+        let date = parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(date, UNIX_EPOCH + Duration::from_secs(784_111_777));
+        let leap_day = parse_http_date("Thu, 29 Feb 2024 00:00:00 GMT").unwrap();
+        assert_eq!(leap_day, UNIX_EPOCH + Duration::from_secs(1_709_164_800));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+
+        // Days which do not exist, and years which would overflow, are rejected.
+        assert_eq!(parse_http_date("Sat, 31 Feb 2024 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Thu, 29 Feb 2100 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Thu, 31 Apr 2024 00:00:00 GMT"), None);
+        assert!(parse_http_date("Fri, 31 Dec 9999 23:59:59 GMT").is_some());
+        assert_eq!(parse_http_date("Thu, 01 Jan 10000 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Thu, 01 Jan 99999999999999 00:00:00 GMT"), None);
+    }
+
+    #[test]
+    fn oversized_dates() {
+        let cache = HttpCache::new(1000);
+        let response = Response::new(200, "body")
+            .with_header("Cache-Control", "max-age=60")
+            .with_header("Age", u64::MAX.to_string());
+        assert!(cache.store(&get("/a"), response));
+        assert!(matches!(cache.get(&get("/a")), Lookup::Stale { .. }));
+
+        // Unparseable expiry times have passed.
+        let response = Response::new(200, "body")
+            .with_header("Expires", "Thu, 01 Jan 99999999999999 00:00:00 GMT");
+        assert!(cache.store(&get("/b"), response));
+        assert!(matches!(cache.get(&get("/b")), Lookup::Stale { .. }));
+    }
+}
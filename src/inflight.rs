@@ -0,0 +1,54 @@
+use std::sync::{Condvar, Mutex};
+
+enum LoadState<V> {
+    Loading,
+    Loaded(V),
+    Failed
+}
+
+/// InFlight coordinates callers that miss on the same key while its value is being computed.
+///
+/// The caller that registers an InFlight computes the value and publishes it with `complete`;
+/// every other caller blocks in `wait` and receives the published value directly, so it does not
+/// depend on the value surviving in the cache until it wakes up.
+pub(crate) struct InFlight<V> {
+    state: Mutex<LoadState<V>>,
+    done: Condvar
+}
+
+impl <V: Clone> InFlight<V> {
+    pub(crate) fn new() -> InFlight<V> {
+        InFlight {
+            state: Mutex::new(LoadState::Loading),
+            done: Condvar::new()
+        }
+    }
+
+    /// Publish the result of the load, waking all waiters.  `None` indicates that the load failed.
+    pub(crate) fn complete(&self, value: Option<V>) {
+        // Never propagate poisoning here: `complete` runs from `Drop` during unwinding.
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *state = match value {
+            Some(value) => LoadState::Loaded(value),
+            None => LoadState::Failed
+        };
+        self.done.notify_all();
+    }
+
+    /// Block until the load completes.
+    ///
+    /// # Returns
+    ///
+    /// The loaded value, or `None` if the load failed.
+    pub(crate) fn wait(&self) -> Option<V> {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            match *state {
+                LoadState::Loading => state = self.done.wait(state).unwrap(),
+                LoadState::Loaded(ref value) => return Some(value.clone()),
+                LoadState::Failed => return None
+            }
+        }
+    }
+}
@@ -1,3 +1,38 @@
-extern crate intrusive_collections;
 
+pub mod admission;
+pub mod array;
+pub mod builder;
+#[cfg(feature = "bytes")]
+pub mod bytes;
 pub mod cache;
+pub mod clock;
+#[cfg(feature = "compress")]
+pub mod compress;
+#[cfg(feature = "distributed")]
+pub mod cluster;
+#[cfg(feature = "distributed")]
+pub mod distributed;
+pub mod front;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod listener;
+pub mod memoize;
+pub mod memory;
+pub mod namespace;
+pub mod policy;
+pub mod raw;
+pub mod sharded;
+pub mod simulate;
+pub mod stats;
+pub mod store;
+pub mod testing;
+pub mod tiered;
+pub mod token;
+pub mod trace;
+pub mod weak;
+
+mod expiry;
+mod group;
+mod inflight;
+mod negative;
+mod refresh;
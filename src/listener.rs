@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, TryLockError};
+
+/// The reason a value left the cache, reported to eviction listeners.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EvictionCause {
+    /// The value was the least recently used when the cache needed room for another.
+    Capacity,
+    /// The value was replaced by a `put` for the same key.
+    Replaced,
+    /// The value was removed explicitly, by `remove` or `clear`.
+    Removed,
+    /// The value's time-to-live elapsed, or its `InvalidationToken` was invalidated.
+    Expired
+}
+
+/// A change to the contents of a cache, delivered to the receivers returned by
+/// `LRUCache::subscribe`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CacheEvent<K, V> {
+    /// A value was put for a key which had none.
+    Insert(K, V),
+    /// A value was put for a key which had one, replacing it.
+    Update(K, V),
+    /// A value was removed explicitly.  See `EvictionCause::Removed`.
+    Remove(K, V),
+    /// A value was evicted, or refused, for lack of room.  See `EvictionCause::Capacity`.
+    Evict(K, V),
+    /// A value expired.  See `EvictionCause::Expired`.
+    Expire(K, V)
+}
+
+/// Makes a `CacheEvent` of one kind from the key and value it is about.
+type MakeEvent<K, V> = fn(K, V) -> CacheEvent<K, V>;
+
+impl <K, V> CacheEvent<K, V> {
+    /// The kind of event for a value which left the cache because of `cause`, or `None` for
+    /// `EvictionCause::Replaced`, since a replaced value is reported by the `Update` which
+    /// replaces it.
+    pub(crate) fn left(cause: EvictionCause) -> Option<MakeEvent<K, V>> {
+        match cause {
+            EvictionCause::Capacity => Some(CacheEvent::Evict),
+            EvictionCause::Replaced => None,
+            EvictionCause::Removed => Some(CacheEvent::Remove),
+            EvictionCause::Expired => Some(CacheEvent::Expire)
+        }
+    }
+}
+
+type Callback<K, V> = Box<dyn FnMut(K, V, EvictionCause) + Send>;
+
+/// EvictionListener delivers evicted values to a user callback.
+///
+/// # Implementation Notes:
+///
+/// Caches collect evicted values while locked and hand them to `notify` after unlocking, so the
+/// callback never runs under a cache lock and may use the cache itself.
+///
+/// Evictions are queued, and whichever thread holds the callback drains the queue.  A thread that
+/// finds the callback busy (because another thread, or the callback itself, is running it) leaves
+/// its evictions in the queue to be delivered by the current holder, rather than blocking.  This
+/// avoids deadlock when the callback re-enters the cache, and delivers evictions in order.
+///
+/// A panicking callback does not lose the evictions queued behind its own: the panic is caught,
+/// and resumed once the queue has been drained.
+pub(crate) struct EvictionListener<K, V> {
+    queue: Mutex<VecDeque<(K, V, EvictionCause)>>,
+    callback: Mutex<Callback<K, V>>
+}
+
+impl <K, V> EvictionListener<K, V> {
+    pub(crate) fn new<F>(callback: F) -> EvictionListener<K, V>
+        where F: FnMut(K, V, EvictionCause) + Send + 'static {
+        EvictionListener {
+            queue: Mutex::new(VecDeque::new()),
+            callback: Mutex::new(Box::new(callback))
+        }
+    }
+
+    /// Deliver `evicted` to the callback.
+    ///
+    /// # Panics
+    ///
+    /// If the callback panicked, with the first of its panics.
+    pub(crate) fn notify(&self, evicted: Vec<(K, V, EvictionCause)>) {
+        if evicted.is_empty() {
+            return;
+        }
+
+        self.queue.lock().unwrap().extend(evicted);
+
+        let mut panicked = None;
+        loop {
+            let mut callback = match self.callback.try_lock() {
+                Ok(callback) => callback,
+                Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+                Err(TryLockError::WouldBlock) => break
+            };
+
+            loop {
+                let next = self.queue.lock().unwrap().pop_front();
+                let (key, value, cause) = match next {
+                    None => break,
+                    Some(eviction) => eviction
+                };
+                let delivered = panic::catch_unwind(AssertUnwindSafe(|| {
+                    (callback)(key, value, cause)
+                }));
+                if let Err(payload) = delivered {
+                    panicked.get_or_insert(payload);
+                }
+            }
+
+            drop(callback);
+
+            // Another thread may have queued evictions after the queue was drained, but before the
+            // callback was released.
+            if self.queue.lock().unwrap().is_empty() {
+                break;
+            }
+        }
+
+        if let Some(payload) = panicked {
+            panic::resume_unwind(payload);
+        }
+    }
+}
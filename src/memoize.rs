@@ -0,0 +1,116 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::time::Duration;
+
+use crate::cache::Cache;
+use crate::policy::{EvictionPolicy, Lru};
+
+/// Return the cached result of calling `f` with `args`, calling it only if `cache` has no result
+/// for `args`.
+///
+/// Concurrent calls with the same arguments call `f` once.  See `LRUCache::get_or_insert_with`.
+///
+/// ```
+/// use cache::cache::LRUCache;
+/// use cache::memoize::memoize;
+///
+/// let cache = LRUCache::new(100);
+/// let square = |x: &u64| x * x;
+/// assert_eq!(memoize(&cache, 12, square), 144);
+/// assert_eq!(cache.peek(&12), Some(144));
+/// ```
+pub fn memoize<A, R, P, S, F>(cache: &Cache<A, R, P, S>, args: A, f: F) -> R
+    where A: Eq + Hash + Clone, R: Clone, P: EvictionPolicy<A>, S: BuildHasher,
+          F: FnOnce(&A) -> R {
+    let key = args.clone();
+    cache.get_or_insert_with(key, || f(&args))
+}
+
+/// Memoized wraps a function, caching its results so that repeated calls with the same
+/// arguments return the cached result instead of calling it again.
+///
+/// Functions of several arguments can be memoized by taking them as a tuple.
+///
+/// ```
+/// use cache::memoize::Memoized;
+///
+/// let add = Memoized::new(100, |&(a, b): &(u64, u64)| a + b);
+/// assert_eq!(add.call((1, 2)), 3);
+/// assert_eq!(add.cache().len(), 1);
+/// ```
+pub struct Memoized<A, R, F, P = Lru, S = RandomState>
+    where A: Eq + Hash + Clone, R: Clone, P: EvictionPolicy<A>, S: BuildHasher {
+    cache: Cache<A, R, P, S>,
+    f: F
+}
+
+impl <A, R, F> Memoized<A, R, F>
+    where A: Eq + Hash + Clone, R: Clone, F: Fn(&A) -> R {
+    /// Memoize `f`, caching the results of the `capacity` most recently used arguments.
+    pub fn new(capacity: usize, f: F) -> Memoized<A, R, F> {
+        Self::with_cache(Cache::new(capacity), f)
+    }
+
+    /// Memoize `f`, caching the results of the `capacity` most recently used arguments for `ttl`
+    /// after they are computed.
+    pub fn with_ttl(capacity: usize, ttl: Duration, f: F) -> Memoized<A, R, F> {
+        Self::with_cache(Cache::with_ttl(capacity, ttl), f)
+    }
+}
+
+impl <A, R, F, P, S> Memoized<A, R, F, P, S>
+    where A: Eq + Hash + Clone, R: Clone, F: Fn(&A) -> R, P: EvictionPolicy<A>, S: BuildHasher {
+    /// Memoize `f`, caching its results in `cache`.
+    pub fn with_cache(cache: Cache<A, R, P, S>, f: F) -> Memoized<A, R, F, P, S> {
+        Memoized { cache, f }
+    }
+
+    /// The result of calling the function with `args`, from the cache if possible.
+    pub fn call(&self, args: A) -> R {
+        memoize(&self.cache, args, &self.f)
+    }
+
+    /// The cache of results, for example to invalidate some of them.
+    pub fn cache(&self) -> &Cache<A, R, P, S> {
+        &self.cache
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::thread;
+
+    #[test]
+    fn call() {
+        let calls = Cell::new(0);
+        let double = Memoized::new(2, |x: &u64| {
+            calls.set(calls.get() + 1);
+            x * 2
+        });
+
+        assert_eq!(double.call(1), 2);
+        assert_eq!(double.call(1), 2);
+        assert_eq!(calls.get(), 1);
+
+        double.call(2);
+        double.call(3);
+        assert_eq!(double.call(1), 2);
+        assert_eq!(calls.get(), 4);
+    }
+
+    #[test]
+    fn with_ttl() {
+        let calls = Cell::new(0);
+        let count = Memoized::with_ttl(2, Duration::from_millis(10), |_: &&str| {
+            calls.set(calls.get() + 1);
+            calls.get()
+        });
+
+        assert_eq!(count.call("key"), 1);
+        assert_eq!(count.call("key"), 1);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(count.call("key"), 2);
+    }
+}
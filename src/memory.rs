@@ -0,0 +1,127 @@
+use std::mem;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::clock::Timestamp;
+
+/// MemSize estimates the memory used by a key or value, for `LRUCache::memory_usage`.
+///
+/// The defaults count only `size_of::<Self>()`, which is exact for types that own no heap memory.
+/// Types which do should override `heap_size`:
+///
+/// ```
+/// use cache::memory::MemSize;
+///
+/// struct Page {
+///     id: u64,
+///     body: String
+/// }
+///
+/// impl MemSize for Page {
+///     fn heap_size(&self) -> usize {
+///         self.body.heap_size()
+///     }
+/// }
+/// ```
+pub trait MemSize {
+    /// The bytes of heap memory owned by `self`, not counting `size_of_val(self)`.
+    fn heap_size(&self) -> usize {
+        0
+    }
+
+    /// The total bytes used by `self`.
+    fn mem_size(&self) -> usize {
+        mem::size_of_val(self) + self.heap_size()
+    }
+}
+
+macro_rules! mem_size_plain {
+    ($($ty:ty),*) => {$(
+        impl MemSize for $ty {}
+    )*}
+}
+
+mem_size_plain!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, bool,
+                char, (), Duration, Instant, SystemTime, Timestamp);
+
+impl MemSize for str {}
+
+impl MemSize for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl <T: MemSize> MemSize for [T] {
+    fn heap_size(&self) -> usize {
+        self.iter().map(MemSize::heap_size).sum()
+    }
+}
+
+impl <T: MemSize> MemSize for Vec<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * mem::size_of::<T>() + self[..].heap_size()
+    }
+}
+
+impl <T: MemSize + ?Sized> MemSize for Box<T> {
+    fn heap_size(&self) -> usize {
+        (**self).mem_size()
+    }
+}
+
+/// Shared values are counted in full by each of their owners, including the reference counts.
+impl <T: MemSize + ?Sized> MemSize for Arc<T> {
+    fn heap_size(&self) -> usize {
+        2 * mem::size_of::<usize>() + (**self).mem_size()
+    }
+}
+
+impl <T: MemSize + ?Sized> MemSize for Rc<T> {
+    fn heap_size(&self) -> usize {
+        2 * mem::size_of::<usize>() + (**self).mem_size()
+    }
+}
+
+impl <T: MemSize> MemSize for Option<T> {
+    fn heap_size(&self) -> usize {
+        self.as_ref().map_or(0, MemSize::heap_size)
+    }
+}
+
+impl <A: MemSize, B: MemSize> MemSize for (A, B) {
+    fn heap_size(&self) -> usize {
+        self.0.heap_size() + self.1.heap_size()
+    }
+}
+
+impl <A: MemSize, B: MemSize, C: MemSize> MemSize for (A, B, C) {
+    fn heap_size(&self) -> usize {
+        self.0.heap_size() + self.1.heap_size() + self.2.heap_size()
+    }
+}
+
+/// The buffer is counted in full by each `Bytes` sharing it.
+#[cfg(feature = "bytes")]
+impl MemSize for bytes::Bytes {
+    fn heap_size(&self) -> usize {
+        self.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mem_size() {
+        assert_eq!(7u64.mem_size(), 8);
+        assert_eq!(String::with_capacity(10).heap_size(), 10);
+        let strings = vec![String::with_capacity(5), String::with_capacity(5)];
+        assert_eq!(strings.heap_size(), 2 * mem::size_of::<String>() + 10);
+        let shared: Arc<[u8]> = vec![0; 100].into();
+        assert_eq!(shared.heap_size(), 2 * mem::size_of::<usize>() + 100);
+        assert_eq!((1u8, Some(Box::new(2u32))).heap_size(), 4);
+    }
+}
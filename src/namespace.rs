@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use crate::cache::{LRUCache, Weigher};
+use crate::listener::EvictionCause;
+use crate::policy::Queue;
+
+/// The most a namespace of a `TenantCache` may hold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quota {
+    /// At most this many values.
+    Len(usize),
+    /// Values of at most this total weight, as computed by the cache's weigher.
+    Weight(u64)
+}
+
+/// The values of one namespace, least recently used first, and their weights.
+struct Tenant<K> {
+    order: Queue<K>,
+    weights: HashMap<K, u64>,
+    weight: u64,
+    quota: Option<Quota>
+}
+
+impl <K: Eq + Hash + Clone> Tenant<K> {
+    fn new() -> Tenant<K> {
+        Tenant {
+            order: Queue::default(),
+            weights: HashMap::new(),
+            weight: 0,
+            quota: None
+        }
+    }
+
+    fn put(&mut self, key: &K, weight: u64) {
+        self.order.push_back(key);
+        self.weight += weight;
+        if let Some(old_weight) = self.weights.insert(key.clone(), weight) {
+            self.weight -= old_weight;
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        self.order.remove(key);
+        if let Some(weight) = self.weights.remove(key) {
+            self.weight -= weight;
+        }
+    }
+
+    fn is_over_quota(&self) -> bool {
+        match self.quota {
+            None => false,
+            Some(Quota::Len(max_len)) => self.weights.len() > max_len,
+            Some(Quota::Weight(max_weight)) => self.weight > max_weight
+        }
+    }
+
+    /// Forget the least recently used values until the namespace is within its quota, returning
+    /// their keys.
+    fn over_quota(&mut self) -> Vec<K> {
+        let mut victims = Vec::new();
+        while self.is_over_quota() {
+            match self.order.pop_front() {
+                None => break,
+                Some(key) => {
+                    self.remove(&key);
+                    victims.push(key);
+                }
+            }
+        }
+        victims
+    }
+}
+
+type Tenants<T, K> = Arc<Mutex<HashMap<T, Tenant<K>>>>;
+
+/// TenantCache shares one cache between many tenants, such as the customers of an API server,
+/// keeping each tenant's values in its own namespace.  See `namespace`.
+///
+/// Namespaces compete for the cache's capacity as usual, but each may also be given a `Quota`,
+/// beyond which its own least recently used values are evicted to make room, so that one tenant
+/// cannot flush out every other.
+///
+/// ```
+/// use cache::namespace::{Quota, TenantCache};
+///
+/// let cache = TenantCache::new(1000);
+/// cache.set_quota("alice", Some(Quota::Len(100)));
+///
+/// let alice = cache.namespace("alice");
+/// alice.put("greeting", "hello");
+/// assert_eq!(alice.get(&"greeting"), Some("hello"));
+/// assert_eq!(cache.namespace("bob").get(&"greeting"), None);
+///
+/// assert_eq!(cache.invalidate_namespace(&"alice"), 1);
+/// ```
+pub struct TenantCache<T, K, V>
+    where T: Eq + Hash + Clone, K: Eq + Hash + Clone, V: Clone {
+    cache: LRUCache<(T, K), V>,
+    tenants: Tenants<T, K>,
+    weigher: Option<Arc<Weigher<(T, K), V>>>
+}
+
+impl <T, K, V> TenantCache<T, K, V>
+    where T: Eq + Hash + Clone + Send + 'static, K: Eq + Hash + Clone + Send + 'static,
+          V: Clone {
+    /// Create a cache with space for `capacity` values across all namespaces.
+    pub fn new(capacity: usize) -> TenantCache<T, K, V> {
+        Self::with_cache(LRUCache::new(capacity), None)
+    }
+
+    /// Create a cache whose values may have a total weight of at most `max_weight` across all
+    /// namespaces, as computed by `weigher`.  See `LRUCache::with_weigher`.
+    pub fn with_weigher<F>(max_weight: u64, weigher: F) -> TenantCache<T, K, V>
+        where F: Fn(&(T, K), &V) -> u64 + Send + Sync + 'static {
+        let weigher: Arc<Weigher<(T, K), V>> = Arc::new(weigher);
+        Self::with_cache(LRUCache::with_shared_weigher(max_weight, Arc::clone(&weigher)),
+                         Some(weigher))
+    }
+
+    fn with_cache(mut cache: LRUCache<(T, K), V>, weigher: Option<Arc<Weigher<(T, K), V>>>)
+        -> TenantCache<T, K, V> {
+        let tenants: Tenants<T, K> = Arc::new(Mutex::new(HashMap::new()));
+        let listening = Arc::clone(&tenants);
+        cache.set_eviction_listener(move |(tenant, key), _, cause| {
+            // A replaced value was accounted for when its replacement was put.
+            if cause != EvictionCause::Replaced {
+                if let Some(tenant) = listening.lock().unwrap().get_mut(&tenant) {
+                    tenant.remove(&key);
+                }
+            }
+        });
+
+        TenantCache { cache, tenants, weigher }
+    }
+
+    /// The view of the cache for `tenant`.
+    pub fn namespace(&self, tenant: T) -> NamespacedCache<'_, T, K, V> {
+        NamespacedCache { cache: self, tenant }
+    }
+
+    /// Limit the values of `tenant` to `quota`, evicting its least recently used values if it is
+    /// already over.  Pass `None` to remove its quota (the default).
+    pub fn set_quota(&self, tenant: T, quota: Option<Quota>) {
+        let victims = {
+            let mut tenants = self.tenants.lock().unwrap();
+            let entry = tenants.entry(tenant.clone()).or_insert_with(Tenant::new);
+            entry.quota = quota;
+            entry.over_quota()
+        };
+        self.remove_all(tenant, victims);
+    }
+
+    /// Remove every value of `tenant`.
+    ///
+    /// # Returns
+    ///
+    /// The number of values removed.
+    pub fn invalidate_namespace(&self, tenant: &T) -> usize {
+        let keys = match self.tenants.lock().unwrap().get_mut(tenant) {
+            None => return 0,
+            Some(entry) => {
+                entry.order = Queue::default();
+                entry.weight = 0;
+                mem::take(&mut entry.weights).into_keys().collect()
+            }
+        };
+        self.remove_all(tenant.clone(), keys)
+    }
+
+    /// The underlying cache, whose keys are pairs of tenant and key.
+    pub fn cache(&self) -> &LRUCache<(T, K), V> {
+        &self.cache
+    }
+
+    /// Remove `keys` of `tenant` from the cache, returning how many were present.
+    fn remove_all(&self, tenant: T, keys: Vec<K>) -> usize {
+        let keys: Vec<(T, K)> = keys.into_iter().map(|key| (tenant.clone(), key)).collect();
+        self.cache.remove_many(keys.iter()).into_iter().filter(Option::is_some).count()
+    }
+}
+
+/// NamespacedCache is the view of a `TenantCache` for one tenant, created by
+/// `TenantCache::namespace`.
+///
+/// Keys are paired with the tenant in the underlying cache, so each lookup clones the tenant and
+/// the key.
+pub struct NamespacedCache<'a, T, K, V>
+    where T: Eq + Hash + Clone, K: Eq + Hash + Clone, V: Clone {
+    cache: &'a TenantCache<T, K, V>,
+    tenant: T
+}
+
+impl <'a, T, K, V> NamespacedCache<'a, T, K, V>
+    where T: Eq + Hash + Clone + Send + 'static, K: Eq + Hash + Clone + Send + 'static,
+          V: Clone {
+    pub fn tenant(&self) -> &T {
+        &self.tenant
+    }
+
+    /// Get the value for `key` in this namespace.  See `LRUCache::get`.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let value = self.cache.cache.get(&(self.tenant.clone(), key.clone()))?;
+        match self.cache.tenants.lock().unwrap().get_mut(&self.tenant) {
+            Some(tenant) if tenant.weights.contains_key(key) => tenant.order.push_back(key),
+            _ => {}
+        }
+        Some(value)
+    }
+
+    /// Put `value` for `key` in this namespace, evicting the namespace's least recently used
+    /// values if it goes over its quota.
+    ///
+    /// # Returns
+    ///
+    /// The previous value for `key`, or `None`.
+    pub fn put(&self, key: K, value: V) -> Option<V> {
+        let full_key = (self.tenant.clone(), key);
+        let weight = self.cache.weigher.as_ref().map_or(1, |weigher| weigher(&full_key, &value));
+        let (tenant, key) = full_key.clone();
+        let old_value = self.cache.cache.put(full_key, value);
+
+        let victims = {
+            let mut tenants = self.cache.tenants.lock().unwrap();
+            let entry = tenants.entry(tenant.clone()).or_insert_with(Tenant::new);
+            entry.put(&key, weight);
+            entry.over_quota()
+        };
+        self.cache.remove_all(tenant, victims);
+        old_value
+    }
+
+    /// Remove `key` from this namespace, returning its value.
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.cache.cache.remove(&(self.tenant.clone(), key.clone()))
+    }
+
+    /// The number of values in this namespace.
+    pub fn len(&self) -> usize {
+        self.usage(|tenant| tenant.weights.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The total weight of the values in this namespace.
+    pub fn weight(&self) -> u64 {
+        self.usage(|tenant| tenant.weight)
+    }
+
+    /// Remove every value in this namespace.  See `TenantCache::invalidate_namespace`.
+    pub fn clear(&self) -> usize {
+        self.cache.invalidate_namespace(&self.tenant)
+    }
+
+    fn usage<U: Default, F: Fn(&Tenant<K>) -> U>(&self, f: F) -> U {
+        self.cache.tenants.lock().unwrap().get(&self.tenant).map(f).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespaces() {
+        let cache = TenantCache::new(10);
+        let (a, b) = (cache.namespace("a"), cache.namespace("b"));
+        a.put(1, 1);
+        b.put(1, 2);
+        assert_eq!((a.get(&1), b.get(&1)), (Some(1), Some(2)));
+        assert_eq!(a.put(1, 3), Some(1));
+        assert_eq!((a.len(), b.len()), (1, 1));
+
+        for key in 2..6 {
+            b.put(key, key);
+        }
+        assert_eq!(cache.invalidate_namespace(&"b"), 5);
+        assert!(b.is_empty());
+        assert_eq!(a.get(&1), Some(3));
+
+        // Values evicted for lack of room leave their namespace.
+        for key in 0..10 {
+            b.put(key, key);
+        }
+        assert_eq!((a.len(), b.len()), (0, 10));
+        assert_eq!(b.remove(&0), Some(0));
+        assert_eq!(b.len(), 9);
+    }
+
+    #[test]
+    fn quotas() {
+        let cache = TenantCache::with_weigher(100, |_: &(&str, u64), value: &u64| *value);
+        cache.set_quota("a", Some(Quota::Weight(10)));
+        let (a, b) = (cache.namespace("a"), cache.namespace("b"));
+
+        a.put(1, 4);
+        a.put(2, 4);
+        a.get(&1);
+        a.put(3, 4);
+        // 2 was the namespace's least recently used value.
+        assert_eq!((a.get(&1), a.get(&2), a.get(&3)), (Some(4), None, Some(4)));
+        assert_eq!(a.weight(), 8);
+
+        b.put(1, 50);
+        cache.set_quota("b", Some(Quota::Len(0)));
+        assert!(b.is_empty());
+        assert_eq!(cache.cache().len(), 2);
+    }
+}
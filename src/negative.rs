@@ -0,0 +1,130 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+use crate::clock::Timestamp;
+
+/// NegativeCache remembers keys which are known to have no value, until their time-to-live
+/// elapses.  See `LRUCache::put_negative`.
+#[derive(Clone)]
+pub(crate) struct NegativeCache<K> {
+    /// The expiry time and sequence number of each key.
+    entries: HashMap<K, (Timestamp, u64)>,
+    /// Keys by expiry time then sequence number, soonest first.
+    order: BTreeMap<(Timestamp, u64), K>,
+    next_seq: u64
+}
+
+impl <K: Eq + Hash + Clone> NegativeCache<K> {
+    pub(crate) fn new() -> NegativeCache<K> {
+        NegativeCache {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next_seq: 0
+        }
+    }
+
+    /// Remember `key` until `expires_at`, forgetting the keys which expire soonest if more than
+    /// `capacity` keys would be remembered.
+    pub(crate) fn insert(&mut self, key: K, expires_at: Timestamp, capacity: usize) {
+        self.remove(&key);
+        while self.entries.len() >= capacity {
+            match self.order.pop_first() {
+                Some((_, oldest)) => self.entries.remove(&oldest),
+                None => return
+            };
+        }
+
+        let entry = (expires_at, self.next_seq);
+        self.next_seq += 1;
+        self.entries.insert(key.clone(), entry);
+        self.order.insert(entry, key);
+    }
+
+    /// Whether `key` is remembered and has not expired at `now`.  Forgets it if it has expired.
+    pub(crate) fn contains<Q>(&mut self, key: &Q, now: Timestamp) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        match self.entries.get(key) {
+            None => false,
+            Some(&(expires_at, _)) if now < expires_at => true,
+            Some(_) => {
+                self.remove(key);
+                false
+            }
+        }
+    }
+
+    pub(crate) fn remove<Q>(&mut self, key: &Q)
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        if self.entries.is_empty() {
+            return;
+        }
+
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(secs: u64) -> Timestamp {
+        Timestamp::from_origin(Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn expiry() {
+        let mut negative = NegativeCache::new();
+        negative.insert("key1", at(5), 4);
+        negative.insert("key2", at(10), 4);
+        assert!(negative.contains("key1", at(4)));
+        assert!(!negative.contains("key3", at(4)));
+
+        // A key expires at its expiry time, and is forgotten once found expired.
+        assert!(!negative.contains("key1", at(5)));
+        assert!(!negative.entries.contains_key("key1"));
+        assert_eq!(negative.order.len(), 1);
+        assert!(negative.contains("key2", at(9)));
+
+        // Inserting a key again replaces its expiry time.
+        negative.insert("key2", at(20), 4);
+        assert!(negative.contains("key2", at(15)));
+        assert_eq!(negative.order.len(), 1);
+
+        negative.remove("key2");
+        assert!(!negative.contains("key2", at(15)));
+        assert!(negative.order.is_empty());
+    }
+
+    #[test]
+    fn capacity() {
+        let mut negative = NegativeCache::new();
+        negative.insert("key1", at(30), 2);
+        negative.insert("key2", at(10), 2);
+        negative.insert("key3", at(20), 2);
+        assert!(negative.contains("key1", at(0)));
+        assert!(!negative.contains("key2", at(0)));
+        assert!(negative.contains("key3", at(0)));
+
+        // Keys with the same expiry time are forgotten in insertion order.
+        negative.insert("key4", at(20), 2);
+        assert!(!negative.contains("key3", at(0)));
+        assert!(negative.contains("key4", at(0)));
+
+        negative.clear();
+        assert!(!negative.contains("key1", at(0)));
+        assert!(negative.order.is_empty());
+
+        // With no capacity, nothing is remembered.
+        negative.insert("key5", at(50), 0);
+        assert!(!negative.contains("key5", at(0)));
+    }
+}
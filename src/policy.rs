@@ -0,0 +1,653 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// EvictionPolicy decides which value a full `Cache` evicts to make room for another.
+///
+/// The cache tells the policy about every key that enters, is read from, or leaves it, and asks it
+/// for a victim when it needs room.  The cache always tracks recency itself (for `peek_lru` and
+/// friends), and passes it to `select_victim` so that recency-based policies need no state of
+/// their own.
+pub trait EvictionPolicy<K> {
+    /// `key` was put into the cache.
+    fn on_insert(&mut self, key: &K);
+
+    /// `key` was put into the cache by `LRUCache::put_cold`, and should be evicted before the
+    /// keys already in the cache unless it is read first.
+    ///
+    /// By default, `key` is treated as any other insertion.
+    fn on_insert_cold(&mut self, key: &K) {
+        self.on_insert(key);
+    }
+
+    /// The value for `key` was read from the cache.
+    fn on_access(&mut self, key: &K);
+
+    /// `key` left the cache, whether evicted, expired, replaced or removed.
+    fn on_remove(&mut self, key: &K);
+
+    /// Forget all keys.
+    fn clear(&mut self);
+
+    /// Choose a key to evict.  `lru_order` yields every key in the cache from least to most
+    /// recently used.
+    ///
+    /// Must return a key in the cache whenever the cache is not empty.
+    fn select_victim<'a>(&mut self, lru_order: &mut dyn Iterator<Item = &'a K>) -> Option<K>
+        where K: 'a;
+}
+
+/// Lru evicts the least recently used value.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Lru;
+
+impl <K: Clone> EvictionPolicy<K> for Lru {
+    fn on_insert(&mut self, _key: &K) {}
+
+    fn on_access(&mut self, _key: &K) {}
+
+    fn on_remove(&mut self, _key: &K) {}
+
+    fn clear(&mut self) {}
+
+    fn select_victim<'a>(&mut self, lru_order: &mut dyn Iterator<Item = &'a K>) -> Option<K>
+        where K: 'a {
+        lru_order.next().cloned()
+    }
+}
+
+/// Queue is an ordered set of keys, which supports moving or removing any key in O(log n).
+#[derive(Clone, Debug)]
+pub(crate) struct Queue<K> {
+    /// The tick at which each key was pushed.  Keys pushed to the front take ticks below zero.
+    ticks: HashMap<K, i64>,
+    /// Keys by tick, oldest first.
+    order: BTreeMap<i64, K>,
+    next_tick: i64
+}
+
+impl <K> Default for Queue<K> {
+    fn default() -> Queue<K> {
+        Queue {
+            ticks: HashMap::new(),
+            order: BTreeMap::new(),
+            next_tick: 0
+        }
+    }
+}
+
+impl <K: Eq + Hash + Clone> Queue<K> {
+    fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.ticks.contains_key(key)
+    }
+
+    /// Push `key` to the back of the queue, moving it there if it is already queued.
+    pub(crate) fn push_back(&mut self, key: &K) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        self.push_at(key, tick);
+    }
+
+    /// Push `key` to the front of the queue, moving it there if it is already queued.
+    pub(crate) fn push_front(&mut self, key: &K) {
+        match self.order.keys().next() {
+            Some(&front) => self.push_at(key, front - 1),
+            None => self.push_back(key)
+        }
+    }
+
+    fn push_at(&mut self, key: &K, tick: i64) {
+        if let Some(old_tick) = self.ticks.insert(key.clone(), tick) {
+            self.order.remove(&old_tick);
+        }
+        self.order.insert(tick, key.clone());
+    }
+
+    /// Remove `key`, returning whether it was queued.
+    pub(crate) fn remove(&mut self, key: &K) -> bool {
+        match self.ticks.remove(key) {
+            None => false,
+            Some(tick) => {
+                self.order.remove(&tick);
+                true
+            }
+        }
+    }
+
+    fn front(&self) -> Option<&K> {
+        self.order.values().next()
+    }
+
+    pub(crate) fn pop_front(&mut self) -> Option<K> {
+        let key = self.front()?.clone();
+        self.remove(&key);
+        Some(key)
+    }
+
+    fn clear(&mut self) {
+        self.ticks.clear();
+        self.order.clear();
+    }
+}
+
+/// Fifo evicts the value which was put longest ago, regardless of how it has been used since.
+///
+/// Replacing a value counts as putting it again.
+#[derive(Clone, Debug)]
+pub struct Fifo<K> {
+    queue: Queue<K>
+}
+
+impl <K> Default for Fifo<K> {
+    fn default() -> Fifo<K> {
+        Fifo {
+            queue: Queue::default()
+        }
+    }
+}
+
+impl <K: Eq + Hash + Clone> EvictionPolicy<K> for Fifo<K> {
+    fn on_insert(&mut self, key: &K) {
+        self.queue.push_back(key);
+    }
+
+    fn on_insert_cold(&mut self, key: &K) {
+        self.queue.push_front(key);
+    }
+
+    fn on_access(&mut self, _key: &K) {}
+
+    fn on_remove(&mut self, key: &K) {
+        self.queue.remove(key);
+    }
+
+    fn clear(&mut self) {
+        self.queue.clear();
+    }
+
+    fn select_victim<'a>(&mut self, _lru_order: &mut dyn Iterator<Item = &'a K>) -> Option<K>
+        where K: 'a {
+        self.queue.front().cloned()
+    }
+}
+
+/// Slru (segmented LRU) protects values which have been used more than once from being flushed
+/// out by values which are only used once, such as those read by a scan.
+///
+/// Values enter a probationary segment when they are put, and are promoted to a protected segment
+/// when they are read.  Values are evicted from the probationary segment first, least recently
+/// used first.  When the protected segment grows beyond its share of the cache, its least recently
+/// used value is demoted back to the probationary segment, to be promoted again if it is read
+/// before being evicted.
+#[derive(Clone, Debug)]
+pub struct Slru<K> {
+    probation: Queue<K>,
+    protected: Queue<K>,
+    /// The largest fraction of the cache that the protected segment may hold.
+    protected_ratio: f64
+}
+
+impl <K> Slru<K> {
+    /// Create an Slru policy whose protected segment may hold at most `protected_ratio` of the
+    /// values in the cache.
+    ///
+    /// # Panics
+    ///
+    /// If `protected_ratio` is not between 0 and 1.
+    pub fn new(protected_ratio: f64) -> Slru<K> {
+        assert!((0.0..=1.0).contains(&protected_ratio), "protected_ratio must be between 0 and 1");
+
+        Slru {
+            probation: Queue::default(),
+            protected: Queue::default(),
+            protected_ratio
+        }
+    }
+}
+
+impl <K> Default for Slru<K> {
+    /// An Slru policy whose protected segment may hold 80% of the cache.
+    fn default() -> Slru<K> {
+        Slru::new(0.8)
+    }
+}
+
+impl <K: Eq + Hash + Clone> EvictionPolicy<K> for Slru<K> {
+    fn on_insert(&mut self, key: &K) {
+        self.probation.push_back(key);
+    }
+
+    fn on_insert_cold(&mut self, key: &K) {
+        self.probation.push_front(key);
+    }
+
+    fn on_access(&mut self, key: &K) {
+        if self.protected.contains(key) {
+            self.protected.push_back(key);
+            return;
+        }
+
+        self.probation.remove(key);
+        self.protected.push_back(key);
+
+        let len = self.probation.len() + self.protected.len();
+        while self.protected.len() as f64 > self.protected_ratio * len as f64 {
+            match self.protected.pop_front() {
+                Some(demoted) => self.probation.push_back(&demoted),
+                None => break
+            }
+        }
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        if !self.probation.remove(key) {
+            self.protected.remove(key);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.probation.clear();
+        self.protected.clear();
+    }
+
+    fn select_victim<'a>(&mut self, _lru_order: &mut dyn Iterator<Item = &'a K>) -> Option<K>
+        where K: 'a {
+        self.probation.front().or_else(|| self.protected.front()).cloned()
+    }
+}
+
+/// Lfu evicts the least frequently used value, breaking ties by evicting the least recently used.
+///
+/// A value's frequency counts the put that inserted it and every read since.  Frequencies are
+/// forgotten when a value leaves the cache.
+#[derive(Clone, Debug)]
+pub struct Lfu<K> {
+    /// The frequency and last access tick of each key.
+    entries: HashMap<K, (u64, u64)>,
+    /// Keys by frequency then last access tick, least frequently used first.
+    order: BTreeMap<(u64, u64), K>,
+    next_tick: u64
+}
+
+impl <K> Default for Lfu<K> {
+    fn default() -> Lfu<K> {
+        Lfu {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next_tick: 0
+        }
+    }
+}
+
+impl <K: Eq + Hash + Clone> Lfu<K> {
+    /// Increment the frequency of `key`, starting from zero if it is not known.
+    fn increment(&mut self, key: &K) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+
+        let frequency = match self.entries.get_mut(key) {
+            Some(entry) => {
+                self.order.remove(entry);
+                *entry = (entry.0 + 1, tick);
+                entry.0
+            },
+            None => {
+                self.entries.insert(key.clone(), (1, tick));
+                1
+            }
+        };
+
+        self.order.insert((frequency, tick), key.clone());
+    }
+}
+
+impl <K: Eq + Hash + Clone> EvictionPolicy<K> for Lfu<K> {
+    fn on_insert(&mut self, key: &K) {
+        self.increment(key);
+    }
+
+    fn on_access(&mut self, key: &K) {
+        self.increment(key);
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    fn select_victim<'a>(&mut self, _lru_order: &mut dyn Iterator<Item = &'a K>) -> Option<K>
+        where K: 'a {
+        self.order.values().next().cloned()
+    }
+}
+
+/// The status of a key known to an `Lirs` policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LirsStatus {
+    /// In the cache, with a low inter-reference recency.
+    Lir,
+    /// In the cache, with a high inter-reference recency.
+    Hir,
+    /// Not in the cache, but remembered in the stack.
+    Ghost
+}
+
+/// Lirs (Low Inter-reference Recency Set) evicts the values whose last two uses were furthest
+/// apart, so that values used in a loop or scan slightly larger than the cache do not flush out
+/// each other and everything else, as they do with `Lru`.
+///
+/// Most of the cache holds LIR values, which have been used twice within a short interval.  The
+/// rest holds HIR values, which are evicted first, in the order they were last used.  A stack
+/// ordered by recency tracks every LIR value, recently used HIR values, and ghosts of recently
+/// evicted HIR values.  An HIR value or ghost used again while still in the stack has a lower
+/// inter-reference recency than the least recently used LIR value, so it becomes LIR and that
+/// value becomes HIR.
+///
+/// Any value leaving the cache while in the stack is remembered as a ghost, so putting it again
+/// (including replacing it) counts as a use.  At most as many ghosts as values are remembered.
+#[derive(Clone, Debug)]
+pub struct Lirs<K> {
+    status: HashMap<K, LirsStatus>,
+    /// LIR values, recently used HIR values and ghosts, least recently used first.  The first
+    /// key is always LIR.
+    stack: Queue<K>,
+    /// HIR values in the cache, in the order they will be evicted.
+    hir: Queue<K>,
+    /// Ghosts, oldest first.
+    ghosts: Queue<K>,
+    lir_len: usize,
+    /// The largest fraction of the cache that LIR values may hold.
+    lir_ratio: f64
+}
+
+impl <K> Lirs<K> {
+    /// Create an Lirs policy whose LIR values may hold at most `lir_ratio` of the values in the
+    /// cache.
+    ///
+    /// # Panics
+    ///
+    /// If `lir_ratio` is not between 0 and 1.
+    pub fn new(lir_ratio: f64) -> Lirs<K> {
+        assert!((0.0..=1.0).contains(&lir_ratio), "lir_ratio must be between 0 and 1");
+
+        Lirs {
+            status: HashMap::new(),
+            stack: Queue::default(),
+            hir: Queue::default(),
+            ghosts: Queue::default(),
+            lir_len: 0,
+            lir_ratio
+        }
+    }
+}
+
+impl <K> Default for Lirs<K> {
+    /// An Lirs policy whose LIR values may hold 99% of the cache.
+    fn default() -> Lirs<K> {
+        Lirs::new(0.99)
+    }
+}
+
+impl <K: Eq + Hash + Clone> Lirs<K> {
+    /// The most LIR values permitted with `len` values in the cache.  At least one, so that a
+    /// small cache still has a stack.
+    fn max_lir_len(&self, len: usize) -> usize {
+        ((self.lir_ratio * len as f64) as usize).max(1)
+    }
+
+    fn len(&self) -> usize {
+        self.lir_len + self.hir.len()
+    }
+
+    /// Make `key`, which is in the cache, LIR and the most recently used, demoting the least
+    /// recently used LIR values if there are too many.
+    fn promote(&mut self, key: &K) {
+        self.hir.remove(key);
+        self.status.insert(key.clone(), LirsStatus::Lir);
+        self.lir_len += 1;
+        self.stack.push_back(key);
+
+        while self.lir_len > self.max_lir_len(self.len()) {
+            let demoted = match self.stack.pop_front() {
+                Some(demoted) => demoted,
+                None => break
+            };
+            self.status.insert(demoted.clone(), LirsStatus::Hir);
+            self.lir_len -= 1;
+            self.hir.push_back(&demoted);
+            self.prune();
+        }
+    }
+
+    /// Remove keys from the bottom of the stack until it is LIR, forgetting ghosts.
+    fn prune(&mut self) {
+        while let Some(bottom) = self.stack.front().cloned() {
+            match self.status[&bottom] {
+                LirsStatus::Lir => return,
+                LirsStatus::Hir => {},
+                LirsStatus::Ghost => {
+                    self.status.remove(&bottom);
+                    self.ghosts.remove(&bottom);
+                }
+            }
+            self.stack.remove(&bottom);
+        }
+    }
+
+    /// Forget the oldest ghosts until there are no more ghosts than values.
+    fn limit_ghosts(&mut self) {
+        while self.ghosts.len() > self.len() {
+            if let Some(ghost) = self.ghosts.pop_front() {
+                self.status.remove(&ghost);
+                self.stack.remove(&ghost);
+            }
+        }
+    }
+}
+
+impl <K: Eq + Hash + Clone> EvictionPolicy<K> for Lirs<K> {
+    fn on_insert(&mut self, key: &K) {
+        match self.status.get(key) {
+            Some(LirsStatus::Ghost) => {
+                self.ghosts.remove(key);
+                self.promote(key);
+            },
+            Some(_) => self.on_access(key),
+            None if self.lir_len < self.max_lir_len(self.len() + 1) => {
+                self.status.insert(key.clone(), LirsStatus::Lir);
+                self.lir_len += 1;
+                self.stack.push_back(key);
+                self.prune();
+            },
+            None => {
+                self.status.insert(key.clone(), LirsStatus::Hir);
+                self.stack.push_back(key);
+                self.hir.push_back(key);
+            }
+        }
+    }
+
+    fn on_access(&mut self, key: &K) {
+        match self.status.get(key) {
+            Some(LirsStatus::Lir) => {
+                self.stack.push_back(key);
+                self.prune();
+            },
+            Some(LirsStatus::Hir) if self.stack.contains(key) => self.promote(key),
+            Some(LirsStatus::Hir) => {
+                self.stack.push_back(key);
+                self.hir.push_back(key);
+            },
+            Some(LirsStatus::Ghost) | None => {}
+        }
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        match self.status.get(key) {
+            Some(LirsStatus::Lir) => self.lir_len -= 1,
+            Some(LirsStatus::Hir) => {
+                self.hir.remove(key);
+            },
+            Some(LirsStatus::Ghost) | None => return
+        }
+
+        if self.stack.contains(key) {
+            self.status.insert(key.clone(), LirsStatus::Ghost);
+            self.ghosts.push_back(key);
+            self.prune();
+            self.limit_ghosts();
+        } else {
+            self.status.remove(key);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.status.clear();
+        self.stack.clear();
+        self.hir.clear();
+        self.ghosts.clear();
+        self.lir_len = 0;
+    }
+
+    fn select_victim<'a>(&mut self, _lru_order: &mut dyn Iterator<Item = &'a K>) -> Option<K>
+        where K: 'a {
+        self.hir.front().or_else(|| self.stack.front()).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::Cache;
+
+    fn victim<P: EvictionPolicy<u64>>(policy: &mut P) -> Option<u64> {
+        policy.select_victim(&mut [].iter())
+    }
+
+    #[test]
+    fn fifo() {
+        let mut fifo = Fifo::default();
+        for key in 0..3 {
+            fifo.on_insert(&key);
+        }
+        fifo.on_access(&0);
+        assert_eq!(victim(&mut fifo), Some(0));
+
+        fifo.on_remove(&0);
+        assert_eq!(victim(&mut fifo), Some(1));
+
+        fifo.on_insert(&1);
+        assert_eq!(victim(&mut fifo), Some(2));
+        fifo.on_insert_cold(&3);
+        assert_eq!(victim(&mut fifo), Some(3));
+
+        fifo.clear();
+        assert_eq!(victim(&mut fifo), None);
+    }
+
+    #[test]
+    fn slru() {
+        let mut slru = Slru::new(0.5);
+        for key in 0..4 {
+            slru.on_insert(&key);
+        }
+        slru.on_access(&0);
+        slru.on_access(&1);
+        assert_eq!(victim(&mut slru), Some(2));
+
+        // Promoting a third value demotes the least recently used protected value.
+        slru.on_access(&2);
+        assert_eq!(victim(&mut slru), Some(3));
+        slru.on_remove(&3);
+        assert_eq!(victim(&mut slru), Some(0));
+
+        slru.on_remove(&0);
+        slru.on_remove(&2);
+        assert_eq!(victim(&mut slru), Some(1));
+
+        // Cold values are evicted before the rest of the probationary segment.
+        slru.on_insert(&4);
+        slru.on_insert_cold(&5);
+        assert_eq!(victim(&mut slru), Some(5));
+    }
+
+    #[test]
+    fn lirs() {
+        let mut lirs = Lirs::new(0.5);
+        // With half the cache LIR, 0 and 3 are LIR and 1 and 2 are HIR.
+        for key in 0..4 {
+            lirs.on_insert(&key);
+        }
+        assert_eq!(victim(&mut lirs), Some(1));
+
+        // Using an HIR value in the stack makes it LIR, demoting the least recently used LIR value.
+        lirs.on_access(&2);
+        assert_eq!(lirs.status[&2], LirsStatus::Lir);
+        assert_eq!(lirs.status[&0], LirsStatus::Hir);
+        assert_eq!(victim(&mut lirs), Some(1));
+
+        // 1 is no longer in the stack, so it is forgotten when evicted.
+        lirs.on_remove(&1);
+        assert!(!lirs.status.contains_key(&1));
+        assert_eq!(victim(&mut lirs), Some(0));
+
+        // A new value is HIR, and a ghost put again is LIR.
+        lirs.on_insert(&4);
+        assert_eq!(lirs.status[&4], LirsStatus::Hir);
+        lirs.on_remove(&4);
+        assert_eq!(lirs.status[&4], LirsStatus::Ghost);
+        lirs.on_insert(&4);
+        assert_eq!(lirs.status[&4], LirsStatus::Lir);
+        assert_eq!(lirs.lir_len, 2);
+
+        lirs.clear();
+        assert_eq!(victim(&mut lirs), None);
+    }
+
+    #[test]
+    fn lirs_loop() {
+        // Looping over 12 keys with room for 10 misses every time with LRU, but LIRS keeps most
+        // of the loop.
+        let cache = Cache::with_policy(10, Lirs::new(0.8));
+        let mut hits = 0;
+        for _ in 0..10 {
+            for key in 0..12 {
+                if cache.get(&key).is_some() {
+                    hits += 1;
+                } else {
+                    cache.put(key, key);
+                }
+            }
+        }
+        assert!(hits > 60, "{} hits", hits);
+    }
+
+    #[test]
+    fn lfu() {
+        let mut lfu = Lfu::default();
+        for key in 0..3 {
+            lfu.on_insert(&key);
+        }
+        lfu.on_access(&0);
+        lfu.on_access(&1);
+        lfu.on_access(&0);
+        assert_eq!(victim(&mut lfu), Some(2));
+
+        lfu.on_remove(&2);
+        assert_eq!(victim(&mut lfu), Some(1));
+
+        // A reinserted key starts over.
+        lfu.on_remove(&0);
+        lfu.on_insert(&0);
+        assert_eq!(victim(&mut lfu), Some(0));
+    }
+}
@@ -0,0 +1,472 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, BuildHasherDefault, Hash, Hasher};
+use std::mem;
+
+/// The index of no slot.
+const NIL: usize = usize::MAX;
+
+/// EntryHandle refers to a value in a `RawLRUCache`, so that it can be used or removed again
+/// without finding its key.
+///
+/// A handle stays valid until its value is removed or evicted.  After that, methods taking the
+/// handle act as though the value were missing, even if another value reuses its storage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct EntryHandle {
+    index: usize,
+    generation: u64
+}
+
+struct RawEntry<K, V> {
+    hash: u64,
+    key: K,
+    value: V,
+    /// The next entry whose key has the same hash.
+    next_with_hash: usize
+}
+
+struct Slot<K, V> {
+    /// `None` if the slot is vacant.
+    entry: Option<RawEntry<K, V>>,
+    /// Incremented each time the slot is vacated, so that handles to its old entries are stale.
+    generation: u64,
+    prev: usize,
+    /// The next occupied slot, or the next vacant slot if this slot is vacant.
+    next: usize
+}
+
+/// Hashes the `u64` hashes of keys to themselves, since they have already been hashed.
+#[derive(Default)]
+struct IdentityHasher(u64);
+
+impl Hasher for IdentityHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ u64::from(byte);
+        }
+    }
+
+    fn write_u64(&mut self, hash: u64) {
+        self.0 = hash;
+    }
+}
+
+/// RawLRUCache is a least-recently-used cache of at most `capacity` values, for building other
+/// structures on, such as a buffer pool.
+///
+/// Unlike `LRUCache`, it has no lock (so updating it takes `&mut self`), no expiry, statistics or
+/// listeners, and exposes the hashes of keys and the entries that hold values:
+///
+/// - The `_hashed` methods take the hash of the key, from `hash`, so that callers who already
+///   know it (for example, because it is stored alongside the key) need not hash the key again.
+/// - `put` and `put_hashed` return an `EntryHandle` for the value, with which `touch_handle`,
+///   `peek_handle` and `remove_handle` find it in O(1) time without hashing or comparing keys.
+///
+/// ```
+/// use cache::raw::RawLRUCache;
+///
+/// let mut cache = RawLRUCache::new(2);
+/// let (page1, _) = cache.put(1, "page 1");
+/// let hash = cache.hash(&2);
+/// cache.put_hashed(hash, 2, "page 2");
+/// assert_eq!(cache.get_hashed(hash, &2), Some(&"page 2"));
+///
+/// // Page 1 was used again, so page 2 is the least recently used.
+/// assert!(cache.touch_handle(page1));
+/// cache.put(3, "page 3");
+/// assert_eq!(cache.peek_hashed(hash, &2), None);
+/// assert_eq!(cache.remove_handle(page1), Some((1, "page 1")));
+/// assert_eq!(cache.peek_handle(page1), None);
+/// ```
+///
+/// # Implementation Notes:
+///
+/// Entries are kept in slots of a `Vec`, linked into a doubly-linked list from most to least
+/// recently used by the indexes in `prev` and `next`, as in `ArrayLRUCache`.  A `HashMap` maps each
+/// hash to the first entry with that hash, and entries with equal hashes are chained through
+/// `next_with_hash`.
+pub struct RawLRUCache<K, V, S = RandomState> {
+    slots: Vec<Slot<K, V>>,
+    /// The first entry for each hash.
+    buckets: HashMap<u64, usize, BuildHasherDefault<IdentityHasher>>,
+    hash_builder: S,
+    /// The most recently used entry.
+    head: usize,
+    /// The least recently used entry.
+    tail: usize,
+    /// The most recently vacated slot.  Vacant slots are chained through `next`.
+    free: usize,
+    len: usize,
+    capacity: usize
+}
+
+impl <K: Eq, V> RawLRUCache<K, V> {
+    /// Create an empty cache with space for `capacity` values.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is zero.
+    pub fn new(capacity: usize) -> RawLRUCache<K, V> {
+        Self::with_hasher(capacity, RandomState::new())
+    }
+}
+
+impl <K: Eq, V, S: BuildHasher> RawLRUCache<K, V, S> {
+    /// Create an empty cache with space for `capacity` values, whose `hash` method hashes keys
+    /// with `hash_builder`.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is zero.
+    pub fn with_hasher(capacity: usize, hash_builder: S) -> RawLRUCache<K, V, S> {
+        assert!(capacity > 0, "RawLRUCache must have space for at least one value");
+        RawLRUCache {
+            slots: Vec::with_capacity(capacity),
+            buckets: HashMap::with_capacity_and_hasher(capacity, Default::default()),
+            hash_builder,
+            head: NIL,
+            tail: NIL,
+            free: NIL,
+            len: 0,
+            capacity
+        }
+    }
+
+    /// The hash of `key`, to pass to the `_hashed` methods.
+    pub fn hash<Q: Hash + ?Sized>(&self, key: &Q) -> u64 {
+        self.hash_builder.hash_one(key)
+    }
+
+    /// The number of values in the cache.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The most values the cache can hold.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Put `value` for `key` as the most recently used value, evicting the least recently used
+    /// value if the cache is full.  See `put_hashed`.
+    pub fn put(&mut self, key: K, value: V) -> (EntryHandle, Option<V>)
+        where K: Hash {
+        let hash = self.hash(&key);
+        self.put_hashed(hash, key, value)
+    }
+
+    /// Put `value` for `key`, whose hash is `hash`, as the most recently used value, evicting the
+    /// least recently used value if the cache is full.
+    ///
+    /// To do something with the evicted value, such as writing it back to storage, remove it
+    /// with `pop_lru` before putting a new key into a full cache.
+    ///
+    /// # Returns
+    ///
+    /// A handle to the value, and the previous value for `key`, or `None`.  If there was a
+    /// previous value, its handle remains valid.
+    pub fn put_hashed(&mut self, hash: u64, key: K, value: V) -> (EntryHandle, Option<V>) {
+        if let Some(index) = self.find(hash, &key) {
+            self.move_to_front(index);
+            let entry = self.slots[index].entry.as_mut().expect("Slot must be occupied");
+            let old_value = mem::replace(&mut entry.value, value);
+            return (self.handle(index), Some(old_value));
+        }
+
+        if self.len == self.capacity {
+            self.pop_lru();
+        }
+
+        let next_with_hash = self.buckets.insert(hash, self.vacant_index()).unwrap_or(NIL);
+        let index = self.occupy(RawEntry { hash, key, value, next_with_hash });
+        self.link_front(index);
+        (self.handle(index), None)
+    }
+
+    /// Get the value for `key`, whose hash is `hash`, marking it as the most recently used.
+    pub fn get_hashed<Q>(&mut self, hash: u64, key: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Eq + ?Sized {
+        let index = self.find(hash, key)?;
+        self.move_to_front(index);
+        self.slots[index].entry.as_ref().map(|entry| &entry.value)
+    }
+
+    /// Get the value for `key`, whose hash is `hash`, without updating its recency.
+    pub fn peek_hashed<Q>(&self, hash: u64, key: &Q) -> Option<&V>
+        where K: Borrow<Q>, Q: Eq + ?Sized {
+        let index = self.find(hash, key)?;
+        self.slots[index].entry.as_ref().map(|entry| &entry.value)
+    }
+
+    /// A handle to the value for `key`, whose hash is `hash`, without updating its recency.
+    pub fn find_hashed<Q>(&self, hash: u64, key: &Q) -> Option<EntryHandle>
+        where K: Borrow<Q>, Q: Eq + ?Sized {
+        self.find(hash, key).map(|index| self.handle(index))
+    }
+
+    /// Remove the value for `key`, whose hash is `hash`, returning it.
+    pub fn remove_hashed<Q>(&mut self, hash: u64, key: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Eq + ?Sized {
+        let index = self.find(hash, key)?;
+        Some(self.remove_index(index).1)
+    }
+
+    /// Mark the value of `handle` as the most recently used, returning whether it is still in
+    /// the cache.
+    pub fn touch_handle(&mut self, handle: EntryHandle) -> bool {
+        match self.index(handle) {
+            Some(index) => {
+                self.move_to_front(index);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// The key and value of `handle`, without updating its recency.
+    pub fn peek_handle(&self, handle: EntryHandle) -> Option<(&K, &V)> {
+        let index = self.index(handle)?;
+        self.slots[index].entry.as_ref().map(|entry| (&entry.key, &entry.value))
+    }
+
+    /// A mutable reference to the value of `handle`, without updating its recency.
+    pub fn peek_handle_mut(&mut self, handle: EntryHandle) -> Option<&mut V> {
+        let index = self.index(handle)?;
+        self.slots[index].entry.as_mut().map(|entry| &mut entry.value)
+    }
+
+    /// Remove the value of `handle`, returning its key and value.
+    pub fn remove_handle(&mut self, handle: EntryHandle) -> Option<(K, V)> {
+        let index = self.index(handle)?;
+        Some(self.remove_index(index))
+    }
+
+    /// A handle to the least recently used value, which the next put into a full cache evicts.
+    pub fn lru_handle(&self) -> Option<EntryHandle> {
+        match self.tail {
+            NIL => None,
+            tail => Some(self.handle(tail))
+        }
+    }
+
+    /// Remove and return the least recently used key and value.
+    pub fn pop_lru(&mut self) -> Option<(K, V)> {
+        match self.tail {
+            NIL => None,
+            tail => Some(self.remove_index(tail))
+        }
+    }
+
+    /// Remove all values.  Handles to them become stale.
+    pub fn clear(&mut self) {
+        while self.pop_lru().is_some() {}
+    }
+
+    /// Iterate over the handles, keys and values from the most recently used to the least.
+    pub fn iter(&self) -> Iter<'_, K, V, S> {
+        Iter { cache: self, index: self.head }
+    }
+
+    /// The index of the entry for `key`, following the chain of entries with `hash`.
+    fn find<Q>(&self, hash: u64, key: &Q) -> Option<usize>
+        where K: Borrow<Q>, Q: Eq + ?Sized {
+        let mut index = *self.buckets.get(&hash)?;
+        while index != NIL {
+            let entry = self.slots[index].entry.as_ref().expect("Slot must be occupied");
+            if entry.key.borrow() == key {
+                return Some(index);
+            }
+            index = entry.next_with_hash;
+        }
+        None
+    }
+
+    /// The index of the entry of `handle`, if it has not been removed.
+    fn index(&self, handle: EntryHandle) -> Option<usize> {
+        match self.slots.get(handle.index) {
+            Some(slot) if slot.generation == handle.generation && slot.entry.is_some() => {
+                Some(handle.index)
+            },
+            _ => None
+        }
+    }
+
+    fn handle(&self, index: usize) -> EntryHandle {
+        EntryHandle { index, generation: self.slots[index].generation }
+    }
+
+    /// The index that the next entry will be stored at.
+    fn vacant_index(&self) -> usize {
+        match self.free {
+            NIL => self.slots.len(),
+            free => free
+        }
+    }
+
+    /// Store `entry` in a vacant slot, returning its index.  The slot is not yet linked.
+    fn occupy(&mut self, entry: RawEntry<K, V>) -> usize {
+        let index = self.vacant_index();
+        if index == self.slots.len() {
+            self.slots.push(Slot { entry: Some(entry), generation: 0, prev: NIL, next: NIL });
+        } else {
+            self.free = self.slots[index].next;
+            self.slots[index].entry = Some(entry);
+        }
+        self.len += 1;
+        index
+    }
+
+    /// Remove the entry at `index` from the list and its hash chain, and vacate its slot.
+    fn remove_index(&mut self, index: usize) -> (K, V) {
+        self.detach(index);
+        let slot = &mut self.slots[index];
+        let entry = slot.entry.take().expect("Slot must be occupied");
+        slot.generation += 1;
+        slot.next = self.free;
+        self.free = index;
+        self.len -= 1;
+
+        let first = self.buckets[&entry.hash];
+        if first == index {
+            match entry.next_with_hash {
+                NIL => self.buckets.remove(&entry.hash),
+                next => self.buckets.insert(entry.hash, next)
+            };
+        } else {
+            let mut prev = first;
+            loop {
+                let prev_entry = self.slots[prev].entry.as_mut().expect("Slot must be occupied");
+                if prev_entry.next_with_hash == index {
+                    prev_entry.next_with_hash = entry.next_with_hash;
+                    break;
+                }
+                prev = prev_entry.next_with_hash;
+            }
+        }
+
+        (entry.key, entry.value)
+    }
+
+    fn move_to_front(&mut self, index: usize) {
+        if self.head != index {
+            self.detach(index);
+            self.link_front(index);
+        }
+    }
+
+    /// Remove the entry at `index` from the list, leaving it occupied.
+    fn detach(&mut self, index: usize) {
+        let (prev, next) = (self.slots[index].prev, self.slots[index].next);
+        match prev {
+            NIL => self.head = next,
+            prev => self.slots[prev].next = next
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.slots[next].prev = prev
+        }
+    }
+
+    fn link_front(&mut self, index: usize) {
+        self.slots[index].prev = NIL;
+        self.slots[index].next = self.head;
+        match self.head {
+            NIL => self.tail = index,
+            head => self.slots[head].prev = index
+        }
+        self.head = index;
+    }
+}
+
+impl <K, V, S> fmt::Debug for RawLRUCache<K, V, S>
+    where K: Eq + fmt::Debug, V: fmt::Debug, S: BuildHasher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter().map(|(_, key, value)| (key, value))).finish()
+    }
+}
+
+/// Iterator over the handles, keys and values of a `RawLRUCache`, created by `iter`.
+pub struct Iter<'a, K, V, S> {
+    cache: &'a RawLRUCache<K, V, S>,
+    index: usize
+}
+
+impl <'a, K, V, S> Iterator for Iter<'a, K, V, S> {
+    type Item = (EntryHandle, &'a K, &'a V);
+
+    fn next(&mut self) -> Option<(EntryHandle, &'a K, &'a V)> {
+        let slot = self.cache.slots.get(self.index)?;
+        let handle = EntryHandle { index: self.index, generation: slot.generation };
+        self.index = slot.next;
+        slot.entry.as_ref().map(|entry| (handle, &entry.key, &entry.value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(cache: &RawLRUCache<u32, u32>) -> Vec<u32> {
+        cache.iter().map(|(_, key, _)| *key).collect()
+    }
+
+    #[test]
+    fn handles() {
+        let mut cache = RawLRUCache::new(3);
+        let handles: Vec<EntryHandle> = (0..3).map(|key| cache.put(key, key).0).collect();
+        assert_eq!(keys(&cache), vec![2, 1, 0]);
+
+        assert!(cache.touch_handle(handles[0]));
+        assert_eq!(keys(&cache), vec![0, 2, 1]);
+        *cache.peek_handle_mut(handles[2]).unwrap() += 10;
+        assert_eq!(cache.put(2, 20), (handles[2], Some(12)));
+        assert_eq!(cache.lru_handle(), Some(handles[1]));
+
+        // 1 is evicted, and its handle is not confused with the value that reuses its slot.
+        let (handle, old_value) = cache.put(3, 3);
+        assert_eq!(old_value, None);
+        assert!(!cache.touch_handle(handles[1]));
+        assert_eq!(cache.peek_handle(handles[1]), None);
+        assert_eq!(cache.peek_handle(handle), Some((&3, &3)));
+        assert_eq!(cache.find_hashed(cache.hash(&3), &3), Some(handle));
+
+        assert_eq!(cache.remove_handle(handles[0]), Some((0, 0)));
+        assert_eq!(cache.remove_handle(handles[0]), None);
+        assert_eq!(keys(&cache), vec![3, 2]);
+        assert_eq!(format!("{:?}", cache), "{3: 3, 2: 20}");
+    }
+
+    #[test]
+    fn equal_hashes() {
+        // Keys with the same hash are told apart by comparing them.
+        let mut cache = RawLRUCache::new(3);
+        for key in 0..3 {
+            cache.put_hashed(7, key, key * 10);
+        }
+        assert_eq!(cache.get_hashed(7, &1), Some(&10));
+        assert_eq!(cache.peek_hashed(7, &3), None);
+        assert_eq!(cache.peek_hashed(8, &1), None);
+
+        assert_eq!(cache.remove_hashed(7, &1), Some(10));
+        assert_eq!(cache.peek_hashed(7, &0), Some(&0));
+        assert_eq!(cache.peek_hashed(7, &2), Some(&20));
+        assert_eq!(cache.pop_lru(), Some((0, 0)));
+        assert_eq!(cache.remove_hashed(7, &2), Some(20));
+        assert!(cache.is_empty());
+        assert!(cache.buckets.is_empty());
+
+        cache.put_hashed(7, 4, 40);
+        cache.clear();
+        assert_eq!(cache.iter().count(), 0);
+    }
+}
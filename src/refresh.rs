@@ -0,0 +1,134 @@
+use std::collections::HashSet;
+use std::hash::Hash;
+#[cfg(not(target_arch = "wasm32"))]
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+#[cfg(not(target_arch = "wasm32"))]
+use std::thread;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+use crate::cache::LoaderListener;
+
+/// Reloads a value for `LRUCache::set_refresh`, returning `None` if it cannot.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) type Loader<K, V> = dyn Fn(&K) -> Option<V> + Send + Sync;
+
+type Spawn<K> = dyn Fn(K, u64, Option<Arc<LoaderListener>>) + Send + Sync;
+
+struct RefreshState<K, V> {
+    /// Keys being reloaded.
+    refreshing: HashSet<K>,
+    /// Reloaded values, with the version of the value each replaces, waiting to be put into the
+    /// cache.
+    loaded: Vec<(K, V, u64)>
+}
+
+/// Refresh reloads values on background threads before they expire.
+///
+/// Reloaded values cannot be put into the cache from the background thread, which does not own
+/// it, so they are collected here and put by the cache's next operation.
+pub(crate) struct Refresh<K, V> {
+    /// How long before a value expires that reading it starts a reload.
+    pub(crate) window: Duration,
+    state: Arc<Mutex<RefreshState<K, V>>>,
+    spawn: Box<Spawn<K>>
+}
+
+impl <K: Eq + Hash + Clone, V> Refresh<K, V> {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn new(window: Duration, loader: Arc<Loader<K, V>>) -> Refresh<K, V>
+        where K: Send + 'static, V: Send + 'static {
+        let state = Arc::new(Mutex::new(RefreshState {
+            refreshing: HashSet::new(),
+            loaded: Vec::new()
+        }));
+
+        let thread_state = Arc::clone(&state);
+        let spawn = move |key: K, version: u64, listener: Option<Arc<LoaderListener>>| {
+            let (state, loader) = (Arc::clone(&thread_state), Arc::clone(&loader));
+            thread::spawn(move || {
+                let started = Instant::now();
+                // A panicking loader counts as a failed reload, so that `key` can be reloaded
+                // again.
+                let value = panic::catch_unwind(AssertUnwindSafe(|| loader(&key))).ok().flatten();
+                if let Some(listener) = listener {
+                    listener(started.elapsed(), value.is_some());
+                }
+
+                let mut state = state.lock().unwrap();
+                state.refreshing.remove(&key);
+                if let Some(value) = value {
+                    state.loaded.push((key, value, version));
+                }
+            });
+        };
+
+        Refresh {
+            window,
+            state,
+            spawn: Box::new(spawn)
+        }
+    }
+
+    /// Reload `key`, whose cached value has `version`, unless it is already being reloaded.  The
+    /// reload is reported to `listener`, if any.
+    pub(crate) fn start(&self, key: K, version: u64, listener: Option<Arc<LoaderListener>>) {
+        if self.state.lock().unwrap().refreshing.insert(key.clone()) {
+            (self.spawn)(key, version, listener);
+        }
+    }
+
+    /// Take the values which have been reloaded since the last call.
+    pub(crate) fn take_loaded(&self) -> Vec<(K, V, u64)> {
+        std::mem::take(&mut self.state.lock().unwrap().loaded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Wait for the reloads started on `refresh` to finish.
+    fn wait(refresh: &Refresh<u64, u64>) {
+        while !refresh.state.lock().unwrap().refreshing.is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn loader_fails() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let loader_calls = Arc::clone(&calls);
+        let loader: Arc<Loader<u64, u64>> = Arc::new(move |&key: &u64| {
+            loader_calls.fetch_add(1, Ordering::SeqCst);
+            match key {
+                1 => None,
+                2 => panic!("loader failed"),
+                _ => Some(key * 10)
+            }
+        });
+        let refresh = Refresh::new(Duration::from_secs(1), loader);
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let listener_results = Arc::clone(&results);
+        let listener: Arc<LoaderListener> = Arc::new(move |_, loaded| {
+            listener_results.lock().unwrap().push(loaded);
+        });
+
+        refresh.start(1, 0, Some(Arc::clone(&listener)));
+        refresh.start(2, 0, Some(Arc::clone(&listener)));
+        wait(&refresh);
+        assert!(refresh.take_loaded().is_empty());
+        assert_eq!(*results.lock().unwrap(), vec![false, false]);
+
+        // A failed reload can be started again.
+        refresh.start(1, 1, None);
+        refresh.start(3, 1, Some(listener));
+        wait(&refresh);
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        assert_eq!(refresh.take_loaded(), vec![(3, 30, 1)]);
+        assert_eq!(*results.lock().unwrap(), vec![false, false, true]);
+    }
+}
@@ -0,0 +1,992 @@
+use std::borrow::Borrow;
+use std::cmp::Reverse;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver};
+#[cfg(not(target_arch = "wasm32"))]
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+#[cfg(feature = "rayon")]
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator,
+                  ParallelExtend, ParallelIterator};
+
+use crate::cache::{Cache, CacheResult, ChangeHook, Entry, EvictionBatchListener, LoaderListener,
+                   Priority, SlowLockListener, Snapshot, StaleResult, ValueGuard, ValueGuardMut,
+                   Weigher};
+use crate::clock::{Clock, Timestamp};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::expiry;
+use crate::front::ThreadLocalFront;
+use crate::group::GroupExtractor;
+use crate::listener::{CacheEvent, EvictionCause, EvictionListener};
+use crate::memory::MemSize;
+use crate::policy::{EvictionPolicy, Lru};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::refresh::Loader;
+use crate::stats::{CacheStats, EvictionAges, HotKey, MetricsRecorder};
+use crate::token::InvalidationToken;
+use crate::trace::TraceRecorder;
+
+/// ShardedLRUCache partitions keys across several independent `Cache`s, each with its own copy
+/// of the eviction policy `P` (least recently used by default).
+///
+/// Each key is hashed to one shard, which has its own lock and LRU list, so operations on keys in
+/// different shards do not contend with each other.
+///
+/// # Implementation Notes:
+///
+/// The policy runs per shard, so eviction is only approximately least-recently-used (or whatever
+/// the policy prefers) across the whole cache: a full shard evicts its own least recently used
+/// value even if another shard holds an older one.  Capacity is divided as evenly as possible
+/// between shards.  A cache created with less capacity (or maximum weight) than shards has only as
+/// many shards as its capacity, so that every shard can hold a value.
+pub struct ShardedLRUCache<K, V, P = Lru, S = RandomState>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    shards: Vec<Cache<K, V, P, S>>,
+    hash_builder: S
+}
+
+impl <K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K> + Default> ShardedLRUCache<K, V, P> {
+    /// Create a ShardedLRUCache with space for `capacity` items, split across `shards` shards.
+    ///
+    /// # Panics
+    ///
+    /// If `shards` is zero.
+    pub fn new(capacity: usize, shards: usize) -> ShardedLRUCache<K, V, P> {
+        let shards = clamp_shards(shards, capacity as u64);
+        Self::with_shards(shards, RandomState::new(), |idx| {
+            Cache::new(split(capacity as u64, shards, idx) as usize)
+        })
+    }
+
+    /// Create a ShardedLRUCache with space for `capacity` items, split across `shards` shards,
+    /// which expire `ttl` after they are put.
+    ///
+    /// # Panics
+    ///
+    /// If `shards` is zero.
+    pub fn with_ttl(capacity: usize, shards: usize, ttl: Duration) -> ShardedLRUCache<K, V, P> {
+        let shards = clamp_shards(shards, capacity as u64);
+        Self::with_shards(shards, RandomState::new(), |idx| {
+            Cache::with_ttl(split(capacity as u64, shards, idx) as usize, ttl)
+        })
+    }
+
+    /// Create a ShardedLRUCache whose values may have a total weight of at most `max_weight`, as
+    /// computed by `weigher`, split across `shards` shards.  See `LRUCache::with_weigher`.
+    ///
+    /// # Panics
+    ///
+    /// If `shards` is zero.
+    pub fn with_weigher<F>(max_weight: u64, shards: usize, weigher: F) -> ShardedLRUCache<K, V, P>
+        where F: Fn(&K, &V) -> u64 + Send + Sync + 'static {
+        let weigher: Arc<Weigher<K, V>> = Arc::new(weigher);
+        let shards = clamp_shards(shards, max_weight);
+        Self::with_shards(shards, RandomState::new(), |idx| {
+            Cache::with_shared_weigher(split(max_weight, shards, idx), Arc::clone(&weigher))
+        })
+    }
+}
+
+impl <K, V, P, S> ShardedLRUCache<K, V, P, S>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    /// Create a ShardedLRUCache with space for `capacity` items, split across `shards` shards,
+    /// which hashes keys with `hash_builder`.  See `LRUCache::with_hasher`.
+    ///
+    /// # Panics
+    ///
+    /// If `shards` is zero.
+    pub fn with_hasher(capacity: usize, shards: usize, hash_builder: S)
+        -> ShardedLRUCache<K, V, P, S>
+        where P: Default, S: Clone {
+        let shards = clamp_shards(shards, capacity as u64);
+        Self::with_shards(shards, hash_builder.clone(), |idx| {
+            Cache::with_hasher(split(capacity as u64, shards, idx) as usize, hash_builder.clone())
+        })
+    }
+
+    pub(crate) fn with_shards<F>(shards: usize, hash_builder: S, new_shard: F)
+        -> ShardedLRUCache<K, V, P, S>
+        where F: FnMut(usize) -> Cache<K, V, P, S> {
+        assert!(shards > 0, "ShardedLRUCache requires at least one shard");
+
+        ShardedLRUCache {
+            shards: (0..shards).map(new_shard).collect(),
+            hash_builder
+        }
+    }
+
+    /// The shard responsible for `key`.
+    fn shard<Q>(&self, key: &Q) -> &Cache<K, V, P, S>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        &self.shards[self.shard_index(key)]
+    }
+
+    /// The index of the shard responsible for `key`.
+    fn shard_index<Q>(&self, key: &Q) -> usize
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        // Shards may hash with the same hasher, and their maps index buckets by the low bits of
+        // the hash, so choose the shard by the high bits.
+        let hash = self.hash_builder.hash_one(key) >> 32;
+        (hash % self.shards.len() as u64) as usize
+    }
+
+    /// Split `items` into one batch per shard, pairing each item with its position in `items`.
+    fn batches<T, I, F>(&self, items: I, shard_index: F) -> Vec<Vec<(usize, T)>>
+        where I: IntoIterator<Item = T>, F: Fn(&T) -> usize {
+        let mut batches: Vec<Vec<(usize, T)>> = self.shards.iter().map(|_| Vec::new()).collect();
+        for (position, item) in items.into_iter().enumerate() {
+            batches[shard_index(&item)].push((position, item));
+        }
+        batches
+    }
+
+    /// Run `f` on each shard's batch of `items`, returning the results in the order of `items`.
+    fn run_batches<T, F>(&self, batches: Vec<Vec<(usize, T)>>, f: F) -> Vec<Option<V>>
+        where F: Fn(&Cache<K, V, P, S>, Vec<T>) -> Vec<Option<V>> {
+        let mut results = vec![None; batches.iter().map(Vec::len).sum()];
+        for (shard, batch) in self.shards.iter().zip(batches) {
+            if batch.is_empty() {
+                continue;
+            }
+
+            let (positions, items): (Vec<usize>, Vec<T>) = batch.into_iter().unzip();
+            for (position, result) in positions.into_iter().zip(f(shard, items)) {
+                results[position] = result;
+            }
+        }
+        results
+    }
+
+    /// Register `listener` to be called with each value that leaves any shard.  See
+    /// `LRUCache::set_eviction_listener`.
+    pub fn set_eviction_listener<F>(&mut self, listener: F)
+        where F: FnMut(K, V, EvictionCause) + Send + 'static {
+        let listener = Arc::new(EvictionListener::new(listener));
+        for shard in self.shards.iter_mut() {
+            shard.set_shared_eviction_listener(Arc::clone(&listener));
+        }
+    }
+
+    /// Call `listener` whenever an operation waits at least `threshold` for a shard's lock.  See
+    /// `LRUCache::set_slow_lock_listener`.
+    pub fn set_slow_lock_listener<F>(&mut self, threshold: Duration, listener: F)
+        where F: Fn(Duration) + Send + Sync + 'static {
+        let listener: Arc<SlowLockListener> = Arc::new(listener);
+        for shard in self.shards.iter_mut() {
+            shard.set_shared_slow_lock_listener(threshold, Arc::clone(&listener));
+        }
+    }
+
+    /// Call `listener` whenever an operation evicts values from a shard to keep it within its
+    /// limits.  See `LRUCache::set_eviction_batch_listener`.
+    pub fn set_eviction_batch_listener<F>(&mut self, listener: F)
+        where F: Fn(usize, Duration) + Send + Sync + 'static {
+        let listener: Arc<EvictionBatchListener> = Arc::new(listener);
+        for shard in self.shards.iter_mut() {
+            shard.set_shared_eviction_batch_listener(Arc::clone(&listener));
+        }
+    }
+
+    /// Call `listener` whenever a loader finishes running for any shard.  See
+    /// `LRUCache::set_loader_listener`.
+    pub fn set_loader_listener<F>(&mut self, listener: F)
+        where F: Fn(Duration, bool) + Send + Sync + 'static {
+        let listener: Arc<LoaderListener> = Arc::new(listener);
+        for shard in self.shards.iter_mut() {
+            shard.set_shared_loader_listener(Arc::clone(&listener));
+        }
+    }
+
+    /// Subscribe to changes to the contents of every shard.  See `LRUCache::subscribe`.
+    ///
+    /// Events from each shard arrive in order, interleaved with the events of other shards.
+    pub fn subscribe(&self) -> Receiver<CacheEvent<K, V>> {
+        let (sender, receiver) = mpsc::channel();
+        for shard in self.shards.iter() {
+            shard.add_subscriber(sender.clone());
+        }
+        receiver
+    }
+
+    pub(crate) fn add_change_hook(&self, hook: Arc<ChangeHook<K>>) {
+        for shard in self.shards.iter() {
+            shard.add_change_hook(Arc::clone(&hook));
+        }
+    }
+
+    /// Keep a least-recently-used cache of the `capacity` values each thread reads most in front
+    /// of this cache, so that they are read without taking any lock.  See `ThreadLocalFront`.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is zero.
+    pub fn with_thread_local_front(self, capacity: usize) -> ThreadLocalFront<K, V, P, S>
+        where K: 'static, V: 'static {
+        ThreadLocalFront::new(self, capacity)
+    }
+
+    /// Start recording statistics in every shard.  See `LRUCache::enable_stats`.
+    pub fn enable_stats(&mut self) {
+        for shard in self.shards.iter_mut() {
+            shard.enable_stats();
+        }
+    }
+
+    /// Report the statistics of every shard to `recorder`.  See `LRUCache::set_metrics_recorder`.
+    pub fn set_metrics_recorder(&mut self, recorder: Arc<dyn MetricsRecorder>) {
+        for shard in self.shards.iter_mut() {
+            shard.set_metrics_recorder(Arc::clone(&recorder));
+        }
+    }
+
+    /// The statistics recorded by all shards, combined.  See `LRUCache::stats`.
+    pub fn stats(&self) -> CacheStats {
+        self.shards.iter().map(Cache::stats).fold(CacheStats::default(), |a, b| a + b)
+    }
+
+    /// Start recording the ages of values evicted from every shard.  See
+    /// `LRUCache::enable_eviction_ages`.
+    pub fn enable_eviction_ages(&mut self) {
+        for shard in self.shards.iter_mut() {
+            shard.enable_eviction_ages();
+        }
+    }
+
+    /// The ages of the values most recently evicted from all shards, combined.  See
+    /// `LRUCache::eviction_ages`.
+    pub fn eviction_ages(&self) -> EvictionAges {
+        self.shards.iter().map(Cache::eviction_ages).fold(EvictionAges::default(), |a, b| a + b)
+    }
+
+    /// The `k` most used keys in all shards, most used first.  See `LRUCache::hot_keys`.
+    ///
+    /// The recency of keys in different shards cannot be compared, so keys of equal frequency
+    /// are interleaved by their rank within their shards.
+    pub fn hot_keys(&self, k: usize) -> Vec<HotKey<K>> {
+        let mut ranked: Vec<(usize, HotKey<K>)> = self.shards.iter()
+            .flat_map(|shard| shard.hot_keys(k).into_iter().enumerate())
+            .collect();
+        ranked.sort_by_key(|(rank, hot_key)| (Reverse(hot_key.frequency), *rank));
+        ranked.into_iter().take(k).map(|(_, hot_key)| hot_key).collect()
+    }
+
+    /// Reset the statistics of every shard to zero.
+    pub fn reset_stats(&self) {
+        for shard in self.shards.iter() {
+            shard.reset_stats();
+        }
+    }
+
+    /// The statistics, `len` and `weight` of all shards combined, in the Prometheus text
+    /// exposition format.  See `LRUCache::prometheus_text`.
+    #[cfg(feature = "prometheus")]
+    pub fn prometheus_text(&self, name: &str) -> String {
+        crate::stats::prometheus_text(name, &self.stats(), self.len(), self.weight())
+    }
+
+    /// Index values by the group that `extract` assigns to their key.  See
+    /// `LRUCache::set_group_index`.
+    pub fn set_group_index<F>(&mut self, extract: F)
+        where F: Fn(&K) -> Option<String> + Send + Sync + 'static {
+        let extract: Arc<GroupExtractor<K>> = Arc::new(extract);
+        for shard in self.shards.iter_mut() {
+            shard.set_shared_group_index(Arc::clone(&extract));
+        }
+    }
+
+    /// Keep expired values in every shard to be served stale until they are evicted.  See
+    /// `LRUCache::set_keep_stale`.
+    pub fn set_keep_stale(&mut self, keep_stale: bool) {
+        for shard in self.shards.iter_mut() {
+            shard.set_keep_stale(keep_stale);
+        }
+    }
+
+    /// Let lookups treat values as expired shortly before their time-to-live elapses.  See
+    /// `LRUCache::set_early_expiration`.
+    pub fn set_early_expiration(&mut self, recompute_time: Option<Duration>) {
+        for shard in self.shards.iter_mut() {
+            shard.set_early_expiration(recompute_time);
+        }
+    }
+
+    /// Expire values in every shard which have not been read for `tti`.  See
+    /// `LRUCache::set_time_to_idle`.
+    pub fn set_time_to_idle(&mut self, tti: Option<Duration>) {
+        for shard in self.shards.iter_mut() {
+            shard.set_time_to_idle(tti);
+        }
+    }
+
+    /// Expire values in every shard `max_lifetime` after they are put.  See
+    /// `LRUCache::set_max_lifetime`.
+    pub fn set_max_lifetime(&mut self, max_lifetime: Option<Duration>) {
+        for shard in self.shards.iter_mut() {
+            shard.set_max_lifetime(max_lifetime);
+        }
+    }
+
+    /// The current time by the cache's clock.  See `LRUCache::now`.
+    pub fn now(&self) -> Timestamp {
+        self.shards[0].now()
+    }
+
+    /// Read the time for expiration in every shard from `clock`.  See `LRUCache::set_clock`.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        for shard in self.shards.iter_mut() {
+            shard.set_clock(Arc::clone(&clock));
+        }
+    }
+
+    /// Record the operations on every shard to `recorder`.  See `LRUCache::set_trace_recorder`.
+    pub fn set_trace_recorder(&mut self, recorder: Option<Arc<TraceRecorder>>) {
+        for shard in self.shards.iter_mut() {
+            shard.set_trace_recorder(recorder.clone());
+        }
+    }
+
+    /// Track when each value expires in every shard.  See `LRUCache::enable_expiration_timers`.
+    pub fn enable_expiration_timers(&mut self) {
+        for shard in self.shards.iter_mut() {
+            shard.enable_expiration_timers();
+        }
+    }
+
+    /// Reload values in the background when they are read within `window` of expiring.  See
+    /// `LRUCache::set_refresh`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn set_refresh<F>(&mut self, window: Duration, loader: F)
+        where F: Fn(&K) -> Option<V> + Send + Sync + 'static, K: Send + 'static, V: Send + 'static {
+        let loader: Arc<Loader<K, V>> = Arc::new(loader);
+        for shard in self.shards.iter_mut() {
+            shard.set_shared_refresh(window, Arc::clone(&loader));
+        }
+    }
+
+    /// The number of shards.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Call `f` with the shard responsible for `key`, for work the sharded cache has no method
+    /// for, such as several operations on that shard under one lock, diagnostics of one shard, or
+    /// experiments with its policy.
+    ///
+    /// The shard is an ordinary `Cache`, so take care to only put keys which belong to it: a value
+    /// put for another key cannot be found through `self`.
+    ///
+    /// ```
+    /// use cache::sharded::ShardedLRUCache;
+    ///
+    /// let cache: ShardedLRUCache<&str, u64> = ShardedLRUCache::new(64, 4);
+    /// cache.put("key", 1);
+    /// let (len, lru) = cache.with_shard(&"key", |shard| (shard.len(), shard.peek_lru()));
+    /// assert_eq!((len, lru), (1, Some(("key", 1))));
+    /// ```
+    pub fn with_shard<Q, F, T>(&self, key: &Q, f: F) -> T
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized, F: FnOnce(&Cache<K, V, P, S>) -> T {
+        f(self.shard(key))
+    }
+
+    /// The number of values in the cache, across all shards.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(Cache::len).sum()
+    }
+
+    /// Whether the cache contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(Cache::is_empty)
+    }
+
+    /// The maximum number of values permitted in the cache, across all shards.
+    pub fn capacity(&self) -> usize {
+        self.shards.iter().map(Cache::capacity).fold(0, usize::saturating_add)
+    }
+
+    /// The total weight of the values in the cache, across all shards.
+    pub fn weight(&self) -> u64 {
+        self.shards.iter().map(Cache::weight).sum()
+    }
+
+    /// Estimate the bytes of memory used by every shard.  See `LRUCache::memory_usage`.
+    pub fn memory_usage(&self) -> usize
+        where K: MemSize, V: MemSize {
+        self.shards.iter().map(Cache::memory_usage).sum()
+    }
+
+    /// The maximum total weight of the values permitted in the cache, across all shards.
+    pub fn max_weight(&self) -> u64 {
+        self.shards.iter().map(Cache::max_weight).fold(0, u64::saturating_add)
+    }
+
+    /// Change the maximum number of values permitted in the cache, split across shards.  See
+    /// `LRUCache::set_capacity`.
+    ///
+    /// The number of shards does not change, so if `capacity` is less than it, some shards hold
+    /// no values.
+    pub fn set_capacity(&self, capacity: usize) {
+        for (idx, shard) in self.shards.iter().enumerate() {
+            shard.set_capacity(split(capacity as u64, self.shards.len(), idx) as usize);
+        }
+    }
+
+    /// Change the maximum total weight of the values permitted in the cache, split across
+    /// shards.  See `LRUCache::set_max_weight`.
+    pub fn set_max_weight(&self, max_weight: u64) {
+        for (idx, shard) in self.shards.iter().enumerate() {
+            shard.set_max_weight(split(max_weight, self.shards.len(), idx));
+        }
+    }
+
+    /// Permit at most `max_pinned` values to be pinned, split across shards.  See
+    /// `LRUCache::set_max_pinned`.
+    pub fn set_max_pinned(&self, max_pinned: usize) {
+        for (idx, shard) in self.shards.iter().enumerate() {
+            shard.set_max_pinned(split(max_pinned as u64, self.shards.len(), idx) as usize);
+        }
+    }
+
+    /// Evict at most `max_evictions` values from a shard to make room for each new value.  See
+    /// `LRUCache::set_max_evictions`.
+    pub fn set_max_evictions(&self, max_evictions: usize) {
+        for shard in self.shards.iter() {
+            shard.set_max_evictions(max_evictions);
+        }
+    }
+
+    /// Evict each full shard down to `fraction` of its limits in one pass.  See
+    /// `LRUCache::set_low_watermark`.
+    pub fn set_low_watermark(&self, fraction: f64) {
+        for shard in self.shards.iter() {
+            shard.set_low_watermark(fraction);
+        }
+    }
+
+    /// Remove all values from the cache.
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            shard.clear();
+        }
+    }
+
+    /// Take a snapshot of the contents of every shard.  See `LRUCache::snapshot`.
+    ///
+    /// Each shard is locked only while its own snapshot is taken, so each is viewed at a slightly
+    /// different point in time.  Shards assign versions independently, and the snapshot's version
+    /// is the greatest of theirs.
+    pub fn snapshot(&self) -> Snapshot<K, V> {
+        Snapshot::concat(self.shards.iter().map(Cache::snapshot))
+    }
+
+    /// Iterate over the values in the cache, in arbitrary order.  See `LRUCache::iter`.
+    ///
+    /// Each shard is viewed at a slightly different point in time.
+    pub fn iter(&self) -> std::vec::IntoIter<ValueGuard<K, V>> {
+        self.shards.iter().flat_map(Cache::iter).collect::<Vec<_>>().into_iter()
+    }
+
+    /// Remove all values from the cache, returning them shard by shard.  See `LRUCache::drain`.
+    pub fn drain(&self) -> std::vec::IntoIter<(K, V)> {
+        self.shards.iter().flat_map(Cache::drain).collect::<Vec<_>>().into_iter()
+    }
+
+    /// Get the value for `key`.  See `LRUCache::get`.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.shard(key).get(key)
+    }
+
+    /// Get the value for `key` along with its version.  See `LRUCache::get_versioned`.
+    pub fn get_versioned<Q>(&self, key: &Q) -> Option<(V, u64)>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.shard(key).get_versioned(key)
+    }
+
+    /// Get a reference to the value for `key` without cloning it.  See `LRUCache::get_ref`.
+    pub fn get_ref<Q>(&self, key: &Q) -> Option<ValueGuard<K, V>>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.shard(key).get_ref(key)
+    }
+
+    /// Get a mutable reference to the value for `key`, to update it in place.  The key's shard
+    /// stays locked until the guard is dropped.  See `LRUCache::get_mut`.
+    pub fn get_mut<Q>(&self, key: &Q) -> Option<ValueGuardMut<'_, K, V, P, S>>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.shard(key).get_mut(key)
+    }
+
+    /// Get the values for `keys`, in the same order, locking each shard once.  See
+    /// `LRUCache::get_many`.
+    pub fn get_many<'a, Q, I>(&self, keys: I) -> Vec<Option<V>>
+        where I: IntoIterator<Item = &'a Q>, K: Borrow<Q>, Q: Hash + Eq + ?Sized + 'a {
+        let batches = self.batches(keys, |key| self.shard_index(*key));
+        self.run_batches(batches, |shard, keys| shard.get_many(keys))
+    }
+
+    /// Look up `key`, distinguishing negative entries from unknown keys.  See
+    /// `LRUCache::lookup`.
+    pub fn lookup<Q>(&self, key: &Q) -> CacheResult<V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.shard(key).lookup(key)
+    }
+
+    /// Get the value for `key`, including a stale value kept after expiring.  See
+    /// `LRUCache::get_allow_stale`.
+    pub fn get_allow_stale<Q>(&self, key: &Q) -> StaleResult<V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.shard(key).get_allow_stale(key)
+    }
+
+    /// Get the value for `key` without updating its recency.  See `LRUCache::peek`.
+    pub fn peek<Q>(&self, key: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.shard(key).peek(key)
+    }
+
+    /// Whether the cache holds a value for `key`, without updating its recency.  See
+    /// `LRUCache::contains_key`.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.shard(key).contains_key(key)
+    }
+
+    /// Mark the value for `key` as used without retrieving it.  See `LRUCache::touch`.
+    pub fn touch<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.shard(key).touch(key)
+    }
+
+    /// Get the value for `key`, waiting up to `timeout` for it to be inserted.  See
+    /// `LRUCache::wait_for`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn wait_for<Q>(&self, key: &Q, timeout: Duration) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.shard(key).wait_for(key, timeout)
+    }
+
+    /// Put `value` for `key`.  See `LRUCache::put`.
+    pub fn put(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).put(key, value)
+    }
+
+    /// Put `value` for `key` with a time-to-live of `ttl`.  See `LRUCache::put_with_ttl`.
+    pub fn put_with_ttl(&self, key: K, value: V, ttl: Duration) -> Option<V> {
+        self.shard(&key).put_with_ttl(key, value, ttl)
+    }
+
+    /// Put every key and value in `items`, locking each shard once.  See `LRUCache::put_many`.
+    pub fn put_many<I>(&self, items: I) -> Vec<Option<V>>
+        where I: IntoIterator<Item = (K, V)> {
+        let batches = self.batches(items, |(key, _)| self.shard_index(key));
+        self.run_batches(batches, |shard, items| shard.put_many(items))
+    }
+
+    /// Put every key and value in `items` using all of rayon's threads, such as to warm the cache
+    /// at startup.
+    ///
+    /// The items are partitioned by shard in parallel, and then each shard puts its own items,
+    /// locking once, while the others do the same.  Items for the same key are put in the order
+    /// of `items` if it is indexed, such as a `Vec`'s.
+    #[cfg(feature = "rayon")]
+    pub fn par_extend<I>(&self, items: I)
+        where I: IntoParallelIterator<Item = (K, V)>, K: Send, V: Send, Cache<K, V, P, S>: Sync,
+              S: Sync {
+        let new_batches = || -> Vec<Vec<(K, V)>> {
+            self.shards.iter().map(|_| Vec::new()).collect()
+        };
+        let batches = items.into_par_iter()
+            .fold(new_batches, |mut batches, item| {
+                batches[self.shard_index(&item.0)].push(item);
+                batches
+            })
+            .reduce(new_batches, |mut batches, other| {
+                for (batch, other) in batches.iter_mut().zip(other) {
+                    batch.extend(other);
+                }
+                batches
+            });
+
+        self.shards.par_iter().zip(batches).for_each(|(shard, batch)| {
+            shard.put_many(batch);
+        });
+    }
+
+    /// Put `value` for `key` with `priority`.  See `LRUCache::put_with_priority`.
+    pub fn put_with_priority(&self, key: K, value: V, priority: Priority) -> Option<V> {
+        self.shard(&key).put_with_priority(key, value, priority)
+    }
+
+    /// Put `value` for `key` as the least recently used value of its shard.  See
+    /// `LRUCache::put_cold`.
+    pub fn put_cold(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).put_cold(key, value)
+    }
+
+    /// Put `value` for `key` if its version is `expected_version`.  See
+    /// `LRUCache::put_if_version`.
+    pub fn put_if_version(&self, key: K, value: V, expected_version: u64) -> Result<u64, V> {
+        self.shard(&key).put_if_version(key, value, expected_version)
+    }
+
+    /// Put `value` for `key`, tied to `token`.  See `LRUCache::put_with_token`.
+    pub fn put_with_token(&self, key: K, value: V, token: &InvalidationToken) -> Option<V> {
+        self.shard(&key).put_with_token(key, value, token)
+    }
+
+    /// Record that `key` has no value, for `ttl`.  See `LRUCache::put_negative`.  Each shard keeps
+    /// at most its own capacity of negative entries.
+    pub fn put_negative(&self, key: K, ttl: Duration) {
+        self.shard(&key).put_negative(key, ttl)
+    }
+
+    /// Get the value for `key`, or compute it with `f` and insert it.  See
+    /// `LRUCache::get_or_insert_with`.
+    pub fn get_or_insert_with<F>(&self, key: K, f: F) -> V
+        where F: FnOnce() -> V {
+        self.shard(&key).get_or_insert_with(key, f)
+    }
+
+    /// Get the value for `key`, or compute it with the fallible `f` and insert it.  See
+    /// `LRUCache::try_get_or_insert_with`.
+    pub fn try_get_or_insert_with<F, E>(&self, key: K, f: F) -> Result<V, E>
+        where F: FnOnce() -> Result<V, E> {
+        self.shard(&key).try_get_or_insert_with(key, f)
+    }
+
+    /// Get the entry for `key`, for in-place inspection and update.  Only the shard responsible
+    /// for `key` is locked.  See `LRUCache::entry`.
+    pub fn entry(&self, key: K) -> Entry<'_, K, V, P, S> {
+        self.shard(&key).entry(key)
+    }
+
+    /// Remove `key`.  See `LRUCache::remove`.
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.shard(key).remove(key)
+    }
+
+    /// Remove `keys`, locking each shard once.  See `LRUCache::remove_many`.
+    pub fn remove_many<'a, Q, I>(&self, keys: I) -> Vec<Option<V>>
+        where I: IntoIterator<Item = &'a Q>, K: Borrow<Q>, Q: Hash + Eq + ?Sized + 'a {
+        let batches = self.batches(keys, |key| self.shard_index(*key));
+        self.run_batches(batches, |shard, keys| shard.remove_many(keys))
+    }
+
+    /// Pin the value for `key`, exempting it from eviction.  See `LRUCache::pin`.  Each shard has
+    /// its own share of the pinning budget.
+    pub fn pin<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.shard(key).pin(key)
+    }
+
+    /// Unpin the value for `key`.  See `LRUCache::unpin`.
+    pub fn unpin<Q>(&self, key: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.shard(key).unpin(key)
+    }
+
+    /// Remove all values for which `f(key, value)` returns `false`, shard by shard.  See
+    /// `LRUCache::retain`.
+    pub fn retain<F>(&self, mut f: F) -> usize
+        where F: FnMut(&K, &V) -> bool {
+        self.shards.iter().map(|shard| shard.retain(&mut f)).sum()
+    }
+
+    /// Remove every value in `group` from every shard.  See `LRUCache::invalidate_group`.
+    pub fn invalidate_group(&self, group: &str) -> usize {
+        self.shards.iter().map(|shard| shard.invalidate_group(group)).sum()
+    }
+
+    /// Remove every value put before `before` from every shard.  See
+    /// `LRUCache::invalidate_all_before`.
+    pub fn invalidate_all_before(&self, before: Timestamp) -> usize {
+        self.shards.iter().map(|shard| shard.invalidate_all_before(before)).sum()
+    }
+
+    /// Remove all expired values from every shard.  See `LRUCache::purge_expired`.
+    pub fn purge_expired(&self) -> usize {
+        self.shards.iter().map(Cache::purge_expired).sum()
+    }
+
+    /// Perform pending maintenance in every shard.  See `LRUCache::run_pending_tasks`.
+    pub fn run_pending_tasks(&self) -> usize {
+        self.shards.iter().map(Cache::run_pending_tasks).sum()
+    }
+
+    /// Start a thread which calls `run_pending_tasks` on `cache` every `period`.  See
+    /// `LRUCache::spawn_maintenance`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn spawn_maintenance(cache: &Arc<Self>, period: Duration) -> JoinHandle<()>
+        where Self: Send + Sync + 'static {
+        expiry::spawn_maintenance(cache, period, |cache: &Self| {
+            cache.run_pending_tasks();
+            !cache.shards[0].is_shut_down()
+        })
+    }
+
+    /// Shut every shard down.  See `LRUCache::shutdown`.
+    pub fn shutdown(&self) {
+        for shard in self.shards.iter() {
+            shard.shutdown();
+        }
+    }
+}
+
+impl <K, V, P, S> Extend<(K, V)> for ShardedLRUCache<K, V, P, S>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    /// Put each item of `iter`, in order, locking each shard once.  See `put_many`.
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        self.put_many(iter);
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl <K, V, P, S> ParallelExtend<(K, V)> for ShardedLRUCache<K, V, P, S>
+    where K: Eq + Hash + Clone + Send, V: Clone + Send, P: EvictionPolicy<K>, S: BuildHasher + Sync,
+          Cache<K, V, P, S>: Sync {
+    /// Put each item of `par_iter` using all of rayon's threads.  See `par_extend`.
+    fn par_extend<I: IntoParallelIterator<Item = (K, V)>>(&mut self, par_iter: I) {
+        ShardedLRUCache::par_extend(self, par_iter);
+    }
+}
+
+/// The number of shards to split `limit` across: `shards`, but no more than `limit` so that each
+/// shard's share is at least one, unless `limit` is zero.
+pub(crate) fn clamp_shards(shards: usize, limit: u64) -> usize {
+    (shards as u64).min(limit.max(1)) as usize
+}
+
+/// The share of `total` for shard `idx` of `shards`, dividing `total` as evenly as possible.
+pub(crate) fn split(total: u64, shards: usize, idx: usize) -> u64 {
+    let shards = shards as u64;
+    total / shards + if (idx as u64) < total % shards { 1 } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::BuildHasherDefault;
+
+    #[test]
+    fn capacity() {
+        let cache: ShardedLRUCache<u64, u64> = ShardedLRUCache::new(10, 4);
+        assert_eq!(cache.shard_count(), 4);
+        assert_eq!(cache.capacity(), 10);
+
+        // With less capacity than shards, there are fewer shards, each of which can hold a value.
+        let cache: ShardedLRUCache<u64, u64> = ShardedLRUCache::new(2, 4);
+        assert_eq!(cache.shard_count(), 2);
+        assert_eq!(cache.capacity(), 2);
+        for idx in 0..cache.shard_count() {
+            let key = (0..).find(|key| cache.shard_index(key) == idx).unwrap();
+            cache.put(key, key);
+            assert_eq!(cache.get(&key), Some(key));
+        }
+        assert_eq!(cache.len(), 2);
+        for key in 0..16 {
+            cache.put(key, key);
+        }
+        assert_eq!(cache.len(), 2);
+
+        let cache: ShardedLRUCache<u64, u64> = ShardedLRUCache::with_weigher(3, 8, |_, _| 1);
+        assert_eq!(cache.shard_count(), 3);
+        let cache: ShardedLRUCache<u64, u64> = ShardedLRUCache::new(0, 4);
+        assert_eq!(cache.shard_count(), 1);
+    }
+
+    #[test]
+    fn with_shard() {
+        let cache: ShardedLRUCache<u64, u64> = ShardedLRUCache::new(64, 4);
+        for key in 0..16 {
+            cache.put(key, key);
+        }
+
+        // Each key is in the shard given for it, which holds only keys of that shard.
+        for key in 0..16 {
+            assert_eq!(cache.with_shard(&key, |shard| shard.peek(&key)), Some(key));
+            let shard_keys: Vec<u64> = cache.with_shard(&key, |shard| {
+                shard.iter().map(|value| *value.key()).collect()
+            });
+            for other in shard_keys {
+                assert_eq!(cache.shard_index(&other), cache.shard_index(&key));
+            }
+        }
+
+        // Changes made through the shard are seen through the cache.
+        cache.with_shard(&3, |shard| shard.put(3, 30));
+        assert_eq!(cache.get(&3), Some(30));
+    }
+
+    #[test]
+    fn batch() {
+        let mut cache: ShardedLRUCache<u64, u64> = ShardedLRUCache::new(64, 4);
+        let old_values = cache.put_many((0..16).map(|idx| (idx, idx)));
+        assert_eq!(old_values, vec![None; 16]);
+        assert_eq!(cache.put_many(vec![(3, 30), (20, 20)]), vec![Some(3), None]);
+
+        let keys = [20, 3, 99, 0];
+        assert_eq!(cache.get_many(keys.iter()), vec![Some(20), Some(30), None, Some(0)]);
+        assert_eq!(cache.remove_many(keys.iter()), vec![Some(20), Some(30), None, Some(0)]);
+        assert_eq!(cache.len(), 14);
+
+        cache.extend(vec![(0, 0)]);
+        assert_eq!(cache.len(), 15);
+    }
+
+    #[test]
+    fn with_hasher() {
+        type FixedState = BuildHasherDefault<DefaultHasher>;
+
+        let cache: ShardedLRUCache<u64, u64, Lru, FixedState> =
+            ShardedLRUCache::with_hasher(16, 4, FixedState::default());
+        assert_eq!(cache.capacity(), 16);
+        for idx in 0..4 {
+            cache.put(idx, idx);
+        }
+        for idx in 0..4 {
+            assert_eq!(cache.get(&idx), Some(idx));
+        }
+    }
+
+    #[test]
+    fn put_get() {
+        let cache: ShardedLRUCache<u64, u64> = ShardedLRUCache::new(1024, 8);
+        for idx in 0..512 {
+            cache.put(idx, idx * 2);
+        }
+
+        assert_eq!(cache.len(), 512);
+        for idx in 0..512 {
+            assert_eq!(cache.get(&idx), Some(idx * 2));
+        }
+
+        let mut values: Vec<_> = cache.iter().map(|value| *value).collect();
+        values.sort();
+        assert_eq!(values, (0..512).map(|idx| idx * 2).collect::<Vec<_>>());
+
+        assert_eq!(cache.remove(&7), Some(14));
+        assert_eq!(cache.get(&7), None);
+        assert_eq!(cache.len(), 511);
+
+        assert_eq!(cache.drain().len(), 511);
+        assert!(cache.is_empty());
+
+        cache.put(7, 14);
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn snapshot() {
+        let cache: ShardedLRUCache<u64, u64> = ShardedLRUCache::new(64, 4);
+        for idx in 0..10 {
+            cache.put(idx, idx);
+        }
+
+        let snapshot = cache.snapshot();
+        cache.put(0, 100);
+        cache.remove(&1);
+        cache.put(10, 10);
+
+        let mut contents: Vec<_> = snapshot.iter().map(|(k, v)| (*k, *v)).collect();
+        contents.sort();
+        assert_eq!(contents, (0..10).map(|idx| (idx, idx)).collect::<Vec<_>>());
+        assert!(cache.get_versioned(&10).unwrap().1 > 0);
+        assert!(snapshot.version() > 0);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_extend() {
+        use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+        let cache: ShardedLRUCache<u64, u64> = ShardedLRUCache::new(10_000, 8);
+        cache.par_extend((0..10_000u64).into_par_iter().map(|idx| (idx % 5000, idx)));
+        assert_eq!(cache.len(), 5000);
+        // Later items for a key replace earlier ones.
+        assert_eq!(cache.get(&1), Some(5001));
+
+        let snapshot = cache.snapshot();
+        assert_eq!(snapshot.par_iter().map(|(_, value)| *value).sum::<u64>(),
+                   (5000..10_000).sum::<u64>());
+    }
+
+    #[test]
+    fn bounded() {
+        let cache: ShardedLRUCache<u64, u64> = ShardedLRUCache::new(64, 4);
+        for idx in 0..1000 {
+            cache.put(idx, idx);
+        }
+
+        assert!(cache.len() <= 64);
+
+        cache.set_capacity(10);
+        assert_eq!(cache.capacity(), 10);
+        assert!(cache.len() <= 10);
+    }
+
+    #[test]
+    fn weigher() {
+        let weigher = |_: &u64, value: &Vec<u8>| value.len() as u64;
+        let cache: ShardedLRUCache<u64, Vec<u8>> = ShardedLRUCache::with_weigher(1000, 4, weigher);
+        assert_eq!(cache.max_weight(), 1000);
+
+        for idx in 0..100 {
+            cache.put(idx, vec![0; 50]);
+        }
+
+        assert!(cache.weight() <= 1000);
+        assert_eq!(cache.weight(), 50 * cache.len() as u64);
+    }
+
+    #[test]
+    fn eviction_listener() {
+        let mut cache: ShardedLRUCache<u64, u64> = ShardedLRUCache::new(64, 4);
+        let evicted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        {
+            let evicted = Arc::clone(&evicted);
+            cache.set_eviction_listener(move |key, _, cause| {
+                evicted.lock().unwrap().push((key, cause))
+            });
+        }
+
+        for idx in 0..1000 {
+            cache.put(idx, idx);
+        }
+
+        let evicted = evicted.lock().unwrap();
+        assert_eq!(evicted.len() + cache.len(), 1000);
+        assert!(evicted.iter().all(|&(_, cause)| cause == EvictionCause::Capacity));
+    }
+
+    #[test]
+    fn stats() {
+        let mut cache: ShardedLRUCache<u64, u64> = ShardedLRUCache::new(64, 4);
+        cache.enable_stats();
+        for idx in 0..100 {
+            cache.put(idx, idx);
+        }
+        for idx in 0..100 {
+            cache.get(&idx);
+        }
+
+        let stats = cache.stats();
+        assert_eq!(stats.insertions, 100);
+        assert_eq!(stats.evictions as usize, 100 - cache.len());
+        assert_eq!(stats.hits as usize, cache.len());
+        assert_eq!(stats.requests(), 100);
+
+        cache.reset_stats();
+        assert_eq!(cache.stats(), CacheStats::default());
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_shards() {
+        let _cache: ShardedLRUCache<u64, u64> = ShardedLRUCache::new(64, 0);
+    }
+}
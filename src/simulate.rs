@@ -0,0 +1,152 @@
+use std::hash::Hash;
+
+use crate::cache::Cache;
+use crate::policy::EvictionPolicy;
+use crate::stats::CacheStats;
+
+/// Replay is a cache being simulated, with its value type erased so that caches with different
+/// policies can be simulated together.
+trait Replay<K> {
+    fn access(&self, key: &K);
+
+    fn stats(&self) -> CacheStats;
+}
+
+impl <K: Eq + Hash + Clone, P: EvictionPolicy<K>> Replay<K> for Cache<K, (), P> {
+    fn access(&self, key: &K) {
+        if self.get(key).is_none() {
+            self.put(key.clone(), ());
+        }
+    }
+
+    fn stats(&self) -> CacheStats {
+        Cache::stats(self)
+    }
+}
+
+struct Candidate<K> {
+    name: String,
+    capacity: usize,
+    cache: Box<dyn Replay<K>>
+}
+
+/// SimulationResult is the outcome of replaying a trace against one candidate configuration.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimulationResult {
+    /// The name the configuration was added with.
+    pub name: String,
+    pub capacity: usize,
+    /// The hits, misses and evictions the configuration would have had.
+    pub stats: CacheStats
+}
+
+impl SimulationResult {
+    pub fn hit_ratio(&self) -> f64 {
+        self.stats.hit_ratio()
+    }
+}
+
+/// Simulation replays a trace of accessed keys against several candidate cache configurations at
+/// once, to find the capacity and policy which would serve real traffic best.
+///
+/// Each candidate is a cache of ghost entries, which store keys but no values.  An access which
+/// misses puts the key, as a read-through cache would.
+///
+/// ```
+/// use cache::policy::{Lirs, Lru};
+/// use cache::simulate::Simulation;
+///
+/// let mut simulation = Simulation::new();
+/// for &capacity in [10, 100, 1000].iter() {
+///     simulation.add("lru", capacity, Lru);
+///     simulation.add("lirs", capacity, Lirs::default());
+/// }
+///
+/// simulation.replay((0..10000u64).map(|i| i * i % 997));
+/// for result in simulation.results() {
+///     println!("{} {}: {:.2}", result.name, result.capacity, result.hit_ratio());
+/// }
+/// ```
+pub struct Simulation<K> {
+    candidates: Vec<Candidate<K>>
+}
+
+impl <K: Eq + Hash + Clone + 'static> Simulation<K> {
+    pub fn new() -> Simulation<K> {
+        Simulation { candidates: Vec::new() }
+    }
+
+    /// Add a candidate with space for `capacity` keys, which evicts the keys chosen by `policy`.
+    ///
+    /// `name` identifies the candidate's result, and need not be unique among candidates with
+    /// different capacities.
+    pub fn add<P>(&mut self, name: &str, capacity: usize, policy: P) -> &mut Simulation<K>
+        where P: EvictionPolicy<K> + 'static {
+        let mut cache = Cache::with_policy(capacity, policy);
+        cache.enable_stats();
+        self.candidates.push(Candidate {
+            name: name.to_string(),
+            capacity,
+            cache: Box::new(cache)
+        });
+        self
+    }
+
+    /// Access `key` in every candidate.
+    pub fn access(&mut self, key: &K) {
+        for candidate in self.candidates.iter() {
+            candidate.cache.access(key);
+        }
+    }
+
+    /// Access every key of `trace` in order.
+    pub fn replay<I: IntoIterator<Item = K>>(&mut self, trace: I) {
+        for key in trace {
+            self.access(&key);
+        }
+    }
+
+    /// The results of every candidate so far, in the order they were added.
+    pub fn results(&self) -> Vec<SimulationResult> {
+        self.candidates.iter()
+            .map(|candidate| SimulationResult {
+                name: candidate.name.clone(),
+                capacity: candidate.capacity,
+                stats: candidate.cache.stats()
+            })
+            .collect()
+    }
+}
+
+impl <K: Eq + Hash + Clone + 'static> Default for Simulation<K> {
+    fn default() -> Simulation<K> {
+        Simulation::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::{Lirs, Lru};
+
+    #[test]
+    fn simulate() {
+        let mut simulation = Simulation::new();
+        simulation.add("lru", 5, Lru).add("lru", 10, Lru).add("lirs", 10, Lirs::new(0.8));
+
+        // A loop over 12 keys, which only LIRS can partly keep.
+        let trace: Vec<u64> = (0..120).map(|i| i % 12).collect();
+        simulation.replay(trace);
+
+        let results = simulation.results();
+        assert_eq!(results.len(), 3);
+        assert_eq!((results[0].name.as_str(), results[0].capacity), ("lru", 5));
+        assert_eq!(results[0].stats.requests(), 120);
+        assert_eq!(results[0].stats.hits, 0);
+        assert_eq!(results[1].stats.hits, 0);
+        assert!(results[2].stats.hits > 60, "{:?}", results[2]);
+
+        simulation.access(&11);
+        assert_eq!(simulation.results()[2].stats.requests(), 121);
+    }
+}
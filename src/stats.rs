@@ -0,0 +1,430 @@
+use std::collections::VecDeque;
+use std::ops::Add;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::clock::{Clock, Timestamp};
+use crate::listener::EvictionCause;
+
+/// The number of the most recent evictions whose ages are kept by `EvictionAgeRecorder`.
+const EVICTION_AGE_SAMPLES: usize = 1024;
+
+/// A snapshot of a cache's activity counters, returned by `LRUCache::stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups which found a value.
+    pub hits: u64,
+    /// Lookups which did not find a value.
+    pub misses: u64,
+    /// Values stored by a `put` (including replacements).
+    pub insertions: u64,
+    /// Values evicted to make room for others.
+    pub evictions: u64,
+    /// Values discarded because they expired (or their `InvalidationToken` was invalidated).
+    pub expirations: u64,
+    /// Values replaced by a `put` for the same key.
+    pub replaced: u64
+}
+
+impl CacheStats {
+    /// The total number of lookups.
+    pub fn requests(&self) -> u64 {
+        self.hits + self.misses
+    }
+
+    /// The fraction of lookups which found a value, or `1.0` if there have been no lookups.
+    pub fn hit_ratio(&self) -> f64 {
+        match self.requests() {
+            0 => 1.0,
+            requests => self.hits as f64 / requests as f64
+        }
+    }
+}
+
+impl Add for CacheStats {
+    type Output = CacheStats;
+
+    fn add(self, other: CacheStats) -> CacheStats {
+        CacheStats {
+            hits: self.hits + other.hits,
+            misses: self.misses + other.misses,
+            insertions: self.insertions + other.insertions,
+            evictions: self.evictions + other.evictions,
+            expirations: self.expirations + other.expirations,
+            replaced: self.replaced + other.replaced
+        }
+    }
+}
+
+/// A key among the most used in a cache, returned by `LRUCache::hot_keys`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HotKey<K> {
+    pub key: K,
+    /// The estimated number of recent accesses to `key`, from the cache's admission filter, or
+    /// `None` if it has none.  See `TinyLfu`.
+    pub frequency: Option<u8>
+}
+
+/// The ages of the values a cache evicted most recently to make room for others, returned by
+/// `LRUCache::eviction_ages`.  A value's age is the time from when it was put until it was
+/// evicted.
+///
+/// If values are evicted young, before they could be read again, a larger cache may raise the hit
+/// ratio.  If they are evicted old yet the hit ratio is low, the workload has little reuse, and a
+/// larger cache would not help.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EvictionAges {
+    /// Youngest first.
+    ages: Vec<Duration>
+}
+
+impl EvictionAges {
+    /// The number of evictions sampled.
+    pub fn len(&self) -> usize {
+        self.ages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ages.is_empty()
+    }
+
+    /// The age which `fraction` of the sampled evictions were no older than, such as `0.5` for
+    /// the median age, or `None` if there are no samples.
+    ///
+    /// # Panics
+    ///
+    /// If `fraction` is not between 0 and 1.
+    pub fn quantile(&self, fraction: f64) -> Option<Duration> {
+        assert!((0.0..=1.0).contains(&fraction), "fraction must be between 0 and 1");
+        let rank = (fraction * self.ages.len() as f64).ceil() as usize;
+        self.ages.get(rank.saturating_sub(1)).copied()
+    }
+
+    /// The sampled ages, youngest first.
+    pub fn ages(&self) -> &[Duration] {
+        &self.ages
+    }
+}
+
+impl Add for EvictionAges {
+    type Output = EvictionAges;
+
+    fn add(mut self, other: EvictionAges) -> EvictionAges {
+        self.ages.extend(other.ages);
+        self.ages.sort();
+        self
+    }
+}
+
+/// EvictionAgeRecorder keeps the ages of the values most recently evicted from a cache which has
+/// eviction ages enabled.  See `LRUCache::enable_eviction_ages`.
+pub(crate) struct EvictionAgeRecorder {
+    clock: Arc<dyn Clock>,
+    /// Oldest eviction first.
+    ages: VecDeque<Duration>
+}
+
+impl EvictionAgeRecorder {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> EvictionAgeRecorder {
+        EvictionAgeRecorder {
+            clock,
+            ages: VecDeque::with_capacity(EVICTION_AGE_SAMPLES)
+        }
+    }
+
+    /// Record the eviction of a value put at `put_at`.
+    pub(crate) fn record(&mut self, put_at: Timestamp) {
+        if self.ages.len() == EVICTION_AGE_SAMPLES {
+            self.ages.pop_front();
+        }
+        self.ages.push_back(self.clock.now().saturating_duration_since(put_at));
+    }
+
+    pub(crate) fn snapshot(&self) -> EvictionAges {
+        let mut ages: Vec<Duration> = self.ages.iter().copied().collect();
+        ages.sort();
+        EvictionAges { ages }
+    }
+}
+
+/// MetricsRecorder receives a cache's statistics as they change, to forward them to a metrics
+/// system, such as the facade of the `metrics` crate.  See `LRUCache::set_metrics_recorder`.
+///
+/// Methods may be called while the cache is locked, so they should be quick, and must not use the
+/// cache.
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+/// use cache::cache::LRUCache;
+/// use cache::listener::EvictionCause;
+/// use cache::stats::MetricsRecorder;
+///
+/// // A bridge to the `metrics` facade would call `metrics::counter!(...).increment(1)` and
+/// // `metrics::gauge!(...).increment(...)` instead.
+/// #[derive(Default)]
+/// struct Metrics {
+///     misses: AtomicU64,
+///     size: AtomicI64
+/// }
+///
+/// impl MetricsRecorder for Metrics {
+///     fn record_lookup(&self, hit: bool) {
+///         if !hit {
+///             self.misses.fetch_add(1, Ordering::Relaxed);
+///         }
+///     }
+///
+///     fn record_insertion(&self) {}
+///
+///     fn record_eviction(&self, _cause: EvictionCause) {}
+///
+///     fn record_size_change(&self, len_delta: i64, _weight_delta: i64) {
+///         self.size.fetch_add(len_delta, Ordering::Relaxed);
+///     }
+/// }
+///
+/// let metrics = Arc::new(Metrics::default());
+/// let mut cache = LRUCache::new(2);
+/// cache.set_metrics_recorder(metrics.clone());
+/// for key in 0..3 {
+///     cache.get_or_insert_with(key, || key);
+/// }
+/// assert_eq!(metrics.misses.load(Ordering::Relaxed), 3);
+/// assert_eq!(metrics.size.load(Ordering::Relaxed), 2);
+/// ```
+pub trait MetricsRecorder: Send + Sync {
+    /// Count a lookup which found a value, if `hit`, or which did not.  See `CacheStats::hits`.
+    fn record_lookup(&self, hit: bool);
+
+    /// Count a value stored by a `put`.  See `CacheStats::insertions`.
+    fn record_insertion(&self);
+
+    /// Count a value which left the cache for `cause`.
+    fn record_eviction(&self, cause: EvictionCause);
+
+    /// Adjust the number of values in the cache by `len_delta`, and their total weight by
+    /// `weight_delta`, as gauges.
+    ///
+    /// Every shard of a `ShardedLRUCache` reports its own changes, so the gauges are the totals
+    /// across shards.
+    fn record_size_change(&self, len_delta: i64, weight_delta: i64);
+}
+
+/// StatsCounter accumulates `CacheStats` for a cache which has them enabled, and forwards them
+/// to its `MetricsRecorder`, if any.
+///
+/// Counters are atomic so that they can be read without taking the cache's lock.
+#[derive(Default)]
+pub(crate) struct StatsCounter {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    insertions: AtomicU64,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
+    replaced: AtomicU64,
+    recorder: Option<Arc<dyn MetricsRecorder>>,
+    /// The number of values and total weight last reported to `recorder`.
+    reported_len: AtomicU64,
+    reported_weight: AtomicU64
+}
+
+impl StatsCounter {
+    /// Continue counting from `stats`, forwarding changes to `recorder`.
+    pub(crate) fn new(stats: CacheStats, recorder: Option<Arc<dyn MetricsRecorder>>)
+        -> StatsCounter {
+        StatsCounter {
+            hits: AtomicU64::new(stats.hits),
+            misses: AtomicU64::new(stats.misses),
+            insertions: AtomicU64::new(stats.insertions),
+            evictions: AtomicU64::new(stats.evictions),
+            expirations: AtomicU64::new(stats.expirations),
+            replaced: AtomicU64::new(stats.replaced),
+            recorder,
+            ..StatsCounter::default()
+        }
+    }
+
+    pub(crate) fn recorder(&self) -> Option<Arc<dyn MetricsRecorder>> {
+        self.recorder.clone()
+    }
+
+    pub(crate) fn record_lookup(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if let Some(ref recorder) = self.recorder {
+            recorder.record_lookup(hit);
+        }
+    }
+
+    pub(crate) fn record_insertion(&self) {
+        self.insertions.fetch_add(1, Ordering::Relaxed);
+        if let Some(ref recorder) = self.recorder {
+            recorder.record_insertion();
+        }
+    }
+
+    /// Report the cache's number of values and total weight to the recorder, if they changed
+    /// since last reported.  Called with the cache locked, so that changes are reported in order.
+    pub(crate) fn record_size(&self, len: usize, weight: u64) {
+        if let Some(ref recorder) = self.recorder {
+            let reported_len = self.reported_len.swap(len as u64, Ordering::Relaxed);
+            let reported_weight = self.reported_weight.swap(weight, Ordering::Relaxed);
+            let len_delta = len as i64 - reported_len as i64;
+            let weight_delta = weight as i64 - reported_weight as i64;
+            if len_delta != 0 || weight_delta != 0 {
+                recorder.record_size_change(len_delta, weight_delta);
+            }
+        }
+    }
+
+    pub(crate) fn record_eviction(&self, cause: EvictionCause) {
+        if let Some(ref recorder) = self.recorder {
+            recorder.record_eviction(cause);
+        }
+        let counter = match cause {
+            EvictionCause::Capacity => &self.evictions,
+            EvictionCause::Expired => &self.expirations,
+            EvictionCause::Replaced => &self.replaced,
+            EvictionCause::Removed => return
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            insertions: self.insertions.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            expirations: self.expirations.load(Ordering::Relaxed),
+            replaced: self.replaced.load(Ordering::Relaxed)
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        for counter in [&self.hits, &self.misses, &self.insertions, &self.evictions,
+                        &self.expirations, &self.replaced].iter() {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Format `stats`, `len` and `weight` in the Prometheus text exposition format, naming each
+/// metric with the prefix `name`.
+#[cfg(feature = "prometheus")]
+pub(crate) fn prometheus_text(name: &str, stats: &CacheStats, len: usize, weight: u64) -> String {
+    let metrics = [
+        ("hits_total", "counter", "Lookups which found a value.", stats.hits),
+        ("misses_total", "counter", "Lookups which did not find a value.", stats.misses),
+        ("insertions_total", "counter", "Values stored.", stats.insertions),
+        ("evictions_total", "counter", "Values evicted to make room for others.",
+         stats.evictions),
+        ("expirations_total", "counter", "Values discarded because they expired.",
+         stats.expirations),
+        ("size", "gauge", "The number of values in the cache.", len as u64),
+        ("weight", "gauge", "The total weight of the values in the cache.", weight)
+    ];
+
+    let mut text = String::new();
+    for (metric, kind, help, value) in metrics.iter() {
+        text.push_str(&format!("# HELP {}_{} {}\n", name, metric, help));
+        text.push_str(&format!("# TYPE {}_{} {}\n", name, metric, kind));
+        text.push_str(&format!("{}_{} {}\n", name, metric, value));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sharded::ShardedLRUCache;
+    use crate::testing::MockClock;
+
+    #[test]
+    fn eviction_ages() {
+        let clock = Arc::new(MockClock::new());
+        let mut recorder = EvictionAgeRecorder::new(clock.clone());
+        assert_eq!(recorder.snapshot().quantile(0.5), None);
+
+        let put_at = clock.now();
+        for _ in 0..EVICTION_AGE_SAMPLES + 4 {
+            clock.advance(Duration::from_secs(1));
+            recorder.record(put_at);
+        }
+
+        // Only the most recent evictions are kept.
+        let ages = recorder.snapshot();
+        assert_eq!(ages.len(), EVICTION_AGE_SAMPLES);
+        assert_eq!(ages.quantile(0.0), Some(Duration::from_secs(5)));
+        assert_eq!(ages.quantile(0.5), Some(Duration::from_secs(516)));
+        assert_eq!(ages.quantile(1.0), Some(Duration::from_secs(1028)));
+
+        let merged = ages + EvictionAges { ages: vec![Duration::from_secs(1)] };
+        assert_eq!(merged.ages()[0], Duration::from_secs(1));
+    }
+
+    /// Accumulates what a cache reports to its `MetricsRecorder`.
+    #[derive(Default)]
+    struct Recorded {
+        stats: std::sync::Mutex<CacheStats>,
+        removed: std::sync::atomic::AtomicU64,
+        len: std::sync::atomic::AtomicI64,
+        weight: std::sync::atomic::AtomicI64
+    }
+
+    impl MetricsRecorder for Recorded {
+        fn record_lookup(&self, hit: bool) {
+            let mut stats = self.stats.lock().unwrap();
+            if hit { stats.hits += 1 } else { stats.misses += 1 }
+        }
+
+        fn record_insertion(&self) {
+            self.stats.lock().unwrap().insertions += 1;
+        }
+
+        fn record_eviction(&self, cause: EvictionCause) {
+            let mut stats = self.stats.lock().unwrap();
+            match cause {
+                EvictionCause::Capacity => stats.evictions += 1,
+                EvictionCause::Expired => stats.expirations += 1,
+                EvictionCause::Replaced => stats.replaced += 1,
+                EvictionCause::Removed => {
+                    self.removed.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        fn record_size_change(&self, len_delta: i64, weight_delta: i64) {
+            self.len.fetch_add(len_delta, Ordering::Relaxed);
+            self.weight.fetch_add(weight_delta, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn metrics_recorder() {
+        let recorded = Arc::new(Recorded::default());
+        let mut cache: ShardedLRUCache<u64, u64> = ShardedLRUCache::with_weigher(100, 4, |_, &v| v);
+        cache.put(0, 1);
+        cache.set_metrics_recorder(recorded.clone());
+        for key in 1..40 {
+            cache.put(key, key % 7);
+            cache.get(&(key / 2));
+        }
+        cache.put(3, 2);
+        cache.remove(&5);
+
+        // The recorder sees every change, and the size of every shard.
+        assert_eq!(*recorded.stats.lock().unwrap(), cache.stats());
+        assert_eq!(recorded.len.load(Ordering::Relaxed), cache.len() as i64);
+        assert_eq!(recorded.weight.load(Ordering::Relaxed), cache.weight() as i64);
+
+        // Removals are reported whether or not the cache has listeners.
+        let removed = recorded.removed.load(Ordering::Relaxed) + cache.len() as u64;
+        cache.clear();
+        assert_eq!(recorded.removed.load(Ordering::Relaxed), removed);
+        assert_eq!(recorded.len.load(Ordering::Relaxed), 0);
+        assert_eq!(recorded.weight.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::HashSet;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use crate::cache::Cache;
+use crate::listener::EvictionCause;
+use crate::policy::{EvictionPolicy, Lru};
+
+/// Store is the backing store behind a `CacheLayer`, such as a database.
+pub trait Store<K, V> {
+    type Error;
+
+    /// Read the value for `key`, or `None` if the store has no value for it.
+    fn load(&self, key: &K) -> Result<Option<V>, Self::Error>;
+
+    /// Write `value` for `key`.
+    fn store(&self, key: &K, value: &V) -> Result<(), Self::Error>;
+
+    /// Delete the value for `key`, if any.
+    fn delete(&self, key: &K) -> Result<(), Self::Error>;
+}
+
+/// When a `CacheLayer` writes values to its store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteMode {
+    /// Write each value to the store before caching it.
+    Through,
+    /// Cache each value immediately, and write it to the store only when it leaves the cache or
+    /// the layer is flushed.
+    Back
+}
+
+/// A failed `Store::load`, or a key the store has no value for.
+enum LoadError<E> {
+    Missing,
+    Store(E)
+}
+
+/// CacheLayer puts a `Cache` in front of a `Store`.
+///
+/// Reads are served from the cache, and read through to the store on a miss.  Writes go to the
+/// store according to the layer's `WriteMode`.
+///
+/// # Write-back
+///
+/// In `WriteMode::Back`, values which have been put but not yet written are dirty.  Dirty values
+/// are written when they are evicted (or expire), by the next operation on the layer, which
+/// returns any error from the store.  Call `flush` to write all dirty values, or `shutdown` before
+/// dropping the layer.  Dropping the layer writes the dirty values still in the cache, but any
+/// error from the store is lost.
+pub struct CacheLayer<K, V, T, P = Lru, S = RandomState>
+    where K: Eq + Hash + Clone, V: Clone, T: Store<K, V>, P: EvictionPolicy<K>, S: BuildHasher {
+    cache: Cache<K, V, P, S>,
+    store: T,
+    mode: WriteMode,
+    /// Keys whose cached values have not been written to the store.
+    dirty: Arc<Mutex<HashSet<K>>>,
+    /// Dirty values which have left the cache, waiting to be written to the store.
+    pending: Arc<Mutex<Vec<(K, V)>>>
+}
+
+impl <K, V, T, P, S> CacheLayer<K, V, T, P, S>
+    where K: Eq + Hash + Clone + Send + 'static, V: Clone + Send + 'static, T: Store<K, V>,
+          P: EvictionPolicy<K>, S: BuildHasher {
+    /// Put `cache` in front of `store`.
+    ///
+    /// The layer registers its own eviction listener with `cache`, replacing any other.
+    pub fn new(mut cache: Cache<K, V, P, S>, store: T, mode: WriteMode)
+        -> CacheLayer<K, V, T, P, S> {
+        let dirty = Arc::new(Mutex::new(HashSet::new()));
+        let pending = Arc::new(Mutex::new(Vec::new()));
+
+        let (listener_dirty, listener_pending) = (Arc::clone(&dirty), Arc::clone(&pending));
+        cache.set_eviction_listener(move |key, value, cause| {
+            // A replaced value is superseded by the new one, which inherits its dirtiness.
+            if cause != EvictionCause::Replaced && listener_dirty.lock().unwrap().remove(&key) {
+                listener_pending.lock().unwrap().push((key, value));
+            }
+        });
+
+        CacheLayer {
+            cache,
+            store,
+            mode,
+            dirty,
+            pending
+        }
+    }
+
+    pub fn cache(&self) -> &Cache<K, V, P, S> {
+        &self.cache
+    }
+
+    pub fn store(&self) -> &T {
+        &self.store
+    }
+
+    /// Get the value for `key` from the cache, or load it from the store and cache it.
+    ///
+    /// Concurrent misses on the same key load it once; see `LRUCache::get_or_insert_with`.
+    ///
+    /// # Returns
+    ///
+    /// The value, or `None` if neither the cache nor the store has a value for `key`.
+    pub fn get(&self, key: &K) -> Result<Option<V>, T::Error> {
+        let result = self.cache.try_get_or_insert_with(key.clone(), || {
+            match self.store.load(key) {
+                Ok(Some(value)) => Ok(value),
+                Ok(None) => Err(LoadError::Missing),
+                Err(err) => Err(LoadError::Store(err))
+            }
+        });
+        self.write_pending()?;
+
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(LoadError::Missing) => Ok(None),
+            Err(LoadError::Store(err)) => Err(err)
+        }
+    }
+
+    /// Put `value` for `key`, writing it to the store according to the layer's `WriteMode`.
+    ///
+    /// In `WriteMode::Through`, nothing is cached if the store fails.
+    pub fn put(&self, key: K, value: V) -> Result<(), T::Error> {
+        match self.mode {
+            WriteMode::Through => self.store.store(&key, &value)?,
+            // Mark the key before caching it, so that it is written if evicted immediately.
+            WriteMode::Back => {
+                self.dirty.lock().unwrap().insert(key.clone());
+            }
+        }
+
+        self.cache.put(key, value);
+        self.write_pending()
+    }
+
+    /// Remove `key` from both the cache and the store.
+    ///
+    /// # Returns
+    ///
+    /// The cached value for `key`, if any.
+    pub fn remove(&self, key: &K) -> Result<Option<V>, T::Error> {
+        self.dirty.lock().unwrap().remove(key);
+        let value = self.cache.remove(key);
+        self.store.delete(key)?;
+        self.write_pending()?;
+        Ok(value)
+    }
+}
+
+impl <K, V, T, P, S> CacheLayer<K, V, T, P, S>
+    where K: Eq + Hash + Clone, V: Clone, T: Store<K, V>, P: EvictionPolicy<K>, S: BuildHasher {
+    /// Write every dirty value to the store.  Does nothing in `WriteMode::Through`.
+    ///
+    /// If the store fails, the values which were not written remain dirty.
+    pub fn flush(&self) -> Result<(), T::Error> {
+        // Expired values can no longer be read from the cache, so send them to `pending`.
+        self.cache.purge_expired();
+
+        let dirty = mem::take(&mut *self.dirty.lock().unwrap());
+        let mut keys = dirty.iter();
+        while let Some(key) = keys.next() {
+            let value = match self.cache.peek(key) {
+                Some(value) => value,
+                // The value left the cache after `dirty` was taken, so it is not in `pending`.
+                None => continue
+            };
+
+            if let Err(err) = self.store.store(key, &value) {
+                let mut unwritten = self.dirty.lock().unwrap();
+                unwritten.insert(key.clone());
+                unwritten.extend(keys.cloned());
+                return Err(err);
+            }
+        }
+
+        self.write_pending()
+    }
+
+    /// Write every dirty value to the store, and then shut the cache down.  See `flush` and
+    /// `LRUCache::shutdown`.
+    ///
+    /// If the store fails, the cache is not shut down, and the values which were not written
+    /// remain dirty.
+    pub fn shutdown(&self) -> Result<(), T::Error> {
+        self.flush()?;
+        self.cache.shutdown();
+        // Values put since the flush are dirty when the shutdown removes them.
+        self.write_pending()
+    }
+
+    /// Write dirty values which have left the cache to the store.
+    fn write_pending(&self) -> Result<(), T::Error> {
+        let pending = mem::take(&mut *self.pending.lock().unwrap());
+        let mut entries = pending.into_iter();
+        while let Some((key, value)) = entries.next() {
+            if let Err(err) = self.store.store(&key, &value) {
+                let mut unwritten = self.pending.lock().unwrap();
+                unwritten.push((key, value));
+                unwritten.extend(entries);
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl <K, V, T, P, S> Drop for CacheLayer<K, V, T, P, S>
+    where K: Eq + Hash + Clone, V: Clone, T: Store<K, V>, P: EvictionPolicy<K>, S: BuildHasher {
+    /// Write the dirty values, ignoring any error from the store.
+    fn drop(&mut self) {
+        drop(self.shutdown());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::cache::LRUCache;
+
+    #[derive(Default)]
+    struct MapStore {
+        values: Arc<Mutex<HashMap<u64, u64>>>,
+        loads: Mutex<usize>
+    }
+
+    impl Store<u64, u64> for MapStore {
+        type Error = ();
+
+        fn load(&self, key: &u64) -> Result<Option<u64>, ()> {
+            *self.loads.lock().unwrap() += 1;
+            Ok(self.values.lock().unwrap().get(key).cloned())
+        }
+
+        fn store(&self, key: &u64, value: &u64) -> Result<(), ()> {
+            self.values.lock().unwrap().insert(*key, *value);
+            Ok(())
+        }
+
+        fn delete(&self, key: &u64) -> Result<(), ()> {
+            self.values.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    impl MapStore {
+        fn get(&self, key: u64) -> Option<u64> {
+            self.values.lock().unwrap().get(&key).cloned()
+        }
+    }
+
+    #[test]
+    fn write_through() {
+        let layer = CacheLayer::new(LRUCache::new(2), MapStore::default(), WriteMode::Through);
+        layer.put(1, 10).unwrap();
+        assert_eq!(layer.store().get(1), Some(10));
+        assert_eq!(layer.get(&1), Ok(Some(10)));
+        assert_eq!(*layer.store().loads.lock().unwrap(), 0);
+
+        layer.cache().clear();
+        assert_eq!(layer.get(&1), Ok(Some(10)));
+        assert_eq!(layer.get(&2), Ok(None));
+        assert_eq!(*layer.store().loads.lock().unwrap(), 2);
+
+        assert_eq!(layer.remove(&1), Ok(Some(10)));
+        assert_eq!(layer.store().get(1), None);
+    }
+
+    #[test]
+    fn write_back() {
+        let layer = CacheLayer::new(LRUCache::new(2), MapStore::default(), WriteMode::Back);
+        layer.put(1, 10).unwrap();
+        layer.put(1, 11).unwrap();
+        layer.put(2, 20).unwrap();
+        assert_eq!(layer.store().get(1), None);
+
+        // Evicting a dirty value writes it.
+        layer.put(3, 30).unwrap();
+        assert_eq!(layer.store().get(1), Some(11));
+        assert_eq!(layer.store().get(2), None);
+
+        layer.flush().unwrap();
+        assert_eq!(layer.store().get(2), Some(20));
+        assert_eq!(layer.store().get(3), Some(30));
+
+        // Clean values are not written again.
+        layer.store().delete(&2).unwrap();
+        layer.put(4, 40).unwrap();
+        assert_eq!(layer.store().get(2), None);
+
+        // Dropping the layer writes dirty values.
+        layer.put(5, 50).unwrap();
+        let values = Arc::clone(&layer.store().values);
+        drop(layer);
+        assert_eq!(values.lock().unwrap().get(&5), Some(&50));
+    }
+
+    #[test]
+    fn shutdown() {
+        let layer = CacheLayer::new(LRUCache::new(2), MapStore::default(), WriteMode::Back);
+        layer.put(1, 10).unwrap();
+        layer.shutdown().unwrap();
+        assert_eq!(layer.store().get(1), Some(10));
+        assert!(layer.cache().is_empty());
+    }
+}
@@ -0,0 +1,55 @@
+//! Utilities for testing code which uses a cache.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::clock::{Clock, Timestamp};
+
+/// MockClock only advances when told to, so that tests of expiration need not sleep.
+///
+/// ```
+/// use std::sync::Arc;
+/// use std::time::Duration;
+/// use cache::cache::LRUCache;
+/// use cache::testing::MockClock;
+///
+/// let clock = Arc::new(MockClock::new());
+/// let mut cache = LRUCache::with_ttl(10, Duration::from_secs(60));
+/// cache.set_clock(clock.clone());
+///
+/// cache.put("key", 1);
+/// clock.advance(Duration::from_secs(59));
+/// assert_eq!(cache.get(&"key"), Some(1));
+/// clock.advance(Duration::from_secs(1));
+/// assert_eq!(cache.get(&"key"), None);
+/// ```
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<Timestamp>
+}
+
+impl MockClock {
+    /// A clock starting at its origin.
+    pub fn new() -> MockClock {
+        MockClock {
+            now: Mutex::new(Timestamp::default())
+        }
+    }
+
+    /// Move the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Timestamp {
+        *self.now.lock().unwrap()
+    }
+}
@@ -0,0 +1,790 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::convert::TryInto;
+use std::fs;
+use std::hash::{BuildHasher, Hash};
+use std::io::{self, Read};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(not(target_arch = "wasm32"))]
+use std::thread::{self, JoinHandle};
+
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::cache::{Cache, Entry, LRUCache};
+use crate::listener::EvictionCause;
+use crate::policy::{EvictionPolicy, Lru};
+
+/// Encode converts keys and values to and from bytes, for the disk tier of a `TieredCache` using
+/// `EncodeCodec`.
+///
+/// Implement it for your own types, or spill them with another `Codec`.
+pub trait Encode: Sized {
+    fn encode(&self) -> Vec<u8>;
+
+    /// Decode bytes produced by `encode`, or return `None` if they are not valid.
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+impl Encode for Vec<u8> {
+    fn encode(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Vec<u8>> {
+        Some(bytes.to_vec())
+    }
+}
+
+impl Encode for String {
+    fn encode(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<String> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+macro_rules! encode_int {
+    ($($int:ty),*) => {$(
+        impl Encode for $int {
+            fn encode(&self) -> Vec<u8> {
+                self.to_le_bytes().to_vec()
+            }
+
+            fn decode(bytes: &[u8]) -> Option<$int> {
+                bytes.try_into().ok().map(<$int>::from_le_bytes)
+            }
+        }
+    )*}
+}
+
+encode_int!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+/// Codec converts keys and values of type `T` to and from bytes, for the disk tier of a
+/// `TieredCache`.
+///
+/// `EncodeCodec`, the default, uses their `Encode` implementations.  With the `json` or `cbor`
+/// feature, `Json` or `Cbor` spills any type implementing serde's `Serialize` and
+/// `Deserialize`.
+pub trait Codec<T> {
+    fn encode(&self, value: &T) -> io::Result<Vec<u8>>;
+
+    /// Decode bytes produced by `encode`, or return `None` if they are not valid.
+    fn decode(&self, bytes: &[u8]) -> Option<T>;
+}
+
+/// EncodeCodec converts types to and from bytes with their `Encode` implementations.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EncodeCodec;
+
+impl <T: Encode> Codec<T> for EncodeCodec {
+    fn encode(&self, value: &T) -> io::Result<Vec<u8>> {
+        Ok(value.encode())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<T> {
+        T::decode(bytes)
+    }
+}
+
+/// Json serializes types as JSON, with serde_json.
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl <T: Serialize + DeserializeOwned> Codec<T> for Json {
+    fn encode(&self, value: &T) -> io::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<T> {
+        serde_json::from_slice(bytes).ok()
+    }
+}
+
+/// Cbor serializes types as CBOR, with ciborium, which is more compact than JSON and keeps bytes
+/// as they are.
+#[cfg(feature = "cbor")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl <T: Serialize + DeserializeOwned> Codec<T> for Cbor {
+    fn encode(&self, value: &T) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+        Ok(bytes)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<T> {
+        ciborium::from_reader(bytes).ok()
+    }
+}
+
+/// The number of keys whose accesses `TieredCache` counts, beyond twice the number in memory,
+/// before it prunes those no longer in memory.
+const MIN_PRUNED_ACCESSES: usize = 64;
+
+/// The length of the names of spill files: a 64-bit number in hexadecimal.
+const SPILL_FILE_NAME_LEN: usize = 16;
+/// The length of the header of a spill file: the value's accesses and the length of its key.
+const SPILL_HEADER_LEN: usize = 16;
+
+/// A file holding a spilled value.
+#[derive(Clone, Copy)]
+struct SpillFile {
+    /// The number naming the file, unique within its `DiskTier`.
+    id: u64,
+    size: u64,
+    /// The number of times the value was read before it was spilled.  See `TieredCache::warm`.
+    accesses: u64
+}
+
+/// DiskTier keeps the values spilled by a `TieredCache`, one file per value, in least recently
+/// used order.
+///
+/// Each file is named by a number never used for another, so that no two values share a file.
+/// It holds the value's accesses and the length of its encoded key, as little-endian 64-bit
+/// numbers, followed by the encoded key (to detect corruption, and to reopen the tier) and the
+/// encoded value.
+struct DiskTier<K: Eq + Hash + Clone, C> {
+    dir: PathBuf,
+    codec: C,
+    /// The file of each spilled value.  Files are deleted as they leave the index.
+    index: LRUCache<K, SpillFile>,
+    next_id: AtomicU64
+}
+
+impl <K: Eq + Hash + Clone + Send + 'static, C: Codec<K>> DiskTier<K, C> {
+    /// Open the tier in `dir`, deleting the spill files already there, or if `reopen`, indexing
+    /// them from least to most accessed.
+    fn open(dir: PathBuf, max_bytes: u64, codec: C, reopen: bool) -> io::Result<DiskTier<K, C>> {
+        fs::create_dir_all(&dir)?;
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let id = match spill_file_id(&path) {
+                Some(id) => id,
+                None => continue
+            };
+
+            // Files which cannot be read, such as one being written when the process stopped,
+            // are deleted.
+            match if reopen { read_spill_header(&path, &codec) } else { None } {
+                Some((key, accesses)) => {
+                    let size = fs::metadata(&path)?.len();
+                    files.push((key, SpillFile { id, size, accesses }));
+                },
+                None => fs::remove_file(path)?
+            }
+        }
+
+        let mut index = LRUCache::with_weigher(max_bytes, |_, file: &SpillFile| file.size);
+        let listener_dir = dir.clone();
+        index.set_eviction_listener(move |_, file: SpillFile, _| {
+            // The file is only lost space if it cannot be deleted.
+            let _ = fs::remove_file(spill_path(&listener_dir, file.id));
+        });
+
+        // The most accessed values are indexed last, as the most recently used, so that they are
+        // the last discarded if the files do not all fit.
+        files.sort_by_key(|(_, file)| file.accesses);
+        let next_id = files.iter().map(|(_, file)| file.id + 1).max().unwrap_or(0);
+        for (key, file) in files {
+            index.put(key, file);
+        }
+
+        Ok(DiskTier { dir, codec, index, next_id: AtomicU64::new(next_id) })
+    }
+
+    /// Write `value` for `key`, which has been read `accesses` times, evicting the least recently
+    /// used files if it does not fit.
+    fn put<V>(&self, key: K, value: &V, accesses: u64) -> io::Result<()>
+        where C: Codec<V> {
+        let key_bytes = Codec::<K>::encode(&self.codec, &key)?;
+        let mut contents = accesses.to_le_bytes().to_vec();
+        contents.extend_from_slice(&(key_bytes.len() as u64).to_le_bytes());
+        contents.extend_from_slice(&key_bytes);
+        contents.extend_from_slice(&Codec::<V>::encode(&self.codec, value)?);
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        fs::write(spill_path(&self.dir, id), &contents)?;
+        self.index.put(key, SpillFile { id, size: contents.len() as u64, accesses });
+        Ok(())
+    }
+
+    /// Read and delete the value for `key`, if it was spilled, along with its accesses.
+    ///
+    /// If the file cannot be read, it is left in place to be read again.
+    fn take<V>(&self, key: &K) -> io::Result<Option<(V, u64)>>
+        where C: Codec<V> {
+        let file = match self.index.peek(key) {
+            Some(file) => file,
+            None => return Ok(None)
+        };
+
+        let key_bytes = Codec::<K>::encode(&self.codec, key)?;
+        let contents = fs::read(spill_path(&self.dir, file.id))?;
+        // Unless the value was spilled again meanwhile, in another file.
+        if let Entry::Occupied(entry) = self.index.entry(key.clone()) {
+            if entry.get().id == file.id {
+                entry.remove();
+            }
+        }
+
+        // A corrupted file is treated as missing.
+        if contents.len() < SPILL_HEADER_LEN {
+            return Ok(None);
+        }
+        let (header, rest) = contents.split_at(SPILL_HEADER_LEN);
+        let len = u64::from_le_bytes(header[8..].try_into().unwrap()) as usize;
+        if rest.len() < len || rest[..len] != key_bytes[..] {
+            return Ok(None);
+        }
+
+        Ok(Codec::<V>::decode(&self.codec, &rest[len..]).map(|value| (value, file.accesses)))
+    }
+
+    /// Delete the value for `key`, if it was spilled, returning its accesses.
+    fn remove(&self, key: &K) -> Option<u64> {
+        self.index.remove(key).map(|file| file.accesses)
+    }
+
+    /// The `count` spilled keys whose values were read most, most read first, and then most
+    /// recently spilled first.
+    fn hottest(&self, count: usize) -> Vec<K> {
+        let mut files: Vec<(u64, K)> = self.index.iter_lru_order()
+            .map(|file| (file.accesses, file.key().clone()))
+            .collect();
+        files.reverse();
+        // The sort is stable, so keys read equally often stay in recency order.
+        files.sort_by_key(|&(accesses, _)| Reverse(accesses));
+        files.into_iter().take(count).map(|(_, key)| key).collect()
+    }
+}
+
+/// The path of the spill file numbered `id`.
+fn spill_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:0width$x}", id, width = SPILL_FILE_NAME_LEN))
+}
+
+/// The number of the spill file at `path`, or `None` if it is not a spill file.
+fn spill_file_id(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    if name.len() != SPILL_FILE_NAME_LEN || !name.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(name, 16).ok()
+}
+
+/// Read the key of the spill file at `path` and its value's accesses, without reading the value,
+/// or return `None` if they cannot be read.
+fn read_spill_header<K, C: Codec<K>>(path: &Path, codec: &C) -> Option<(K, u64)> {
+    let mut file = fs::File::open(path).ok()?;
+    let mut header = [0; SPILL_HEADER_LEN];
+    file.read_exact(&mut header).ok()?;
+    let accesses = u64::from_le_bytes(header[..8].try_into().unwrap());
+    let len = u64::from_le_bytes(header[8..].try_into().unwrap());
+
+    let mut key_bytes = Vec::new();
+    file.take(len).read_to_end(&mut key_bytes).ok()?;
+    if key_bytes.len() as u64 != len {
+        return None;
+    }
+    codec.decode(&key_bytes).map(|key| (key, accesses))
+}
+
+/// TieredCache keeps the most recently used values in an in-memory `Cache`, and spills values
+/// evicted from it to a directory on disk, so that it can hold far more than fits in memory.
+///
+/// A value read from disk is moved back into memory, which may spill another.  The disk tier is
+/// itself bounded by the total size of its files, and discards its least recently used values
+/// when full.
+///
+/// Each value's file records how many times it was read while in memory, so that a cache
+/// reopened after a restart can bring the most read values back into memory first.  See `open`,
+/// `close` and `warm`.
+///
+/// # NB:
+///
+/// - Only values evicted for capacity are spilled.  Values which expire, or are removed or
+///   replaced, are not.  Spilled values do not keep their time-to-live, and are not passed to
+///   the memory cache's eviction listener when they leave the disk tier.
+/// - Values survive the cache only if it is closed with `close` and the directory reopened with
+///   `open`.  Otherwise, the directory is emptied of spill files when the cache is created, and
+///   files still on disk when it is dropped are deleted, if they can be.
+pub struct TieredCache<K, V, P = Lru, S = RandomState, C = EncodeCodec>
+    where K: Eq + Hash + Clone, V: Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    memory: Cache<K, V, P, S>,
+    disk: DiskTier<K, C>,
+    /// The number of times each key in `memory` has been read, if any, which is written with its
+    /// value when it is spilled.
+    accesses: Arc<Mutex<HashMap<K, u64>>>,
+    /// Values evicted from `memory` and their accesses, waiting to be written to `disk`.
+    spilled: Arc<Mutex<Vec<(K, V, u64)>>>
+}
+
+impl <K, V, P, S> TieredCache<K, V, P, S>
+    where K: Eq + Hash + Clone + Encode + Send + 'static, V: Clone + Encode + Send + 'static,
+          P: EvictionPolicy<K>, S: BuildHasher {
+    /// Put `memory` in front of a disk tier in `dir` which holds at most `max_disk_bytes` of
+    /// files, encoding keys and values with `Encode`.  See `with_codec`.
+    pub fn new<D: Into<PathBuf>>(memory: Cache<K, V, P, S>, dir: D, max_disk_bytes: u64)
+        -> io::Result<TieredCache<K, V, P, S>> {
+        Self::with_codec(memory, dir, max_disk_bytes, EncodeCodec)
+    }
+
+    /// Put `memory` in front of the disk tier left in `dir` by `close`, encoding keys and values
+    /// with `Encode`.  See `open_with_codec`.
+    pub fn open<D: Into<PathBuf>>(memory: Cache<K, V, P, S>, dir: D, max_disk_bytes: u64)
+        -> io::Result<TieredCache<K, V, P, S>> {
+        Self::open_with_codec(memory, dir, max_disk_bytes, EncodeCodec)
+    }
+}
+
+impl <K, V, P, S, C> TieredCache<K, V, P, S, C>
+    where K: Eq + Hash + Clone + Send + 'static, V: Clone + Send + 'static, P: EvictionPolicy<K>,
+          S: BuildHasher, C: Codec<K> + Codec<V> {
+    /// Put `memory` in front of a disk tier in `dir` which holds at most `max_disk_bytes` of
+    /// files, encoding keys and values with `codec`.
+    ///
+    /// `dir` is created if it does not exist, and should be dedicated to the cache.  The cache
+    /// registers its own eviction listener with `memory`, replacing any other.
+    ///
+    /// ```
+    /// # #[cfg(feature = "json")] {
+    /// use cache::cache::LRUCache;
+    /// use cache::tiered::{Json, TieredCache};
+    ///
+    /// let dir = std::env::temp_dir().join(format!("cache-json-{}", std::process::id()));
+    /// let cache = TieredCache::with_codec(LRUCache::new(1), &dir, 1 << 20, Json).unwrap();
+    /// cache.put((1, 2), vec!["a".to_string()]).unwrap();
+    /// cache.put((3, 4), vec!["b".to_string()]).unwrap();
+    /// assert_eq!(cache.get(&(1, 2)).unwrap(), Some(vec!["a".to_string()]));
+    /// # drop(cache);
+    /// # std::fs::remove_dir_all(dir).unwrap();
+    /// # }
+    /// ```
+    pub fn with_codec<D: Into<PathBuf>>(memory: Cache<K, V, P, S>, dir: D, max_disk_bytes: u64,
+                                        codec: C) -> io::Result<TieredCache<K, V, P, S, C>> {
+        Self::with_disk(memory, DiskTier::open(dir.into(), max_disk_bytes, codec, false)?)
+    }
+
+    /// Put `memory` in front of the disk tier left in `dir` by `close`, which holds at most
+    /// `max_disk_bytes` of files, encoding keys and values with `codec`.
+    ///
+    /// The values on disk stay there until read, or moved into memory by `warm`.  If they do not
+    /// all fit within `max_disk_bytes`, the least read are discarded.  Files which cannot be
+    /// read, or whose keys cannot be decoded, are deleted.  Otherwise, as `with_codec`.
+    pub fn open_with_codec<D: Into<PathBuf>>(memory: Cache<K, V, P, S>, dir: D,
+                                             max_disk_bytes: u64, codec: C)
+        -> io::Result<TieredCache<K, V, P, S, C>> {
+        Self::with_disk(memory, DiskTier::open(dir.into(), max_disk_bytes, codec, true)?)
+    }
+
+    fn with_disk(mut memory: Cache<K, V, P, S>, disk: DiskTier<K, C>)
+        -> io::Result<TieredCache<K, V, P, S, C>> {
+        let accesses = Arc::new(Mutex::new(HashMap::new()));
+        let spilled = Arc::new(Mutex::new(Vec::new()));
+        let (listener_accesses, listener_spilled) = (Arc::clone(&accesses), Arc::clone(&spilled));
+        memory.set_eviction_listener(move |key, value, cause| {
+            let mut accesses = listener_accesses.lock().unwrap();
+            match cause {
+                EvictionCause::Capacity => {
+                    let key_accesses = accesses.remove(&key).unwrap_or(0);
+                    listener_spilled.lock().unwrap().push((key, value, key_accesses));
+                },
+                // The key is still in memory, with its new value.
+                EvictionCause::Replaced => {},
+                EvictionCause::Removed | EvictionCause::Expired => {
+                    accesses.remove(&key);
+                }
+            }
+        });
+
+        Ok(TieredCache { memory, disk, accesses, spilled })
+    }
+
+    pub fn memory(&self) -> &Cache<K, V, P, S> {
+        &self.memory
+    }
+
+    /// The number of values spilled to disk.
+    pub fn disk_len(&self) -> usize {
+        self.disk.index.len()
+    }
+
+    /// The total size of the files spilled to disk, in bytes.
+    pub fn disk_bytes(&self) -> u64 {
+        self.disk.index.weight()
+    }
+
+    /// Get the value for `key` from memory, or read it back from disk into memory.
+    ///
+    /// A value which cannot be decoded is discarded and reported as missing.  A value which
+    /// cannot be read is left on disk, and the error returned.
+    pub fn get(&self, key: &K) -> io::Result<Option<V>> {
+        if let Some(value) = self.memory.get(key) {
+            self.record_access(key);
+            return Ok(Some(value));
+        }
+
+        let value = match self.disk.take::<V>(key)? {
+            Some((value, accesses)) => {
+                self.accesses.lock().unwrap().insert(key.clone(), accesses + 1);
+                self.memory.put(key.clone(), value.clone());
+                Some(value)
+            },
+            None => None
+        };
+        self.write_spilled()?;
+        Ok(value)
+    }
+
+    /// Put `value` for `key` in memory, replacing any value in either tier.
+    pub fn put(&self, key: K, value: V) -> io::Result<()> {
+        if let Some(accesses) = self.disk.remove(&key) {
+            self.accesses.lock().unwrap().insert(key.clone(), accesses);
+        }
+        self.memory.put(key, value);
+        self.write_spilled()
+    }
+
+    /// Remove the value for `key` from both tiers.
+    ///
+    /// # Returns
+    ///
+    /// The value, if any.
+    pub fn remove(&self, key: &K) -> io::Result<Option<V>> {
+        self.spilled.lock().unwrap().retain(|(spilled_key, _, _)| spilled_key != key);
+        // Any value on disk is older than the one in memory, but must not be served later.
+        if let Some(value) = self.memory.remove(key) {
+            self.disk.remove(key);
+            return Ok(Some(value));
+        }
+
+        Ok(self.disk.take(key)?.map(|(value, _)| value))
+    }
+
+    /// Remove all values from both tiers, deleting their files.
+    pub fn clear(&self) {
+        self.memory.clear();
+        self.spilled.lock().unwrap().clear();
+        self.disk.index.clear();
+    }
+
+    /// Move the `count` values on disk which were read most before they were spilled back into
+    /// memory, so that a cache reopened with `open` serves its most popular values from memory
+    /// sooner.
+    ///
+    /// The most read values are moved last, so that they are the most recently used.  Keys put
+    /// in the meantime keep their new values.  See also `spawn_warm`.
+    ///
+    /// # Returns
+    ///
+    /// The number of values moved.
+    pub fn warm(&self, count: usize) -> io::Result<usize> {
+        let mut warmed = 0;
+        for key in self.disk.hottest(count).into_iter().rev() {
+            // A key put in memory since was removed from disk, so a value taken is never newer.
+            if let Some((value, accesses)) = self.disk.take(&key)? {
+                if let Entry::Vacant(entry) = self.memory.entry(key.clone()) {
+                    self.accesses.lock().unwrap().insert(key, accesses);
+                    entry.insert(value);
+                    warmed += 1;
+                }
+            }
+        }
+
+        self.write_spilled()?;
+        Ok(warmed)
+    }
+
+    /// Start a thread which calls `warm(count)` on `cache`, so that it can be used while it
+    /// warms up.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn spawn_warm(cache: &Arc<Self>, count: usize) -> JoinHandle<io::Result<usize>>
+        where Self: Send + Sync + 'static {
+        let cache = Arc::clone(cache);
+        thread::spawn(move || cache.warm(count))
+    }
+
+    /// Write the values in memory to disk, with how many times each has been read, and leave the
+    /// files in place, for `open` to reopen.
+    ///
+    /// Values are written from least to most recently used, so if they do not all fit, the least
+    /// recently used are discarded.  If a value cannot be written, the files already written are
+    /// still left in place.
+    pub fn close(mut self) -> io::Result<()> {
+        let result = self.write_spilled().and_then(|()| {
+            let accesses = mem::take(&mut *self.accesses.lock().unwrap());
+            for value in self.memory.iter_lru_order() {
+                let key_accesses = accesses.get(value.key()).copied().unwrap_or(0);
+                self.disk.put(value.key().clone(), &*value, key_accesses)?;
+            }
+            Ok(())
+        });
+
+        // Dropping the index would otherwise delete its files.
+        self.disk.index.set_eviction_listener(|_, _, _| {});
+        result
+    }
+
+    /// Count a read of `key` from memory.
+    fn record_access(&self, key: &K) {
+        let mut accesses = self.accesses.lock().unwrap();
+        *accesses.entry(key.clone()).or_insert(0) += 1;
+
+        // A key evicted between being read and counted keeps its count.  Such counts are pruned
+        // once they could outnumber the keys in memory, so that they take amortized constant time.
+        if accesses.len() > 2 * self.memory.len() + MIN_PRUNED_ACCESSES {
+            accesses.retain(|key, _| self.memory.contains_key(key));
+        }
+    }
+
+    /// Write values evicted from memory to disk.
+    fn write_spilled(&self) -> io::Result<()> {
+        let spilled = mem::take(&mut *self.spilled.lock().unwrap());
+        let mut entries = spilled.into_iter();
+        while let Some((key, value, accesses)) = entries.next() {
+            if let Err(err) = self.disk.put(key.clone(), &value, accesses) {
+                let mut unwritten = self.spilled.lock().unwrap();
+                unwritten.push((key, value, accesses));
+                unwritten.extend(entries);
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("cache-tiered-{}-{}", name, std::process::id()))
+    }
+
+    fn spill_files(dir: &Path) -> usize {
+        fs::read_dir(dir).unwrap()
+            .filter(|entry| spill_file_id(&entry.as_ref().unwrap().path()).is_some())
+            .count()
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(u64::decode(&42u64.encode()), Some(42));
+        assert_eq!(i32::decode(&[1, 2]), None);
+        assert_eq!(String::decode(&"key".to_string().encode()), Some("key".to_string()));
+    }
+
+    #[cfg(feature = "serde")]
+    #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Record {
+        name: String,
+        tags: Vec<String>,
+        score: Option<f64>
+    }
+
+    #[cfg(feature = "serde")]
+    fn spill_records<C: Codec<String> + Codec<Record>>(name: &str, codec: C) {
+        let dir = temp_dir(name);
+        let cache = TieredCache::with_codec(LRUCache::new(1), &dir, 1000, codec).unwrap();
+        let records: Vec<Record> = (0..3).map(|n| Record {
+            name: format!("record{}", n),
+            tags: vec!["tag".to_string(); n],
+            score: if n > 0 { Some(n as f64 / 2.0) } else { None }
+        }).collect();
+        for record in &records {
+            cache.put(record.name.clone(), record.clone()).unwrap();
+        }
+        assert_eq!(cache.disk_len(), 2);
+
+        for record in &records {
+            assert_eq!(cache.get(&record.name).unwrap().as_ref(), Some(record));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json() {
+        spill_records("json", Json);
+        assert_eq!(Codec::<Vec<u8>>::decode(&Json, b"[1, 2]"), Some(vec![1, 2]));
+        assert_eq!(Codec::<u8>::decode(&Json, b"256"), None);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor() {
+        spill_records("cbor", Cbor);
+        let bytes = Codec::<String>::encode(&Cbor, &"key".to_string()).unwrap();
+        assert_eq!(Codec::<String>::decode(&Cbor, &bytes), Some("key".to_string()));
+        assert_eq!(Codec::<String>::decode(&Cbor, &bytes[..2]), None);
+    }
+
+    #[test]
+    fn spill() {
+        let dir = temp_dir("spill");
+        let cache = TieredCache::new(LRUCache::new(2), &dir, 1000).unwrap();
+        for key in 0..4u64 {
+            cache.put(key, format!("value{}", key)).unwrap();
+        }
+        assert_eq!(cache.memory().len(), 2);
+        assert_eq!(cache.disk_len(), 2);
+        assert_eq!(spill_files(&dir), 2);
+
+        // Reading a spilled value moves it back into memory, spilling the least recently used.
+        assert_eq!(cache.get(&0).unwrap(), Some("value0".to_string()));
+        assert_eq!(cache.memory().peek(&0), Some("value0".to_string()));
+        assert_eq!(cache.memory().peek(&2), None);
+        assert_eq!(cache.disk_len(), 2);
+
+        // Putting a spilled key replaces it.
+        cache.put(1, "new".to_string()).unwrap();
+        assert_eq!(cache.get(&1).unwrap(), Some("new".to_string()));
+        assert_eq!(cache.remove(&2).unwrap(), Some("value2".to_string()));
+        assert_eq!(cache.get(&2).unwrap(), None);
+
+        cache.clear();
+        assert_eq!(cache.disk_len(), 0);
+        assert_eq!(spill_files(&dir), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn respill() {
+        let dir = temp_dir("respill");
+        let cache = TieredCache::new(LRUCache::new(1), &dir, 1000).unwrap();
+        cache.put(0u64, 0u64).unwrap();
+        cache.put(1, 1).unwrap();
+
+        // Each spill writes a new file, and deletes the file of the value it replaces.
+        for _ in 0..3 {
+            assert_eq!(cache.get(&0).unwrap(), Some(0));
+            assert_eq!(cache.get(&1).unwrap(), Some(1));
+            assert_eq!(cache.disk_len(), 1);
+            assert_eq!(spill_files(&dir), 1);
+        }
+        cache.put(2, 2).unwrap();
+        assert_eq!(spill_files(&dir), 2);
+        assert_eq!(cache.get(&0).unwrap(), Some(0));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_error() {
+        let dir = temp_dir("read-error");
+        let cache = TieredCache::new(LRUCache::new(1), &dir, 1000).unwrap();
+        cache.put(0u64, 0u64).unwrap();
+        cache.put(1, 1).unwrap();
+
+        // A value whose file cannot be read stays on disk, to be read once it can be.
+        let path = spill_path(&dir, cache.disk.index.peek(&0).unwrap().id);
+        let moved = dir.join("moved");
+        fs::rename(&path, &moved).unwrap();
+        fs::create_dir(&path).unwrap();
+        assert!(cache.get(&0).is_err());
+        assert_eq!(cache.disk_len(), 1);
+
+        fs::remove_dir(&path).unwrap();
+        fs::rename(&moved, &path).unwrap();
+        assert_eq!(cache.get(&0).unwrap(), Some(0));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn remove_both_tiers() {
+        let dir = temp_dir("remove-both-tiers");
+        let cache = TieredCache::new(LRUCache::new(1), &dir, 1000).unwrap();
+        cache.put(0u64, 0u64).unwrap();
+        cache.put(1, 1).unwrap();
+
+        // A key in memory and on disk is removed from both.
+        cache.memory().put(0, 10);
+        assert_eq!(cache.remove(&0).unwrap(), Some(10));
+        assert_eq!(cache.disk_len(), 0);
+        assert_eq!(cache.get(&0).unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn accesses_pruned() {
+        let dir = temp_dir("accesses-pruned");
+        let cache = TieredCache::new(LRUCache::new(2), &dir, 1000).unwrap();
+        cache.put(0u64, 0u64).unwrap();
+
+        // Counts left behind by keys which have left memory are pruned.
+        cache.accesses.lock().unwrap().extend((1..1000).map(|key| (key, 1)));
+        cache.get(&0).unwrap();
+        assert_eq!(*cache.accesses.lock().unwrap(), vec![(0, 1)].into_iter().collect());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn disk_limit() {
+        let dir = temp_dir("disk-limit");
+        // Each file holds an 8-byte count of accesses, an 8-byte length, an 8-byte key and an
+        // 8-byte value.
+        let cache = TieredCache::new(LRUCache::new(1), &dir, 64).unwrap();
+        for key in 0..4u64 {
+            cache.put(key, key).unwrap();
+        }
+        assert_eq!(cache.disk_len(), 2);
+        assert_eq!(cache.disk_bytes(), 64);
+        assert_eq!(spill_files(&dir), 2);
+        assert_eq!(cache.get(&0).unwrap(), None);
+        assert_eq!(cache.get(&1).unwrap(), Some(1));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reopen() {
+        let dir = temp_dir("reopen");
+        let cache = TieredCache::new(LRUCache::new(2), &dir, 1000).unwrap();
+        for key in 0..6u64 {
+            cache.put(key, key * 10).unwrap();
+        }
+        for _ in 0..3 {
+            cache.get(&1).unwrap();
+        }
+        for _ in 0..2 {
+            cache.get(&3).unwrap();
+        }
+        cache.get(&5).unwrap();
+        assert_eq!(cache.memory().len(), 2);
+        cache.close().unwrap();
+        assert_eq!(spill_files(&dir), 6);
+
+        // Values which do not fit are discarded, least read first.
+        let cache: TieredCache<u64, u64> = TieredCache::open(LRUCache::new(2), &dir, 96).unwrap();
+        assert!(cache.memory().is_empty());
+        assert_eq!(cache.disk_len(), 3);
+        assert_eq!(spill_files(&dir), 3);
+
+        // The most read values are moved into memory.
+        let cache = Arc::new(cache);
+        assert_eq!(TieredCache::spawn_warm(&cache, 2).join().unwrap().unwrap(), 2);
+        assert_eq!(cache.memory().peek_mru(), Some((1, 10)));
+        assert_eq!(cache.memory().peek(&3), Some(30));
+        assert_eq!(cache.disk_len(), 1);
+        assert_eq!(cache.get(&5).unwrap(), Some(50));
+
+        // Creating a cache discards the values on disk.
+        drop(cache);
+        let cache: TieredCache<u64, u64> = TieredCache::new(LRUCache::new(2), &dir, 1000).unwrap();
+        assert_eq!(cache.disk_len(), 0);
+        assert_eq!(spill_files(&dir), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,81 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// InvalidationToken ties the lifetime of cache entries to an external scope.
+///
+/// Entries inserted with `LRUCache::put_with_token` are treated as absent once their token has been
+/// invalidated.  Clones of a token share state, so invalidating any clone invalidates all entries
+/// associated with the original (e.g. all entries cached during one request, or under one
+/// configuration epoch).
+///
+/// # Implementation Notes:
+///
+/// Invalidation is lazy: the token only flips a flag, and the cache discards the entry the next
+/// time it is accessed.  Until then the entry still occupies a slot and is subject to normal LRU
+/// eviction.
+#[derive(Clone, Debug, Default)]
+pub struct InvalidationToken {
+    invalidated: Arc<AtomicBool>
+}
+
+impl InvalidationToken {
+    /// Create a new, valid token.
+    pub fn new() -> InvalidationToken {
+        InvalidationToken {
+            invalidated: Arc::new(AtomicBool::new(false))
+        }
+    }
+
+    /// Invalidate all entries associated with this token.
+    pub fn invalidate(&self) {
+        self.invalidated.store(true, Ordering::Release);
+    }
+
+    /// Whether `invalidate` has been called on this token (or any of its clones).
+    pub fn is_invalidated(&self) -> bool {
+        self.invalidated.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::LRUCache;
+
+    #[test]
+    fn invalidate() {
+        let token = InvalidationToken::default();
+        let clone = token.clone();
+        let other = InvalidationToken::new();
+        assert!(!token.is_invalidated());
+
+        clone.invalidate();
+        assert!(token.is_invalidated());
+        assert!(clone.is_invalidated());
+        assert!(!other.is_invalidated());
+
+        // Invalidating again changes nothing.
+        token.invalidate();
+        assert!(clone.is_invalidated());
+    }
+
+    #[test]
+    fn invalidate_entries() {
+        let (token, other) = (InvalidationToken::new(), InvalidationToken::new());
+        let cache: LRUCache<&str, u64> = LRUCache::new(4);
+        cache.put_with_token("key1", 1, &token);
+        cache.put_with_token("key2", 2, &token.clone());
+        cache.put_with_token("key3", 3, &other);
+        cache.put("key4", 4);
+
+        token.invalidate();
+        assert_eq!(cache.get(&"key1"), None);
+        assert_eq!(cache.get(&"key2"), None);
+        assert_eq!(cache.get(&"key3"), Some(3));
+        assert_eq!(cache.get(&"key4"), Some(4));
+
+        // A value put with an invalidated token is absent from the start.
+        cache.put_with_token("key5", 5, &token);
+        assert_eq!(cache.get(&"key5"), None);
+    }
+}
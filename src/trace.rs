@@ -0,0 +1,228 @@
+//! Recording cache operations to a compact binary trace, and reading them back to replay.
+//!
+//! A trace begins with `MAGIC`, followed by one 17-byte record per operation: the microseconds
+//! since the first operation and the hash of the key, both little-endian `u64`s, separated by a
+//! byte for the operation.  Keys are hashed with a fixed hasher, so a key has the same hash in
+//! every trace, and traces reveal no keys.
+
+use std::collections::hash_map::DefaultHasher;
+use std::convert::TryInto;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufWriter, Read, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::clock::Timestamp;
+
+/// The bytes every trace begins with.
+pub const MAGIC: &[u8; 8] = b"CTRACE1\n";
+
+const RECORD_LEN: usize = 17;
+
+/// The kinds of cache operation a trace records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceOp {
+    /// A lookup, such as `get` or `get_or_insert_with`.
+    Get,
+    /// A value was put, such as by `put` or `get_or_insert_with` after a miss.
+    Put,
+    /// A value was removed at the caller's request.
+    Remove
+}
+
+impl TraceOp {
+    fn to_byte(self) -> u8 {
+        match self {
+            TraceOp::Get => 0,
+            TraceOp::Put => 1,
+            TraceOp::Remove => 2
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<TraceOp> {
+        match byte {
+            0 => Some(TraceOp::Get),
+            1 => Some(TraceOp::Put),
+            2 => Some(TraceOp::Remove),
+            _ => None
+        }
+    }
+}
+
+/// TraceRecord is one operation in a trace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// The time since the first operation in the trace, to the microsecond.
+    pub timestamp: Duration,
+    pub op: TraceOp,
+    /// The hash of the key, as computed by `key_hash`.
+    pub key_hash: u64
+}
+
+/// The hash of `key` recorded in traces.
+pub fn key_hash<Q: Hash + ?Sized>(key: &Q) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+struct RecorderState {
+    writer: BufWriter<Box<dyn Write + Send>>,
+    started: Option<Timestamp>,
+    /// The first error writing the trace, after which nothing more is written.
+    error: Option<io::Error>
+}
+
+/// TraceRecorder writes a trace of every operation on the caches it is set on.  See
+/// `LRUCache::set_trace_recorder`.
+///
+/// Writing is buffered, and a failed write stops recording rather than failing the cache
+/// operation.  Call `finish` to flush the trace and learn of any error.
+pub struct TraceRecorder {
+    state: Mutex<RecorderState>
+}
+
+impl TraceRecorder {
+    /// Record a trace to `writer`, writing `MAGIC` immediately.
+    pub fn new<W: Write + Send + 'static>(writer: W) -> io::Result<TraceRecorder> {
+        let mut writer = BufWriter::new(Box::new(writer) as Box<dyn Write + Send>);
+        writer.write_all(MAGIC)?;
+        Ok(TraceRecorder {
+            state: Mutex::new(RecorderState { writer, started: None, error: None })
+        })
+    }
+
+    /// Record `op` on `key` at `now`.
+    pub fn record<Q: Hash + ?Sized>(&self, op: TraceOp, key: &Q, now: Timestamp) {
+        let key_hash = key_hash(key);
+        let mut state = self.state.lock().unwrap();
+        if state.error.is_some() {
+            return;
+        }
+
+        let started = *state.started.get_or_insert(now);
+        let micros = now.saturating_duration_since(started).as_micros() as u64;
+        let mut record = [0; RECORD_LEN];
+        record[..8].copy_from_slice(&micros.to_le_bytes());
+        record[8] = op.to_byte();
+        record[9..].copy_from_slice(&key_hash.to_le_bytes());
+        if let Err(err) = state.writer.write_all(&record) {
+            state.error = Some(err);
+        }
+    }
+
+    /// Flush the trace, returning the first error in writing it, if any.
+    ///
+    /// Recording may continue afterwards.
+    pub fn finish(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(err) = state.error.take() {
+            return Err(err);
+        }
+        state.writer.flush()
+    }
+}
+
+/// TraceReader reads the records of a trace written by a `TraceRecorder`.
+pub struct TraceReader<R> {
+    reader: R
+}
+
+impl <R: Read> TraceReader<R> {
+    /// Read a trace from `reader`, checking that it begins with `MAGIC`.
+    ///
+    /// `reader` should be buffered.
+    pub fn new(mut reader: R) -> io::Result<TraceReader<R>> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a cache trace"));
+        }
+        Ok(TraceReader { reader })
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<TraceRecord>> {
+        let mut record = [0; RECORD_LEN];
+        let mut len = 0;
+        while len < RECORD_LEN {
+            match self.reader.read(&mut record[len..]) {
+                Ok(0) if len == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => len += read,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+                Err(err) => return Err(err)
+            }
+        }
+
+        let op = TraceOp::from_byte(record[8]).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "unknown trace operation")
+        })?;
+        Ok(Some(TraceRecord {
+            timestamp: Duration::from_micros(u64::from_le_bytes(record[..8].try_into().unwrap())),
+            op,
+            key_hash: u64::from_le_bytes(record[9..].try_into().unwrap())
+        }))
+    }
+}
+
+impl <R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<io::Result<TraceRecord>> {
+        self.read_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::cache::LRUCache;
+    use crate::testing::MockClock;
+
+    /// A writer whose output can be read after it is moved into a recorder.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn record_and_read() {
+        let buffer = SharedBuffer::default();
+        let recorder = Arc::new(TraceRecorder::new(buffer.clone()).unwrap());
+        let clock = Arc::new(MockClock::new());
+        let mut cache = LRUCache::new(10);
+        cache.set_clock(clock.clone());
+        cache.set_trace_recorder(Some(Arc::clone(&recorder)));
+
+        cache.get_or_insert_with("a".to_string(), || 1);
+        clock.advance(Duration::from_millis(5));
+        cache.get("a");
+        cache.remove("a");
+        cache.peek("a");
+        recorder.finish().unwrap();
+
+        let trace = buffer.0.lock().unwrap().clone();
+        assert_eq!(trace.len(), MAGIC.len() + 4 * RECORD_LEN);
+        let records: Vec<TraceRecord> = TraceReader::new(&trace[..]).unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        let ops: Vec<TraceOp> = records.iter().map(|record| record.op).collect();
+        assert_eq!(ops, vec![TraceOp::Get, TraceOp::Put, TraceOp::Get, TraceOp::Remove]);
+        assert!(records.iter().all(|record| record.key_hash == key_hash("a")));
+        assert_eq!(records[1].timestamp, Duration::from_secs(0));
+        assert_eq!(records[2].timestamp, Duration::from_millis(5));
+
+        assert!(TraceReader::new(&b"CTRACE0\n"[..]).is_err());
+        let truncated = TraceReader::new(&trace[..trace.len() - 1]).unwrap();
+        assert!(truncated.last().unwrap().is_err());
+    }
+}
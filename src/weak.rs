@@ -0,0 +1,151 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, Weak};
+
+use crate::cache::{Cache, Entry};
+use crate::policy::{EvictionPolicy, Lru};
+
+/// WeakValueCache holds `Weak` references to values owned elsewhere, so that a value stays alive
+/// only while something outside the cache holds an `Arc` to it, for interning large shared
+/// immutable objects.
+///
+/// Each entry counts as one towards the capacity, however large its value, since the memory
+/// belongs to the value's other owners.  Entries whose values have been dropped are removed when
+/// they are next read or interned, when they are evicted, or by `purge_dead`.
+///
+/// ```
+/// use std::sync::Arc;
+/// use cache::weak::WeakValueCache;
+///
+/// let cache = WeakValueCache::new(128);
+/// let schema = cache.get_or_insert_with("users", || String::from("id, name"));
+///
+/// // While `schema` is held, interning the same key shares it.
+/// let again = cache.get_or_insert_with("users", || unreachable!());
+/// assert!(Arc::ptr_eq(&schema, &again));
+///
+/// drop((schema, again));
+/// assert_eq!(cache.get(&"users"), None);
+/// ```
+pub struct WeakValueCache<K, V, P = Lru, S = RandomState>
+    where K: Eq + Hash + Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    cache: Cache<K, Weak<V>, P, S>
+}
+
+impl <K: Eq + Hash + Clone, V> WeakValueCache<K, V> {
+    /// Create a cache with space for `capacity` entries.
+    pub fn new(capacity: usize) -> WeakValueCache<K, V> {
+        Self::with_cache(Cache::new(capacity))
+    }
+}
+
+impl <K, V, P, S> WeakValueCache<K, V, P, S>
+    where K: Eq + Hash + Clone, P: EvictionPolicy<K>, S: BuildHasher {
+    /// Store entries in `cache`.
+    pub fn with_cache(cache: Cache<K, Weak<V>, P, S>) -> WeakValueCache<K, V, P, S> {
+        WeakValueCache { cache }
+    }
+
+    /// The underlying cache, for example to read its statistics or remove many values.
+    pub fn cache(&self) -> &Cache<K, Weak<V>, P, S> {
+        &self.cache
+    }
+
+    /// Get the value for `key` if something still holds it, removing the entry if not.  See
+    /// `LRUCache::get`.
+    pub fn get<Q>(&self, key: &Q) -> Option<Arc<V>>
+        where K: Borrow<Q>, Q: Hash + Eq + ToOwned<Owned = K> + ?Sized {
+        let value = self.cache.get(key)?.upgrade();
+        if value.is_none() {
+            // Another thread may have put a live value since, so check again under the lock.
+            if let Entry::Occupied(entry) = self.cache.entry(key.to_owned()) {
+                if entry.get().strong_count() == 0 {
+                    entry.remove();
+                }
+            }
+        }
+        value
+    }
+
+    /// Get the value for `key` if something still holds it, or otherwise create it with `f` and
+    /// store a reference to it.
+    ///
+    /// The cache is locked while `f` runs, so that every caller gets the same value, and `f`
+    /// must not use the cache.
+    pub fn get_or_insert_with<F>(&self, key: K, f: F) -> Arc<V>
+        where F: FnOnce() -> V {
+        match self.cache.entry(key) {
+            Entry::Occupied(mut entry) => match entry.get().upgrade() {
+                Some(value) => value,
+                None => {
+                    let value = Arc::new(f());
+                    entry.insert(Arc::downgrade(&value));
+                    value
+                }
+            },
+            Entry::Vacant(entry) => {
+                let value = Arc::new(f());
+                entry.insert(Arc::downgrade(&value));
+                value
+            }
+        }
+    }
+
+    /// Store a reference to `value` for `key`.
+    ///
+    /// # Returns
+    ///
+    /// The previous value for `key`, if something still holds it.
+    pub fn put(&self, key: K, value: &Arc<V>) -> Option<Arc<V>> {
+        self.cache.put(key, Arc::downgrade(value)).and_then(|old_value| old_value.upgrade())
+    }
+
+    /// Remove `key`, returning its value if something still holds it.
+    pub fn remove<Q>(&self, key: &Q) -> Option<Arc<V>>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.cache.remove(key).and_then(|value| value.upgrade())
+    }
+
+    /// Remove every entry whose value has been dropped.
+    ///
+    /// # Returns
+    ///
+    /// The number of entries removed.
+    pub fn purge_dead(&self) -> usize {
+        self.cache.retain(|_, value| value.strong_count() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weak_values() {
+        let cache: WeakValueCache<String, Vec<u8>> = WeakValueCache::new(2);
+        let large = Arc::new(vec![0; 1024]);
+        assert_eq!(cache.put("large".to_string(), &large), None);
+        assert!(Arc::ptr_eq(&cache.get("large").unwrap(), &large));
+
+        // Dropped values are removed when read.
+        let small = cache.get_or_insert_with("small".to_string(), || vec![1]);
+        drop(large);
+        assert_eq!(cache.get("large"), None);
+        assert_eq!(cache.cache().len(), 1);
+
+        // Or interned again.
+        drop(small);
+        let small = cache.get_or_insert_with("small".to_string(), || vec![2]);
+        assert_eq!(*small, vec![2]);
+        assert_eq!(cache.remove("small"), Some(small));
+
+        // Or purged.
+        let values: Vec<Arc<Vec<u8>>> = (0..2).map(|n| {
+            cache.get_or_insert_with(n.to_string(), || vec![n])
+        }).collect();
+        drop(values);
+        assert_eq!(cache.purge_dead(), 2);
+        assert!(cache.cache().is_empty());
+    }
+}
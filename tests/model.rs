@@ -0,0 +1,172 @@
+//! Randomized tests which check the cache against a simple reference model, and the sharded cache
+//! under concurrent use.
+
+extern crate cache;
+
+use std::sync::Arc;
+use std::thread;
+
+use cache::cache::LRUCache;
+use cache::sharded::ShardedLRUCache;
+
+/// The number of random operation sequences to check.
+const SEEDS: u64 = 50;
+
+/// The number of operations in each sequence.
+const OPERATIONS: usize = 2000;
+
+/// A small deterministic random number generator (xorshift64*), so that failures can be
+/// reproduced from their seed.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // The state must not be zero.
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A number in `low..high`.
+    fn range(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next() % (high - low)
+    }
+}
+
+/// The reference model: keys and values ordered from most to least recently used.
+struct Model {
+    capacity: usize,
+    entries: Vec<(u64, u64)>
+}
+
+impl Model {
+    fn position(&self, key: u64) -> Option<usize> {
+        self.entries.iter().position(|&(entry_key, _)| entry_key == key)
+    }
+
+    fn put(&mut self, key: u64, value: u64) -> Option<u64> {
+        let old_value = self.remove(key);
+        self.entries.insert(0, (key, value));
+        self.entries.truncate(self.capacity);
+        old_value
+    }
+
+    fn get(&mut self, key: u64) -> Option<u64> {
+        let entry = self.entries.remove(self.position(key)?);
+        self.entries.insert(0, entry);
+        Some(entry.1)
+    }
+
+    fn peek(&self, key: u64) -> Option<u64> {
+        self.position(key).map(|index| self.entries[index].1)
+    }
+
+    fn remove(&mut self, key: u64) -> Option<u64> {
+        self.position(key).map(|index| self.entries.remove(index).1)
+    }
+
+    fn pop_mru(&mut self) -> Option<(u64, u64)> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        Some(self.entries.remove(0))
+    }
+}
+
+/// Apply a random sequence of operations to a cache and the model, checking that they agree.
+fn check_sequence(seed: u64) {
+    let mut rng = Rng::new(seed);
+    let capacity = rng.range(1, 8) as usize;
+    let cache = LRUCache::new(capacity);
+    let mut model = Model { capacity, entries: Vec::new() };
+
+    for step in 0..OPERATIONS {
+        let key = rng.range(0, 16);
+        let value = rng.next();
+        let context = format!("seed {}, step {}", seed, step);
+        match rng.range(0, 100) {
+            0..=34 => assert_eq!(cache.put(key, value), model.put(key, value), "{}", context),
+            35..=64 => assert_eq!(cache.get(&key), model.get(key), "{}", context),
+            65..=74 => assert_eq!(cache.peek(&key), model.peek(key), "{}", context),
+            75..=79 => {
+                assert_eq!(cache.contains_key(&key), model.peek(key).is_some(), "{}", context)
+            },
+            80..=84 => {
+                assert_eq!(cache.touch(&key), model.get(key).is_some(), "{}", context)
+            },
+            85..=92 => assert_eq!(cache.remove(&key), model.remove(key), "{}", context),
+            93..=95 => assert_eq!(cache.pop_lru(), model.entries.pop(), "{}", context),
+            96..=98 => assert_eq!(cache.pop_mru(), model.pop_mru(), "{}", context),
+            _ => {
+                cache.clear();
+                model.entries.clear();
+            }
+        }
+
+        assert_eq!(cache.len(), model.entries.len(), "{}", context);
+        let order: Vec<(u64, u64)> = cache.iter_lru_order()
+            .map(|value| (*value.key(), *value))
+            .collect();
+        let expected: Vec<(u64, u64)> = model.entries.iter().rev().cloned().collect();
+        assert_eq!(order, expected, "{}", context);
+    }
+}
+
+#[test]
+fn matches_model() {
+    for seed in 0..SEEDS {
+        check_sequence(seed);
+    }
+}
+
+#[test]
+fn sharded_stress() {
+    const THREADS: u64 = 8;
+    const KEYS: u64 = 256;
+
+    let cache: Arc<ShardedLRUCache<u64, (u64, u64)>> = Arc::new(ShardedLRUCache::new(64, 4));
+    let workers: Vec<_> = (0..THREADS).map(|thread| {
+        let cache = Arc::clone(&cache);
+        thread::spawn(move || {
+            let mut rng = Rng::new(thread);
+            for step in 0..20000 {
+                let key = rng.range(0, KEYS);
+                match rng.range(0, 10) {
+                    0..=3 => {
+                        cache.put(key, (key, step));
+                    },
+                    4..=7 => {
+                        // Values are never attached to the wrong key.
+                        if let Some((value_key, _)) = cache.get(&key) {
+                            assert_eq!(value_key, key);
+                        }
+                    },
+                    8 => {
+                        cache.remove(&key);
+                    },
+                    _ => {
+                        let (value_key, _) = cache.get_or_insert_with(key, || (key, step));
+                        assert_eq!(value_key, key);
+                    }
+                }
+                assert!(cache.len() <= cache.capacity());
+            }
+        })
+    }).collect();
+
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    // Every value left can be read, and the lengths agree.
+    let len = cache.len();
+    assert!(len <= cache.capacity());
+    assert_eq!(cache.weight(), len as u64);
+    let remaining = (0..KEYS).filter(|key| cache.peek(key).is_some()).count();
+    assert_eq!(remaining, len);
+}